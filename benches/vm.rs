@@ -0,0 +1,46 @@
+//!Interpreter loop benchmarks. Each program counts a loop down from `N`
+//!with an absolute `y` jump; addresses are zero-padded so the code's
+//!layout doesn't depend on `N`.
+
+#[macro_use]
+extern crate criterion;
+extern crate greengold;
+
+use std::hint::black_box;
+
+use criterion::Criterion;
+
+use greengold::{Data, NullExtender, Vm};
+
+const N: u32 = 100_000;
+
+fn run(code: &[u8], memory: usize) -> Vm {
+    let mut vm = Vm::new(code.to_vec(), vec![Data::Int(0); memory]);
+    vm.run(&mut NullExtender {}).unwrap();
+    vm
+}
+
+///Sum the counter into an accumulator: `( sum n -- sum' n-1 )`.
+fn arithmetic(c: &mut Criterion) {
+    let code = format!("#0'#{:06}'sv+s#1'-d#00011'y", N);
+
+    c.bench_function("arithmetic", |b| b.iter(|| run(black_box(code.as_bytes()), 0)));
+}
+
+///Call a word that touches the stack and returns, once per iteration.
+fn calls(c: &mut Criterion) {
+    let code = format!("#00011'bdr;#{:06}'#00008'c#1'-d#00019'y", N);
+
+    c.bench_function("calls", |b| b.iter(|| run(black_box(code.as_bytes()), 0)));
+}
+
+///Write and read a memory cell and allocate and free an array, once per
+///iteration.
+fn memory(c: &mut Criterion) {
+    let code = format!("#{:06}'ddWdRr#4'af#1'-d#00008'y", N);
+
+    c.bench_function("memory", |b| b.iter(|| run(black_box(code.as_bytes()), 1024)));
+}
+
+criterion_group!(benches, arithmetic, calls, memory);
+criterion_main!(benches);
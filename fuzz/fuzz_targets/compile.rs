@@ -0,0 +1,28 @@
+//!Compiles arbitrary source and runs whatever compiles, so the compiler
+//!and the code it emits are both covered.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate greengold;
+
+use std::str;
+
+use greengold::compiler::compile;
+use greengold::{run_untrusted, SandboxConfig};
+
+fuzz_target!(|source: &[u8]| {
+    let source = match str::from_utf8(source) {
+        Ok(n) => n,
+        Err(_) => { return; }
+    };
+
+    if let Ok(code) = compile(source) {
+        let limits = SandboxConfig {
+            max_steps: 10_000,
+            ..SandboxConfig::default()
+        };
+
+        let _ = run_untrusted(&code, &limits);
+    }
+});
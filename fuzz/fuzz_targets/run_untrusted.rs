@@ -0,0 +1,18 @@
+//!Runs arbitrary bytes as code. Any panic, hang or runaway allocation is
+//!a bug; errors are fine.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate greengold;
+
+use greengold::{run_untrusted, SandboxConfig};
+
+fuzz_target!(|code: &[u8]| {
+    let limits = SandboxConfig {
+        max_steps: 10_000,
+        ..SandboxConfig::default()
+    };
+
+    let _ = run_untrusted(code, &limits);
+});
@@ -0,0 +1,251 @@
+//!Typed arguments and results for extenders and host functions, and the
+//!`From`/`TryFrom` conversions between `Data` and native types behind
+//!them.
+//!
+//!`Stack::pop_args` pops a tuple of native values in stack-effect order,
+//!so `( n x -- )` is `pop_args::<(i64, f64)>()`, and `Stack::push_all`
+//!pushes one the same way. A value of the wrong type is a `TypeMismatch`
+//!and a short stack is a `StackUnderflow`, with nothing popped in either
+//!case.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::convert::TryFrom;
+
+use storage::Storage;
+use {Data, Error, Stack, TypeTag};
+
+///A native type a value can be taken as. Anything `TryFrom<Data>` with
+///`Error` as its error can be made one with a one-line impl.
+pub trait FromData: Sized {
+    fn from_data(value: Data) -> Result<Self,Error>;
+}
+
+///A native type that can be pushed as a value: anything `Into<Data>`.
+pub trait IntoData {
+    fn into_data(self) -> Data;
+}
+
+impl<T: Into<Data>> IntoData for T {
+    fn into_data(self) -> Data {self.into()}
+}
+
+impl FromData for Data {
+    fn from_data(value: Data) -> Result<Data,Error> {Ok(value)}
+}
+
+impl From<i64> for Data {
+    fn from(n: i64) -> Data {Data::Int(n)}
+}
+
+impl TryFrom<Data> for i64 {
+    type Error = Error;
+
+    fn try_from(value: Data) -> Result<i64,Error> {
+        match value {
+            Data::Int(n) => Ok(n),
+            other => Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() }),
+        }
+    }
+}
+
+impl FromData for i64 {
+    fn from_data(value: Data) -> Result<i64,Error> {i64::try_from(value)}
+}
+
+impl From<f64> for Data {
+    fn from(n: f64) -> Data {Data::Float(n)}
+}
+
+impl TryFrom<Data> for f64 {
+    type Error = Error;
+
+    fn try_from(value: Data) -> Result<f64,Error> {
+        match value {
+            Data::Float(n) => Ok(n),
+            other => Err(Error::TypeMismatch { expected: TypeTag::Float, found: other.type_tag() }),
+        }
+    }
+}
+
+impl FromData for f64 {
+    fn from_data(value: Data) -> Result<f64,Error> {f64::try_from(value)}
+}
+
+///The canonical flag; see `Data::from_bool`.
+impl From<bool> for Data {
+    fn from(n: bool) -> Data {Data::from_bool(n)}
+}
+
+///Any number is a flag; see `Data::is_truthy`.
+impl TryFrom<Data> for bool {
+    type Error = Error;
+
+    fn try_from(value: Data) -> Result<bool,Error> {value.is_truthy()}
+}
+
+impl FromData for bool {
+    fn from_data(value: Data) -> Result<bool,Error> {bool::try_from(value)}
+}
+
+impl From<Arc<str>> for Data {
+    fn from(n: Arc<str>) -> Data {Data::Str(n)}
+}
+
+impl TryFrom<Data> for Arc<str> {
+    type Error = Error;
+
+    fn try_from(value: Data) -> Result<Arc<str>,Error> {
+        match value {
+            Data::Str(n) => Ok(n),
+            other => Err(Error::TypeMismatch { expected: TypeTag::Str, found: other.type_tag() }),
+        }
+    }
+}
+
+impl FromData for Arc<str> {
+    fn from_data(value: Data) -> Result<Arc<str>,Error> {Arc::<str>::try_from(value)}
+}
+
+impl From<String> for Data {
+    fn from(n: String) -> Data {Data::Str(Arc::from(n))}
+}
+
+impl TryFrom<Data> for String {
+    type Error = Error;
+
+    fn try_from(value: Data) -> Result<String,Error> {
+        Arc::<str>::try_from(value).map(|n| String::from(&n[..]))
+    }
+}
+
+impl FromData for String {
+    fn from_data(value: Data) -> Result<String,Error> {String::try_from(value)}
+}
+
+impl From<&str> for Data {
+    fn from(n: &str) -> Data {Data::Str(Arc::from(n))}
+}
+
+///A group of values popped together.
+pub trait FromStack: Sized {
+    fn pop_from<S: Storage>(stack: &mut Stack<S>) -> Result<Self,Error>;
+}
+
+///A group of values pushed together.
+pub trait ToStack {
+    fn push_to<S: Storage>(self, stack: &mut Stack<S>) -> Result<(),Error>;
+}
+
+macro_rules! tuple {
+    ($count:expr; $($name:ident),+) => {
+        impl<$($name: FromData),+> FromStack for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn pop_from<S: Storage>(stack: &mut Stack<S>) -> Result<Self,Error> {
+                stack.require($count)?;
+                let values = stack.as_slice()[stack.len() - $count..].to_vec();
+                let mut values = values.into_iter();
+                $(let $name = $name::from_data(values.next().unwrap())?;)+
+                stack.truncate(stack.len() - $count);
+
+                Ok(($($name,)+))
+            }
+        }
+
+        impl<$($name: IntoData),+> ToStack for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn push_to<S: Storage>(self, stack: &mut Stack<S>) -> Result<(),Error> {
+                let ($($name,)+) = self;
+                $(stack.try_push($name.into_data())?;)+
+
+                Ok(())
+            }
+        }
+    }
+}
+
+tuple!(1; A);
+tuple!(2; A, B);
+tuple!(3; A, B, C);
+tuple!(4; A, B, C, D);
+tuple!(5; A, B, C, D, E);
+tuple!(6; A, B, C, D, E, F);
+
+impl<S: Storage> Stack<S> {
+    ///Pop a value as some native type.
+    pub fn pop_as<T: FromData>(&mut self) -> Result<T,Error> {
+        let (value,) = self.pop_args::<(T,)>()?;
+        Ok(value)
+    }
+
+    ///Push anything that converts to a value.
+    pub fn push_value<T: Into<Data>>(&mut self, value: T) -> Result<(),Error> {
+        self.try_push(value.into())
+    }
+
+    ///Pop an int.
+    pub fn pop_int(&mut self) -> Result<i64,Error> {self.pop_as()}
+
+    ///Pop a float. Ints are not converted.
+    pub fn pop_float(&mut self) -> Result<f64,Error> {self.pop_as()}
+
+    ///Pop a string.
+    pub fn pop_str(&mut self) -> Result<Arc<str>,Error> {self.pop_as()}
+
+    ///Pop a flag.
+    pub fn pop_bool(&mut self) -> Result<bool,Error> {self.pop_as()}
+
+    ///Pop a tuple of values, the last element from TOS.
+    pub fn pop_args<T: FromStack>(&mut self) -> Result<T,Error> {
+        T::pop_from(self)
+    }
+
+    ///Push a tuple of values, the last element ending up as TOS.
+    pub fn push_all<T: ToStack>(&mut self, values: T) -> Result<(),Error> {
+        values.push_to(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::{String, ToString};
+    use core::convert::TryFrom;
+
+    use {Data, Error, Stack, TypeTag};
+
+    #[test]
+    fn typed_arguments() {
+        let mut s = Stack::new();
+
+        s.push_all((3i64, 0.5, "hi", true)).unwrap();
+        assert_eq!(s.to_string(), "<4> 3 0.5 \"hi\" 1");
+
+        let (flag,) = s.pop_args::<(bool,)>().unwrap();
+        assert!(flag);
+        assert!(matches!(s.pop_args::<(i64, i64)>(), Err(Error::TypeMismatch { .. })));
+        assert_eq!(s.len(), 3);
+
+        let (n, x, text) = s.pop_args::<(i64, f64, String)>().unwrap();
+        assert_eq!((n, x, &text[..]), (3, 0.5, "hi"));
+
+        s.push(Data::Int(1));
+        assert!(matches!(s.pop_float(), Err(Error::TypeMismatch { expected: TypeTag::Float, found: TypeTag::Int })));
+        assert_eq!(s.pop_int().unwrap(), 1);
+        assert!(matches!(s.pop_args::<(Data,)>(), Err(Error::StackUnderflow)));
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Data::from(-4), Data::Int(-4));
+        assert_eq!(Data::from(false), Data::FALSE);
+        assert_eq!(Data::from("x"), Data::Str("x".into()));
+        assert_eq!(i64::try_from(Data::Int(9)).unwrap(), 9);
+        assert!(matches!(f64::try_from(Data::Int(9)), Err(Error::TypeMismatch { .. })));
+        assert!(bool::try_from(Data::Float(0.5)).unwrap());
+
+        let mut s = Stack::new();
+        s.push_value(2.5).unwrap();
+        s.push_value(String::from("y")).unwrap();
+        assert_eq!(s.to_string(), "<2> 2.5 \"y\"");
+    }
+}
@@ -0,0 +1,197 @@
+//!Terminal debugger for greengold modules. Run it with the path of a
+//!module or bare bytecode file. The screen shows the code around PC, with
+//!its place in the source if the module has debug info, both stacks, its
+//!variables and constants and a window of memory; each command is a key
+//!followed by enter:
+//!
+//!* `s`: step one instruction.
+//!* `u`: undo the last instruction, stepping back.
+//!* `c`: continue to the next breakpoint or watchpoint.
+//!* `b N`: toggle a breakpoint at address N, or at PC without N.
+//!* `w N`: toggle a watchpoint on writes to memory cell N, which may be
+//!  the name of a variable.
+//!* `m N`: show memory from address N.
+//!* `r`: reset to the start.
+//!* `q`: quit.
+
+extern crate greengold;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::io::Write;
+use std::process;
+
+use greengold::debug::{Debugger, Stop, Watch};
+use greengold::disasm::decode;
+use greengold::module::{Global, Module};
+use greengold::{Data, NullExtender, Vm};
+
+const MEMORY_CELLS: usize = 1024;
+const CODE_LINES: usize = 7;
+const MEMORY_LINES: usize = 4;
+const MEMORY_COLUMNS: usize = 4;
+const RSTACK_ITEMS: usize = 8;
+
+fn draw(debugger: &Debugger, globals: &BTreeMap<String, Global>, memory_base: usize, message: &str) {
+    let vm = &debugger.vm;
+    let breakpoints: Vec<usize> = debugger.breakpoints().collect();
+
+    //Clear the screen and home the cursor.
+    print!("\x1b[2J\x1b[H");
+
+    match vm.locate(vm.pc) {
+        Some(location) => match location.word {
+            Some(ref word) => println!("-- code -- {} in {}", location, word),
+            None => println!("-- code -- {}", location),
+        },
+        None => println!("-- code --"),
+    }
+    let listing: Vec<_> = decode(vm.code()).collect();
+    let current = listing.iter().position(|&(addr, _)| addr >= vm.pc).unwrap_or(listing.len());
+    let first = current.saturating_sub(CODE_LINES / 2);
+    for &(addr, ref instruction) in listing.iter().skip(first).take(CODE_LINES) {
+        let marker = if addr == vm.pc { '>' } else { ' ' };
+        let breakpoint = if breakpoints.contains(&addr) { '*' } else { ' ' };
+        println!("{}{} {:04}  {}", marker, breakpoint, addr, instruction);
+    }
+
+    println!("-- stack --");
+    println!("{}", vm.stack);
+
+    println!("-- return stack --");
+    //Only the innermost calls fit on a line.
+    let skipped = vm.rstack.len().saturating_sub(RSTACK_ITEMS);
+    let mut rstack: Vec<String> = vm.rstack[skipped..].iter().map(|n| format!("{:04}", n)).collect();
+    if skipped > 0 {
+        rstack.insert(0, String::from(".."));
+    }
+    print!("<{}>", vm.rstack.len());
+    for item in rstack {
+        print!(" {}", item);
+    }
+    println!();
+
+    if !globals.is_empty() {
+        println!("-- globals --");
+        for (name, global) in globals {
+            match *global {
+                Global::Variable(address) => match vm.memory.get(address) {
+                    Some(value) => println!("{:<16} {:04}  {}", name, address, value),
+                    None => println!("{:<16} {:04}", name, address),
+                },
+                Global::Constant(ref value) => println!("{:<16} const {}", name, value),
+            }
+        }
+    }
+
+    println!("-- memory --");
+    for row in 0..MEMORY_LINES {
+        let start = memory_base + row * MEMORY_COLUMNS;
+        if start >= vm.memory.len() {
+            break;
+        }
+        let cells: Vec<String> = vm.memory[start..].iter().take(MEMORY_COLUMNS).map(|n| format!("{:>12}", n.to_string())).collect();
+        println!("{:04}  {}", start, cells.join(" "));
+    }
+
+    println!("{}", message);
+    print!("[s]tep [u]ndo [c]ontinue [b]reak [w]atch [m]emory [r]eset [q]uit > ");
+    let _ = io::stdout().flush();
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(n) => n,
+        None => {
+            eprintln!("usage: greengold-debug <module>");
+            process::exit(2);
+        },
+    };
+
+    let module = match Module::load(&path) {
+        Ok(n) => n,
+        Err(n) => {
+            eprintln!("error: {}", n);
+            process::exit(1);
+        },
+    };
+
+    let globals = module.globals.clone();
+    let vm = match Vm::from_module(module, vec![Data::Int(0); MEMORY_CELLS]) {
+        Ok(n) => n,
+        Err(n) => {
+            eprintln!("error: {}", n);
+            process::exit(1);
+        },
+    };
+
+    let mut debugger = Debugger::new(vm);
+    let mut extender = NullExtender {};
+    let mut memory_base = 0;
+    let mut message = String::new();
+
+    loop {
+        draw(&debugger, &globals, memory_base, &message);
+        message.clear();
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        }
+
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("s");
+        let word = words.next();
+        let argument = word.and_then(|n| n.parse::<usize>().ok());
+
+        match command {
+            "s" => {
+                if let Err(n) = debugger.step(&mut extender) {
+                    message = format!("error: {}", n);
+                }
+            },
+            "u" => {
+                match debugger.step_back() {
+                    Ok(true) => {},
+                    Ok(false) => { message = String::from("no history"); },
+                    Err(n) => { message = format!("error: {}", n); },
+                }
+            },
+            "c" => {
+                message = match debugger.resume(&mut extender) {
+                    Ok(Stop::Breakpoint(n)) => format!("breakpoint at {:04}", n),
+                    Ok(Stop::Watchpoint(n)) if n.write => format!("{:04} wrote {} to {:04}, was {}", n.pc, n.new, n.addr, n.old),
+                    Ok(Stop::Watchpoint(n)) => format!("{:04} read {} from {:04}", n.pc, n.new, n.addr),
+                    Ok(Stop::Halted) => String::from("halted"),
+                    Err(n) => format!("error: {}", n),
+                };
+            },
+            "b" => {
+                let pc = argument.unwrap_or(debugger.vm.pc);
+                if !debugger.clear_breakpoint(pc) {
+                    debugger.set_breakpoint(pc);
+                }
+            },
+            "w" => {
+                let addr = match argument {
+                    Some(n) => Some(n),
+                    None => word.and_then(|n| debugger.vm.variable(n)),
+                };
+                match addr {
+                    Some(n) => if !debugger.clear_watchpoint(n) {
+                        debugger.set_watchpoint(n, Watch::Write);
+                    },
+                    None => { message = String::from("watch what?"); },
+                }
+            },
+            "m" => { memory_base = argument.unwrap_or(0); },
+            "r" => { debugger.reset(); },
+            "q" => break,
+            _ => { message = format!("unknown command: {}", command); },
+        }
+    }
+
+    println!();
+}
@@ -0,0 +1,77 @@
+//!Language server for greengold source. Editors start it and talk to it
+//!over standard input and output; see `lsp` for what it can do.
+
+extern crate greengold;
+extern crate serde_json;
+
+use std::io;
+use std::io::{BufRead, Write};
+use std::process;
+
+use greengold::lsp::Server;
+
+///Read a message framed by a `Content-Length` header, or `None` at the
+///end of input.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            length = n.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = match length {
+        Some(n) => n,
+        None => { return Err(io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length")); }
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(body))
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut server = Server::new();
+
+    loop {
+        let body = match read_message(&mut input) {
+            Ok(Some(n)) => n,
+            Ok(None) => break,
+            Err(n) => {
+                eprintln!("error: {}", n);
+                process::exit(1);
+            },
+        };
+
+        let message = match serde_json::from_slice(&body) {
+            Ok(n) => n,
+            Err(n) => {
+                eprintln!("error: {}", n);
+                continue;
+            },
+        };
+
+        for reply in server.handle(&message) {
+            let reply = reply.to_string();
+            let _ = write!(output, "Content-Length: {}\r\n\r\n{}", reply.len(), reply);
+            let _ = output.flush();
+        }
+
+        if let Some(shut_down) = server.exited() {
+            process::exit(if shut_down { 0 } else { 1 });
+        }
+    }
+}
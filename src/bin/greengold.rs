@@ -0,0 +1,307 @@
+//!Interactive greengold session. Each line is compiled and run against
+//!the same machine, then the stack is printed. Pass `--bytecode` to type
+//!raw bytecode instead of Forth.
+//!
+//!Subcommands work on files. A file ending in `.gg` or starting with a
+//!`#!` line is taken as source and compiled first; anything else is read
+//!as a module, or as bare code if it has no module header.
+//!
+//!- `greengold run FILE [--memory N] [--fuel N] [ARG...]` runs a program
+//!  with N cells of memory, stopping after N steps. Any other arguments,
+//!  and all of those after a `--`, are left for the program, which can
+//!  read them and the environment with the words
+//!  `HostFunctions::bind_process` binds. `greengold FILE` is short for
+//!  this, so a script starting `#!/usr/bin/env greengold` can be run
+//!  directly.
+//!- `greengold compile FILE.gg [-o FILE.ggb]` writes a module, by
+//!  default next to the source.
+//!- `greengold disasm FILE` prints a listing.
+//!- `greengold check FILE` runs the validator, failing if it finds a bad
+//!  jump or a broken literal.
+//!
+//!`greengold fmt FILE...` lays source files out canonically, in place;
+//!see `format`. With no files it formats standard input to standard
+//!output. With `--check` nothing is written, and the files that aren't
+//!formatted are listed and the exit status is 1.
+
+extern crate greengold;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+
+use greengold::compiler::Compiler;
+use greengold::disasm::disasm_module;
+use greengold::format::format_source;
+use greengold::host::HostFunctions;
+use greengold::module::Module;
+use greengold::validate::validate;
+use greengold::{Data, NullExtender, Vm};
+
+const MEMORY_CELLS: usize = 1024;
+
+const USAGE: &str = "\
+usage: greengold [--bytecode]
+       greengold [run] FILE [--memory N] [--fuel N] [ARG...]
+       greengold compile FILE.gg [-o FILE.ggb]
+       greengold disasm FILE
+       greengold check FILE
+       greengold fmt [--check] [FILE...]";
+
+///Print an error and exit.
+fn fail<T: std::fmt::Display>(message: T) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+///The host words scripts can call, reading some arguments. Compiling
+///binds the same words in the same order, so a compiled script calls
+///them by the right index.
+fn process(args: Vec<String>) -> HostFunctions {
+    let mut host = HostFunctions::new();
+    host.bind_process(args);
+
+    host
+}
+
+///Read a module, compiling it first if the file is source.
+fn load(file: &str) -> Module {
+    let bytes = fs::read(file).unwrap_or_else(|n| fail(format!("{}: {}", file, n)));
+    let shebang = bytes.starts_with(b"#!");
+    let source = shebang || Path::new(file).extension().is_some_and(|n| n == "gg");
+    if !source {
+        return Module::parse(&bytes).unwrap_or_else(|n| fail(format!("{}: {}", file, n)));
+    }
+
+    let mut source = String::from_utf8(bytes).unwrap_or_else(|n| fail(format!("{}: {}", file, n)));
+    if shebang {
+        //Blanked rather than removed, so positions in errors still match
+        //the file.
+        let end = source.find('\n').unwrap_or(source.len());
+        source.replace_range(..end, &" ".repeat(end));
+    }
+
+    let mut compiler = Compiler::new();
+    compiler.use_host(&process(Vec::new()));
+    match compiler.compile(&source) {
+        Ok(_) => compiler.into_module(),
+        Err(n) => fail(format!("{}: {}", file, n)),
+    }
+}
+
+///Split a subcommand's arguments into the file and the options, each
+///with the value after it.
+fn parse<'a>(args: &'a [String], options: &[&str]) -> (&'a str, Vec<(&'a str, &'a str)>) {
+    let mut file = None;
+    let mut values = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if options.contains(&&arg[..]) {
+            match args.next() {
+                Some(value) => values.push((&arg[..], &value[..])),
+                None => usage(),
+            }
+        } else if file.is_none() && !arg.starts_with('-') {
+            file = Some(&arg[..]);
+        } else {
+            usage();
+        }
+    }
+
+    match file {
+        Some(n) => (n, values),
+        None => usage(),
+    }
+}
+
+fn number(value: &str) -> u64 {
+    value.parse().unwrap_or_else(|_| usage())
+}
+
+fn run(args: &[String]) {
+    let mut file = None;
+    let mut rest = Vec::new();
+    let mut memory = MEMORY_CELLS;
+    let mut fuel = None;
+
+    //Options are read up to a `--`; the program gets everything after
+    //it, and anything else after its file.
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--" => {
+                rest.extend(args.by_ref().cloned());
+            },
+            "--memory" => memory = number(args.next().unwrap_or_else(|| usage())) as usize,
+            "--fuel" => fuel = Some(number(args.next().unwrap_or_else(|| usage()))),
+            _ if file.is_some() => rest.push(arg.clone()),
+            _ if arg.starts_with('-') => usage(),
+            _ => file = Some(arg.clone()),
+        }
+    }
+    let file = file.unwrap_or_else(|| usage());
+    rest.insert(0, file.clone());
+
+    let mut vm = Vm::from_module(load(&file), vec![Data::Int(0); memory]).unwrap_or_else(|n| fail(n));
+    vm.host = process(rest);
+    vm.config.max_steps = fuel;
+    if let Err(n) = vm.run(&mut NullExtender {}) {
+        let _ = io::stdout().flush();
+        eprintln!("error: {} {}", n, vm.stack);
+        if n.backtrace().len() > 1 {
+            for frame in n.backtrace() {
+                eprintln!("  {}", frame);
+            }
+        }
+        process::exit(1);
+    }
+}
+
+fn compile(args: &[String]) {
+    let (file, options) = parse(args, &["-o"]);
+    let out = match options.last() {
+        Some(&(_, n)) => n.to_string(),
+        None => Path::new(file).with_extension("ggb").to_string_lossy().into_owned(),
+    };
+    if out == file {
+        fail(format!("{}: would overwrite the source", file));
+    }
+
+    if let Err(n) = fs::write(&out, load(file).serialize()) {
+        fail(format!("{}: {}", out, n));
+    }
+}
+
+fn disasm(args: &[String]) {
+    let (file, _) = parse(args, &[]);
+    print!("{}", disasm_module(&load(file)));
+}
+
+fn check(args: &[String]) {
+    let (file, _) = parse(args, &[]);
+    if let Err(n) = validate(&load(file).code) {
+        fail(format!("{}: {}", file, n));
+    }
+}
+
+fn fmt(args: &[String]) {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+
+    if files.is_empty() {
+        let mut source = String::new();
+        if let Err(n) = io::stdin().read_to_string(&mut source) {
+            eprintln!("error: {}", n);
+            process::exit(1);
+        }
+        let formatted = format_source(&source);
+        if check {
+            process::exit((formatted != source) as i32);
+        }
+        print!("{}", formatted);
+        return;
+    }
+
+    let mut unformatted = false;
+    for file in files {
+        let source = match fs::read_to_string(file) {
+            Ok(n) => n,
+            Err(n) => {
+                eprintln!("error: {}: {}", file, n);
+                process::exit(1);
+            },
+        };
+        let formatted = format_source(&source);
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("{}", file);
+            unformatted = true;
+        } else if let Err(n) = fs::write(file, formatted) {
+            eprintln!("error: {}: {}", file, n);
+            process::exit(1);
+        }
+    }
+
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|n| &n[..]) {
+        Some("run") => { return run(&args[1..]); },
+        Some("compile") => { return compile(&args[1..]); },
+        Some("disasm") => { return disasm(&args[1..]); },
+        Some("check") => { return check(&args[1..]); },
+        Some("fmt") => { return fmt(&args[1..]); },
+        Some("--bytecode") | None => {},
+        Some(n) if n.starts_with('-') => usage(),
+        Some(_) => { return run(&args); },
+    }
+
+    let bytecode = !args.is_empty();
+
+    let mut vm = Vm::new(Vec::new(), vec![Data::Int(0); MEMORY_CELLS]);
+    let mut compiler = Compiler::new();
+    let mut extender = NullExtender {};
+
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+
+        //Programs may read standard input too, so only lock it per line.
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+
+        let loaded = if bytecode {
+            vm.load(line.into_bytes())
+        } else {
+            compiler.assume_depth(vm.stack.len());
+            match compiler.compile(&line) {
+                Ok(entry) => {
+                    let loaded = vm.load(compiler.module().code.clone());
+                    vm.dictionary = compiler.module().dictionary.clone();
+                    vm.debug_info = compiler.module().debug_info.clone();
+                    vm.constants = compiler.module().constants.clone();
+                    vm.pc = entry;
+                    loaded
+                },
+                Err(n) => {
+                    println!("error: {}", n);
+                    continue;
+                },
+            }
+        };
+
+        match loaded.and_then(|_| vm.run(&mut extender)) {
+            Ok(()) => println!("ok {}", vm.stack),
+            Err(n) => {
+                println!("error: {} {}", n, vm.stack);
+                //Only worth showing once the error is inside a word.
+                if n.backtrace().len() > 1 {
+                    for frame in n.backtrace() {
+                        println!("  {}", frame);
+                    }
+                }
+            },
+        }
+    }
+
+    println!();
+}
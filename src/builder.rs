@@ -0,0 +1,420 @@
+//!Building bytecode from Rust, for tests and for hosts that generate code.
+//!
+//!`CodeBuilder` writes literals and opcodes and resolves named labels for
+//!jumps and calls:
+//!
+//!```
+//!# use greengold::builder::CodeBuilder;
+//!let code = CodeBuilder::new()
+//!    .lit_int(10)
+//!    .label("loop")
+//!    .lit_int(1).sub().dup().jump_if("loop")
+//!    .build()
+//!    .unwrap();
+//!```
+//!
+//!Jumps and calls are relative, like the compiler's, so the code can be
+//!appended to other code. The `greengold!` macro writes the same thing
+//!inline, one method per word with literals pushed as they are:
+//!
+//!```
+//!# #[macro_use] extern crate greengold;
+//!# fn main() {
+//!let code = greengold! { 10 label("loop") 1 sub dup jump_if("loop") }.unwrap();
+//!# }
+//!```
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use compiler;
+use module::Module;
+use opcodes::ESCAPE;
+
+///Build bytecode inline with a `CodeBuilder`, giving a
+///`Result<Vec<u8>,BuildError>`. Each literal is pushed, and each name is
+///a builder method, with its arguments in parentheses if it takes any.
+#[macro_export]
+macro_rules! greengold {
+    (@build $builder:ident) => {};
+    (@build $builder:ident $value:literal $($rest:tt)*) => {
+        $builder.lit($value);
+        greengold!(@build $builder $($rest)*);
+    };
+    (@build $builder:ident $method:ident ($($arg:expr),*) $($rest:tt)*) => {
+        $builder.$method($($arg),*);
+        greengold!(@build $builder $($rest)*);
+    };
+    (@build $builder:ident $method:ident $($rest:tt)*) => {
+        $builder.$method();
+        greengold!(@build $builder $($rest)*);
+    };
+    ($($body:tt)*) => {{
+        let mut builder = $crate::builder::CodeBuilder::new();
+        greengold!(@build builder $($body)*);
+        builder.build()
+    }};
+}
+
+///Something wrong with the code a builder was given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    ///A jump or call to a label that was never placed.
+    UnknownLabel(String),
+    ///A label placed twice.
+    DuplicateLabel(String),
+    ///A number with no literal form, like `i64::MIN` or infinity.
+    InvalidNumber(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::UnknownLabel(ref n) => write!(f, "Unknown label: {}", n),
+            BuildError::DuplicateLabel(ref n) => write!(f, "Duplicate label: {}", n),
+            BuildError::InvalidNumber(ref n) => write!(f, "Invalid number: {}", n),
+        }
+    }
+}
+
+enum Item {
+    Code(Vec<u8>),
+    ///A relative jump or call opcode and the label it goes to.
+    Jump(u8, String),
+    Label(String),
+}
+
+///Builds bytecode a word at a time. Problems are kept until `build`, so
+///calls can be chained.
+#[derive(Default)]
+pub struct CodeBuilder {
+    items: Vec<Item>,
+    labels: BTreeSet<String>,
+    error: Option<BuildError>,
+}
+
+///Values that can be pushed as literals.
+pub trait Literal {
+    fn push(self, builder: &mut CodeBuilder) -> &mut CodeBuilder;
+}
+
+impl Literal for i32 {
+    fn push(self, builder: &mut CodeBuilder) -> &mut CodeBuilder {builder.lit_int(self as i64)}
+}
+
+impl Literal for i64 {
+    fn push(self, builder: &mut CodeBuilder) -> &mut CodeBuilder {builder.lit_int(self)}
+}
+
+impl Literal for f64 {
+    fn push(self, builder: &mut CodeBuilder) -> &mut CodeBuilder {builder.lit_float(self)}
+}
+
+impl Literal for &str {
+    fn push(self, builder: &mut CodeBuilder) -> &mut CodeBuilder {builder.lit_str(self)}
+}
+
+macro_rules! opcodes {
+    ($($(#[$doc:meta])* $name:ident => $op:expr,)*) => {
+        $(
+            $(#[$doc])*
+            pub fn $name(&mut self) -> &mut CodeBuilder {self.op($op)}
+        )*
+    }
+}
+
+impl CodeBuilder {
+    ///Start with no code.
+    pub fn new() -> CodeBuilder {
+        CodeBuilder::default()
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> &mut CodeBuilder {
+        if let Some(&mut Item::Code(ref mut code)) = self.items.last_mut() {
+            code.extend_from_slice(bytes);
+            return self;
+        }
+        self.items.push(Item::Code(bytes.to_vec()));
+        self
+    }
+
+    fn fail(&mut self, error: BuildError) -> &mut CodeBuilder {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+        self
+    }
+
+    ///Emit any opcode.
+    pub fn op(&mut self, op: u8) -> &mut CodeBuilder {
+        self.emit(&[op])
+    }
+
+    ///Emit an opcode from the extended page, after a `!`.
+    pub fn extended_op(&mut self, op: u8) -> &mut CodeBuilder {
+        self.emit(&[ESCAPE, op])
+    }
+
+    ///Push an int.
+    pub fn lit_int(&mut self, value: i64) -> &mut CodeBuilder {
+        //The digits are built up as a positive number, so the most
+        //negative int has no literal.
+        let digits = match value.checked_abs() {
+            Some(n) => n,
+            None => { return self.fail(BuildError::InvalidNumber(value.to_string())); }
+        };
+        let sign = if value < 0 { "$" } else { "" };
+        self.emit(format!("#{}{}'", digits, sign).as_bytes())
+    }
+
+    ///Push a float, given in full so it is exact.
+    pub fn lit_float(&mut self, value: f64) -> &mut CodeBuilder {
+        self.emit(&compiler::float(value))
+    }
+
+    ///Push a string.
+    pub fn lit_str(&mut self, text: &str) -> &mut CodeBuilder {
+        self.emit(&compiler::string(text))
+    }
+
+    ///Push any literal.
+    pub fn lit<T: Literal>(&mut self, value: T) -> &mut CodeBuilder {
+        value.push(self)
+    }
+
+    ///Place a label at the end of the code so far.
+    pub fn label(&mut self, name: &str) -> &mut CodeBuilder {
+        if !self.labels.insert(String::from(name)) {
+            return self.fail(BuildError::DuplicateLabel(String::from(name)));
+        }
+        self.items.push(Item::Label(String::from(name)));
+        self
+    }
+
+    fn jump_to(&mut self, op: u8, label: &str) -> &mut CodeBuilder {
+        self.items.push(Item::Jump(op, String::from(label)));
+        self
+    }
+
+    ///Jump to a label.
+    pub fn jump(&mut self, label: &str) -> &mut CodeBuilder {self.jump_to(b'B', label)}
+
+    ///Pop a flag and jump to a label if it is non-zero.
+    pub fn jump_if(&mut self, label: &str) -> &mut CodeBuilder {self.jump_to(b'Y', label)}
+
+    ///Pop a flag and jump to a label if it is zero.
+    pub fn jump_if_zero(&mut self, label: &str) -> &mut CodeBuilder {self.jump_to(b'Z', label)}
+
+    ///Step the innermost counted loop's index by 1 and jump to a label
+    ///unless it reached the limit.
+    pub fn loop_to(&mut self, label: &str) -> &mut CodeBuilder {self.jump_to(b'J', label)}
+
+    ///Pop a step, add it to the innermost counted loop's index and jump
+    ///to a label unless it crossed the limit.
+    pub fn plus_loop_to(&mut self, label: &str) -> &mut CodeBuilder {self.jump_to(b'j', label)}
+
+    ///Call the code at a label.
+    pub fn call_label(&mut self, label: &str) -> &mut CodeBuilder {self.jump_to(b'C', label)}
+
+    ///Start a frame whose locals are the top `count` items.
+    pub fn enter_frame(&mut self, count: usize) -> &mut CodeBuilder {
+        self.lit_int(count as i64).extended_op(b'{')
+    }
+
+    ///Drop the current frame of locals.
+    pub fn leave_frame(&mut self) -> &mut CodeBuilder {self.extended_op(b'}')}
+
+    ///Push a local of the current frame.
+    pub fn read_local(&mut self, index: usize) -> &mut CodeBuilder {
+        self.lit_int(index as i64).extended_op(b'R')
+    }
+
+    ///Pop a value into a local of the current frame.
+    pub fn write_local(&mut self, index: usize) -> &mut CodeBuilder {
+        self.lit_int(index as i64).extended_op(b'W')
+    }
+
+    ///Call a word by name, resolved by `Vm::link`.
+    pub fn call_word(&mut self, name: &str) -> &mut CodeBuilder {
+        self.emit(format!("`{}`", name).as_bytes())
+    }
+
+    opcodes! {
+        add => b'+',
+        sub => b'-',
+        mul => b'*',
+        div => b'/',
+        rem => b'%',
+        lt => b'<',
+        eq => b'=',
+        gt => b'>',
+        and => b'&',
+        or => b'|',
+        xor => b'^',
+        not => b'~',
+        dup => b'd',
+        drop => b'r',
+        swap => b's',
+        over => b'v',
+        rot => b'o',
+        nip => b'n',
+        tuck => b't',
+        ///Read a memory cell.
+        read => b'R',
+        ///Write a memory cell.
+        write => b'W',
+        ///Print a number and a space.
+        print => b',',
+        emit_char => b'e',
+        ///Print a string.
+        type_str => b'T',
+        ///Start a counted loop from a limit and a first index.
+        do_loop => b':',
+        ///Return, or stop at the top level.
+        ret => b';',
+        ///End the running task's turn.
+        pause => b'w',
+        ///Hand control back to the host.
+        yield_now => b'i',
+    }
+
+    ///Choose how many digits to write offsets with, widening until every
+    ///address fits, and find the address of each label.
+    fn layout(&self) -> (usize, BTreeMap<&str, usize>) {
+        let mut width = 1;
+        loop {
+            let mut labels = BTreeMap::new();
+            let mut offset = 0;
+            for item in &self.items {
+                match *item {
+                    Item::Code(ref code) => { offset += code.len(); },
+                    Item::Jump(..) => { offset += width + 4; },
+                    Item::Label(ref name) => { labels.insert(name.as_str(), offset); },
+                }
+            }
+            if offset.to_string().len() <= width {
+                return (width, labels);
+            }
+            width += 1;
+        }
+    }
+
+    ///Resolve the labels and produce the code.
+    pub fn build(&self) -> Result<Vec<u8>,BuildError> {
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+
+        let (width, labels) = self.layout();
+        let mut out = Vec::new();
+        for item in &self.items {
+            let (op, label) = match *item {
+                Item::Code(ref code) => {
+                    out.extend_from_slice(code);
+                    continue;
+                },
+                Item::Jump(op, ref label) => (op, label),
+                Item::Label(_) => { continue; }
+            };
+
+            let target = match labels.get(label.as_str()) {
+                Some(&n) => n,
+                None => { return Err(BuildError::UnknownLabel(label.clone())); }
+            };
+
+            //The offset counts from the end of the jump; the sign slot is
+            //a space when the offset is positive.
+            let next = (out.len() + width + 4) as i64;
+            let offset = target as i64 - next;
+            let sign = if offset < 0 { '$' } else { ' ' };
+            out.extend_from_slice(format!("#{:02$}{}'", offset.abs(), sign, width).as_bytes());
+            out.push(op);
+        }
+
+        Ok(out)
+    }
+
+    ///Build a module whose dictionary holds the address of every label.
+    pub fn build_module(&self) -> Result<Module,BuildError> {
+        let mut module = Module {
+            code: self.build()?,
+            ..Module::default()
+        };
+        for (name, address) in self.layout().1 {
+            module.dictionary.insert(name, address);
+        }
+
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use builder::{BuildError, CodeBuilder};
+    use {Data, NullExtender, Vm};
+
+    fn run(code: Vec<u8>) -> Vm {
+        let mut vm = Vm::new(code, vec![Data::Int(0); 4]);
+        vm.run(&mut NullExtender {}).unwrap();
+        vm
+    }
+
+    #[test]
+    fn labels() {
+        //Sum 1 to 10, with the loop body in a word.
+        let code = CodeBuilder::new()
+            .lit_int(0).lit_int(10)
+            .label("loop")
+            .call_label("step")
+            .lit_int(1).sub().dup().jump_if("loop")
+            .drop().jump("done")
+            .label("step")
+            .swap().over().add().swap().ret()
+            .label("done")
+            .build()
+            .unwrap();
+        let mut vm = run(code);
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(55))));
+
+        //Wide enough offsets to need more than one digit.
+        let mut builder = CodeBuilder::new();
+        builder.jump("end");
+        for _ in 0..200 {
+            builder.lit_int(1).drop();
+        }
+        builder.label("end").lit(-2.5).lit("x");
+        let mut vm = run(builder.build().unwrap());
+        assert!(matches!(vm.stack.pop(), Ok(Data::Str(ref n)) if &**n == "x"));
+        assert!(matches!(vm.stack.pop(), Ok(Data::Float(n)) if n == -2.5));
+        assert_eq!(vm.stack.len(), 0);
+
+        let module = CodeBuilder::new().lit_int(1).label("here").ret().build_module().unwrap();
+        assert_eq!(module.dictionary.get("here"), Some(3));
+    }
+
+    #[test]
+    fn inline() {
+        let code = greengold! { 0 10 label("loop") 1 sub dup jump_if("loop") 4.0 "ok" }.unwrap();
+        let mut vm = run(code);
+        assert!(matches!(vm.stack.pop(), Ok(Data::Str(ref n)) if &**n == "ok"));
+        assert!(matches!(vm.stack.pop(), Ok(Data::Float(n)) if n == 4.0));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(CodeBuilder::new().jump("nowhere").build(), Err(BuildError::UnknownLabel(String::from("nowhere"))));
+        assert_eq!(CodeBuilder::new().label("a").label("a").build(), Err(BuildError::DuplicateLabel(String::from("a"))));
+        assert!(matches!(CodeBuilder::new().lit_int(i64::MIN).build(), Err(BuildError::InvalidNumber(_))));
+
+        //Floats are exact, whatever their digits.
+        let mut vm = run(CodeBuilder::new().lit_float(f64::INFINITY).lit(0.1).lit(1e-300).build().unwrap());
+        assert!(matches!(vm.stack.pop(), Ok(Data::Float(n)) if n == 1e-300));
+        assert!(matches!(vm.stack.pop(), Ok(Data::Float(n)) if n == 0.1));
+        assert!(matches!(vm.stack.pop(), Ok(Data::Float(n)) if n == f64::INFINITY));
+    }
+}
@@ -0,0 +1,1585 @@
+//!A compiler from conventional Forth-style source to greengold bytecode.
+//!
+//!Top-level code is compiled first and finished with a return, so running
+//!from PC 0 executes it and stops. Word definitions are laid out after it.
+//!A call straight before a return, as at the end of a word or before
+//!`exit`, is compiled as a jump, so tail-recursive words run in constant
+//!return stack space.
+//!
+//!`flag if ... else ... then` runs one branch or the other, and `begin ...
+//!flag until`, `begin ... again` and `begin ... flag while ... repeat`
+//!loop. A structure that isn't closed, or is closed by the wrong word, is
+//!a compile error.
+//!
+//!`limit first do ... loop` runs its body with the index `i` counting from
+//!`first` up to `limit - 1`, keeping the limit and index on the return
+//!stack; `+loop` steps by a count it pops instead of 1. `leave` jumps out
+//!of the innermost loop, `j` is the index of the loop around it, and
+//!`unloop` drops the innermost loop's state before an `exit`.
+//!
+//!`variable count` gives a memory cell a name, which pushes its address,
+//!so `count @` and `count !` read and write it. `42 constant answer`
+//!names a value. Cells are allocated at compile time as the module's
+//!initial memory, and both kinds of name are kept in its globals.
+//!
+//!`: hyp2 { a b -- } a a * b b * + ;` moves the top two items into the
+//!locals `a` and `b` of a new frame, which lasts until the word returns.
+//!A local's name pushes its value and `to a` pops a new one into it. A
+//!word may declare its locals once, outside of any control structure,
+//!and a call before its return isn't made a jump, since the frame has to
+//!be dropped first.
+//!
+//!`catch name` calls a word and pushes 0 if it returns, or, if it fails,
+//!puts the stack back as deep as it was and pushes the error's code.
+//!`code throw` fails with a code of the program's own, unless it is 0.
+//!
+//!`c@` and `c!` read and write the machine's byte memory, `from to len
+//!cmove` copies within it and `addr len byte fill` sets a range of it.
+//!`string@` turns an address and a length into a string, and `string!`
+//!copies a string's bytes to an address and pushes how many there were.
+//!
+//!Floats, and ints with more digits than it takes to refer to one, go in
+//!the module's constant pool and are pushed with `\`. Bare bytecode has
+//!nowhere to keep a pool, so `compile` builds ints from digits and gives
+//!floats in full after a `]`, which keeps them exact.
+//!
+//!The stack effect of each word is worked out as it is compiled, from the
+//!effects of the opcodes and words it uses. A stack comment straight after
+//!the name, like `: square ( n -- n*n ) dup * ;`, is checked against it.
+//!
+//!`tokenize` splits source into tokens the way the compiler reads it, for
+//!editors and playgrounds to highlight, and never fails.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use core::slice;
+
+use debuginfo::DebugInfo;
+use host::HostFunctions;
+use module::{Global, Module};
+use opcodes::{extended, ESCAPE};
+use storage::Storage;
+use validate::{opcode_effect, StackEffect};
+use Data;
+
+///Something wrong with the source text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    ///A word that is neither built in nor defined earlier.
+    UnknownWord(String),
+    ///A numeric literal that can't be represented.
+    InvalidNumber(String),
+    ///A `:`, `variable` or `constant` without a name after it.
+    MissingName,
+    ///A `:`, `variable` or `constant` inside a definition.
+    NestedDefinition,
+    ///A `;` outside of a definition.
+    UnexpectedSemicolon,
+    ///The source ended inside a definition.
+    UnterminatedDefinition,
+    ///The source ended inside a comment.
+    UnterminatedComment,
+    ///The source ended inside a string literal.
+    UnterminatedString,
+    ///A `{` outside of a definition, inside a control structure, or in a
+    ///word that already has locals.
+    MisplacedLocals,
+    ///A `{` with no `}` to end its list of locals.
+    UnterminatedLocals,
+    ///A word that closes a control structure, like `loop`, with none
+    ///open for it to close.
+    UnmatchedControl(String),
+    ///A definition or the source that ended with a control structure
+    ///still open. Names the word that opened it.
+    UnclosedControl(String),
+    ///A word whose stack comment doesn't match what its body does.
+    StackEffectMismatch { word: String, declared: StackEffect, inferred: StackEffect },
+    ///A word that takes more items than its stack comment says, or
+    ///top-level code that takes more than `Compiler::assume_depth`
+    ///allows. Names the word, or `None` for top-level code.
+    StackUnderflow(Option<String>),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompileError::UnknownWord(ref w) => write!(f, "Unknown word: {}", w),
+            CompileError::InvalidNumber(ref w) => write!(f, "Invalid number: {}", w),
+            CompileError::MissingName => write!(f, "Missing name"),
+            CompileError::NestedDefinition => write!(f, "Nested definition"),
+            CompileError::UnexpectedSemicolon => write!(f, "';' outside of a definition"),
+            CompileError::UnterminatedDefinition => write!(f, "Unterminated definition"),
+            CompileError::UnterminatedComment => write!(f, "Unterminated comment"),
+            CompileError::UnterminatedString => write!(f, "Unterminated string"),
+            CompileError::MisplacedLocals => write!(f, "Misplaced '{{'"),
+            CompileError::UnterminatedLocals => write!(f, "Unterminated locals"),
+            CompileError::UnmatchedControl(ref w) => write!(f, "'{}' without a structure to close", w),
+            CompileError::UnclosedControl(ref w) => write!(f, "Unclosed '{}'", w),
+            CompileError::StackEffectMismatch { ref word, declared, inferred } => {
+                write!(f, "Stack effect of {} is {}, not {}", word, inferred, declared)
+            },
+            CompileError::StackUnderflow(Some(ref w)) => write!(f, "Stack underflow in {}", w),
+            CompileError::StackUnderflow(None) => write!(f, "Stack underflow"),
+        }
+    }
+}
+
+///The built-in words and their opcodes.
+pub(crate) const BUILTINS: &[(&str, u8)] = &[
+    ("+", b'+'),
+    ("-", b'-'),
+    ("*", b'*'),
+    ("/", b'/'),
+    ("mod", b'%'),
+    ("dup", b'd'),
+    ("drop", b'r'),
+    ("swap", b's'),
+    ("over", b'v'),
+    ("rot", b'o'),
+    ("-rot", b'u'),
+    ("nip", b'n'),
+    ("tuck", b't'),
+    ("2dup", b'D'),
+    ("2swap", b'S'),
+    ("pick", b'P'),
+    ("roll", b'O'),
+    ("@", b'R'),
+    ("!", b'W'),
+    (".", b','),
+    ("emit", b'e'),
+    ("type", b'T'),
+    ("key", b'K'),
+    ("array", b'a'),
+    ("array@", b'g'),
+    ("array!", b'x'),
+    ("array-length", b'q'),
+    ("array-free", b'f'),
+    ("map", b'M'),
+    ("map@", b'G'),
+    ("map!", b'V'),
+    ("map-remove", b'E'),
+    ("map-size", b'm'),
+    ("map-free", b'f'),
+    ("call-host", b'h'),
+    ("random", b'X'),
+    ("frandom", b'U'),
+    ("read-line", b'I'),
+    ("exit", b';'),
+    ("pause", b'w'),
+    ("yield", b'i'),
+    (">r", b'('),
+    ("r>", b')'),
+    ("r@", b'@'),
+    ("i", b'@'),
+    ("here", b'H'),
+    ("allot", b'A'),
+    ("release", b'F'),
+    ("=", b'='),
+    ("<", b'<'),
+    (">", b'>'),
+    ("and", b'&'),
+    ("or", b'|'),
+    ("xor", b'^'),
+    ("not", b'~'),
+    ("lshift", b'{'),
+    ("rshift", b'}'),
+    ("arshift", b'_'),
+    ("rotl", b'L'),
+    ("rotr", b'Q'),
+    ("popcount", b'N'),
+    ("concat", b'k'),
+    ("length", b'l'),
+    ("compare", b'?'),
+];
+
+///The built-in words that run from the extended page, and the byte after
+///the `!`.
+pub(crate) const EXTENDED_BUILTINS: &[(&str, u8)] = &[
+    ("c@", b'b'),
+    ("c!", b'B'),
+    ("cmove", b'm'),
+    ("fill", b'f'),
+    ("string@", b's'),
+    ("string!", b'S'),
+];
+
+///The words the compiler handles itself, apart from `:` and `;`, rather
+///than compiling each to an opcode.
+pub(crate) const KEYWORDS: &[&str] = &[
+    "variable", "constant", "if", "else", "then", "begin", "until", "again", "while", "repeat",
+    "do", "loop", "+loop", "j", "leave", "unloop", "recurse", "catch", "throw", "to", "s\"",
+];
+
+///Map a built-in word to its opcode.
+pub(crate) fn builtin(word: &str) -> Option<u8> {
+    BUILTINS.iter().find(|n| n.0 == word).map(|n| n.1)
+}
+
+///Map a built-in word that runs from the extended page to the byte after
+///the `!`.
+pub(crate) fn extended_builtin(word: &str) -> Option<u8> {
+    EXTENDED_BUILTINS.iter().find(|n| n.0 == word).map(|n| n.1)
+}
+
+enum Item {
+    Code(Vec<u8>),
+    ///The line and column of the token whose code follows.
+    Mark(u32, u32),
+    ///A call to a word defined in the same piece of source.
+    Call(usize),
+    ///A call to a word that already has an address.
+    Address(usize),
+    ///A `catch` of a word defined in the same piece of source.
+    CatchCall(usize),
+    ///A `catch` of a word that already has an address.
+    CatchAddress(usize),
+    ///Where a label of the fragment is placed.
+    Label(usize),
+    ///A relative jump or loop opcode, and the label it goes to.
+    Jump(u8, usize),
+}
+
+impl Item {
+    ///Size in bytes when every offset is written with `width` digits.
+    fn size(&self, width: usize) -> usize {
+        match *self {
+            Item::Code(ref code) => code.len(),
+            Item::Mark(..) | Item::Label(_) => 0,
+            Item::Call(_) | Item::Address(_) | Item::Jump(..) => width + 4,
+            Item::CatchCall(_) | Item::CatchAddress(_) => width + 5,
+        }
+    }
+}
+
+///A control structure that is still open. Each holds the labels it
+///jumps to and the stack effect of the code where it was opened, or
+///`None` if that isn't known.
+enum Control {
+    ///A `do` loop, with the labels at the start of its body and after
+    ///its end.
+    Do { body: usize, exit: usize, effect: Option<StackEffect> },
+    ///An `if`, with the label of the code run when its flag is false.
+    If { otherwise: usize, effect: Option<StackEffect> },
+    ///An `else`, with the label after its `then` and the effect at the
+    ///end of the code run when the flag is true.
+    Else { end: usize, effect: Option<StackEffect> },
+    ///A `begin`, with the label at the start of the loop.
+    Begin { start: usize, effect: Option<StackEffect> },
+    ///A `while`, with the labels at the start of its loop and after the
+    ///`repeat`, and the effect where it leaves the loop.
+    While { start: usize, exit: usize, effect: Option<StackEffect>, after: Option<StackEffect> },
+}
+
+impl Control {
+    ///The word that opened the structure.
+    fn opener(&self) -> &'static str {
+        match *self {
+            Control::Do { .. } => "do",
+            Control::If { .. } | Control::Else { .. } => "if",
+            Control::Begin { .. } | Control::While { .. } => "begin",
+        }
+    }
+}
+
+///The stack effect of code that takes one of two paths and then carries
+///on. The stack must be as deep after either, or the effect isn't known.
+fn join(a: Option<StackEffect>, b: Option<StackEffect>) -> Option<StackEffect> {
+    match (a, b) {
+        (Some(a), Some(b)) if a.net() == b.net() => {
+            let inputs = a.inputs.max(b.inputs);
+            Some(StackEffect::new(inputs, (inputs as isize + a.net()) as usize))
+        },
+        _ => None,
+    }
+}
+
+///A run of compiled code whose calls have not been given addresses yet.
+#[derive(Default)]
+struct Fragment {
+    items: Vec<Item>,
+    ///The stack effect of the code up to the first thing whose effect
+    ///isn't known.
+    effect: StackEffect,
+    unknown: bool,
+    ///How many labels have been made.
+    labels: usize,
+    control: Vec<Control>,
+    ///The names of the word's locals, by index in its frame.
+    locals: Vec<String>,
+}
+
+impl Fragment {
+    ///Follow the stack effect so far with that of the next piece of code.
+    fn apply(&mut self, effect: Option<StackEffect>) {
+        match effect {
+            _ if self.unknown => {},
+            Some(n) => { self.effect = self.effect.then(n); },
+            None => { self.unknown = true; },
+        }
+    }
+
+    ///The stack effect of all of the code, if it is known.
+    fn effect(&self) -> Option<StackEffect> {
+        if self.unknown { None } else { Some(self.effect) }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        if let Some(&mut Item::Code(ref mut code)) = self.items.last_mut() {
+            code.extend_from_slice(bytes);
+            return;
+        }
+        self.items.push(Item::Code(bytes.to_vec()));
+    }
+
+    fn call(&mut self, word: usize) {
+        self.items.push(Item::Call(word));
+    }
+
+    ///Note where in the source the code emitted next comes from.
+    fn mark(&mut self, (line, column): (u32, u32)) {
+        self.items.push(Item::Mark(line, column));
+    }
+
+    fn call_address(&mut self, address: usize) {
+        self.items.push(Item::Address(address));
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.items.push(Item::Label(label));
+    }
+
+    fn jump(&mut self, op: u8, label: usize) {
+        self.items.push(Item::Jump(op, label));
+    }
+
+    ///Note that the code so far joins up with the start of a loop, whose
+    ///stack effect was `effect`. If the loop doesn't leave the stack as
+    ///deep as it found it, the effect depends on how often it runs.
+    fn rejoin(&mut self, effect: Option<StackEffect>) {
+        if self.effect().map(StackEffect::net) != effect.map(StackEffect::net) {
+            self.unknown = true;
+        }
+    }
+
+    ///Carry on from code with some effect, as after a jump.
+    fn resume(&mut self, effect: Option<StackEffect>) {
+        match effect {
+            Some(n) => {
+                self.effect = n;
+                self.unknown = false;
+            },
+            None => { self.unknown = true; },
+        }
+    }
+
+    ///Close the innermost control structure, which must be of the kind
+    ///`word` closes.
+    fn close(&mut self, word: &str) -> Result<Control, CompileError> {
+        let closes = match word {
+            "loop" | "+loop" => "do",
+            "else" | "then" => "if",
+            _ => "begin",
+        };
+        match self.control.pop() {
+            Some(n) if n.opener() == closes => Ok(n),
+            _ => Err(CompileError::UnmatchedControl(String::from(word))),
+        }
+    }
+
+    ///Compile a word that opens or closes a control structure, or works
+    ///with the one that is open. Returns false if `word` isn't one.
+    fn control(&mut self, word: &str) -> Result<bool, CompileError> {
+        match word {
+            "if" => {
+                self.apply(Some(StackEffect::new(1, 0)));
+                let otherwise = self.label();
+                self.jump(b'Z', otherwise);
+                self.control.push(Control::If { otherwise, effect: self.effect() });
+            },
+            "else" => {
+                let (otherwise, effect) = match self.close(word)? {
+                    Control::If { otherwise, effect } => (otherwise, effect),
+                    _ => { return Err(CompileError::UnmatchedControl(String::from(word))); }
+                };
+                let end = self.label();
+                self.jump(b'B', end);
+                self.place(otherwise);
+                self.control.push(Control::Else { end, effect: self.effect() });
+                self.resume(effect);
+            },
+            "then" => {
+                let (label, effect) = match self.close(word)? {
+                    Control::If { otherwise, effect } => (otherwise, effect),
+                    Control::Else { end, effect } => (end, effect),
+                    _ => { return Err(CompileError::UnmatchedControl(String::from(word))); }
+                };
+                self.place(label);
+                let joined = join(self.effect(), effect);
+                self.resume(joined);
+            },
+            "begin" => {
+                let start = self.label();
+                self.place(start);
+                self.control.push(Control::Begin { start, effect: self.effect() });
+            },
+            "until" | "again" => {
+                let (start, effect) = match self.close(word)? {
+                    Control::Begin { start, effect } => (start, effect),
+                    _ => { return Err(CompileError::UnmatchedControl(String::from(word))); }
+                };
+                if word == "until" {
+                    self.apply(Some(StackEffect::new(1, 0)));
+                }
+                self.rejoin(effect);
+                self.jump(if word == "until" { b'Z' } else { b'B' }, start);
+            },
+            "while" => {
+                let (start, effect) = match self.close(word)? {
+                    Control::Begin { start, effect } => (start, effect),
+                    _ => { return Err(CompileError::UnmatchedControl(String::from(word))); }
+                };
+                self.apply(Some(StackEffect::new(1, 0)));
+                let exit = self.label();
+                self.jump(b'Z', exit);
+                self.control.push(Control::While { start, exit, effect, after: self.effect() });
+            },
+            "repeat" => {
+                let (start, exit, effect, after) = match self.close(word)? {
+                    Control::While { start, exit, effect, after } => (start, exit, effect, after),
+                    _ => { return Err(CompileError::UnmatchedControl(String::from(word))); }
+                };
+                self.rejoin(effect);
+                self.jump(b'B', start);
+                self.place(exit);
+                let after = if self.unknown { None } else { after };
+                self.resume(after);
+            },
+            "do" => {
+                self.emit(b":");
+                self.apply(opcode_effect(b':'));
+                let (body, exit) = (self.label(), self.label());
+                self.place(body);
+                self.control.push(Control::Do { body, exit, effect: self.effect() });
+            },
+            "loop" | "+loop" => {
+                let (body, exit, effect) = match self.close(word)? {
+                    Control::Do { body, exit, effect } => (body, exit, effect),
+                    _ => { return Err(CompileError::UnmatchedControl(String::from(word))); }
+                };
+                if word == "+loop" {
+                    self.apply(Some(StackEffect::new(1, 0)));
+                }
+                self.rejoin(effect);
+                self.jump(if word == "loop" { b'J' } else { b'j' }, body);
+                self.place(exit);
+            },
+            "leave" => {
+                let found = self.control.iter().rev().find_map(|n| match *n {
+                    Control::Do { exit, effect, .. } => Some((exit, effect)),
+                    _ => None,
+                });
+                let (exit, effect) = match found {
+                    Some(n) => n,
+                    None => { return Err(CompileError::UnmatchedControl(String::from(word))); }
+                };
+                self.emit(b"))rr");
+                self.rejoin(effect);
+                self.jump(b'B', exit);
+            },
+            //Drop the loop state, so the word can `exit` from inside it.
+            "unloop" => {
+                self.emit(b"))rr");
+            },
+            //The index of the loop around the innermost one, from under
+            //the innermost one's limit and index.
+            "j" => {
+                self.emit(b"))@u((");
+                self.apply(Some(StackEffect::new(0, 1)));
+            },
+            _ => { return Ok(false); }
+        }
+
+        Ok(true)
+    }
+
+    ///Start a frame holding the top items as the locals named, the
+    ///deepest first.
+    fn enter(&mut self, locals: Vec<String>) {
+        self.emit(format!("#{}'!{{", locals.len()).as_bytes());
+        self.apply(Some(StackEffect::new(locals.len(), 0)));
+        self.locals = locals;
+    }
+
+    ///The index of a local, if the word has one by that name.
+    fn local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|n| n == name)
+    }
+
+    ///Return, dropping the frame first if the word has locals.
+    fn ret(&mut self) {
+        if !self.locals.is_empty() {
+            self.emit(b"!}");
+        }
+        self.emit(b";");
+    }
+
+    ///Check that every control structure has been closed.
+    fn check_closed(&self) -> Result<(), CompileError> {
+        match self.control.last() {
+            Some(n) => Err(CompileError::UnclosedControl(String::from(n.opener()))),
+            None => Ok(()),
+        }
+    }
+
+    ///Check whether the code after an item starts with a return. A call
+    ///there is a tail call, and jumping instead leaves the return stack
+    ///as it was for the callee's own return to use.
+    fn returns_after(&self, item: usize) -> bool {
+        match self.items[item + 1..].iter().find(|n| !matches!(**n, Item::Mark(..) | Item::Label(_))) {
+            Some(Item::Code(code)) => code.first() == Some(&b';'),
+            _ => false,
+        }
+    }
+
+    ///Size in bytes when every offset is written with `width` digits.
+    fn size(&self, width: usize) -> usize {
+        self.items.iter().map(|item| item.size(width)).sum()
+    }
+
+    ///The opcode for the call at an item.
+    fn call_op(&self, item: usize) -> &'static [u8] {
+        if self.returns_after(item) { b"B" } else { b"C" }
+    }
+
+    ///Append the code to `out`, which must already hold everything before
+    ///it, and record where it came from in `debug_info`. Calls and jumps
+    ///are relative, so the result can be moved as a block.
+    fn write(&self, width: usize, addresses: &[usize], out: &mut Vec<u8>, debug_info: &mut DebugInfo, file: &str, word: Option<&str>) {
+        let mut labels = vec![0; self.labels];
+        let mut address = out.len();
+        for item in &self.items {
+            if let Item::Label(label) = *item {
+                labels[label] = address;
+            }
+            address += item.size(width);
+        }
+
+        for (n, item) in self.items.iter().enumerate() {
+            let (target, op): (usize, &[u8]) = match *item {
+                Item::Code(ref code) => {
+                    out.extend_from_slice(code);
+                    continue;
+                },
+                Item::Mark(line, column) => {
+                    debug_info.insert(out.len(), file, line, column, word);
+                    continue;
+                },
+                Item::Label(_) => { continue; }
+                Item::Call(word) => (addresses[word], self.call_op(n)),
+                Item::Address(address) => (address, self.call_op(n)),
+                Item::Jump(ref op, label) => (labels[label], slice::from_ref(op)),
+                Item::CatchCall(word) => (addresses[word], b"!c"),
+                Item::CatchAddress(address) => (address, b"!c"),
+            };
+
+            //The offset counts from the end of the call; the sign slot is
+            //a space when the offset is positive.
+            let next = (out.len() + item.size(width)) as i64;
+            let offset = target as i64 - next;
+            let sign = if offset < 0 { '$' } else { ' ' };
+            out.extend_from_slice(format!("#{:01$}{2}'", offset.abs(), width, sign).as_bytes());
+            out.extend_from_slice(op);
+        }
+    }
+}
+
+///Encode a numeric literal as the instructions that push it.
+pub(crate) fn literal(token: &str) -> Result<Option<Vec<u8>>, CompileError> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+        return Ok(None);
+    }
+
+    let mut parts = digits.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let fraction = parts.next();
+
+    if let Some(fraction) = fraction {
+        if fraction.contains('.') || (whole.is_empty() && fraction.is_empty()) {
+            return Ok(None);
+        }
+    }
+
+    let mut out = vec![b'#'];
+    let mut all_digits = String::from(whole);
+
+    match fraction {
+        None => {
+            out.extend_from_slice(whole.as_bytes());
+        },
+        Some(fraction) => {
+            //The divider only grows in steps of 1000, so pad the fraction
+            //out to a whole number of steps.
+            let mut fraction = String::from(fraction);
+            while fraction.len() % 3 != 0 {
+                fraction.push('0');
+            }
+            all_digits.push_str(&fraction);
+
+            out.extend_from_slice(whole.as_bytes());
+            out.extend_from_slice(fraction.as_bytes());
+            let steps = out.len() + fraction.len() / 3;
+            out.resize(steps, b'.');
+        },
+    }
+
+    if all_digits.is_empty() || all_digits.parse::<i64>().is_err() {
+        return Err(CompileError::InvalidNumber(String::from(token)));
+    }
+
+    if negative {
+        out.push(b'$');
+    }
+
+    out.push(if fraction.is_some() { b'"' } else { b'\'' });
+
+    Ok(Some(out))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Lexeme<'a> {
+    Word(&'a str),
+    Str(&'a str),
+    ///The text inside a `( ... )` comment.
+    Comment(&'a str),
+    ///The text after a `\`, to the end of the line.
+    LineComment(&'a str),
+}
+
+///Split source into words, string literals and both kinds of comment.
+///Each token comes with the range of bytes it takes up.
+pub(crate) fn lex(source: &str) -> Result<Vec<(Range<usize>, Lexeme<'_>)>, CompileError> {
+    match scan(source) {
+        (tokens, None) => Ok(tokens),
+        (_, Some(err)) => Err(err),
+    }
+}
+
+///Like `lex`, but an unterminated comment or string runs to the end of the
+///source, and the error is given alongside the tokens.
+fn scan(source: &str) -> (Vec<(Range<usize>, Lexeme<'_>)>, Option<CompileError>) {
+    let mut tokens = Vec::new();
+    let mut err = None;
+    let mut rest = source;
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+
+        let offset = source.len() - rest.len();
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..end];
+        rest = &rest[end..];
+
+        let token = match word {
+            "\\" => {
+                let close = rest.find('\n').unwrap_or(rest.len());
+                let text = rest[..close].trim_end_matches('\r');
+                rest = &rest[close..];
+                Lexeme::LineComment(text)
+            },
+            "(" => {
+                let close = rest.find(')').unwrap_or_else(|| {
+                    err = Some(CompileError::UnterminatedComment);
+                    rest.len()
+                });
+                let text = &rest[..close];
+                rest = &rest[(close + 1).min(rest.len())..];
+                Lexeme::Comment(text)
+            },
+            "s\"" => {
+                //One space separates the word from the text.
+                let text = &rest[rest.chars().next().map_or(0, char::len_utf8)..];
+                let close = text.find('"').unwrap_or_else(|| {
+                    err = Some(CompileError::UnterminatedString);
+                    text.len()
+                });
+                rest = &text[(close + 1).min(text.len())..];
+                Lexeme::Str(&text[..close])
+            },
+            _ => Lexeme::Word(word),
+        };
+        tokens.push((offset..source.len() - rest.len(), token));
+    }
+
+    (tokens, err)
+}
+
+///Split source into words, string literals and `( ... )` comments,
+///dropping `\ ...` comments. Each token comes with the byte offset it
+///starts at.
+fn lex_code(source: &str) -> Result<Vec<(usize, Lexeme<'_>)>, CompileError> {
+    Ok(lex(source)?.into_iter()
+        .filter(|n| !matches!(n.1, Lexeme::LineComment(_)))
+        .map(|(span, token)| (span.start, token))
+        .collect())
+}
+
+///What a token is, as far as highlighting it goes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    ///A number or a string.
+    Literal,
+    ///The name being given to a word, variable, constant or local.
+    Definition,
+    ///Any other word, whether or not it is defined anywhere.
+    Call,
+    ///A `( ... )` or `\ ...` comment.
+    Comment,
+    ///A word the compiler handles itself, like `:` or `if`.
+    Control,
+}
+
+///A token of source, and the bytes it takes up there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+}
+
+///Split source into tokens for highlighting, classified the way the
+///compiler reads them. This never fails: an unterminated comment or string
+///runs to the end of the source, and an unknown word is still a call.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let (lexemes, _) = scan(source);
+    let mut tokens = Vec::with_capacity(lexemes.len());
+    //Whether the next word is a name being defined, and whether it is in
+    //a list of locals, before or after the `--`.
+    let mut naming = false;
+    let mut locals = None;
+
+    for (span, lexeme) in lexemes {
+        let kind = match lexeme {
+            Lexeme::Str(_) => TokenKind::Literal,
+            Lexeme::Comment(_) | Lexeme::LineComment(_) => TokenKind::Comment,
+            Lexeme::Word(word) => match locals {
+                Some(_) if word == "}" => {
+                    locals = None;
+                    TokenKind::Control
+                },
+                Some(_) if word == "--" => {
+                    locals = Some(true);
+                    TokenKind::Control
+                },
+                Some(false) => TokenKind::Definition,
+                Some(true) => TokenKind::Comment,
+                None if naming => {
+                    naming = false;
+                    TokenKind::Definition
+                },
+                None if word == "{" => {
+                    locals = Some(false);
+                    TokenKind::Control
+                },
+                None if word == ":" || word == "variable" || word == "constant" => {
+                    naming = true;
+                    TokenKind::Control
+                },
+                None if word == ";" || KEYWORDS.contains(&word) => TokenKind::Control,
+                None if !matches!(literal(word), Ok(None)) => TokenKind::Literal,
+                None => TokenKind::Call,
+            },
+        };
+        tokens.push(Token { kind, span });
+    }
+
+    tokens
+}
+
+///Turns byte offsets into source into lines and columns, counted from 1.
+///Offsets must be given in increasing order.
+struct Lines<'a> {
+    source: &'a str,
+    ///Where the line of the last offset starts.
+    start: usize,
+    line: u32,
+}
+
+impl<'a> Lines<'a> {
+    fn new(source: &'a str) -> Lines<'a> {
+        Lines { source, start: 0, line: 1 }
+    }
+
+    fn at(&mut self, offset: usize) -> (u32, u32) {
+        let skipped = &self.source[self.start..offset];
+        if let Some(n) = skipped.rfind('\n') {
+            self.line += skipped.matches('\n').count() as u32;
+            self.start += n + 1;
+        }
+
+        (self.line, self.source[self.start..offset].chars().count() as u32 + 1)
+    }
+}
+
+///Read the effect in a stack comment like `( a b -- c )`, or `None` if the
+///comment isn't one.
+fn stack_comment(text: &str) -> Option<StackEffect> {
+    let mut items = text.split_whitespace();
+    let inputs = items.position(|n| n == "--")?;
+
+    Some(StackEffect::new(inputs, items.count()))
+}
+
+///Encode a string literal, escaping the bytes the VM treats specially.
+pub(crate) fn string(text: &str) -> Vec<u8> {
+    let mut out = vec![b'['];
+    for &b in text.as_bytes() {
+        if b == b']' || b == b'\\' {
+            out.push(b'\\');
+        }
+        out.push(b);
+    }
+    out.push(b']');
+
+    out
+}
+
+///Read a numeric literal as the value it pushes.
+fn number(token: &str) -> Result<Option<Data>, CompileError> {
+    if literal(token)?.is_none() {
+        return Ok(None);
+    }
+
+    let value = if token.contains('.') {
+        token.parse::<f64>().ok().map(Data::Float)
+    } else {
+        token.parse::<i64>().ok().map(Data::Int)
+    };
+    match value {
+        Some(n) => Ok(Some(n)),
+        None => Err(CompileError::InvalidNumber(String::from(token))),
+    }
+}
+
+///Encode a float in full, as a `]` and its eight bytes.
+pub(crate) fn float(value: f64) -> Vec<u8> {
+    let mut out = vec![b']'];
+    out.extend_from_slice(&value.to_le_bytes());
+
+    out
+}
+
+///Compile Forth-style source into bytecode that starts at PC 0.
+///
+///Supports numeric literals (`42`, `-7`, `3.25`), the built-in words,
+///`: name ... ;` definitions, `recurse`, `variable` and `constant`,
+///`if`, `begin` and `do` control structures, locals, `catch` and
+///`throw`, and comments.
+pub fn compile(source: &str) -> Result<Vec<u8>, CompileError> {
+    let mut compiler = Compiler::new();
+    compiler.inline_constants(true);
+    compiler.compile(source)?;
+
+    Ok(compiler.into_module().code)
+}
+
+///Compile source into a module whose dictionary holds the entry point of
+///every word it defines.
+pub fn compile_module(source: &str) -> Result<Module, CompileError> {
+    let mut compiler = Compiler::new();
+    compiler.compile(source)?;
+
+    Ok(compiler.into_module())
+}
+
+///Compiles source a piece at a time onto the end of a growing module, so
+///later pieces can call words defined by earlier ones.
+#[derive(Debug, Clone, Default)]
+pub struct Compiler {
+    module: Module,
+    host: BTreeMap<String, usize>,
+    effects: BTreeMap<String, StackEffect>,
+    depth: Option<usize>,
+    inline: bool,
+}
+
+///Find a constant in a pool, telling floats apart by their bits so `0.0`
+///and `-0.0` stay distinct.
+fn find_constant(pool: &[Data], value: &Data) -> Option<usize> {
+    pool.iter().position(|n| match (n, value) {
+        (&Data::Int(a), &Data::Int(b)) => a == b,
+        (&Data::Float(a), &Data::Float(b)) => a.to_bits() == b.to_bits(),
+        _ => false,
+    })
+}
+
+impl Compiler {
+    ///Start with an empty module.
+    pub fn new() -> Compiler {
+        Compiler {
+            module: Module::default(),
+            host: BTreeMap::new(),
+            effects: BTreeMap::new(),
+            depth: None,
+            inline: false,
+        }
+    }
+
+    ///Compile the names bound in a registry into calls to them by index.
+    ///Words defined in the source take precedence.
+    pub fn use_host<S: Storage>(&mut self, host: &HostFunctions<S>) {
+        for (name, index) in host.iter() {
+            self.host.insert(String::from(name), index);
+        }
+    }
+
+    ///Check top-level code from now on against a stack that holds `depth`
+    ///items when it runs, so code that is sure to underflow is refused.
+    ///An interactive session can pass the depth of its stack before
+    ///compiling each line.
+    pub fn assume_depth(&mut self, depth: usize) {
+        self.depth = Some(depth);
+    }
+
+    ///Keep every literal in the code, building ints from digits and
+    ///giving floats in full, rather than putting floats and long ints in
+    ///the module's constant pool. For code that will be run without its
+    ///module.
+    pub fn inline_constants(&mut self, inline: bool) {
+        self.inline = inline;
+    }
+
+    ///Encode the code that pushes an int or float, as a reference to the
+    ///constant pool if that is shorter or, for a float, exact. `added`
+    ///holds the constants the source being compiled has added to the
+    ///pool so far.
+    fn push(&self, value: Data, added: &mut Vec<Data>) -> Vec<u8> {
+        let code = match value {
+            Data::Int(n) => format!("#{}{}'", n.unsigned_abs(), if n < 0 { "$" } else { "" }).into_bytes(),
+            Data::Float(n) if self.inline => { return float(n); },
+            _ => Vec::new(),
+        };
+        if self.inline {
+            return code;
+        }
+
+        let pool = &self.module.constants;
+        let index = match find_constant(pool, &value).or_else(|| find_constant(added, &value).map(|n| pool.len() + n)) {
+            Some(n) => n,
+            None => pool.len() + added.len(),
+        };
+        let reference = format!("#{}'\\", index).into_bytes();
+        if let Data::Int(_) = value {
+            if code.len() <= reference.len() {
+                return code;
+            }
+        }
+
+        if index == pool.len() + added.len() {
+            added.push(value);
+        }
+        reference
+    }
+
+    ///The stack effect of a word compiled so far, if it is known. Words
+    ///whose effect depends on the values they are given, like ones using
+    ///`pick` or host functions, have none.
+    pub fn effect(&self, word: &str) -> Option<StackEffect> {
+        self.effects.get(word).cloned()
+    }
+
+    ///The module compiled so far.
+    pub fn module(&self) -> &Module {&self.module}
+
+    ///Finish compiling and take the module.
+    pub fn into_module(self) -> Module {self.module}
+
+    ///Compile more source onto the end of the module, returning the
+    ///address of its top-level code. On error the module is unchanged.
+    pub fn compile(&mut self, source: &str) -> Result<usize, CompileError> {
+        self.compile_file("<input>", source)
+    }
+
+    ///Like `compile`, recording `file` as the name of the source in the
+    ///module's debug info.
+    pub fn compile_file(&mut self, file: &str, source: &str) -> Result<usize, CompileError> {
+        let tokens = lex_code(source)?;
+        let mut lines = Lines::new(source);
+        let mut constants = Vec::new();
+        //Variables and constants defined here, and the memory cells the
+        //variables take.
+        let mut globals: BTreeMap<&str, Global> = BTreeMap::new();
+        let mut cells = 0;
+
+        let mut main = Fragment::default();
+        let mut words: Vec<Fragment> = Vec::new();
+        //The name of each of the words, which may repeat.
+        let mut defined: Vec<&str> = Vec::new();
+        let mut names: BTreeMap<&str, usize> = BTreeMap::new();
+        //Each word's effect, or the one it declares if that isn't known.
+        let mut effects: Vec<Option<StackEffect>> = Vec::new();
+        //The name, body and declared effect of the word being defined.
+        let mut current: Option<(&str, Fragment, Option<StackEffect>)> = None;
+
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some((offset, token)) = tokens.next() {
+            let token = match token {
+                Lexeme::Word(n) => n,
+                Lexeme::Str(text) => {
+                    let fragment = match current {
+                        Some((_, ref mut body, _)) => body,
+                        None => &mut main,
+                    };
+                    fragment.mark(lines.at(offset));
+                    fragment.emit(&string(text));
+                    fragment.apply(Some(StackEffect::new(0, 1)));
+                    continue;
+                },
+                Lexeme::Comment(_) | Lexeme::LineComment(_) => { continue; }
+            };
+
+            if token == ":" {
+                if current.is_some() {
+                    return Err(CompileError::NestedDefinition);
+                }
+                let name = match tokens.next() {
+                    Some((_, Lexeme::Word(n))) => n,
+                    _ => { return Err(CompileError::MissingName); }
+                };
+                let declared = match tokens.peek() {
+                    Some(&(_, Lexeme::Comment(text))) => stack_comment(text),
+                    _ => None,
+                };
+                current = Some((name, Fragment::default(), declared));
+                continue;
+            }
+
+            if token == "variable" {
+                if current.is_some() {
+                    return Err(CompileError::NestedDefinition);
+                }
+                let name = match tokens.next() {
+                    Some((_, Lexeme::Word(n))) => n,
+                    _ => { return Err(CompileError::MissingName); }
+                };
+                globals.insert(name, Global::Variable(self.module.data.len() + cells));
+                cells += 1;
+                continue;
+            }
+
+            if token == ";" {
+                let (name, mut body, declared) = match current.take() {
+                    Some(n) => n,
+                    None => { return Err(CompileError::UnexpectedSemicolon); }
+                };
+                body.check_closed()?;
+                body.mark(lines.at(offset));
+                body.ret();
+
+                if let Some(declared) = declared {
+                    if body.effect.inputs > declared.inputs {
+                        return Err(CompileError::StackUnderflow(Some(String::from(name))));
+                    }
+                    match body.effect() {
+                        Some(inferred) if inferred.net() != declared.net() => {
+                            return Err(CompileError::StackEffectMismatch { word: String::from(name), declared, inferred });
+                        },
+                        _ => {},
+                    }
+                }
+
+                names.insert(name, words.len());
+                effects.push(body.effect().or(declared));
+                defined.push(name);
+                words.push(body);
+                continue;
+            }
+
+            let index = words.len();
+            let defining = current.is_some();
+            let declared = current.as_ref().and_then(|n| n.2);
+            let fragment = match current {
+                Some((_, ref mut body, _)) => body,
+                None => &mut main,
+            };
+            fragment.mark(lines.at(offset));
+
+            if fragment.control(token)? {
+                continue;
+            }
+
+            if token == "{" {
+                if !defining || !fragment.locals.is_empty() || !fragment.control.is_empty() {
+                    return Err(CompileError::MisplacedLocals);
+                }
+                //Names after a `--` only describe what the word leaves.
+                let mut locals = Vec::new();
+                let mut comment = false;
+                loop {
+                    match tokens.next() {
+                        Some((_, Lexeme::Word("}"))) => { break; },
+                        Some((_, Lexeme::Word("--"))) => { comment = true; },
+                        Some((_, Lexeme::Word(n))) if !comment => { locals.push(String::from(n)); },
+                        Some(_) => {},
+                        None => { return Err(CompileError::UnterminatedLocals); }
+                    }
+                }
+                fragment.enter(locals);
+                continue;
+            }
+
+            if let Some(local) = fragment.local(token) {
+                fragment.emit(format!("#{}'!R", local).as_bytes());
+                fragment.apply(Some(StackEffect::new(0, 1)));
+            } else if token == "to" && !fragment.locals.is_empty() {
+                let name = match tokens.next() {
+                    Some((_, Lexeme::Word(n))) => n,
+                    _ => { return Err(CompileError::MissingName); }
+                };
+                let local = match fragment.local(name) {
+                    Some(n) => n,
+                    None => { return Err(CompileError::UnknownWord(String::from(name))); }
+                };
+                fragment.emit(format!("#{}'!W", local).as_bytes());
+                fragment.apply(Some(StackEffect::new(1, 0)));
+            } else if let Some(global) = globals.get(token).or_else(|| self.module.globals.get(token)) {
+                let value = match *global {
+                    Global::Variable(address) => Data::Int(address as i64),
+                    Global::Constant(ref value) => value.clone(),
+                };
+                fragment.emit(&self.push(value, &mut constants));
+                fragment.apply(Some(StackEffect::new(0, 1)));
+            } else if token == "recurse" && defining {
+                //A recursive call has the effect the word declares, if any.
+                fragment.call(index);
+                fragment.apply(declared);
+            } else if let Some(&word) = names.get(token) {
+                fragment.call(word);
+                fragment.apply(effects[word]);
+            } else if let Some(address) = self.module.dictionary.get(token) {
+                fragment.call_address(address);
+                fragment.apply(self.effects.get(token).cloned());
+            } else if let Some(&index) = self.host.get(token) {
+                fragment.emit(format!("#{}'h", index).as_bytes());
+                fragment.apply(None);
+            } else if token == "catch" {
+                let name = match tokens.next() {
+                    Some((_, Lexeme::Word(n))) => n,
+                    _ => { return Err(CompileError::MissingName); }
+                };
+                let effect = if let Some(&word) = names.get(name) {
+                    fragment.items.push(Item::CatchCall(word));
+                    effects[word]
+                } else if let Some(address) = self.module.dictionary.get(name) {
+                    fragment.items.push(Item::CatchAddress(address));
+                    self.effects.get(name).cloned()
+                } else {
+                    return Err(CompileError::UnknownWord(String::from(name)));
+                };
+                //A failure puts the depth back, so the effect is only known
+                //if the word leaves it as it was anyway.
+                fragment.apply(match effect {
+                    Some(n) if n.net() == 0 => Some(StackEffect::new(n.inputs, n.inputs + 1)),
+                    _ => None,
+                });
+            } else if token == "throw" {
+                fragment.emit(b"!t");
+                fragment.apply(Some(StackEffect::new(1, 0)));
+            } else if let Some(op) = extended_builtin(token) {
+                fragment.emit(&[ESCAPE, op]);
+                fragment.apply(extended(op).and_then(|n| n.stack_effect));
+            } else if let Some(op) = builtin(token) {
+                if op == b';' {
+                    fragment.ret();
+                } else {
+                    fragment.emit(&[op]);
+                }
+                fragment.apply(opcode_effect(op));
+            } else if let Some(value) = number(token)? {
+                if let Some(&(_, Lexeme::Word("constant"))) = tokens.peek() {
+                    if defining {
+                        return Err(CompileError::NestedDefinition);
+                    }
+                    tokens.next();
+                    let name = match tokens.next() {
+                        Some((_, Lexeme::Word(n))) => n,
+                        _ => { return Err(CompileError::MissingName); }
+                    };
+                    globals.insert(name, Global::Constant(value));
+                    continue;
+                }
+
+                fragment.emit(&self.push(value, &mut constants));
+                fragment.apply(Some(StackEffect::new(0, 1)));
+            } else {
+                return Err(CompileError::UnknownWord(String::from(token)));
+            }
+        }
+
+        if current.is_some() {
+            return Err(CompileError::UnterminatedDefinition);
+        }
+        main.check_closed()?;
+
+        if let Some(depth) = self.depth {
+            if main.effect.inputs > depth {
+                return Err(CompileError::StackUnderflow(None));
+            }
+        }
+
+        main.emit(b";");
+
+        let base = self.module.code.len();
+        self.module.constants.extend(constants);
+        let len = self.module.data.len() + cells;
+        self.module.data.resize(len, Data::Int(0));
+        for (name, global) in globals {
+            self.module.globals.insert(String::from(name), global);
+        }
+
+        //Offsets are written with a fixed number of digits; widen until
+        //every address fits, which is enough for any offset.
+        let mut width = 1;
+        let addresses = loop {
+            let mut addresses = Vec::new();
+            let mut offset = base + main.size(width);
+            for word in &words {
+                addresses.push(offset);
+                offset += word.size(width);
+            }
+            if offset.to_string().len() <= width {
+                break addresses;
+            }
+            width += 1;
+        };
+
+        let debug_info = self.module.debug_info.get_or_insert_with(DebugInfo::new);
+        main.write(width, &addresses, &mut self.module.code, debug_info, file, None);
+        for (word, name) in words.iter().zip(&defined) {
+            word.write(width, &addresses, &mut self.module.code, debug_info, file, Some(name));
+        }
+
+        for (name, &word) in &names {
+            self.module.dictionary.insert(name, addresses[word]);
+            match effects[word] {
+                Some(effect) => { self.effects.insert(String::from(*name), effect); },
+                None => { self.effects.remove(*name); },
+            }
+        }
+
+        Ok(base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+
+    use compiler::{compile, compile_module, tokenize, CompileError, Compiler, TokenKind};
+    use module::Global;
+    use validate::StackEffect;
+    use {run, Data, Error, NullExtender, RuntimeError, Stack, Vm};
+
+    fn eval(source: &str) -> Vm {
+        let mut vm = Vm::new(compile(source).unwrap(), vec![Data::Int(0); 4]);
+        vm.run(&mut NullExtender {}).unwrap();
+        vm
+    }
+
+    #[test]
+    fn words_and_literals() {
+        let mut vm = eval(": square dup * ; \\ squares TOS\n 7 square ( 49 ) -2 +");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(47))));
+
+        let mut vm = eval("s\" green] \" s\" gold\" concat ( a comment\n over two lines ) length");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(11))));
+
+        let mut vm = eval("-1.5 0.25 *");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Float(n)) if n == -0.375));
+
+        let mut vm = eval(": square dup * ; : cube dup square * ; 3 cube 2 ! 2 @ 1 +");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(28))));
+    }
+
+    #[test]
+    fn call_by_name() {
+        let module = compile_module(": square dup * ; : cube dup square * ;").unwrap();
+        let mut stack = Stack::new();
+        stack.push(Data::Int(4));
+
+        let entry = module.dictionary.get("cube").unwrap();
+        run(&module.code, &mut stack, entry, &mut NullExtender {}, &mut Vec::new()).unwrap();
+        assert!(matches!(stack.pop(), Ok(Data::Int(64))));
+    }
+
+    #[test]
+    fn position_independent() {
+        let mut code = b"#7'".to_vec();
+        code.extend(compile(": square dup * ; : cube dup square * ; cube").unwrap());
+
+        let mut vm = Vm::new(code, Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(343))));
+    }
+
+    #[test]
+    fn incremental() {
+        let mut compiler = Compiler::new();
+        compiler.compile(": square dup * ;").unwrap();
+        let entry = compiler.compile(": cube dup square * ; 3 cube").unwrap();
+        assert!(compiler.compile("frob").is_err());
+
+        let mut vm = Vm::new(compiler.module().code.clone(), Vec::new());
+        vm.pc = entry;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(27))));
+    }
+
+    #[test]
+    fn stack_effects() {
+        let mut compiler = Compiler::new();
+        compiler.compile(": sq ( n -- n*n ) dup * ; : keep over + ; : p ( a b -- a b a ) 1 pick ;").unwrap();
+        assert_eq!(compiler.effect("sq"), Some(StackEffect::new(1, 1)));
+        assert_eq!(compiler.effect("keep"), Some(StackEffect::new(2, 2)));
+        assert_eq!(compiler.effect("p"), Some(StackEffect::new(2, 3)));
+
+        compiler.compile(": quad ( n -- n ) sq sq ; : note ( not a stack comment ) 1 ;").unwrap();
+        assert_eq!(compiler.effect("quad"), Some(StackEffect::new(1, 1)));
+        assert_eq!(compiler.compile(": w ( -- ) sq ;"), Err(CompileError::StackUnderflow(Some(String::from("w")))));
+        assert_eq!(compiler.compile(": m ( a -- b c ) drop ;"), Err(CompileError::StackEffectMismatch {
+            word: String::from("m"),
+            declared: StackEffect::new(1, 2),
+            inferred: StackEffect::new(1, 0),
+        }));
+
+        compiler.assume_depth(1);
+        assert_eq!(compiler.compile("+"), Err(CompileError::StackUnderflow(None)));
+        assert!(compiler.compile("1 +").is_ok());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(compile("1 frob"), Err(CompileError::UnknownWord(String::from("frob"))));
+        assert_eq!(compile(": a 1"), Err(CompileError::UnterminatedDefinition));
+        assert_eq!(compile("s\" abc"), Err(CompileError::UnterminatedString));
+        assert_eq!(compile("1 ;"), Err(CompileError::UnexpectedSemicolon));
+        assert_eq!(compile("99999999999999999999"), Err(CompileError::InvalidNumber(String::from("99999999999999999999"))));
+    }
+
+    #[test]
+    fn tail_calls() {
+        let mut vm = Vm::new(compile(": spin 1 + recurse ; 0 spin").unwrap(), Vec::new());
+        vm.config.max_steps = Some(100_000);
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::FuelExhausted, .. })));
+        assert!(vm.rstack.is_empty());
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(n)) if n > 1024));
+
+        let mut vm = Vm::new(compile(": spin 1 + recurse 0 ; 0 spin").unwrap(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::ReturnStackOverflow, .. })));
+    }
+
+    #[test]
+    fn counted_loops() {
+        let mut vm = eval(": sum ( n -- s ) 0 swap 0 do i + loop ; 5 sum");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(10))));
+
+        let mut vm = eval("0 3 0 do 3 0 do j 3 * i + + loop loop");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(36))));
+
+        let mut vm = eval("0 0 10 do i + -2 +loop");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(30))));
+
+        let mut vm = eval(": first 10 3 do i leave loop ; : last 5 0 do i 2 unloop exit loop ; first last + +");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(5))));
+        assert!(vm.rstack.is_empty());
+
+        let mut compiler = Compiler::new();
+        compiler.compile(": sum ( n -- s ) 0 swap 0 do i + loop ; : spread 3 0 do i loop ;").unwrap();
+        assert_eq!(compiler.effect("sum"), Some(StackEffect::new(1, 1)));
+        assert_eq!(compiler.effect("spread"), None);
+
+        assert_eq!(compile("1 loop"), Err(CompileError::UnmatchedControl(String::from("loop"))));
+        assert_eq!(compile("leave"), Err(CompileError::UnmatchedControl(String::from("leave"))));
+        assert_eq!(compile(": a 3 0 do ;"), Err(CompileError::UnclosedControl(String::from("do"))));
+        assert_eq!(compile("3 0 do i"), Err(CompileError::UnclosedControl(String::from("do"))));
+    }
+
+    #[test]
+    fn control_structures() {
+        let vm = eval(": abs ( n -- n ) dup 0 < if -1 * then ; : sign ( n -- s ) dup 0 < if drop -1 else 0 > if 1 else 0 then then ; -5 abs -3 sign 0 sign 8 sign");
+        assert_eq!(vm.stack.to_string(), "<4> 5 -1 0 1");
+
+        //Count down with each kind of indefinite loop.
+        let vm = eval("3 begin 1 - dup 0 = until 10 begin dup while 2 - repeat : down begin dup 0 = if exit then 1 - again ; 4 down");
+        assert_eq!(vm.stack.to_string(), "<3> 0 0 0");
+
+        let mut vm = eval(": find 10 0 do i 4 = if i leave then loop ; find");
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(4))));
+        assert!(vm.rstack.is_empty());
+
+        let mut compiler = Compiler::new();
+        compiler.compile(": abs dup 0 < if -1 * then ; : pick-one if 1 else 2 then ; : count 0 begin 1 + dup 10 = until ; : odd if 1 then ;").unwrap();
+        assert_eq!(compiler.effect("abs"), Some(StackEffect::new(1, 1)));
+        assert_eq!(compiler.effect("pick-one"), Some(StackEffect::new(1, 1)));
+        assert_eq!(compiler.effect("count"), Some(StackEffect::new(0, 1)));
+        assert_eq!(compiler.effect("odd"), None);
+        assert!(matches!(compiler.compile(": two ( a b -- c ) if 1 else 2 then ;"), Err(CompileError::StackEffectMismatch { .. })));
+
+        assert_eq!(compile("1 then"), Err(CompileError::UnmatchedControl(String::from("then"))));
+        assert_eq!(compile("1 if 2 else 3 else 4 then"), Err(CompileError::UnmatchedControl(String::from("else"))));
+        assert_eq!(compile("begin 1 if until then"), Err(CompileError::UnmatchedControl(String::from("until"))));
+        assert_eq!(compile("3 0 do 1 repeat"), Err(CompileError::UnmatchedControl(String::from("repeat"))));
+        assert_eq!(compile(": a 1 if ;"), Err(CompileError::UnclosedControl(String::from("if"))));
+        assert_eq!(compile("begin 1 while"), Err(CompileError::UnclosedControl(String::from("begin"))));
+    }
+
+    #[test]
+    fn constants() {
+        let mut compiler = Compiler::new();
+        compiler.compile("3.141592653589793 -0.0 12 123456789").unwrap();
+        let entry = compiler.compile("-0.0 0.0 123456789").unwrap();
+        let module = compiler.into_module();
+        assert_eq!(module.constants.len(), 4);
+        assert!(module.code.windows(4).any(|n| n == b"#3'\\"));
+
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+        vm.pc = entry;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<7> 3.141592653589793 -0.0 12 123456789 -0.0 0.0 123456789");
+        assert!(matches!(vm.stack.peek_n(5), Some(&Data::Float(n)) if n.to_bits() == (-0.0f64).to_bits()));
+
+        assert!(!compile("2.5 123456789").unwrap().contains(&b'\\'));
+    }
+
+    #[test]
+    fn globals() {
+        let mut compiler = Compiler::new();
+        compiler.compile("variable count 10 constant step -0.5 constant half 1000000000 constant big").unwrap();
+        let entry = compiler.compile(": bump ( -- ) count @ step + count ! ; variable other bump bump 7 other ! count @ half big").unwrap();
+        let module = compiler.into_module();
+        assert_eq!(module.data.len(), 2);
+        assert_eq!(module.globals.get("other"), Some(&Global::Variable(1)));
+        assert_eq!(module.globals.get("half"), Some(&Global::Constant(Data::Float(-0.5))));
+
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        vm.pc = entry;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<3> 20 -0.5 1000000000");
+        assert_eq!(vm.memory.len(), 2);
+        assert_eq!(vm.memory[1], Data::Int(7));
+
+        let vm = eval("variable a variable b 3 a ! 4 b ! a @ b @ * a b");
+        assert_eq!(vm.stack.to_string(), "<3> 12 0 1");
+
+        assert_eq!(compile(": f variable x ;"), Err(CompileError::NestedDefinition));
+        assert_eq!(compile("1 constant"), Err(CompileError::MissingName));
+        assert_eq!(compile("variable"), Err(CompileError::MissingName));
+    }
+
+    #[test]
+    fn locals() {
+        let vm = eval(": hyp2 { a b -- c } a a * b b * + ; 3 4 hyp2");
+        assert_eq!(vm.stack.to_string(), "<1> 25");
+        assert!(vm.frames.is_empty() && vm.locals.is_empty());
+
+        //Each call gets its own frame, and `to` writes to it.
+        let vm = eval(": fact { n -- n! } n 1 < if 1 exit then n 1 - recurse n * ; : count { a } a 1 + to a a ; 5 fact 7 count");
+        assert_eq!(vm.stack.to_string(), "<2> 120 8");
+
+        let module = compile_module(": f ( a b -- a ) { x y } x ;").unwrap();
+        assert!(module.code.ends_with(b"#2'!{#0'!R!};"));
+        assert_eq!(compile(": f ( a -- a a ) { x } x x x ;"), Err(CompileError::StackEffectMismatch {
+            word: String::from("f"),
+            declared: StackEffect::new(1, 2),
+            inferred: StackEffect::new(1, 3),
+        }));
+
+        assert_eq!(compile("{ a }"), Err(CompileError::MisplacedLocals));
+        assert_eq!(compile(": f { a } { b } ;"), Err(CompileError::MisplacedLocals));
+        assert_eq!(compile(": f 1 if { a } then ;"), Err(CompileError::MisplacedLocals));
+        assert_eq!(compile(": f { a b"), Err(CompileError::UnterminatedLocals));
+        assert_eq!(compile(": f { a } to b ;"), Err(CompileError::UnknownWord(String::from("b"))));
+        assert_eq!(compile(": f a ;"), Err(CompileError::UnknownWord(String::from("a"))));
+    }
+
+    #[test]
+    fn catch_and_throw() {
+        let vm = eval(": check ( n -- n ) dup 0 < if 42 throw then ; -1 catch check 5 catch check");
+        assert_eq!(vm.stack.to_string(), "<4> -1 42 5 0");
+        assert!(vm.catches.is_empty() && vm.rstack.is_empty());
+
+        //Errors from the VM come with their codes, and words called from
+        //the one caught unwind with it.
+        let vm = eval(": inner 1 0 / ; : outer inner 7 ; 3 catch outer 9 0 throw");
+        assert_eq!(vm.stack.to_string(), "<3> 3 -10 9");
+
+        let module = compile_module(": f ; : g catch f ;").unwrap();
+        assert_eq!(Compiler::new().effect("g"), None);
+        assert!(module.code.ends_with(b"!c;"));
+        assert_eq!(compile("catch"), Err(CompileError::MissingName));
+        assert_eq!(compile("catch nope"), Err(CompileError::UnknownWord(String::from("nope"))));
+
+        let mut vm = Vm::new(compile("1 throw").unwrap(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::Thrown(1), .. })));
+    }
+
+    #[test]
+    fn byte_words() {
+        let source = ": pack ( -- n ) s\" GG\" 0 string! 1 2 c! 0 3 1 cmove 4 2 7 fill ; pack 0 6 string@ 3 c@";
+        let mut vm = Vm::new(compile(source).unwrap(), Vec::new());
+        vm.bytes = vec![0; 6];
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<3> 2 \"GG\\u{1}G\\u{7}\\u{7}\" 71");
+    }
+
+    #[test]
+    fn debug_info() {
+        let mut compiler = Compiler::new();
+        compiler.compile_file("boom.gg", ": boom ( n -- n )\n  0 / ;\n\n7 boom 1 +").unwrap();
+        let module = compiler.into_module();
+
+        let info = module.debug_info.as_ref().unwrap();
+        let location = info.locate(module.dictionary.get("boom").unwrap()).unwrap();
+        assert_eq!(location.to_string(), "boom.gg:2:3");
+        assert_eq!(location.word.as_deref(), Some("boom"));
+        assert_eq!(info.locate(0).unwrap().to_string(), "boom.gg:4:1");
+
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        let err = vm.run(&mut NullExtender {}).unwrap_err();
+        assert!(err.to_string().ends_with("(boom.gg:2:5)"));
+        assert_eq!(err.backtrace()[1].location.as_ref().unwrap().to_string(), "boom.gg:4:3");
+    }
+
+    #[test]
+    fn tokens() {
+        use compiler::TokenKind::*;
+
+        let source = ": hyp2 { a b -- c } a a * ; \\ sum\n3 constant three s\" hi\" ( open";
+        let found: Vec<(TokenKind, &str)> = tokenize(source).into_iter()
+            .map(|n| (n.kind, &source[n.span]))
+            .collect();
+        assert_eq!(found, vec![
+            (Control, ":"), (Definition, "hyp2"),
+            (Control, "{"), (Definition, "a"), (Definition, "b"), (Control, "--"), (Comment, "c"), (Control, "}"),
+            (Call, "a"), (Call, "a"), (Call, "*"), (Control, ";"), (Comment, "\\ sum"),
+            (Literal, "3"), (Control, "constant"), (Definition, "three"),
+            (Literal, "s\" hi\""), (Comment, "( open"),
+        ]);
+    }
+}
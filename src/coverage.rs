@@ -0,0 +1,229 @@
+//!Which instructions a program ran, for testing programs.
+//!
+//!Set `RunConfig::coverage` and every instruction a machine runs is
+//!marked in `Vm::coverage`, a bitmap over addresses. Runs with it set go
+//!through `run_bytes`, like sandboxed ones, so nothing is skipped by the
+//!optimizer or threaded code. Marks build up over runs until cleared, so
+//!one bitmap can cover a whole test suite.
+//!
+//!An instruction counts as covered if the address it starts at ran. A
+//!report totals these for the whole program and each word, charging an
+//!instruction to the word with the highest entry point at or below it,
+//!as `profile` does, and by source line if there is debug info.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+
+use debuginfo::DebugInfo;
+use disasm::decode;
+use module::Dictionary;
+use json_string;
+
+///The addresses that have run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    bits: Vec<u64>,
+}
+
+impl Coverage {
+    ///Start with nothing run.
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    ///Mark an address as run.
+    #[inline]
+    pub fn hit(&mut self, pc: usize) {
+        let word = pc / 64;
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= 1 << (pc % 64);
+    }
+
+    ///Check whether an address has run.
+    pub fn is_hit(&self, pc: usize) -> bool {
+        self.bits.get(pc / 64).is_some_and(|n| n & (1 << (pc % 64)) != 0)
+    }
+
+    ///Add the addresses another bitmap has marked, as when runs were
+    ///split across machines.
+    pub fn merge(&mut self, other: &Coverage) {
+        if other.bits.len() > self.bits.len() {
+            self.bits.resize(other.bits.len(), 0);
+        }
+        for (a, b) in self.bits.iter_mut().zip(&other.bits) {
+            *a |= b;
+        }
+    }
+
+    ///Forget everything marked.
+    pub fn clear(&mut self) {
+        self.bits.clear();
+    }
+
+    ///Total up the instructions in some code that have run.
+    pub fn report(&self, code: &[u8], dictionary: &Dictionary, debug_info: Option<&DebugInfo>) -> CoverageReport {
+        let mut entries: Vec<(usize, &str)> = dictionary.iter().map(|(name, address)| (address, name)).collect();
+        entries.sort();
+
+        let mut report = CoverageReport::default();
+        for (pc, _) in decode(code) {
+            let hit = self.is_hit(pc);
+            report.instructions += 1;
+            if hit {
+                report.hit += 1;
+            } else {
+                report.missed.push(pc);
+            }
+
+            let name = match entries.partition_point(|&(address, _)| address <= pc) {
+                0 => "<main>",
+                n => entries[n - 1].1,
+            };
+            match report.words.iter_mut().find(|n| n.name == name) {
+                Some(word) => {
+                    word.instructions += 1;
+                    word.hit += hit as usize;
+                },
+                None => report.words.push(WordCoverage {
+                    name: String::from(name),
+                    instructions: 1,
+                    hit: hit as usize,
+                }),
+            }
+
+            if let Some(location) = debug_info.and_then(|n| n.locate(pc)) {
+                match report.lines.iter_mut().find(|n| n.line == location.line && n.file == location.file) {
+                    Some(line) => { line.hit |= hit; },
+                    None => report.lines.push(LineCoverage {
+                        file: location.file,
+                        line: location.line,
+                        hit,
+                    }),
+                }
+            }
+        }
+        report.lines.sort_by(|a, b| a.file.cmp(&b.file).then(a.line.cmp(&b.line)));
+
+        report
+    }
+}
+
+///Coverage of one word.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordCoverage {
+    pub name: String,
+    pub instructions: usize,
+    pub hit: usize,
+}
+
+///Whether any instruction compiled from a line of source ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineCoverage {
+    pub file: String,
+    pub line: u32,
+    pub hit: bool,
+}
+
+///What a `Coverage` bitmap covers of some code.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoverageReport {
+    ///Instructions in the code.
+    pub instructions: usize,
+    ///Instructions that ran.
+    pub hit: usize,
+    ///Totals per word, in the order they appear in the code.
+    pub words: Vec<WordCoverage>,
+    ///The addresses of the instructions that didn't run.
+    pub missed: Vec<usize>,
+    ///Lines of source, by file and line. Empty without debug info.
+    pub lines: Vec<LineCoverage>,
+}
+
+impl CoverageReport {
+    ///The percentage of instructions that ran, or 100 for no code.
+    pub fn percent(&self) -> f64 {
+        match self.instructions {
+            0 => 100.0,
+            n => self.hit as f64 * 100.0 / n as f64,
+        }
+    }
+
+    ///Export as a JSON object with the totals and `words` and `missed`
+    ///arrays.
+    pub fn to_json(&self) -> String {
+        let words: Vec<String> = self.words.iter().map(|w| {
+            format!("{{\"name\":{},\"instructions\":{},\"hit\":{}}}", json_string(&w.name), w.instructions, w.hit)
+        }).collect();
+        let missed: Vec<String> = self.missed.iter().map(|n| n.to_string()).collect();
+
+        format!("{{\"instructions\":{},\"hit\":{},\"words\":[{}],\"missed\":[{}]}}", self.instructions, self.hit, words.join(","), missed.join(","))
+    }
+
+    ///Export the lines as an LCOV tracefile, with a record per source
+    ///file. A line that ran counts one hit.
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        for (n, line) in self.lines.iter().enumerate() {
+            if n == 0 || self.lines[n - 1].file != line.file {
+                let _ = writeln!(out, "SF:{}", line.file);
+            }
+            let _ = writeln!(out, "DA:{},{}", line.line, line.hit as u32);
+
+            if self.lines.get(n + 1).is_none_or(|next| next.file != line.file) {
+                let lines = self.lines.iter().filter(|n| n.file == line.file);
+                let _ = writeln!(out, "LF:{}", lines.clone().count());
+                let _ = writeln!(out, "LH:{}", lines.filter(|n| n.hit).count());
+                out.push_str("end_of_record\n");
+            }
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    ///A table of words, then the total.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<16} {:>8} {:>8}", "word", "hit", "of")?;
+        for word in &self.words {
+            writeln!(f, "{:<16} {:>8} {:>8}", word.name, word.hit, word.instructions)?;
+        }
+        writeln!(f, "{:.1}% of {} instructions", self.percent(), self.instructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use compiler::compile_module;
+    use coverage::Coverage;
+    use {NullExtender, Vm};
+
+    #[test]
+    fn words_and_lines() {
+        let module = compile_module(": double 2 * ;\n: unused 1 + ;\n3 double").unwrap();
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        vm.config.coverage = true;
+        vm.run(&mut NullExtender {}).unwrap();
+
+        let report = vm.coverage_report();
+        let names: Vec<(&str, usize, usize)> = report.words.iter().map(|n| (&n.name[..], n.instructions, n.hit)).collect();
+        assert!(names.contains(&("double", 3, 3)));
+        assert!(names.contains(&("unused", 3, 0)));
+        assert_eq!(report.hit + report.missed.len(), report.instructions);
+        assert!(report.percent() < 100.0);
+        assert!(report.to_json().contains("{\"name\":\"unused\",\"instructions\":3,\"hit\":0}"));
+        assert_eq!(report.to_lcov(), "SF:<input>\nDA:1,1\nDA:2,0\nDA:3,1\nLF:3\nLH:2\nend_of_record\n");
+
+        let mut more = Coverage::new();
+        more.hit(1000);
+        vm.coverage.merge(&more);
+        assert!(vm.coverage.is_hit(1000));
+        assert!(!vm.coverage.is_hit(999));
+    }
+}
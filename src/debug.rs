@@ -0,0 +1,354 @@
+//!Breakpoints, watchpoints and single-stepping on top of a `Vm`.
+//!
+//!A watchpoint stops on a memory cell being read by `R` or written by
+//!`W`. Writes are also caught when anything else changes the cell, such
+//!as an extender, though then only if the value is different.
+//!
+//!Every instruction the debugger runs is recorded, with a checkpoint
+//!every so often, so `step_back` can undo them one at a time; see
+//!`record`. Only the most recent checkpoints are kept, which bounds how
+//!far back it can go. Changes made to the machine by hand between steps
+//!aren't recorded, and stepping back past one fails.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use alloc::vec::Vec;
+
+use record::{Recorder, ReplayError};
+use storage::Storage;
+use vm::MemoryPolicy;
+use {AtomExtender, Data, RuntimeError, Status, Vm};
+
+///How many instructions run between checkpoints.
+const CHECKPOINT_INTERVAL: usize = 1024;
+///Most checkpoints kept. Stepping back can reach at least this many
+///intervals, less one, before the current instruction.
+const MAX_CHECKPOINTS: usize = 64;
+
+///Why `Debugger::resume` stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    ///The PC reached a breakpoint. The instruction there hasn't run yet.
+    Breakpoint(usize),
+    ///An instruction touched a watched cell. It has already run.
+    Watchpoint(WatchHit),
+    ///The program finished.
+    Halted,
+}
+
+///The kinds of access a watchpoint stops on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Watch {
+    fn reads(self) -> bool {self != Watch::Write}
+
+    fn writes(self) -> bool {self != Watch::Read}
+}
+
+///A watched cell being read or written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    ///The address of the instruction that touched the cell.
+    pub pc: usize,
+    ///The cell's address.
+    pub addr: usize,
+    ///Whether the cell was written rather than read.
+    pub write: bool,
+    ///The cell's value before the instruction and after it, the same
+    ///for a read.
+    pub old: Data,
+    pub new: Data,
+}
+
+///Wraps a machine with a set of breakpoints. The machine is public, so
+///its stack, memory and PC can be inspected or changed while stopped.
+pub struct Debugger<S = Vec<Data>, M = Vec<Data>> {
+    pub vm: Vm<S, M>,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<usize, Watch>,
+    history: Recorder,
+}
+
+impl<S: Storage, M: Storage> Debugger<S, M> {
+    ///Debug a machine, with no breakpoints set.
+    pub fn new(vm: Vm<S, M>) -> Debugger<S, M> {
+        Debugger {
+            history: Recorder::new(&vm),
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+        }
+    }
+
+    ///Stop before the instruction at `pc` runs.
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    ///Remove a breakpoint, returning whether it was set.
+    pub fn clear_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    ///The breakpoints, lowest first.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+
+    ///Stop after an instruction reads or writes the memory cell at
+    ///`addr`, replacing any watchpoint already there.
+    pub fn set_watchpoint(&mut self, addr: usize, watch: Watch) {
+        self.watchpoints.insert(addr, watch);
+    }
+
+    ///Watch the cell of a variable, by name. Returns false if there is
+    ///no such variable.
+    pub fn watch_variable(&mut self, name: &str, watch: Watch) -> bool {
+        match self.vm.variable(name) {
+            Some(n) => {
+                self.set_watchpoint(n, watch);
+                true
+            },
+            None => false,
+        }
+    }
+
+    ///Remove a watchpoint, returning whether it was set.
+    pub fn clear_watchpoint(&mut self, addr: usize) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    ///The watchpoints, lowest address first.
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, Watch)> + '_ {
+        self.watchpoints.iter().map(|(&addr, &watch)| (addr, watch))
+    }
+
+    ///Find the cell the instruction at the PC is about to read or write
+    ///with `R` or `W`, if it is one. Mapped addresses aren't cells.
+    fn access(&mut self) -> Option<(usize, bool)> {
+        let write = match self.vm.code().get(self.vm.pc) {
+            Some(b'R') => false,
+            Some(b'W') => true,
+            _ => { return None; }
+        };
+        let addr = match self.vm.stack.peek() {
+            Some(&Data::Int(n)) => n,
+            _ => { return None; }
+        };
+        if self.vm.memory_map.handler(addr).is_some() {
+            return None;
+        }
+
+        let len = self.vm.memory.len();
+        match self.vm.config.memory {
+            MemoryPolicy::Wrap if len > 0 => Some((addr.rem_euclid(len as i64) as usize, write)),
+            MemoryPolicy::Wrap => None,
+            MemoryPolicy::Trap | MemoryPolicy::Grow if addr >= 0 => Some((addr as usize, write)),
+            MemoryPolicy::Trap | MemoryPolicy::Grow => None,
+        }
+    }
+
+    ///Run an instruction, reporting the first watched cell it touched.
+    fn step_watched<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<(Status, Option<WatchHit>),RuntimeError> {
+        if self.watchpoints.is_empty() {
+            return self.advance(extender).map(|n| (n, None));
+        }
+
+        let pc = self.vm.pc;
+        let access = self.access();
+        let before: Vec<(usize, Data)> = self.watchpoints.iter()
+            .filter(|&(_, watch)| watch.writes())
+            .filter_map(|(&addr, _)| self.vm.memory.as_slice().get(addr).map(|n| (addr, n.clone())))
+            .collect();
+
+        let status = self.advance(extender)?;
+
+        if let Some((addr, write)) = access {
+            match self.watchpoints.get(&addr) {
+                Some(watch) if (write && watch.writes()) || (!write && watch.reads()) => {
+                    let new = self.vm.memory.as_slice().get(addr).cloned().unwrap_or(Data::Int(0));
+                    let old = match before.iter().find(|n| n.0 == addr) {
+                        Some(n) => n.1.clone(),
+                        None => new.clone(),
+                    };
+                    return Ok((status, Some(WatchHit { pc, addr, write, old, new })));
+                },
+                _ => {},
+            }
+        }
+
+        for (addr, old) in before {
+            match self.vm.memory.as_slice().get(addr) {
+                Some(new) if !old.same_as(new) => {
+                    return Ok((status, Some(WatchHit { pc, addr, write: true, old, new: new.clone() })));
+                },
+                _ => {},
+            }
+        }
+
+        Ok((status, None))
+    }
+
+    ///Run an instruction, recording it.
+    fn advance<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<Status,RuntimeError> {
+        let status = self.vm.step_traced(extender, &mut self.history)?;
+
+        if self.history.trace().len().is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.history.checkpoint(&self.vm);
+            if self.history.checkpoints() > MAX_CHECKPOINTS {
+                self.history.forget_oldest();
+            }
+        }
+
+        Ok(status)
+    }
+
+    ///Run a single instruction, ignoring breakpoints and watchpoints.
+    pub fn step<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<Status,RuntimeError> {
+        self.advance(extender)
+    }
+
+    ///Undo the last instruction run, putting the machine back as it was
+    ///before it. Returns false if there is nothing left to undo. Host
+    ///calls and input aren't made again; their results are replayed.
+    pub fn step_back(&mut self) -> Result<bool,ReplayError> {
+        match self.history.trace().len() {
+            0 => Ok(false),
+            n => {
+                self.history.rewind(&mut self.vm, n - 1)?;
+                Ok(true)
+            },
+        }
+    }
+
+    ///Start the program again from the top, forgetting its history.
+    pub fn reset(&mut self) {
+        self.vm.reset();
+        self.history = Recorder::new(&self.vm);
+    }
+
+    ///Run until the PC reaches a breakpoint, an instruction touches a
+    ///watched cell, the program halts, or an instruction fails. The
+    ///instruction at the current PC always runs, so resuming from a
+    ///breakpoint moves past it.
+    pub fn resume<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<Stop,RuntimeError> {
+        loop {
+            let (status, hit) = self.step_watched(extender)?;
+            if let Some(n) = hit {
+                return Ok(Stop::Watchpoint(n));
+            }
+            if status == Status::Halted {
+                return Ok(Stop::Halted);
+            }
+
+            if self.breakpoints.contains(&self.vm.pc) {
+                return Ok(Stop::Breakpoint(self.vm.pc));
+            }
+        }
+    }
+
+    ///Take the machine back.
+    pub fn into_vm(self) -> Vm<S, M> {self.vm}
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+
+    use debug::{Debugger, Stop, Watch, WatchHit, CHECKPOINT_INTERVAL, MAX_CHECKPOINTS};
+    use {Data, NullExtender, Status, Vm};
+
+    #[test]
+    fn breakpoints() {
+        //Counts down from 3, looping back to the dup at 3.
+        let vm = Vm::new(b"#3'd#1'-d#3'y".to_vec(), Vec::new());
+        let mut debugger = Debugger::new(vm);
+        debugger.set_breakpoint(3);
+
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Breakpoint(3));
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Breakpoint(3));
+        assert_eq!(debugger.vm.stack.to_string(), "<2> 3 2");
+
+        debugger.vm.stack.pop().unwrap();
+        debugger.vm.stack.push(Data::Int(1));
+        assert!(debugger.clear_breakpoint(3));
+        assert!(!debugger.clear_breakpoint(3));
+
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Halted);
+        assert_eq!(debugger.into_vm().stack.to_string(), "<3> 3 1 0");
+    }
+
+    #[test]
+    fn watchpoints() {
+        let vm = Vm::new(b"#5'#0'W#0'R#7'#0'W#1'R".to_vec(), vec![Data::Int(0), Data::Int(9)]);
+        let mut debugger = Debugger::new(vm);
+        debugger.set_watchpoint(0, Watch::Write);
+        debugger.set_watchpoint(1, Watch::Read);
+        assert_eq!(debugger.watchpoints().collect::<Vec<_>>(), vec![(0, Watch::Write), (1, Watch::Read)]);
+
+        let hit = WatchHit { pc: 6, addr: 0, write: true, old: Data::Int(0), new: Data::Int(5) };
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Watchpoint(hit));
+        let hit = WatchHit { pc: 17, addr: 0, write: true, old: Data::Int(5), new: Data::Int(7) };
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Watchpoint(hit));
+        let hit = WatchHit { pc: 21, addr: 1, write: false, old: Data::Int(9), new: Data::Int(9) };
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Watchpoint(hit));
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Halted);
+
+        assert!(debugger.clear_watchpoint(0));
+        assert!(!debugger.clear_watchpoint(0));
+    }
+
+    #[test]
+    fn step_back() {
+        let vm = Vm::new(b"#0'h#2'*".to_vec(), Vec::new());
+        let mut debugger = Debugger::new(vm);
+        let mut calls = 0;
+        debugger.vm.bind("next", move |stack| {
+            calls += 1;
+            stack.push(Data::Int(calls));
+            Ok(())
+        });
+
+        assert!(!debugger.step_back().unwrap());
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Halted);
+        assert_eq!(debugger.vm.stack.to_string(), "<1> 2");
+
+        assert!(debugger.step_back().unwrap());
+        assert_eq!((debugger.vm.pc, debugger.vm.stack.to_string()), (7, String::from("<2> 1 2")));
+        for _ in 0..4 {
+            assert!(debugger.step_back().unwrap());
+        }
+        assert_eq!((debugger.vm.pc, debugger.vm.stack.to_string()), (3, String::from("<1> 0")));
+
+        //Going forward again calls the host once more.
+        assert_eq!(debugger.resume(&mut NullExtender {}).unwrap(), Stop::Halted);
+        assert_eq!(debugger.vm.stack.to_string(), "<1> 4");
+        while debugger.step_back().unwrap() {}
+        assert_eq!((debugger.vm.pc, debugger.vm.stack.to_string()), (0, String::from("<0>")));
+    }
+
+    #[test]
+    fn checkpoints() {
+        //Counts down from 8000, running past more checkpoints than are kept.
+        let vm = Vm::new(b"#8000'#1'-d#6'y".to_vec(), Vec::new());
+        let mut debugger = Debugger::new(vm);
+        let mut digests = vec![debugger.vm.digest()];
+        while debugger.step(&mut NullExtender {}).unwrap() != Status::Halted {
+            digests.push(debugger.vm.digest());
+        }
+        assert_eq!(digests.len(), 72007);
+        assert!(debugger.history.trace().len() <= (MAX_CHECKPOINTS + 1) * CHECKPOINT_INTERVAL);
+
+        for _ in 0..2000 {
+            assert!(debugger.step_back().unwrap());
+            digests.pop();
+            assert_eq!(debugger.vm.digest(), *digests.last().unwrap());
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//!Source maps: where in the source each stretch of bytecode came from.
+//!
+//!The compiler records the file, line, column and enclosing word of every
+//!token at the address its code starts. A module keeps the map in its
+//!`debug` section, which is only written if the module has one, so a
+//!module shipped without it costs nothing. The section is a table of
+//!file names and one of word names, each a count then length-prefixed
+//!strings, and then a count of entries, each the address as a step from
+//!the one before, the file, line and column, and the word plus one, or 0
+//!for top-level code. Every number is an unsigned LEB128 varint.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use module::{write_varint, Reader};
+use Error;
+
+///Where an instruction came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    ///Counted from 1.
+    pub line: u32,
+    ///Counted from 1, in characters.
+    pub column: u32,
+    ///The word being defined, or `None` for top-level code.
+    pub word: Option<String>,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Entry {
+    pc: usize,
+    file: usize,
+    line: u32,
+    column: u32,
+    ///Index into the words plus one, or 0 for top-level code.
+    word: usize,
+}
+
+///Maps addresses in some code back to the source it was compiled from.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DebugInfo {
+    files: Vec<String>,
+    words: Vec<String>,
+    ///Sorted by address.
+    entries: Vec<Entry>,
+}
+
+///Find a name in a table, adding it if it isn't there.
+fn intern(table: &mut Vec<String>, name: &str) -> usize {
+    match table.iter().position(|n| n == name) {
+        Some(n) => n,
+        None => {
+            table.push(String::from(name));
+            table.len() - 1
+        },
+    }
+}
+
+fn write_table(out: &mut Vec<u8>, table: &[String]) {
+    write_varint(out, table.len() as u64);
+    for name in table {
+        write_varint(out, name.len() as u64);
+        out.extend_from_slice(name.as_bytes());
+    }
+}
+
+fn read_table(reader: &mut Reader) -> Result<Vec<String>, Error> {
+    let mut table = Vec::new();
+    for _ in 0..reader.varint()? {
+        let len = reader.varint()? as usize;
+        table.push(String::from(reader.str(len)?));
+    }
+
+    Ok(table)
+}
+
+impl DebugInfo {
+    ///Create an empty map.
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    ///Record that the code from `pc` on, up to the next recorded address,
+    ///came from a place in the source.
+    pub fn insert(&mut self, pc: usize, file: &str, line: u32, column: u32, word: Option<&str>) {
+        let entry = Entry {
+            pc,
+            file: intern(&mut self.files, file),
+            line,
+            column,
+            word: word.map_or(0, |n| intern(&mut self.words, n) + 1),
+        };
+
+        let index = self.entries.partition_point(|n| n.pc <= pc);
+        if index > 0 && self.entries[index - 1].pc == pc {
+            self.entries[index - 1] = entry;
+        } else {
+            self.entries.insert(index, entry);
+        }
+    }
+
+    ///Find where the instruction at an address came from.
+    pub fn locate(&self, pc: usize) -> Option<SourceLocation> {
+        let index = self.entries.partition_point(|n| n.pc <= pc).checked_sub(1)?;
+        let entry = &self.entries[index];
+
+        Some(SourceLocation {
+            file: self.files[entry.file].clone(),
+            line: entry.line,
+            column: entry.column,
+            word: entry.word.checked_sub(1).map(|n| self.words[n].clone()),
+        })
+    }
+
+    ///Get the number of addresses recorded.
+    pub fn len(&self) -> usize {self.entries.len()}
+
+    ///Check whether nothing is recorded.
+    pub fn is_empty(&self) -> bool {self.entries.is_empty()}
+
+    ///Encode the map as the payload of a module's `debug` section.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_table(&mut out, &self.files);
+        write_table(&mut out, &self.words);
+
+        write_varint(&mut out, self.entries.len() as u64);
+        let mut pc = 0;
+        for entry in &self.entries {
+            write_varint(&mut out, (entry.pc - pc) as u64);
+            write_varint(&mut out, entry.file as u64);
+            write_varint(&mut out, entry.line as u64);
+            write_varint(&mut out, entry.column as u64);
+            write_varint(&mut out, entry.word as u64);
+            pc = entry.pc;
+        }
+
+        out
+    }
+
+    ///Decode the payload of a `debug` section.
+    pub fn parse(bytes: &[u8]) -> Result<DebugInfo, Error> {
+        let mut reader = Reader::new(bytes);
+        let files = read_table(&mut reader)?;
+        let words = read_table(&mut reader)?;
+
+        let mut entries = Vec::new();
+        let mut pc: usize = 0;
+        for _ in 0..reader.varint()? {
+            pc = pc.checked_add(reader.varint()? as usize).ok_or(Error::InvalidModule)?;
+            let entry = Entry {
+                pc,
+                file: reader.varint()? as usize,
+                line: reader.varint()? as u32,
+                column: reader.varint()? as u32,
+                word: reader.varint()? as usize,
+            };
+            if entry.file >= files.len() || entry.word > words.len() {
+                return Err(Error::InvalidModule);
+            }
+            entries.push(entry);
+        }
+
+        if !reader.is_empty() {
+            return Err(Error::InvalidModule);
+        }
+
+        Ok(DebugInfo { files, words, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use debuginfo::DebugInfo;
+    use Error;
+
+    #[test]
+    fn locate_and_round_trip() {
+        let mut info = DebugInfo::new();
+        info.insert(0, "main.gg", 3, 1, None);
+        info.insert(8, "main.gg", 1, 12, Some("square"));
+        info.insert(4, "main.gg", 3, 5, None);
+        assert_eq!(info.len(), 3);
+
+        assert!(DebugInfo::new().locate(0).is_none());
+        let location = info.locate(6).unwrap();
+        assert_eq!((location.line, location.column, location.word), (3, 5, None));
+        let location = info.locate(200).unwrap();
+        assert_eq!(location.to_string(), "main.gg:1:12");
+        assert_eq!(location.word.as_deref(), Some("square"));
+
+        let bytes = info.serialize();
+        assert_eq!(DebugInfo::parse(&bytes).unwrap(), info);
+        assert!(matches!(DebugInfo::parse(&bytes[..bytes.len() - 1]), Err(Error::InvalidModule)));
+    }
+}
@@ -0,0 +1,234 @@
+//!Turns bytecode back into something a person can read.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+
+use module::{Dictionary, Module};
+use opcodes::{extended, opcode, ESCAPE};
+use vm::{float_immediate, string_literal};
+
+///A decoded instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    ///A complete `#...'` sequence.
+    Int(i64),
+    ///A complete `#..."` sequence, or a `]` and the float after it.
+    Float(f64),
+    ///A `[...]` string literal.
+    Str(String),
+    ///A `` `name` `` symbolic call.
+    Word(String),
+    ///Any other single byte, including the pieces of a literal that
+    ///could not be folded.
+    Op(u8),
+    ///A `!` and the byte after it, which names an instruction on the
+    ///extended page.
+    Extended(u8),
+}
+
+///The mnemonic for a single-byte opcode, if it is built in.
+pub fn mnemonic(op: u8) -> Option<&'static str> {
+    opcode(op).map(|n| n.mnemonic)
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Int(n) => write!(f, "push {}", n),
+            Instruction::Float(n) => write!(f, "push {:?}", n),
+            Instruction::Str(ref s) => write!(f, "push {:?}", s),
+            Instruction::Word(ref w) => write!(f, "call `{}`", w),
+            Instruction::Op(op) => match mnemonic(op) {
+                Some(name) if op.is_ascii_digit() => write!(f, "{} {}", name, op as char),
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "ext {:#04x}", op),
+            },
+            Instruction::Extended(op) => match extended(op) {
+                Some(n) => write!(f, "{}", n.mnemonic),
+                None => write!(f, "escape {:#04x}", op),
+            },
+        }
+    }
+}
+
+///Iterator over the instructions in some code, yielding each with its
+///address. Whitespace is skipped.
+pub struct Decoder<'a> {
+    code: &'a [u8],
+    pc: usize,
+}
+
+///Decode some code.
+pub fn decode(code: &[u8]) -> Decoder<'_> {
+    Decoder { code, pc: 0 }
+}
+
+impl<'a> Decoder<'a> {
+    ///Try to fold a literal starting at a `#`. Returns the instruction and
+    ///the address after it.
+    fn literal(&self, start: usize) -> Option<(Instruction, usize)> {
+        let mut value: i64 = 0;
+        let mut divider: f64 = 1.0;
+        let mut pc = start + 1;
+
+        while pc < self.code.len() {
+            match self.code[pc] {
+                b @ b'0'..=b'9' => {
+                    value = value.checked_mul(10)?.checked_add((b - b'0') as i64)?;
+                },
+                b'.' => { divider *= 1000.0; },
+                b'$' => { value = -value; },
+                b' ' | b'\n' | b'\r' => {},
+                b'\'' => { return Some((Instruction::Int(value), pc + 1)); }
+                b'"' => { return Some((Instruction::Float(value as f64 / divider), pc + 1)); }
+                _ => { return None; }
+            }
+            pc += 1;
+        }
+
+        None
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = (usize, Instruction);
+
+    fn next(&mut self) -> Option<(usize, Instruction)> {
+        while self.pc < self.code.len() {
+            let addr = self.pc;
+            let op = self.code[addr];
+            self.pc += 1;
+
+            let folded = match op {
+                b' ' | b'\n' | b'\r' => { continue; }
+                b'#' => self.literal(addr),
+                b'[' => string_literal(self.code, addr + 1)
+                    .map(|(text, next)| (Instruction::Str(text), next)),
+                b']' => float_immediate(self.code, addr + 1)
+                    .map(|(value, next)| (Instruction::Float(value), next)),
+                ESCAPE => self.code.get(addr + 1).map(|&n| (Instruction::Extended(n), addr + 2)),
+                b'`' => self.code[addr + 1..].iter().position(|&b| b == b'`').map(|n| {
+                    let name = String::from_utf8_lossy(&self.code[addr + 1..addr + 1 + n]);
+                    (Instruction::Word(name.into_owned()), addr + n + 2)
+                }),
+                _ => None,
+            };
+
+            return match folded {
+                Some((instruction, next)) => {
+                    self.pc = next;
+                    Some((addr, instruction))
+                },
+                None => Some((addr, Instruction::Op(op))),
+            };
+        }
+
+        None
+    }
+}
+
+///Disassemble code into one instruction per line.
+pub fn disasm(code: &[u8]) -> String {
+    listing(code, &Dictionary::new())
+}
+
+///Disassemble a module, labelling the entry points of its words.
+pub fn disasm_module(module: &Module) -> String {
+    listing(&module.code, &module.dictionary)
+}
+
+fn listing(code: &[u8], dictionary: &Dictionary) -> String {
+    let mut names: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for (name, address) in dictionary.iter() {
+        names.entry(address).or_default().push(name);
+    }
+    for list in names.values_mut() {
+        list.sort();
+    }
+
+    let mut out = String::new();
+    let mut previous: Option<Instruction> = None;
+
+    for (addr, instruction) in decode(code) {
+        if let Some(list) = names.get(&addr) {
+            for name in list {
+                let _ = writeln!(out, "{}:", name);
+            }
+        }
+
+        let text = instruction.to_string();
+        let target = match (&previous, &instruction) {
+            (&Some(Instruction::Int(n)), &Instruction::Op(b'b'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'c'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'y'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'z')) => Some(n),
+            (&Some(Instruction::Int(n)), &Instruction::Op(b'B'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'C'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'Y'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'Z'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'J'))
+            | (&Some(Instruction::Int(n)), &Instruction::Op(b'j')) => Some(addr as i64 + 1 + n),
+            (&Some(Instruction::Int(n)), &Instruction::Extended(b'c')) => Some(addr as i64 + 2 + n),
+            _ => None,
+        };
+
+        let line = match target {
+            Some(n) => {
+                let label = names.get(&(n as usize)).map(|l| l.join(" ")).unwrap_or_default();
+                format!("{:04}  {:<16}; -> {:04} {}", addr, text, n, label)
+            },
+            None => format!("{:04}  {}", addr, text),
+        };
+        let _ = writeln!(out, "{}", line.trim_end());
+
+        previous = Some(instruction);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use compiler::compile_module;
+    use disasm::{decode, disasm, disasm_module, Instruction};
+
+    #[test]
+    fn folds_literals() {
+        let decoded: Vec<(usize, Instruction)> = decode(b"#12'#1.500$\" [hi] `sq` d #4 d'").collect();
+        assert_eq!(decoded, vec![
+            (0, Instruction::Int(12)),
+            (4, Instruction::Float(-1.5)),
+            (13, Instruction::Str(String::from("hi"))),
+            (18, Instruction::Word(String::from("sq"))),
+            (23, Instruction::Op(b'd')),
+            (25, Instruction::Op(b'#')),
+            (26, Instruction::Op(b'4')),
+            (28, Instruction::Op(b'd')),
+            (29, Instruction::Op(b'\'')),
+        ]);
+
+        assert_eq!(disasm(b"d\xf0"), "0000  dup\n0001  ext 0xf0\n");
+        assert_eq!(disasm(b"#1'!{!}"), "0000  push 1\n0003  enter\n0005  leave\n");
+    }
+
+    #[test]
+    fn annotates_targets() {
+        let module = compile_module(": square dup * ; 3 square").unwrap();
+        assert_eq!(disasm_module(&module), "\
+0000  push 3
+0003  push 1
+0008  rjump           ; -> 0010 square
+0009  ret
+square:
+0010  dup
+0011  mul
+0012  ret
+");
+    }
+}
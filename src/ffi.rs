@@ -0,0 +1,322 @@
+//!A C API for embedding the interpreter, behind the `ffi` feature. Build
+//!a library with
+//!
+//!```text
+//!cargo rustc --lib --release --features ffi --crate-type staticlib
+//!```
+//!
+//!(or `cdylib`) and generate a header with `cbindgen`; see
+//!`cbindgen.toml`.
+//!
+//!A machine is an opaque `GgVm` pointer from `gg_vm_new`, freed with
+//!`gg_vm_free`. Every call that can fail returns a `GgStatus`, and the
+//!machine remembers the last failure of `gg_vm_load` or `gg_vm_run` so its
+//!code, PC and message can be fetched afterwards.
+
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::ptr;
+use core::slice;
+use std::ffi::{c_char, CString};
+
+use {Data, Error, NullExtender, RuntimeError, Vm};
+
+///The result of a call. `Ok` is zero and every error has a fixed code, so
+///the numbers are safe to store.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GgStatus {
+    Ok = 0,
+    StackUnderflow = 1,
+    TypeMismatch = 2,
+    InvalidInstruction = 3,
+    UnknownWord = 4,
+    InvalidModule = 5,
+    FuelExhausted = 6,
+    ReturnStackOverflow = 7,
+    DivisionByZero = 8,
+    UnsupportedVersion = 9,
+    MemoryOutOfBounds = 10,
+    ReturnStackUnderflow = 11,
+    IntegerOverflow = 12,
+    Nondeterministic = 13,
+    InvalidHandle = 14,
+    StackOverflow = 15,
+    Io = 16,
+    StackLimitExceeded = 17,
+    MemoryLimitExceeded = 18,
+    OpcodeNotAllowed = 19,
+    Thrown = 20,
+    Interrupted = 21,
+    Timeout = 22,
+    InvalidCharacter = 23,
+}
+
+impl From<&Error> for GgStatus {
+    fn from(err: &Error) -> GgStatus {
+        match *err {
+            Error::StackUnderflow => GgStatus::StackUnderflow,
+            Error::TypeMismatch { .. } => GgStatus::TypeMismatch,
+            Error::InvalidInstruction { .. } => GgStatus::InvalidInstruction,
+            Error::UnknownWord => GgStatus::UnknownWord,
+            Error::InvalidModule => GgStatus::InvalidModule,
+            Error::FuelExhausted => GgStatus::FuelExhausted,
+            Error::ReturnStackOverflow => GgStatus::ReturnStackOverflow,
+            Error::DivisionByZero => GgStatus::DivisionByZero,
+            Error::UnsupportedVersion(_) => GgStatus::UnsupportedVersion,
+            Error::MemoryOutOfBounds { .. } => GgStatus::MemoryOutOfBounds,
+            Error::ReturnStackUnderflow => GgStatus::ReturnStackUnderflow,
+            Error::IntegerOverflow => GgStatus::IntegerOverflow,
+            Error::Nondeterministic => GgStatus::Nondeterministic,
+            Error::InvalidHandle => GgStatus::InvalidHandle,
+            Error::StackOverflow => GgStatus::StackOverflow,
+            Error::StackLimitExceeded => GgStatus::StackLimitExceeded,
+            Error::MemoryLimitExceeded => GgStatus::MemoryLimitExceeded,
+            Error::OpcodeNotAllowed(_) => GgStatus::OpcodeNotAllowed,
+            Error::Thrown(_) => GgStatus::Thrown,
+            Error::Interrupted => GgStatus::Interrupted,
+            Error::Timeout => GgStatus::Timeout,
+            Error::InvalidCharacter(_) => GgStatus::InvalidCharacter,
+            Error::Io(_) => GgStatus::Io,
+        }
+    }
+}
+
+fn status(result: Result<(),Error>) -> GgStatus {
+    match result {
+        Ok(()) => GgStatus::Ok,
+        Err(ref n) => GgStatus::from(n),
+    }
+}
+
+///A machine owned by C code.
+pub struct GgVm {
+    vm: Vm,
+    error: Option<RuntimeError>,
+    message: CString,
+}
+
+impl GgVm {
+    fn record(&mut self, result: Result<(),RuntimeError>) -> GgStatus {
+        match result {
+            Ok(()) => {
+                self.error = None;
+                GgStatus::Ok
+            },
+            Err(n) => {
+                let code = GgStatus::from(&n.kind);
+                //Only an I/O message could hold a NUL; that one comes out empty.
+                self.message = CString::new(n.to_string()).unwrap_or_default();
+                self.error = Some(n);
+                code
+            },
+        }
+    }
+}
+
+///Create a machine with no code and some cells of zeroed memory. Free it
+///with `gg_vm_free`.
+#[no_mangle]
+pub extern "C" fn gg_vm_new(memory_cells: usize) -> *mut GgVm {
+    let vm = GgVm {
+        vm: Vm::new(Vec::new(), vec![Data::Int(0); memory_cells]),
+        error: None,
+        message: CString::default(),
+    };
+
+    Box::into_raw(Box::new(vm))
+}
+
+///Free a machine. Null is ignored.
+///
+///# Safety
+///
+///`vm` must be null or come from `gg_vm_new`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_free(vm: *mut GgVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+///Load bytecode, copying it, and rewind to PC 0.
+///
+///# Safety
+///
+///`vm` must be a live machine and `code` must point to `len` readable
+///bytes, or be null if `len` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_load(vm: *mut GgVm, code: *const u8, len: usize) -> GgStatus {
+    let vm = &mut *vm;
+    let code = match len {
+        0 => Vec::new(),
+        _ => slice::from_raw_parts(code, len).to_vec(),
+    };
+
+    let result = vm.vm.load(code);
+    vm.record(result)
+}
+
+///Run until the code halts or fails, executing at most `max_steps`
+///instructions. Zero means no limit.
+///
+///# Safety
+///
+///`vm` must be a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_run(vm: *mut GgVm, max_steps: u64) -> GgStatus {
+    let vm = &mut *vm;
+    vm.vm.config.max_steps = match max_steps {
+        0 => None,
+        n => Some(n),
+    };
+
+    let result = vm.vm.run(&mut NullExtender {});
+    vm.record(result)
+}
+
+///Push an int.
+///
+///# Safety
+///
+///`vm` must be a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_push_int(vm: *mut GgVm, value: i64) -> GgStatus {
+    status((*vm).vm.stack.try_push(Data::Int(value)))
+}
+
+///Push a float.
+///
+///# Safety
+///
+///`vm` must be a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_push_float(vm: *mut GgVm, value: f64) -> GgStatus {
+    status((*vm).vm.stack.try_push(Data::Float(value)))
+}
+
+///Pop an int into `out`. Anything else on top is a `TypeMismatch` and is
+///left there.
+///
+///# Safety
+///
+///`vm` must be a live machine and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_pop_int(vm: *mut GgVm, out: *mut i64) -> GgStatus {
+    match (*vm).vm.stack.pop_int() {
+        Ok(n) => {
+            *out = n;
+            GgStatus::Ok
+        },
+        Err(ref n) => GgStatus::from(n),
+    }
+}
+
+///Pop a float into `out`. Ints are not converted.
+///
+///# Safety
+///
+///`vm` must be a live machine and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_pop_float(vm: *mut GgVm, out: *mut f64) -> GgStatus {
+    match (*vm).vm.stack.pop_float() {
+        Ok(n) => {
+            *out = n;
+            GgStatus::Ok
+        },
+        Err(ref n) => GgStatus::from(n),
+    }
+}
+
+///Get the number of values on the data stack.
+///
+///# Safety
+///
+///`vm` must be a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_stack_len(vm: *const GgVm) -> usize {
+    (*vm).vm.stack.len()
+}
+
+///Get the code of the last failed load or run, or `Ok` if the last one
+///succeeded.
+///
+///# Safety
+///
+///`vm` must be a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_error(vm: *const GgVm) -> GgStatus {
+    match (*vm).error {
+        Some(ref n) => GgStatus::from(&n.kind),
+        None => GgStatus::Ok,
+    }
+}
+
+///Get the PC of the last failed load or run, or 0 if it succeeded.
+///
+///# Safety
+///
+///`vm` must be a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_error_pc(vm: *const GgVm) -> usize {
+    match (*vm).error {
+        Some(ref n) => n.pc,
+        None => 0,
+    }
+}
+
+///Describe the last failed load or run, like `Stack Underflow at 3`, or
+///get null if it succeeded. The string belongs to the machine and lasts
+///until its next load or run.
+///
+///# Safety
+///
+///`vm` must be a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn gg_vm_error_message(vm: *const GgVm) -> *const c_char {
+    match (*vm).error {
+        Some(_) => (*vm).message.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    use ffi::*;
+
+    #[test]
+    fn embed() {
+        unsafe {
+            let vm = gg_vm_new(4);
+            let code = b"+ #2'*";
+            assert_eq!(gg_vm_load(vm, code.as_ptr(), code.len()), GgStatus::Ok);
+            assert_eq!(gg_vm_push_int(vm, 3), GgStatus::Ok);
+            assert_eq!(gg_vm_push_int(vm, 4), GgStatus::Ok);
+            assert_eq!(gg_vm_run(vm, 0), GgStatus::Ok);
+            assert!(gg_vm_error_message(vm).is_null());
+
+            let mut n = 0;
+            assert_eq!(gg_vm_pop_int(vm, &mut n), GgStatus::Ok);
+            assert_eq!(n, 14);
+            assert_eq!(gg_vm_pop_int(vm, &mut n), GgStatus::StackUnderflow);
+
+            assert_eq!(gg_vm_push_float(vm, 0.5), GgStatus::Ok);
+            assert_eq!(gg_vm_pop_int(vm, &mut n), GgStatus::TypeMismatch);
+            assert_eq!(gg_vm_stack_len(vm), 1);
+
+            assert_eq!(gg_vm_load(vm, ptr::null(), 0), GgStatus::Ok);
+            let code = b"d d +";
+            gg_vm_load(vm, code.as_ptr(), code.len());
+            assert_eq!(gg_vm_run(vm, 2), GgStatus::FuelExhausted);
+            assert_eq!(gg_vm_error(vm), GgStatus::FuelExhausted);
+            assert_eq!(gg_vm_error_pc(vm), 2);
+            assert_eq!(CStr::from_ptr(gg_vm_error_message(vm)).to_str().unwrap(), "Fuel Exhausted at 2");
+
+            gg_vm_free(vm);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//!A canonical layout for Forth-style source, as `greengold fmt` writes it.
+//!
+//!Words are separated by single spaces and `( ... )` comments by single
+//!spaces inside. Each definition starts a line of its own. One that fits
+//!on a line and has no control structures or `\` comments is written on
+//!one line, and the stack comments of a run of such lines are lined up.
+//!Any other definition puts its name and stack comment on the first line
+//!and its body on the lines after, indented one level and filled up to
+//!the width, with `if`, `begin` and `do` indenting what they enclose. A
+//!`\` comment ends its line. Top-level code keeps its line breaks, and
+//!blank lines between things are kept, at most one at a time.
+//!
+//!Only whitespace changes, so the code compiles the same either way, and
+//!formatting twice gives the same as formatting once.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::iter;
+
+use compiler::{lex, Lexeme};
+
+///Width lines are filled to. A single long word or comment can still
+///go past it.
+const WIDTH: usize = 80;
+///Spaces per level of indentation.
+const INDENT: usize = 4;
+
+///A token as it will be written.
+struct Item<'a> {
+    token: Lexeme<'a>,
+    text: String,
+    ///How many newlines came between it and the token before, up to 2.
+    breaks: usize,
+}
+
+enum Line {
+    Blank,
+    ///A level of indentation and the text after it.
+    Text(usize, String),
+    ///A definition on one line: `: name`, its stack comment, if it has
+    ///one, and the rest.
+    Definition(String, Option<String>, String),
+}
+
+///Check whether a word starts a control structure, comes partway
+///through one, or ends one.
+fn opens(word: &str) -> bool {matches!(word, "if" | "begin" | "do")}
+
+fn divides(word: &str) -> bool {matches!(word, "else" | "while")}
+
+fn closes(word: &str) -> bool {matches!(word, "then" | "until" | "again" | "repeat" | "loop" | "+loop")}
+
+fn width(text: &str) -> usize {text.chars().count()}
+
+fn render(token: Lexeme) -> String {
+    match token {
+        Lexeme::Word(n) => String::from(n),
+        Lexeme::Str(text) => format!("s\" {}\"", text),
+        Lexeme::Comment(text) => {
+            let words: Vec<&str> = text.split_whitespace().collect();
+            match words.len() {
+                0 => String::from("( )"),
+                _ => format!("( {} )", words.join(" ")),
+            }
+        },
+        Lexeme::LineComment(text) => match text.trim() {
+            "" => String::from("\\"),
+            text => format!("\\ {}", text),
+        },
+    }
+}
+
+///Collects lines, filling each with words up to the width.
+#[derive(Default)]
+struct Writer {
+    lines: Vec<Line>,
+    ///The words of the line being filled.
+    words: Vec<String>,
+    indent: usize,
+}
+
+impl Writer {
+    fn flush(&mut self) {
+        if !self.words.is_empty() {
+            self.lines.push(Line::Text(self.indent, self.words.join(" ")));
+            self.words.clear();
+        }
+    }
+
+    fn push(&mut self, text: String) {
+        let used: usize = self.indent * INDENT + self.words.iter().map(|n| width(n) + 1).sum::<usize>();
+        if !self.words.is_empty() && used + width(&text) > WIDTH {
+            self.flush();
+        }
+        self.words.push(text);
+    }
+
+    fn blank(&mut self) {
+        self.flush();
+        if !matches!(self.lines.last(), None | Some(Line::Blank)) {
+            self.lines.push(Line::Blank);
+        }
+    }
+
+    ///Lay out a definition, from its `:` to its `;`.
+    fn definition(&mut self, items: Vec<Item>) {
+        let mut items = items.into_iter().peekable();
+        let mut head = String::from(":");
+        items.next();
+        if let Some(name) = items.next_if(|n| matches!(n.token, Lexeme::Word(_))) {
+            head.push(' ');
+            head.push_str(&name.text);
+        }
+        let comment = items.next_if(|n| matches!(n.token, Lexeme::Comment(_))).map(|n| n.text);
+        let body: Vec<Item> = items.collect();
+
+        let simple = body.iter().all(|n| match n.token {
+            Lexeme::Word(w) => !opens(w) && !divides(w) && !closes(w),
+            Lexeme::LineComment(_) => false,
+            _ => true,
+        });
+        if simple {
+            let rest: Vec<&str> = body.iter().map(|n| &n.text[..]).collect();
+            let rest = rest.join(" ");
+            let len = width(&head) + comment.as_ref().map_or(0, |n| width(n) + 1) + width(&rest) + 1;
+            if len <= WIDTH {
+                self.lines.push(Line::Definition(head, comment, rest));
+                return;
+            }
+        }
+
+        self.words.push(head);
+        self.words.extend(comment);
+        self.flush();
+
+        self.indent = 1;
+        for item in body {
+            match item.token {
+                Lexeme::Word(w) if opens(w) => {
+                    if w == "begin" {
+                        self.flush();
+                    }
+                    self.push(item.text);
+                    self.flush();
+                    self.indent += 1;
+                },
+                Lexeme::Word(w) if divides(w) => {
+                    self.flush();
+                    self.indent = (self.indent - 1).max(1);
+                    self.push(item.text);
+                    self.flush();
+                    self.indent += 1;
+                },
+                Lexeme::Word(w) if closes(w) => {
+                    self.flush();
+                    self.indent = (self.indent - 1).max(1);
+                    self.push(item.text);
+                },
+                Lexeme::Word(";") if self.words.is_empty() => {
+                    self.indent = 0;
+                    self.push(item.text);
+                },
+                Lexeme::LineComment(_) => {
+                    if item.breaks > 0 {
+                        self.flush();
+                    }
+                    self.push(item.text);
+                    self.flush();
+                },
+                _ => self.push(item.text),
+            }
+        }
+        self.flush();
+        self.indent = 0;
+    }
+
+    fn finish(mut self) -> String {
+        self.flush();
+        while let Some(Line::Blank) = self.lines.last() {
+            self.lines.pop();
+        }
+
+        let mut out = String::new();
+        let mut n = 0;
+        while n < self.lines.len() {
+            match self.lines[n] {
+                Line::Blank => out.push('\n'),
+                Line::Text(indent, ref text) => {
+                    out.extend(iter::repeat_n(' ', indent * INDENT));
+                    out.push_str(text);
+                    out.push('\n');
+                },
+                Line::Definition(..) => {
+                    let end = self.lines[n..].iter().position(|n| !matches!(n, Line::Definition(..))).map_or(self.lines.len(), |k| n + k);
+                    definitions(&mut out, &self.lines[n..end]);
+                    n = end;
+                    continue;
+                },
+            }
+            n += 1;
+        }
+
+        out
+    }
+}
+
+///Write a run of one-line definitions, lining up their stack comments if
+///that keeps them all within the width.
+fn definitions(out: &mut String, lines: &[Line]) {
+    let mut column = 0;
+    for line in lines {
+        if let Line::Definition(ref head, Some(_), _) = *line {
+            column = column.max(width(head));
+        }
+    }
+    let fits = lines.iter().all(|n| match *n {
+        Line::Definition(_, Some(ref comment), ref rest) => column + width(comment) + width(rest) + 2 <= WIDTH,
+        _ => true,
+    });
+
+    for line in lines {
+        if let Line::Definition(ref head, ref comment, ref rest) = *line {
+            out.push_str(head);
+            if let Some(ref comment) = *comment {
+                if fits {
+                    out.extend(iter::repeat_n(' ', column - width(head)));
+                }
+                out.push(' ');
+                out.push_str(comment);
+            }
+            if !rest.is_empty() {
+                out.push(' ');
+                out.push_str(rest);
+            }
+            out.push('\n');
+        }
+    }
+}
+
+///Lay source out canonically. Source that doesn't split into tokens,
+///such as one with a comment that isn't closed, is given back as it is.
+pub fn format_source(source: &str) -> String {
+    let tokens = match lex(source) {
+        Ok(n) => n,
+        Err(_) => { return String::from(source); }
+    };
+
+    let mut end = 0;
+    let mut items = Vec::new();
+    for (span, token) in tokens {
+        let breaks = source[end..span.start].matches('\n').count().min(2);
+        end = span.end;
+        items.push(Item { token, text: render(token), breaks });
+    }
+
+    let mut writer = Writer::default();
+    let mut items = items.into_iter();
+    while let Some(item) = items.next() {
+        match item.breaks {
+            0 => {},
+            1 => writer.flush(),
+            _ => writer.blank(),
+        }
+
+        match item.token {
+            Lexeme::Word(":") => {
+                writer.flush();
+                let mut definition = vec![item];
+                for n in items.by_ref() {
+                    let last = n.token == Lexeme::Word(";");
+                    definition.push(n);
+                    if last {
+                        break;
+                    }
+                }
+                writer.definition(definition);
+            },
+            Lexeme::LineComment(_) => {
+                writer.push(item.text);
+                writer.flush();
+            },
+            _ => writer.push(item.text),
+        }
+    }
+
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use compiler::compile;
+    use format::format_source;
+
+    const MESSY: &str = "variable  total   \\ running sum
+: square  ( n -- n*n )dup * ;
+: cube ( n  --  n^3 ) dup square * ;
+
+
+: sum-to ( n -- sum )  0 swap 1 + 1 do i + loop ;
+: classify ( n -- flag ) dup 0 < if drop -1 else 0 > if 1 else 0 then then ;
+: countdown ( n -- ) begin dup . 1 - dup 0 = until drop ; \\ prints n..1
+5 sum-to total !   3 cube
+";
+
+    const TIDY: &str = "variable total \\ running sum
+: square ( n -- n*n ) dup * ;
+: cube   ( n -- n^3 ) dup square * ;
+
+: sum-to ( n -- sum )
+    0 swap 1 + 1 do
+        i +
+    loop ;
+: classify ( n -- flag )
+    dup 0 < if
+        drop -1
+    else
+        0 > if
+            1
+        else
+            0
+        then
+    then ;
+: countdown ( n -- )
+    begin
+        dup . 1 - dup 0 =
+    until drop ;
+\\ prints n..1
+5 sum-to total ! 3 cube
+";
+
+    #[test]
+    fn layout() {
+        assert_eq!(format_source(MESSY), TIDY);
+        assert_eq!(format_source(TIDY), TIDY);
+        assert!(compile(TIDY).is_ok());
+        assert_eq!(compile(MESSY), compile(TIDY));
+        assert_eq!(format_source("( open"), "( open");
+    }
+
+    #[test]
+    fn alignment_and_wrapping() {
+        assert_eq!(format_source(": sq ( n -- n ) dup * ;\n: double ( n -- n ) 2 * ;\n: nop ;"),
+            ": sq     ( n -- n ) dup * ;\n: double ( n -- n ) 2 * ;\n: nop ;\n");
+
+        let long = format!(": long {} ;", vec!["1 +"; 30].join(" "));
+        let formatted = format_source(&long);
+        assert!(formatted.lines().all(|n| n.len() <= 80));
+        assert!(formatted.starts_with(": long\n    1 + 1 +"));
+        assert_eq!(format_source(&formatted), formatted);
+    }
+}
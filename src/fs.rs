@@ -0,0 +1,256 @@
+//!Words for reading and writing files, behind the `fs` feature.
+//!
+//!Nothing here is bound unless the host asks: `FsExtender::install` adds
+//!the words to a machine's host functions, and a sandbox still refuses
+//!them unless `h` is allowed. A chrooted extender only opens paths
+//!inside its directory, given relative to it.
+//!
+//!Files are referred to by handles, small ints from `open-file`:
+//!
+//!- `open-file ( path mode -- handle )` opens a file. The mode is `r` to
+//!  read, `w` to write from empty, `a` to append or `r+` to read and
+//!  write an existing file; `w` and `a` create it if needed.
+//!- `read-file ( n handle -- s )` reads up to n bytes as a string, with
+//!  any invalid UTF-8 replaced. It is empty at the end of the file.
+//!- `write-file ( s handle -- )` writes a string.
+//!- `seek-file ( offset handle -- )` moves to a byte offset from the
+//!  start.
+//!- `close-file ( handle -- )` closes a file.
+//!
+//!A handle that isn't open is an `InvalidHandle`; anything the operating
+//!system refuses, and a path outside the root, is an `Io` error.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use host::HostFunctions;
+use storage::Storage;
+use {Data, Error};
+
+///Open files, and the directory they have to be in, if any.
+#[derive(Debug, Default)]
+pub struct FsExtender {
+    root: Option<PathBuf>,
+    ///Indexed by handle. Closed handles are reused.
+    files: Vec<Option<File>>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+///Make opening a symbolic link fail.
+#[cfg(unix)]
+fn no_follow(options: &mut OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_NOFOLLOW);
+}
+
+#[cfg(not(unix))]
+fn no_follow(_: &mut OpenOptions) {}
+
+impl FsExtender {
+    ///Allow any path the process can reach. Relative paths are from the
+    ///working directory.
+    pub fn new() -> FsExtender {
+        FsExtender::default()
+    }
+
+    ///Only allow paths inside a directory. The check is made when a file
+    ///is opened, against where the path really leads: links between
+    ///directories have to stay inside, and the file itself can't be a
+    ///symbolic link at all.
+    pub fn chrooted<P: AsRef<Path>>(root: P) -> io::Result<FsExtender> {
+        Ok(FsExtender {
+            root: Some(root.as_ref().canonicalize()?),
+            files: Vec::new(),
+        })
+    }
+
+    ///Bind the file words to a set of host functions.
+    pub fn install<S: Storage>(self, host: &mut HostFunctions<S>) {
+        let fs = Arc::new(Mutex::new(self));
+
+        let n = fs.clone();
+        host.bind("open-file", move |stack| {
+            let (path, mode): (String, String) = stack.pop_args()?;
+            let handle = n.lock().unwrap().open(&path, &mode)?;
+            stack.try_push(Data::Int(handle))
+        });
+        let n = fs.clone();
+        host.bind("read-file", move |stack| {
+            let (len, handle): (i64, i64) = stack.pop_args()?;
+            let text = n.lock().unwrap().read(handle, len)?;
+            stack.try_push(Data::Str(text.into()))
+        });
+        let n = fs.clone();
+        host.bind("write-file", move |stack| {
+            let (text, handle): (String, i64) = stack.pop_args()?;
+            n.lock().unwrap().file(handle)?.write_all(text.as_bytes())?;
+            Ok(())
+        });
+        let n = fs.clone();
+        host.bind("seek-file", move |stack| {
+            let (offset, handle): (i64, i64) = stack.pop_args()?;
+            if offset < 0 {
+                return Err(invalid("negative offset"));
+            }
+            n.lock().unwrap().file(handle)?.seek(SeekFrom::Start(offset as u64))?;
+            Ok(())
+        });
+        host.bind("close-file", move |stack| {
+            let handle = stack.pop_int()?;
+            fs.lock().unwrap().close(handle)
+        });
+    }
+
+    ///Where a path leads, if it is allowed.
+    fn resolve(&self, path: &str) -> Result<PathBuf,Error> {
+        let root = match self.root {
+            Some(ref n) => n,
+            None => { return Ok(PathBuf::from(path)); }
+        };
+
+        let path = Path::new(path);
+        if !path.components().all(|n| matches!(n, Component::Normal(_) | Component::CurDir)) {
+            return Err(invalid("path leaves the root"));
+        }
+        let full = root.join(path);
+        let name = match full.file_name() {
+            Some(n) => n,
+            None => { return Err(invalid("path leaves the root")); }
+        };
+        //The file may not exist yet, but its directory has to.
+        let dir = match full.parent() {
+            Some(n) => n.canonicalize()?,
+            None => { return Err(invalid("path leaves the root")); }
+        };
+        if !dir.starts_with(root) {
+            return Err(invalid("path leaves the root"));
+        }
+        let real = dir.join(name);
+        if let Ok(n) = fs::symlink_metadata(&real) {
+            if n.file_type().is_symlink() {
+                return Err(invalid("path is a symbolic link"));
+            }
+        }
+
+        Ok(real)
+    }
+
+    fn open(&mut self, path: &str, mode: &str) -> Result<i64,Error> {
+        let mut options = OpenOptions::new();
+        match mode {
+            "r" => options.read(true),
+            "w" => options.write(true).create(true).truncate(true),
+            "a" => options.append(true).create(true),
+            "r+" => options.read(true).write(true),
+            _ => { return Err(invalid("unknown mode")); }
+        };
+        if self.root.is_some() {
+            //A link made after the check is refused by the open itself.
+            no_follow(&mut options);
+        }
+        let file = options.open(self.resolve(path)?)?;
+
+        match self.files.iter().position(Option::is_none) {
+            Some(n) => {
+                self.files[n] = Some(file);
+                Ok(n as i64)
+            },
+            None => {
+                self.files.push(Some(file));
+                Ok(self.files.len() as i64 - 1)
+            },
+        }
+    }
+
+    fn file(&mut self, handle: i64) -> Result<&mut File,Error> {
+        match self.files.get_mut(handle as usize) {
+            Some(&mut Some(ref mut n)) if handle >= 0 => Ok(n),
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+
+    fn read(&mut self, handle: i64, len: i64) -> Result<String,Error> {
+        if len < 0 {
+            return Err(invalid("negative length"));
+        }
+
+        let mut bytes = Vec::new();
+        self.file(handle)?.take(len as u64).read_to_end(&mut bytes)?;
+
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn close(&mut self, handle: i64) -> Result<(),Error> {
+        self.file(handle)?;
+        self.files[handle as usize] = None;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use compiler::Compiler;
+    use fs::FsExtender;
+    use {Error, NullExtender, Vm};
+
+    fn run(vm: &mut Vm, source: &str) -> Result<(),Error> {
+        let mut compiler = Compiler::new();
+        compiler.use_host(&vm.host);
+        compiler.compile(source).unwrap();
+        let module = compiler.into_module();
+        vm.constants = module.constants;
+        vm.load(module.code).unwrap();
+        vm.run(&mut NullExtender {}).map_err(|n| n.kind)
+    }
+
+    #[test]
+    fn files() {
+        let root = env::temp_dir().join(format!("greengold-fs-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+
+        let mut vm = Vm::new(Vec::new(), Vec::new());
+        FsExtender::chrooted(&root).unwrap().install(&mut vm.host);
+        run(&mut vm, r#"
+            s" sub/data.txt" s" w" open-file
+            dup s" hello world" swap write-file close-file
+            s" sub/data.txt" s" r+" open-file
+            dup 6 swap seek-file
+            dup 3 swap read-file swap
+            dup 6 swap seek-file
+            dup s" WORLD" swap write-file
+            dup 0 swap seek-file
+            dup 100 swap read-file swap close-file
+        "#).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> \"wor\" \"hello WORLD\"");
+        assert_eq!(fs::read_to_string(root.join("sub/data.txt")).unwrap(), "hello WORLD");
+
+        assert!(matches!(run(&mut vm, "0 close-file"), Err(Error::InvalidHandle)));
+        assert!(matches!(run(&mut vm, "s\" ../data.txt\" s\" w\" open-file"), Err(Error::Io(_))));
+        assert!(matches!(run(&mut vm, "s\" /etc/hostname\" s\" r\" open-file"), Err(Error::Io(_))));
+        assert!(matches!(run(&mut vm, "s\" sub/missing\" s\" r\" open-file"), Err(Error::Io(_))));
+        assert!(matches!(run(&mut vm, "s\" sub/data.txt\" s\" x\" open-file"), Err(Error::Io(_))));
+
+        //A link out of the root, to a file that doesn't exist yet.
+        #[cfg(unix)]
+        {
+            let outside = env::temp_dir().join(format!("greengold-fs-{}-outside", process::id()));
+            ::std::os::unix::fs::symlink(&outside, root.join("sub/link")).unwrap();
+            assert!(matches!(run(&mut vm, "s\" sub/link\" s\" w\" open-file"), Err(Error::Io(_))));
+            assert!(matches!(run(&mut vm, "s\" sub/./link\" s\" a\" open-file"), Err(Error::Io(_))));
+            assert!(!outside.exists());
+        }
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,481 @@
+//!Objects that live outside the stack and memory cells, reached through
+//!handles such as `Data::Array` and `Data::Map`.
+//!
+//!A handle is an index into the heap. Freed slots are reused, so a handle
+//!kept after its object is freed may later point at something else;
+//!using it before then fails with `InvalidHandle`.
+//!
+//!Objects are freed either explicitly, or by `Heap::collect`, a
+//!mark-and-sweep pass that frees everything not reachable from a set of
+//!roots. `Vm::gc` runs it with the stack, memory and the host's roots.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use storage::Storage;
+use {Data, Error, Stack, TypeTag};
+
+///A map key. Only ints and strings can be keys.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Key {
+    Int(i64),
+    Str(Arc<str>),
+}
+
+impl Key {
+    ///Turn a value into a key.
+    pub fn from_data(value: Data) -> Result<Key,Error> {
+        match value {
+            Data::Int(n) => Ok(Key::Int(n)),
+            Data::Str(n) => Ok(Key::Str(n)),
+            other => Err(Error::TypeMismatch { expected: TypeTag::Key, found: other.type_tag() }),
+        }
+    }
+}
+
+impl From<Key> for Data {
+    fn from(key: Key) -> Data {
+        match key {
+            Key::Int(n) => Data::Int(n),
+            Key::Str(n) => Data::Str(n),
+        }
+    }
+}
+
+///Something stored in the heap.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Object {
+    Array(Vec<Data>),
+    ///Entries are kept in key order, so walking a map is reproducible.
+    Map(BTreeMap<Key, Data>),
+}
+
+///The arena that owns every heap object.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    free: Vec<usize>,
+    ///Cells in live arrays, as they were when stored.
+    #[cfg_attr(feature = "serde", serde(default))]
+    cells: usize,
+}
+
+impl Heap {
+    ///Create an empty heap.
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    ///Get the number of live objects.
+    pub fn len(&self) -> usize {self.objects.len() - self.free.len()}
+
+    ///Check whether there are no live objects.
+    pub fn is_empty(&self) -> bool {self.len() == 0}
+
+    ///Get the number of cells in live arrays, counted as they were when
+    ///each was stored.
+    pub fn cells(&self) -> usize {self.cells}
+
+    ///Store an object, returning its handle.
+    pub fn insert(&mut self, object: Object) -> usize {
+        self.cells += object_cells(&object);
+        match self.free.pop() {
+            Some(handle) => {
+                self.objects[handle] = Some(object);
+                handle
+            },
+            None => {
+                self.objects.push(Some(object));
+                self.objects.len() - 1
+            },
+        }
+    }
+
+    ///Look up an object.
+    pub fn get(&self, handle: usize) -> Result<&Object,Error> {
+        match self.objects.get(handle) {
+            Some(Some(object)) => Ok(object),
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+
+    ///Look up an object to change it.
+    pub fn get_mut(&mut self, handle: usize) -> Result<&mut Object,Error> {
+        match self.objects.get_mut(handle) {
+            Some(Some(object)) => Ok(object),
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+
+    ///Drop an object, making its handle invalid.
+    pub fn free(&mut self, handle: usize) -> Result<Object,Error> {
+        match self.objects.get_mut(handle).and_then(|slot| slot.take()) {
+            Some(object) => {
+                self.free.push(handle);
+                self.cells = self.cells.saturating_sub(object_cells(&object));
+                Ok(object)
+            },
+            None => Err(Error::InvalidHandle),
+        }
+    }
+
+    ///Iterate over `(handle, object)` pairs for the live objects, lowest
+    ///handle first.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Object)> + '_ {
+        self.objects.iter().enumerate().filter_map(|(handle, slot)| slot.as_ref().map(|object| (handle, object)))
+    }
+
+    ///Free every object that can't be reached from the roots, following
+    ///handles stored inside arrays and map values. Returns how many were
+    ///freed. Roots that aren't handles, or whose object is gone, are
+    ///ignored.
+    pub fn collect<'a, I: IntoIterator<Item = &'a Data>>(&mut self, roots: I) -> usize {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<usize> = roots.into_iter().filter_map(handle).collect();
+
+        while let Some(n) = pending.pop() {
+            if n >= marked.len() || marked[n] {
+                continue;
+            }
+            marked[n] = true;
+
+            match self.objects[n] {
+                Some(Object::Array(ref cells)) => pending.extend(cells.iter().filter_map(handle)),
+                Some(Object::Map(ref entries)) => pending.extend(entries.values().filter_map(handle)),
+                None => {},
+            }
+        }
+
+        let mut freed = 0;
+        for (n, slot) in self.objects.iter_mut().enumerate() {
+            if !marked[n] {
+                if let Some(object) = slot.take() {
+                    self.cells = self.cells.saturating_sub(object_cells(&object));
+                    self.free.push(n);
+                    freed += 1;
+                }
+            }
+        }
+
+        freed
+    }
+
+    ///Look up an array.
+    pub fn array(&self, handle: usize) -> Result<&Vec<Data>,Error> {
+        match *self.get(handle)? {
+            Object::Array(ref cells) => Ok(cells),
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+
+    ///Look up an array to change it.
+    pub fn array_mut(&mut self, handle: usize) -> Result<&mut Vec<Data>,Error> {
+        match *self.get_mut(handle)? {
+            Object::Array(ref mut cells) => Ok(cells),
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+
+    ///Look up a map.
+    pub fn map(&self, handle: usize) -> Result<&BTreeMap<Key, Data>,Error> {
+        match *self.get(handle)? {
+            Object::Map(ref entries) => Ok(entries),
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+
+    ///Look up a map to change it.
+    pub fn map_mut(&mut self, handle: usize) -> Result<&mut BTreeMap<Key, Data>,Error> {
+        match *self.get_mut(handle)? {
+            Object::Map(ref mut entries) => Ok(entries),
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+}
+
+///The cells an object counts for in `Heap::cells`.
+fn object_cells(object: &Object) -> usize {
+    match *object {
+        Object::Array(ref cells) => cells.len(),
+        Object::Map(_) => 0,
+    }
+}
+
+///The heap handle in a value, if it holds one.
+fn handle(value: &Data) -> Option<usize> {
+    match *value {
+        Data::Array(n) | Data::Map(n) => Some(n),
+        _ => None,
+    }
+}
+
+///Pop an array handle.
+fn pop_array<S: Storage>(stack: &mut Stack<S>) -> Result<usize,Error> {
+    match stack.pop()? {
+        Data::Array(handle) => Ok(handle),
+        other => Err(Error::TypeMismatch { expected: TypeTag::Array, found: other.type_tag() }),
+    }
+}
+
+///Pop a map handle.
+fn pop_map<S: Storage>(stack: &mut Stack<S>) -> Result<usize,Error> {
+    match stack.pop()? {
+        Data::Map(handle) => Ok(handle),
+        other => Err(Error::TypeMismatch { expected: TypeTag::Map, found: other.type_tag() }),
+    }
+}
+
+///Check an index into an array of some length.
+fn check_index(value: Data, len: usize) -> Result<usize,Error> {
+    match value {
+        Data::Int(n) if n >= 0 && (n as usize) < len => Ok(n as usize),
+        Data::Int(n) => Err(Error::MemoryOutOfBounds { addr: n, len }),
+        other => Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() }),
+    }
+}
+
+impl<S: Storage> Stack<S> {
+    ///Replace a length with a new array of that many zeroes.
+    pub fn array_new(&mut self, heap: &mut Heap) -> Result<(),Error> {
+        let len = match self.pop()? {
+            Data::Int(n) if n >= 0 => n as usize,
+            Data::Int(n) => { return Err(Error::MemoryOutOfBounds { addr: n, len: 0 }); }
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() }); }
+        };
+
+        //A length from untrusted code may be far more than will fit.
+        let mut cells = Vec::new();
+        if cells.try_reserve_exact(len).is_err() {
+            return Err(Error::MemoryOutOfBounds { addr: len as i64, len: 0 });
+        }
+        cells.resize(len, Data::Int(0));
+
+        let handle = heap.insert(Object::Array(cells));
+        self.try_push(Data::Array(handle))?;
+
+        Ok(())
+    }
+
+    ///Replace an array and an index above it with the cell at the index.
+    pub fn array_get(&mut self, heap: &Heap) -> Result<(),Error> {
+        let index = self.pop()?;
+        let cells = heap.array(pop_array(self)?)?;
+
+        let index = check_index(index, cells.len())?;
+        self.try_push(cells[index].clone())?;
+
+        Ok(())
+    }
+
+    ///Pop a value, an array and an index, in that order from the bottom,
+    ///and store the value at the index.
+    pub fn array_set(&mut self, heap: &mut Heap) -> Result<(),Error> {
+        let index = self.pop()?;
+        let handle = pop_array(self)?;
+        let value = self.pop()?;
+
+        let cells = heap.array_mut(handle)?;
+        let index = check_index(index, cells.len())?;
+        cells[index] = value;
+
+        Ok(())
+    }
+
+    ///Replace an array with its length.
+    pub fn array_len(&mut self, heap: &Heap) -> Result<(),Error> {
+        let len = heap.array(pop_array(self)?)?.len();
+        self.try_push(Data::Int(len as i64))?;
+
+        Ok(())
+    }
+
+    ///Pop an array or map and free it.
+    pub fn heap_free(&mut self, heap: &mut Heap) -> Result<(),Error> {
+        match self.pop()? {
+            Data::Array(handle) | Data::Map(handle) => { heap.free(handle)?; },
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Handle, found: other.type_tag() }); }
+        }
+
+        Ok(())
+    }
+
+    ///Push a new, empty map.
+    pub fn map_new(&mut self, heap: &mut Heap) -> Result<(),Error> {
+        let handle = heap.insert(Object::Map(BTreeMap::new()));
+        self.try_push(Data::Map(handle))?;
+
+        Ok(())
+    }
+
+    ///Pop a value, a map and a key, in that order from the bottom, and
+    ///store the value under the key.
+    pub fn map_insert(&mut self, heap: &mut Heap) -> Result<(),Error> {
+        let key = Key::from_data(self.pop()?)?;
+        let handle = pop_map(self)?;
+        let value = self.pop()?;
+
+        heap.map_mut(handle)?.insert(key, value);
+
+        Ok(())
+    }
+
+    ///Replace a map and a key above it with the value under the key and
+    ///true, or with just false if there is none.
+    pub fn map_get(&mut self, heap: &Heap) -> Result<(),Error> {
+        let key = Key::from_data(self.pop()?)?;
+        match heap.map(pop_map(self)?)?.get(&key) {
+            Some(value) => {
+                self.try_push(value.clone())?;
+                self.try_push(Data::TRUE)?;
+            },
+            None => self.try_push(Data::FALSE)?,
+        }
+
+        Ok(())
+    }
+
+    ///Pop a map and a key above it, and remove the key. Removing a missing
+    ///key does nothing.
+    pub fn map_remove(&mut self, heap: &mut Heap) -> Result<(),Error> {
+        let key = Key::from_data(self.pop()?)?;
+        heap.map_mut(pop_map(self)?)?.remove(&key);
+
+        Ok(())
+    }
+
+    ///Replace a map with its number of entries.
+    pub fn map_len(&mut self, heap: &Heap) -> Result<(),Error> {
+        let len = heap.map(pop_map(self)?)?.len();
+        self.try_push(Data::Int(len as i64))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    use compiler::compile_module;
+    use heap::Heap;
+    use {Data, Error, NullExtender, RuntimeError, Stack, TypeTag, Vm};
+
+    #[test]
+    fn arrays() {
+        let mut heap = Heap::new();
+        let mut s = Stack::new();
+
+        s.push(Data::Int(3));
+        s.array_new(&mut heap).unwrap();
+        let array = s.pop().unwrap();
+
+        s.push(Data::Int(9));
+        s.push(array.clone());
+        s.push(Data::Int(2));
+        s.array_set(&mut heap).unwrap();
+
+        s.push(array.clone());
+        s.push(Data::Int(2));
+        s.array_get(&heap).unwrap();
+        assert_eq!(s.pop().unwrap(), Data::Int(9));
+
+        s.push(array.clone());
+        s.push(Data::Int(3));
+        assert!(matches!(s.array_get(&heap), Err(Error::MemoryOutOfBounds { addr: 3, len: 3 })));
+
+        s.push(array.clone());
+        s.heap_free(&mut heap).unwrap();
+        assert!(heap.is_empty());
+        s.push(array);
+        assert!(matches!(s.array_len(&heap), Err(Error::InvalidHandle)));
+
+        let mut vm = Vm::new(b"#4'a d q s #1'g".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> 4 0");
+
+        let mut vm = Vm::new(b"#4'a d f q".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::InvalidHandle, .. })));
+    }
+
+    #[test]
+    fn maps() {
+        let mut heap = Heap::new();
+        let mut s = Stack::new();
+
+        s.map_new(&mut heap).unwrap();
+        let map = s.pop().unwrap();
+
+        s.push(Data::Float(1.5));
+        s.push(map.clone());
+        s.push(Data::Str("x".into()));
+        s.map_insert(&mut heap).unwrap();
+
+        s.push(map.clone());
+        s.push(Data::Str("x".into()));
+        s.map_get(&heap).unwrap();
+        assert_eq!(s.to_string(), "<2> 1.5 1");
+        s.clear();
+
+        s.push(map.clone());
+        s.push(Data::Float(1.0));
+        assert!(matches!(s.map_get(&heap), Err(Error::TypeMismatch { expected: TypeTag::Key, found: TypeTag::Float })));
+        s.clear();
+
+        s.push(map.clone());
+        s.push(Data::Str("x".into()));
+        s.map_remove(&mut heap).unwrap();
+        s.push(map.clone());
+        s.push(Data::Str("x".into()));
+        s.map_get(&heap).unwrap();
+        s.push(map);
+        s.map_len(&heap).unwrap();
+        assert_eq!(s.to_string(), "<2> 0 0");
+
+        let mut vm = Vm::new(b"M #7' v #1' V d #1' G o m".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<3> 7 1 1");
+    }
+
+    #[test]
+    fn collect() {
+        //An array kept only through a map, a map kept only by a root, and
+        //an array nothing refers to.
+        let mut vm = Vm::new(b"#1'a M s v #0' V #2'a r".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.heap.len(), 3);
+
+        let map = vm.stack.pop().unwrap();
+        assert_eq!(map, Data::Map(1));
+        vm.roots.push(map);
+        assert_eq!(vm.gc(), 1);
+        assert_eq!(vm.heap.len(), 2);
+
+        vm.roots.clear();
+        assert_eq!(vm.gc(), 2);
+        assert!(vm.heap.is_empty());
+
+        //An array kept only in a local of a word that yielded.
+        let module = compile_module(": w { a -- } yield a ; 3 array w").unwrap();
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.gc(), 0);
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Array(0));
+        assert_eq!(vm.heap.len(), 1);
+
+        //The same in a task waiting while another yields.
+        let mut vm = Vm::new(b"#3'a#1'!{w#0'!R!};i".to_vec(), Vec::new());
+        vm.spawn(18, Vec::new());
+        vm.run_round_robin(&mut NullExtender {}, 100).unwrap();
+        assert_eq!(vm.tasks.len(), 1);
+        assert_eq!(vm.gc(), 0);
+        vm.run_round_robin(&mut NullExtender {}, 100).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Array(0));
+        assert_eq!(vm.heap.len(), 1);
+    }
+}
@@ -0,0 +1,402 @@
+//!Rust closures bound to names, so a host can add words without writing
+//!an `AtomExtender` or picking opcode bytes.
+//!
+//!Each bound function gets an index. The `h` opcode pops an index and
+//!calls that function on the stack, and a `Compiler` given the registry
+//!compiles a bound name into a call by index.
+//!
+//!With `std`, `bind_process` binds words for a script to read its
+//!command-line arguments and environment.
+//!
+//!With the `async` feature a function can also be bound with
+//!`bind_async`, for work like network requests. It pops its arguments and
+//!returns a future of the values to push. `Vm::run` stops at such a call
+//!as though the code had yielded; `Vm::run_async` awaits the future on
+//!whatever runtime polls it, pushes the values and carries on.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "async")]
+use core::future::Future;
+#[cfg(feature = "async")]
+use core::pin::Pin;
+#[cfg(feature = "async")]
+use core::task::{Context, Poll};
+
+use storage::Storage;
+use {Data, Error, Stack};
+#[cfg(feature = "async")]
+use {AtomExtender, RuntimeError, Vm};
+
+///A function the VM can call by index. The type parameter is the data
+///stack's storage.
+pub type HostFunction<S = Vec<Data>> = Box<dyn FnMut(&mut Stack<S>) -> Result<(),Error> + Send>;
+
+///The values an async host function pushes once it finishes, bottom
+///first.
+#[cfg(feature = "async")]
+pub type HostFuture = Pin<Box<dyn Future<Output = Result<Vec<Data>,Error>> + Send>>;
+
+///A function that pops its arguments and starts some work.
+#[cfg(feature = "async")]
+pub type AsyncHostFunction<S = Vec<Data>> = Box<dyn FnMut(&mut Stack<S>) -> Result<HostFuture,Error> + Send>;
+
+enum Function<S> {
+    Sync(HostFunction<S>),
+    #[cfg(feature = "async")]
+    Async(AsyncHostFunction<S>),
+}
+
+struct Binding<S> {
+    name: String,
+    deterministic: bool,
+    function: Function<S>,
+}
+
+///The functions bound by the host.
+pub struct HostFunctions<S = Vec<Data>> {
+    bindings: Vec<Binding<S>>,
+    ///The work started by the last async call, until `run_async` takes it.
+    #[cfg(feature = "async")]
+    pending: Option<HostFuture>,
+}
+
+impl<S> Default for HostFunctions<S> {
+    fn default() -> HostFunctions<S> {
+        HostFunctions {
+            bindings: Vec::new(),
+            #[cfg(feature = "async")]
+            pending: None,
+        }
+    }
+}
+
+impl HostFunctions {
+    ///Start with nothing bound.
+    pub fn new() -> HostFunctions {
+        HostFunctions::default()
+    }
+}
+
+impl<S: Storage> HostFunctions<S> {
+    fn insert(&mut self, name: &str, deterministic: bool, function: Function<S>) -> usize {
+        let binding = Binding {
+            name: String::from(name),
+            deterministic,
+            function,
+        };
+
+        match self.index(name) {
+            Some(n) => {
+                self.bindings[n] = binding;
+                n
+            },
+            None => {
+                self.bindings.push(binding);
+                self.bindings.len() - 1
+            },
+        }
+    }
+
+    ///Bind a function to a name, returning its index. Binding a name again
+    ///replaces the function and keeps the index. Deterministic runs refuse
+    ///to call it.
+    pub fn bind<F>(&mut self, name: &str, function: F) -> usize
+    where F: FnMut(&mut Stack<S>) -> Result<(),Error> + Send + 'static {
+        self.insert(name, false, Function::Sync(Box::new(function)))
+    }
+
+    ///Bind a function that promises to be deterministic, in the sense of
+    ///`AtomExtender::is_deterministic`, so deterministic runs may call it.
+    pub fn bind_deterministic<F>(&mut self, name: &str, function: F) -> usize
+    where F: FnMut(&mut Stack<S>) -> Result<(),Error> + Send + 'static {
+        self.insert(name, true, Function::Sync(Box::new(function)))
+    }
+
+    ///Bind a function that pops its arguments and returns a future of
+    ///the values to push. Deterministic runs refuse to call it.
+    #[cfg(feature = "async")]
+    pub fn bind_async<F>(&mut self, name: &str, function: F) -> usize
+    where F: FnMut(&mut Stack<S>) -> Result<HostFuture,Error> + Send + 'static {
+        self.insert(name, false, Function::Async(Box::new(function)))
+    }
+
+    ///Bind words for a standalone script: `arg-count ( -- n )` and
+    ///`arg ( i -- s )` for some arguments, the script's own name first as
+    ///is usual, and `env-count ( -- n )` and `env ( i -- s )` for the
+    ///process environment as `NAME=value` strings. The environment is
+    ///read now, with any invalid UTF-8 replaced. An index out of range is
+    ///a `MemoryOutOfBounds`.
+    #[cfg(feature = "std")]
+    pub fn bind_process(&mut self, args: Vec<String>) {
+        let env = ::std::env::vars_os()
+            .map(|(name, value)| format!("{}={}", name.to_string_lossy(), value.to_string_lossy()))
+            .collect();
+
+        for (name, list) in [("arg", args), ("env", env)] {
+            let list: Arc<[Arc<str>]> = list.into_iter().map(Arc::from).collect();
+            let len = list.len() as i64;
+            self.bind(&format!("{}-count", name), move |stack| stack.try_push(Data::Int(len)));
+            self.bind(name, move |stack| {
+                let index = stack.pop_int()?;
+                match list.get(index as usize) {
+                    Some(n) if index >= 0 => stack.try_push(Data::Str(n.clone())),
+                    _ => Err(Error::MemoryOutOfBounds { addr: index, len: list.len() }),
+                }
+            });
+        }
+    }
+
+    ///Take the work an async function started, if the last call was to
+    ///one and it hasn't been taken yet.
+    #[cfg(feature = "async")]
+    pub(crate) fn take_pending(&mut self) -> Option<HostFuture> {
+        self.pending.take()
+    }
+
+    ///Check whether an async function has started work that hasn't been
+    ///taken yet.
+    #[cfg(feature = "async")]
+    pub(crate) fn is_pending(&self) -> bool {self.pending.is_some()}
+
+    ///Get the index bound to a name.
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.bindings.iter().position(|n| n.name == name)
+    }
+
+    ///Iterate over `(name, index)` pairs in index order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.bindings.iter().enumerate().map(|(index, n)| (&n.name[..], index))
+    }
+
+    ///Get the number of bound functions.
+    pub fn len(&self) -> usize {self.bindings.len()}
+
+    ///Check whether nothing is bound.
+    pub fn is_empty(&self) -> bool {self.bindings.is_empty()}
+
+    ///Check whether the function at an index was bound as deterministic.
+    pub fn is_deterministic(&self, index: usize) -> bool {
+        self.bindings.get(index).is_some_and(|n| n.deterministic)
+    }
+
+    ///Call the function at an index. An index nothing is bound to is an
+    ///`UnknownWord`. An async function only starts its work, which is
+    ///kept for `Vm::run_async`.
+    ///
+    ///With the `tracing` feature each call is a `host_call` span with the
+    ///index and name.
+    pub fn call(&mut self, index: usize, stack: &mut Stack<S>) -> Result<(),Error> {
+        let binding = match self.bindings.get_mut(index) {
+            Some(n) => n,
+            None => { return Err(Error::UnknownWord); }
+        };
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("host_call", index, name = &*binding.name).entered();
+
+        match binding.function {
+            Function::Sync(ref mut function) => function(stack),
+            #[cfg(feature = "async")]
+            Function::Async(ref mut function) => {
+                self.pending = Some(function(stack)?);
+                Ok(())
+            },
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: Storage, M: Storage> Vm<S, M> {
+    ///Run like `run`, awaiting the work of async host functions and
+    ///pushing what it gives before carrying on. The step budget covers
+    ///the whole run, not each stretch of code between awaits.
+    pub fn run_async<'a, T: AtomExtender<S, M> + ?Sized>(&'a mut self, extender: &'a mut T) -> RunAsync<'a, S, M, T> {
+        RunAsync {
+            vm: self,
+            extender,
+            pending: None,
+            fuel: 0,
+        }
+    }
+}
+
+///The future `Vm::run_async` gives.
+#[cfg(feature = "async")]
+pub struct RunAsync<'a, S: 'a, M: 'a, T: 'a + ?Sized> {
+    vm: &'a mut Vm<S, M>,
+    extender: &'a mut T,
+    pending: Option<HostFuture>,
+    //What the stretches run so far have used of the budget.
+    fuel: u64,
+}
+
+#[cfg(feature = "async")]
+impl<'a, S: Storage, M: Storage, T: AtomExtender<S, M> + ?Sized> Future for RunAsync<'a, S, M, T> {
+    type Output = Result<(),RuntimeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(),RuntimeError>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(ref mut future) = this.pending {
+                let values = match future.as_mut().poll(cx) {
+                    Poll::Ready(n) => n,
+                    Poll::Pending => { return Poll::Pending; }
+                };
+                this.pending = None;
+
+                //The PC is already past the `h`, where the error belongs.
+                let pushed = values.and_then(|values| values.into_iter().try_for_each(|n| this.vm.stack.try_push(n)));
+                if let Err(n) = pushed {
+                    return Poll::Ready(Err(RuntimeError::new(this.vm.pc, n)));
+                }
+            }
+
+            if let Err(n) = this.vm.run_metered(this.extender, &mut this.fuel) {
+                return Poll::Ready(Err(n));
+            }
+            this.pending = match this.vm.host.take_pending() {
+                Some(n) => Some(n),
+                None => { return Poll::Ready(Ok(())); }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    use compiler::Compiler;
+    use {Data, Error, NullExtender, RuntimeError, Vm};
+
+    #[test]
+    fn bind_and_call() {
+        let mut vm = Vm::new(Vec::new(), Vec::new());
+        let sensor = vm.bind("read-sensor", |stack| {
+            stack.push(Data::Int(21));
+            Ok(())
+        });
+        vm.bind("double", |stack| {
+            stack.push(Data::Int(2));
+            stack.mul()
+        });
+        assert_eq!(vm.bind("read-sensor", |stack| {
+            stack.push(Data::Int(20));
+            Ok(())
+        }), sensor);
+
+        let mut compiler = Compiler::new();
+        compiler.use_host(&vm.host);
+        compiler.compile("read-sensor double 1 call-host").unwrap();
+        vm.load(compiler.into_module().code).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<1> 80");
+
+        vm.load(b"#9'h".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::UnknownWord, .. })));
+
+        vm.config.deterministic = true;
+        vm.load(b"#0'h".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::Nondeterministic, .. })));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn process() {
+        let mut vm = Vm::new(Vec::new(), Vec::new());
+        vm.host.bind_process(vec!["script".into(), "one".into()]);
+
+        let mut compiler = Compiler::new();
+        compiler.use_host(&vm.host);
+        compiler.compile("arg-count 1 arg env-count 0 < 5 arg").unwrap();
+        vm.load(compiler.into_module().code).unwrap();
+        let err = vm.run(&mut NullExtender {}).unwrap_err();
+        assert!(matches!(err.kind, Error::MemoryOutOfBounds { addr: 5, len: 2 }));
+        assert_eq!(vm.stack.to_string(), "<3> 2 \"one\" 0");
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_calls() {
+        use alloc::boxed::Box;
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::task::{Context, Poll, Waker};
+
+        ///Ready on the second poll, like a reply that takes a while.
+        struct Reply(Option<i64>, bool);
+
+        impl Future for Reply {
+            type Output = Result<Vec<Data>,Error>;
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                if !self.1 {
+                    self.1 = true;
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                match self.0 {
+                    Some(n) => Poll::Ready(Ok(vec![Data::Int(n * 10)])),
+                    None => Poll::Ready(Err(Error::InvalidHandle)),
+                }
+            }
+        }
+
+        let mut vm = Vm::new(Vec::new(), Vec::new());
+        vm.bind_async("fetch", |stack| {
+            let n = stack.pop_int()?;
+            Ok(Box::pin(Reply(if n < 0 { None } else { Some(n) }, false)))
+        });
+
+        let mut compiler = Compiler::new();
+        compiler.use_host(&vm.host);
+        compiler.compile("1 fetch 2 fetch + -1 fetch").unwrap();
+        vm.load(compiler.into_module().code).unwrap();
+
+        //Poll by hand, counting how often the run had to wait.
+        let mut extender = NullExtender {};
+        let mut future = vm.run_async(&mut extender);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut waits = 0;
+        let result = loop {
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Ready(n) => break n,
+                Poll::Pending => { waits += 1; },
+            }
+        };
+        assert_eq!(waits, 3);
+        assert!(matches!(result, Err(RuntimeError { kind: Error::InvalidHandle, .. })));
+        assert_eq!(vm.stack.to_string(), "<1> 30");
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_budget() {
+        use alloc::boxed::Box;
+        use core::future::{self, Future};
+        use core::pin::Pin;
+        use core::task::{Context, Poll, Waker};
+
+        let mut vm = Vm::new(Vec::new(), Vec::new());
+        vm.bind_async("tick", |_| Ok(Box::pin(future::ready(Ok(Vec::new())))));
+
+        let mut compiler = Compiler::new();
+        compiler.use_host(&vm.host);
+        compiler.compile("begin tick again").unwrap();
+        vm.load(compiler.into_module().code).unwrap();
+        vm.config.max_steps = Some(100);
+
+        //Every stretch is short; the loop only stops if the budget is
+        //shared between them.
+        let mut extender = NullExtender {};
+        let mut future = vm.run_async(&mut extender);
+        let mut cx = Context::from_waker(Waker::noop());
+        let result = Pin::new(&mut future).poll(&mut cx);
+        assert!(matches!(result, Poll::Ready(Err(RuntimeError { kind: Error::FuelExhausted, .. }))));
+    }
+}
@@ -0,0 +1,71 @@
+//!Where the input words get their data from.
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::io::BufRead;
+
+use Error;
+
+///Supplies bytes and lines to a running program.
+pub trait InputProvider {
+    ///Read one byte, or `None` at the end of input.
+    fn key(&mut self) -> Result<Option<u8>,Error>;
+
+    ///Read a line without its line ending, or `None` at the end of input.
+    fn read_line(&mut self) -> Result<Option<String>,Error>;
+}
+
+///Any buffered reader is an input provider.
+#[cfg(feature = "std")]
+impl<R> InputProvider for R where R: BufRead {
+    fn key(&mut self) -> Result<Option<u8>,Error> {
+        let mut byte = [0];
+        match self.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>,Error> {
+        let mut line = String::new();
+        if BufRead::read_line(self, &mut line)? == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(line))
+    }
+}
+
+///Reads standard input, taking the lock only for each read so the host
+///can share it.
+#[cfg(feature = "std")]
+pub struct StdinInput {}
+
+#[cfg(feature = "std")]
+impl InputProvider for StdinInput {
+    fn key(&mut self) -> Result<Option<u8>,Error> {
+        io::stdin().lock().key()
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>,Error> {
+        InputProvider::read_line(&mut io::stdin().lock())
+    }
+}
+
+///Input that is always at its end.
+pub struct NullInput {}
+
+impl InputProvider for NullInput {
+    fn key(&mut self) -> Result<Option<u8>,Error> {Ok(None)}
+
+    fn read_line(&mut self) -> Result<Option<String>,Error> {Ok(None)}
+}
@@ -0,0 +1,338 @@
+//!A pre-decoded form of bytecode, so `Vm::run` doesn't rebuild literals
+//!digit by digit every time round a loop.
+//!
+//!Decoding folds each complete literal into one instruction, and a
+//!literal int followed straight away by a jump or call into a jump or
+//!call with the target worked out. Everything else stays a single byte
+//!run exactly as the raw interpreter would run it.
+//!
+//!`Program::optimize` goes further, fusing common pairs like `#1'+` and
+//!`d*` into superinstructions and folding arithmetic on literals away.
+//!
+//!Each instruction remembers how many raw steps it stands for, so fuel
+//!runs out at the same place either way. The machine's PC is still a
+//!byte address: a jump into the middle of a folded instruction, or one
+//!that would fail, is simply run a byte at a time.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use opcodes::ESCAPE;
+use vm::{float_immediate, string_literal};
+
+///What a decoded instruction does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    ///Push an int literal.
+    Int(i64),
+    ///Push a float literal: its digits, divided by its divider.
+    Float(i64, f64),
+    ///Push a float given in full after a `]`.
+    ExactFloat(f64),
+    ///Push a string literal.
+    Str(Arc<str>),
+    ///Jump to an address. The literal target is never pushed, so this
+    ///doesn't need room on the stack.
+    Jump(usize),
+    ///Pop a flag and jump to an address if it is non-zero.
+    JumpIf(usize),
+    ///Pop a flag and jump to an address if it is zero.
+    JumpUnless(usize),
+    ///Call an address, returning to the next instruction.
+    Call(usize),
+    ///Push an int literal and apply an arithmetic or comparison byte
+    ///(`+ - * < = >`) to it, like `#1'+`. Only runs on an int.
+    IntThen(i64, u8),
+    ///Duplicate and apply an arithmetic or comparison byte, like `d*`.
+    ///Only runs on an int.
+    DupThen(u8),
+    ///Whitespace.
+    Skip,
+    ///Any other byte.
+    Byte(u8),
+}
+
+///An instruction and the bytes it was decoded from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+    ///Address of its first byte.
+    pub pc: usize,
+    ///Address of the byte after it.
+    pub next: usize,
+    ///How many steps the raw interpreter takes over the same bytes.
+    pub steps: u64,
+    ///The value and divider a folded literal leaves behind.
+    pub literal: Option<(i64, f64)>,
+}
+
+const NONE: u32 = u32::MAX;
+
+///Decoded code, with a way back from byte addresses to instructions.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    ///The instruction starting at each byte, or `NONE`.
+    index: Vec<u32>,
+    optimized: bool,
+}
+
+impl Program {
+    ///Decode some code. Symbolic calls are left as single bytes.
+    pub fn decode(code: &[u8]) -> Program {
+        Program::decode_linked(code, &BTreeMap::new())
+    }
+
+    ///Decode some code, turning the symbolic calls `Vm::link` resolved
+    ///into calls.
+    pub(crate) fn decode_linked(code: &[u8], links: &BTreeMap<usize, (usize, usize)>) -> Program {
+        let mut program = Program {
+            instructions: Vec::new(),
+            index: vec![NONE; code.len()],
+            optimized: false,
+        };
+
+        let mut pc = 0;
+        while pc < code.len() {
+            let instruction = match code[pc] {
+                b' ' | b'\n' | b'\r' => {
+                    let next = code[pc..].iter().position(|&b| !is_space(b)).map_or(code.len(), |n| pc + n);
+                    Instruction { op: Op::Skip, pc, next, steps: (next - pc) as u64, literal: None }
+                },
+                b'#' => literal(code, pc).unwrap_or_else(|| byte(code, pc)),
+                b'[' => match string_literal(code, pc + 1) {
+                    Some((text, next)) => Instruction { op: Op::Str(Arc::from(text)), pc, next, steps: 1, literal: None },
+                    None => byte(code, pc),
+                },
+                b']' => match float_immediate(code, pc + 1) {
+                    Some((value, next)) => Instruction { op: Op::ExactFloat(value), pc, next, steps: 1, literal: None },
+                    None => byte(code, pc),
+                },
+                //Run a byte at a time, but with the byte after it kept
+                //out of the decoded code.
+                ESCAPE if pc + 1 < code.len() => Instruction { op: Op::Byte(ESCAPE), pc, next: pc + 2, steps: 1, literal: None },
+                b'`' => match links.get(&pc) {
+                    Some(&(target, next)) => Instruction { op: Op::Call(target), pc, next, steps: 1, literal: None },
+                    None => byte(code, pc),
+                },
+                _ => byte(code, pc),
+            };
+
+            program.index[pc] = program.instructions.len() as u32;
+            pc = instruction.next;
+            program.instructions.push(instruction);
+        }
+
+        program
+    }
+
+    ///Fuse pairs of instructions into superinstructions and fold
+    ///arithmetic on literals, so `#2'#3'+` becomes a push of 5. Nothing
+    ///is fused into an instruction a decoded jump or call lands on, and
+    ///arithmetic that would overflow is left for the arithmetic policy.
+    pub fn optimize(&mut self) {
+        if self.optimized {
+            return;
+        }
+        self.optimized = true;
+
+        let targets: BTreeSet<usize> = self.instructions.iter().filter_map(|n| match n.op {
+            Op::Jump(n) | Op::JumpIf(n) | Op::JumpUnless(n) | Op::Call(n) => Some(n),
+            _ => None,
+        }).collect();
+
+        let mut fused: Vec<Instruction> = Vec::with_capacity(self.instructions.len());
+        for instruction in self.instructions.drain(..) {
+            fused.push(instruction);
+
+            while fused.len() >= 2 {
+                let last = &fused[fused.len() - 1];
+                if targets.contains(&last.pc) {
+                    break;
+                }
+
+                let prev = &fused[fused.len() - 2];
+                let op = match (&prev.op, &last.op) {
+                    (&Op::Int(n), &Op::Byte(op)) if fold(op, 0, 0).is_some() => Op::IntThen(n, op),
+                    (&Op::Byte(b'd'), &Op::Byte(op)) if fold(op, 0, 0).is_some() => Op::DupThen(op),
+                    (&Op::Int(a), &Op::IntThen(b, op)) => match fold(op, a, b) {
+                        Some(n) => Op::Int(n),
+                        None => { break; }
+                    },
+                    (&Op::Int(a), &Op::DupThen(op)) => match fold(op, a, a) {
+                        Some(n) => Op::Int(n),
+                        None => { break; }
+                    },
+                    _ => { break; }
+                };
+
+                //The literal built last is the one left behind.
+                let literal = last.literal.or(prev.literal);
+                let last = fused.pop().unwrap();
+                let prev = fused.last_mut().unwrap();
+                prev.op = op;
+                prev.next = last.next;
+                prev.steps += last.steps;
+                prev.literal = literal;
+            }
+        }
+
+        for n in self.index.iter_mut() {
+            *n = NONE;
+        }
+        for (n, instruction) in fused.iter().enumerate() {
+            self.index[instruction.pc] = n as u32;
+        }
+        self.instructions = fused;
+    }
+
+    ///Check whether `optimize` has been run.
+    pub fn is_optimized(&self) -> bool {self.optimized}
+
+    ///Get the instructions in address order.
+    pub fn instructions(&self) -> &[Instruction] {&self.instructions}
+
+    ///Get the instruction starting at an address, if one does.
+    #[inline]
+    pub fn at(&self, pc: usize) -> Option<&Instruction> {
+        match self.index.get(pc) {
+            Some(&n) if n != NONE => Some(&self.instructions[n as usize]),
+            _ => None,
+        }
+    }
+}
+
+///Apply an arithmetic or comparison byte to NOS and TOS, if it is one and
+///the answer is the same under every arithmetic policy. Comparisons give
+///the usual 1 or 0.
+pub(crate) fn fold(op: u8, nos: i64, tos: i64) -> Option<i64> {
+    match op {
+        b'+' => nos.checked_add(tos),
+        b'-' => nos.checked_sub(tos),
+        b'*' => nos.checked_mul(tos),
+        b'<' => Some((nos < tos) as i64),
+        b'=' => Some((nos == tos) as i64),
+        b'>' => Some((nos > tos) as i64),
+        _ => None,
+    }
+}
+
+fn is_space(b: u8) -> bool {
+    b == b' ' || b == b'\n' || b == b'\r'
+}
+
+fn byte(code: &[u8], pc: usize) -> Instruction {
+    Instruction { op: Op::Byte(code[pc]), pc, next: pc + 1, steps: 1, literal: None }
+}
+
+///Fold the literal starting at the `#` at `start`, along with a jump or
+///call right after it. Literals that would overflow aren't folded, so the
+///arithmetic policy still decides what they do.
+fn literal(code: &[u8], start: usize) -> Option<Instruction> {
+    let mut value: i64 = 0;
+    let mut divider: f64 = 1.0;
+    let mut pc = start + 1;
+
+    let float = loop {
+        match *code.get(pc)? {
+            b @ b'0'..=b'9' => {
+                value = value.checked_mul(10)?.checked_add((b - b'0') as i64)?;
+            },
+            b'.' => { divider *= 1000.0; },
+            b'$' => { value = value.checked_neg()?; },
+            b'\'' => { break false; },
+            b'"' => { break true; },
+            b if is_space(b) => {},
+            _ => { return None; }
+        }
+        pc += 1;
+    };
+
+    let next = pc + 1;
+    let mut instruction = Instruction {
+        op: if float { Op::Float(value, divider) } else { Op::Int(value) },
+        pc: start,
+        next,
+        steps: (next - start) as u64,
+        literal: Some((value, divider)),
+    };
+    if float {
+        return Some(instruction);
+    }
+
+    //Relative targets count from the end of the jump, like `relative`.
+    let relative = match value.checked_add(next as i64 + 1) {
+        Some(n) if n >= 0 => Some(n as usize),
+        _ => None,
+    };
+    let op = match (code.get(next), relative) {
+        (Some(b'b'), _) => Op::Jump(value as usize),
+        (Some(b'c'), _) => Op::Call(value as usize),
+        (Some(b'y'), _) => Op::JumpIf(value as usize),
+        (Some(b'z'), _) => Op::JumpUnless(value as usize),
+        (Some(b'B'), Some(n)) => Op::Jump(n),
+        (Some(b'C'), Some(n)) => Op::Call(n),
+        (Some(b'Y'), Some(n)) => Op::JumpIf(n),
+        (Some(b'Z'), Some(n)) => Op::JumpUnless(n),
+        _ => { return Some(instruction); }
+    };
+
+    instruction.op = op;
+    instruction.next += 1;
+    instruction.steps += 1;
+
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use ir::{Op, Program};
+
+    #[test]
+    fn folding() {
+        let program = Program::decode(b"#12'#1.5\"  d#3$'B[hi]#9'y #99999999999999999999'");
+        let ops: Vec<Op> = program.instructions().iter().take(8).map(|n| n.op.clone()).collect();
+        assert_eq!(ops, vec![
+            Op::Int(12),
+            Op::Float(15, 1000.0),
+            Op::Skip,
+            Op::Byte(b'd'),
+            Op::Jump(14),
+            Op::Str("hi".into()),
+            Op::JumpIf(9),
+            Op::Skip,
+        ]);
+
+        let jump = program.at(12).unwrap();
+        assert_eq!((jump.next, jump.steps, jump.literal), (17, 5, Some((-3, 1.0))));
+        assert!(program.at(13).is_none());
+        assert_eq!(program.at(26).unwrap().op, Op::Byte(b'#'));
+    }
+
+    #[test]
+    fn optimizing() {
+        let mut program = Program::decode(b"#2'#3'+#4'd*- v#1'+ d< #00035'b #7'#1'+");
+        program.optimize();
+        let ops: Vec<Op> = program.instructions().iter().map(|n| n.op.clone()).collect();
+        assert_eq!(ops, vec![
+            Op::Int(-11),
+            Op::Skip,
+            Op::Byte(b'v'),
+            Op::IntThen(1, b'+'),
+            Op::Skip,
+            Op::DupThen(b'<'),
+            Op::Skip,
+            Op::Jump(35),
+            Op::Skip,
+            Op::Int(7),
+            Op::IntThen(1, b'+'),
+        ]);
+
+        let folded = program.at(0).unwrap();
+        assert_eq!((folded.next, folded.steps, folded.literal), (13, 13, Some((4, 1.0))));
+        assert!(program.at(3).is_none());
+    }
+}
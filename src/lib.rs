@@ -1,63 +1,526 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "wasm")]
+extern crate js_sys;
+#[cfg(all(feature = "fs", unix))]
+extern crate libc;
+#[cfg(feature = "proptest")]
+extern crate proptest;
+#[cfg(feature = "pyo3")]
+extern crate pyo3;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "lsp")]
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt;
+use core::mem;
+use core::slice;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
 use std::fs::File;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
 use std::io::Read;
-
-pub fn load_module(path: &'static str) -> Vec<u8> {
-    let mut file = File::open(path).unwrap();
+#[cfg(feature = "std")]
+use std::path::Path;
+
+use debuginfo::SourceLocation;
+use rng::Rng;
+use storage::Storage;
+
+pub mod args;
+#[macro_use]
+pub mod builder;
+pub mod compiler;
+pub mod coverage;
+pub mod debug;
+pub mod debuginfo;
+pub mod disasm;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
+#[cfg(feature = "fs")]
+pub mod fs;
+pub mod heap;
+pub mod host;
+pub mod input;
+pub mod ir;
+pub mod link;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+#[cfg(feature = "std")]
+pub mod mathext;
+pub mod memmap;
+pub mod module;
+pub mod opcodes;
+pub mod output;
+#[cfg(feature = "rayon")]
+pub mod parallel;
+#[cfg(feature = "playground")]
+pub mod playground;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "pyo3")]
+pub mod python;
+pub mod record;
+pub mod reference;
+pub mod registry;
+pub mod rng;
+pub mod storage;
+#[cfg(feature = "proptest")]
+pub mod strategy;
+#[cfg(feature = "std")]
+pub mod testing;
+pub mod trace;
+pub mod validate;
+mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use vm::{ArithmeticPolicy, CatchFrame, CoercionPolicy, ExecutionStats, MemoryPolicy, RunConfig, SandboxConfig, Vm, VmState, Status, Task};
+
+///Read a module from disk.
+#[cfg(feature = "std")]
+pub fn load_module<P: AsRef<Path>>(path: P) -> Result<Vec<u8>,Error> {
+    let mut file = File::open(path)?;
     let mut program: Vec<u8> = Vec::new();
-    
-    file.read_to_end(&mut program).unwrap();
 
-    program
+    file.read_to_end(&mut program)?;
+
+    Ok(program)
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug)]
 pub enum Error {
     StackUnderflow,
-    TypeMismatch,
-    InvalidInstruction,
+    TypeMismatch { expected: TypeTag, found: TypeTag },
+    InvalidInstruction { opcode: u8 },
+    UnknownWord,
+    InvalidModule,
+    FuelExhausted,
+    ReturnStackOverflow,
+    DivisionByZero,
+    UnsupportedVersion(u16),
+    ///`addr` is the address or index that was out of range and `len` the
+    ///size of the memory or array it was checked against.
+    MemoryOutOfBounds { addr: i64, len: usize },
+    ReturnStackUnderflow,
+    IntegerOverflow,
+    Nondeterministic,
+    InvalidHandle,
+    StackOverflow,
+    StackLimitExceeded,
+    MemoryLimitExceeded,
+    OpcodeNotAllowed(u8),
+    ///A `throw` with a code no `catch` was waiting for.
+    Thrown(i64),
+    ///The host asked the code to stop through the machine's
+    ///cancellation token.
+    Interrupted,
+    ///A run with a timeout took too long.
+    Timeout,
+    ///An int that was meant as a character isn't a Unicode code point.
+    InvalidCharacter(i64),
+    #[cfg(feature = "std")]
+    Io(io::Error),
+}
+
+///Everything an extender can reach while handling an opcode.
+pub struct Context<'a, S: 'a = Vec<Data>, M: 'a = Vec<Data>> {
+    pub stack: &'a mut Stack<S>,
+    pub memory: &'a mut M,
+    pub rstack: &'a mut Vec<usize>,
+    ///Address of the instruction after the opcode being handled. Change
+    ///it to jump; push the old value to `rstack` first to make a call.
+    pub pc: usize,
+}
+
+///Handles the opcodes the VM doesn't know. Implement `atom` for words
+///that only need the stack, or `atom_with_context` for words that need
+///memory or control flow.
+///
+///The type parameters are the storage of the data stack and memory; see
+///`storage`. Extenders that don't care implement it for any storage.
+pub trait AtomExtender<S: Storage = Vec<Data>, M: Storage = Vec<Data>> {
+    fn atom(&mut self, opcode: u8, _stack: &mut Stack<S>) -> Result<(),Error> {
+        Err(Error::InvalidInstruction { opcode })
+    }
+
+    fn atom_with_context(&mut self, opcode: u8, context: &mut Context<S, M>) -> Result<(),Error> {
+        self.atom(opcode, context.stack)
+    }
+
+    ///Whether an opcode gives the same result on every machine and every
+    ///run. Deterministic runs refuse opcodes that don't, so only say yes
+    ///if the opcode avoids clocks, I/O, platform maths libraries and
+    ///randomness other than the VM's own generator.
+    fn is_deterministic(&self, _opcode: u8) -> bool {
+        false
+    }
+
+    ///The opcodes this extender handles, for `registry::Extenders` to
+    ///check against the VM's and other extenders'. Each must be at least
+    ///`registry::FIRST_EXTENSION`.
+    fn opcodes(&self) -> &[u8] {
+        &[]
+    }
+
+    ///What an opcode costs in fuel when the run has a cost model, such as
+    ///more for one that calls out to a service. `None` leaves it to the
+    ///model.
+    fn cost(&self, _opcode: u8) -> Option<u64> {
+        None
+    }
 }
 
-pub trait AtomExtender {
-    fn atom(&mut self, u8, &mut Stack) -> Result<(),Error>;
+///Any closure taking an opcode and the stack is an extender.
+impl<F, S: Storage, M: Storage> AtomExtender<S, M> for F where F: FnMut(u8, &mut Stack<S>) -> Result<(),Error> {
+    fn atom(&mut self, opcode: u8, stack: &mut Stack<S>) -> Result<(),Error> {
+        self(opcode, stack)
+    }
 }
 
 pub struct NullExtender {}
-impl AtomExtender for NullExtender {
-    fn atom(&mut self, _: u8, _: &mut Stack) -> Result<(),Error> {
-        Err(Error::InvalidInstruction)
+impl<S: Storage, M: Storage> AtomExtender<S, M> for NullExtender {
+    fn atom(&mut self, opcode: u8, _: &mut Stack<S>) -> Result<(),Error> {
+        Err(Error::InvalidInstruction { opcode })
     }
+
+    fn is_deterministic(&self, _: u8) -> bool {true}
 }
 
+#[cfg(feature = "std")]
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
 
 impl Error {
     pub fn to_string(&self) -> &'static str {
-        match self {
-            &Error::StackUnderflow => {return "Stack Underflow";},
-            &Error::TypeMismatch   => {return "Type Mismatch";},
-            &Error::InvalidInstruction => {return "Invalid Instruction";},
+        match *self {
+            Error::StackUnderflow => "Stack Underflow",
+            Error::TypeMismatch { .. } => "Type Mismatch",
+            Error::InvalidInstruction { .. } => "Invalid Instruction",
+            Error::UnknownWord => "Unknown Word",
+            Error::InvalidModule => "Invalid Module",
+            Error::FuelExhausted => "Fuel Exhausted",
+            Error::ReturnStackOverflow => "Return Stack Overflow",
+            Error::DivisionByZero => "Division By Zero",
+            Error::UnsupportedVersion(_) => "Unsupported Module Version",
+            Error::MemoryOutOfBounds { .. } => "Memory Out Of Bounds",
+            Error::ReturnStackUnderflow => "Return Stack Underflow",
+            Error::IntegerOverflow => "Integer Overflow",
+            Error::Nondeterministic => "Nondeterministic Instruction",
+            Error::InvalidHandle => "Invalid Handle",
+            Error::StackOverflow => "Stack Overflow",
+            Error::StackLimitExceeded => "Stack Limit Exceeded",
+            Error::MemoryLimitExceeded => "Memory Limit Exceeded",
+            Error::OpcodeNotAllowed(_) => "Opcode Not Allowed",
+            Error::Thrown(_) => "Uncaught Throw",
+            Error::Interrupted => "Interrupted",
+            Error::Timeout => "Timeout",
+            Error::InvalidCharacter(_) => "Invalid Character",
+            #[cfg(feature = "std")]
+            Error::Io(_) => "I/O Error",
+        }
+    }
+
+    ///The code `catch` pushes for the error. Where the Forth standard has
+    ///a code for the same condition it is used; the rest are from -256
+    ///down, and a `throw` keeps its own code.
+    pub fn code(&self) -> i64 {
+        match *self {
+            Error::StackOverflow | Error::StackLimitExceeded => -3,
+            Error::StackUnderflow => -4,
+            Error::ReturnStackOverflow => -5,
+            Error::ReturnStackUnderflow => -6,
+            Error::MemoryOutOfBounds { .. } => -9,
+            Error::DivisionByZero => -10,
+            Error::IntegerOverflow => -11,
+            Error::TypeMismatch { .. } => -12,
+            Error::UnknownWord => -13,
+            Error::InvalidInstruction { .. } => -21,
+            Error::Interrupted => -28,
+            Error::InvalidModule => -256,
+            Error::FuelExhausted => -257,
+            Error::UnsupportedVersion(_) => -258,
+            Error::Nondeterministic => -259,
+            Error::InvalidHandle => -260,
+            Error::MemoryLimitExceeded => -261,
+            Error::OpcodeNotAllowed(_) => -262,
+            Error::Timeout => -263,
+            Error::InvalidCharacter(_) => -264,
+            Error::Thrown(n) => n,
+            #[cfg(feature = "std")]
+            Error::Io(_) => -37,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "std")]
+            Error::Io(ref err) => write!(f, "{}: {}", self.to_string(), err),
+            Error::TypeMismatch { expected, found } => write!(f, "{}: expected {}, found {}", self.to_string(), expected, found),
+            Error::InvalidInstruction { opcode } => write!(f, "{}: {}", self.to_string(), opcode as char),
+            Error::UnsupportedVersion(n) => write!(f, "{}: {}", self.to_string(), n),
+            Error::MemoryOutOfBounds { addr, len } => write!(f, "{}: address {} outside {} cells", self.to_string(), addr, len),
+            Error::OpcodeNotAllowed(n) => write!(f, "{}: {}", self.to_string(), n as char),
+            Error::Thrown(n) => write!(f, "{}: {}", self.to_string(), n),
+            Error::InvalidCharacter(n) => write!(f, "{}: {}", self.to_string(), n),
+            _ => write!(f, "{}", self.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+///An error raised while running code, with the PC it was raised at.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub pc: usize,
+    pub kind: Error,
+    backtrace: Vec<Frame>,
+}
+
+impl RuntimeError {
+    pub fn new(pc: usize, kind: Error) -> RuntimeError {
+        RuntimeError { pc, kind, backtrace: Vec::new() }
+    }
+
+    ///The calls that hadn't returned when the error was raised, innermost
+    ///first. The first frame is at the error's PC and the rest at return
+    ///addresses; a value moved to the return stack with `(` shows up as a
+    ///frame too. Empty if the error didn't come from running a machine.
+    pub fn backtrace(&self) -> &[Frame] {&self.backtrace}
+}
+
+///A call on the way to an error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub pc: usize,
+    ///The word running at `pc`, if it was entered by a call to an entry
+    ///point in the machine's dictionary or the debug info names it.
+    pub word: Option<String>,
+    ///Where the instruction before `pc` is in the source, if the machine
+    ///has debug info.
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at {}", self.pc)?;
+        if let Some(ref word) = self.word {
+            write!(f, " in {}", word)?;
+        }
+        if let Some(ref location) = self.location {
+            write!(f, " ({})", location)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.pc)?;
+        if let Some(location) = self.backtrace.first().and_then(|n| n.location.as_ref()) {
+            write!(f, " ({})", location)?;
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.kind)
     }
 }
 
 
 
-#[derive(Debug, Copy, Clone)]
-///Represents a piece of Forth data, either an int or a float.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+///Represents a piece of Forth data: an int, a float, a string, or a
+///handle to an array or map in the heap.
 pub enum Data {
     Int(i64),
     Float(f64),
+    Str(Arc<str>),
+    ///A handle to an array in the VM's heap.
+    Array(usize),
+    ///A handle to a map in the VM's heap.
+    Map(usize),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 ///Represents a homogeneous pair of Data.
 pub enum Pair {
     Int(i64,i64),
     Float(f64,f64),
+    Str(Arc<str>,Arc<str>),
 }
 
-///The Forth stack.
-pub struct Stack {
-    stack: Vec<Data>,
+///The type of a value, or the types an instruction accepts, as reported
+///by `TypeMismatch`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TypeTag {
+    Int,
+    Float,
+    Str,
+    Array,
+    Map,
+    ///An int or a float.
+    Number,
+    ///An int or a string, as map keys are.
+    Key,
+    ///An array or a map.
+    Handle,
+}
+
+impl fmt::Display for TypeTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            TypeTag::Int => "int",
+            TypeTag::Float => "float",
+            TypeTag::Str => "string",
+            TypeTag::Array => "array",
+            TypeTag::Map => "map",
+            TypeTag::Number => "number",
+            TypeTag::Key => "int or string",
+            TypeTag::Handle => "array or map",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Pair {
+    ///The type of both values.
+    pub fn type_tag(&self) -> TypeTag {
+        match *self {
+            Pair::Int(..) => TypeTag::Int,
+            Pair::Float(..) => TypeTag::Float,
+            Pair::Str(..) => TypeTag::Str,
+        }
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Data::Int(n) => write!(f, "{}", n),
+            Data::Float(n) => write!(f, "{:?}", n),
+            Data::Str(ref n) => write!(f, "{:?}", n),
+            Data::Array(n) => write!(f, "<array {}>", n),
+            Data::Map(n) => write!(f, "<map {}>", n),
+        }
+    }
+}
+
+impl Data {
+    ///The type of the value.
+    pub fn type_tag(&self) -> TypeTag {
+        match *self {
+            Data::Int(_) => TypeTag::Int,
+            Data::Float(_) => TypeTag::Float,
+            Data::Str(_) => TypeTag::Str,
+            Data::Array(_) => TypeTag::Array,
+            Data::Map(_) => TypeTag::Map,
+        }
+    }
+
+    ///Check whether two values are the same, counting a NaN as the same
+    ///as itself, unlike `==`.
+    pub(crate) fn same_as(&self, other: &Data) -> bool {
+        match (self, other) {
+            (&Data::Float(a), &Data::Float(b)) => a.to_bits() == b.to_bits(),
+            _ => self == other,
+        }
+    }
+
+    ///The flag pushed by comparisons for true.
+    pub const TRUE: Data = Data::Int(1);
+    ///The flag pushed by comparisons for false.
+    pub const FALSE: Data = Data::Int(0);
+
+    ///Get the canonical flag for a bool.
+    #[inline]
+    pub fn from_bool(value: bool) -> Data {
+        if value { Data::TRUE } else { Data::FALSE }
+    }
+
+    ///Check whether a value counts as true for conditional jumps and
+    ///`not`: any non-zero number does. Strings are neither.
+    #[inline]
+    pub fn is_truthy(&self) -> Result<bool,Error> {
+        match *self {
+            Data::Int(n) => Ok(n != 0),
+            Data::Float(n) => Ok(n != 0.0),
+            Data::Str(_) | Data::Array(_) | Data::Map(_) => Err(Error::TypeMismatch { expected: TypeTag::Number, found: self.type_tag() }),
+        }
+    }
+}
+
+///The Forth stack. Its items live in a `Vec` unless another `Storage` is
+///given.
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct Stack<S = Vec<Data>> {
+    stack: S,
+}
+
+impl<S: Storage> fmt::Display for Stack<S> {
+    ///Formats like Forth's `.s`: the depth, then each item from the bottom.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.stack.len())?;
+        for value in self.stack.as_slice() {
+            write!(f, " {}", value)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Vec<Data>> for Stack {
+    ///Make a stack holding some items, the last of which is TOS.
+    fn from(stack: Vec<Data>) -> Stack {
+        Stack { stack }
+    }
 }
 
 impl Stack {
@@ -67,15 +530,63 @@ impl Stack {
             stack: Vec::new()
         }
     }
+}
 
+impl<S: Storage> Stack<S> {
+    ///Initialize a stack over some storage, keeping any items in it.
+    pub fn with_storage(stack: S) -> Stack<S> {
+        Stack { stack }
+    }
 
     ///Get the length of the stack.
     pub fn len(&self) -> usize {self.stack.len()}
 
+    ///Check whether the stack is empty.
+    pub fn is_empty(&self) -> bool {self.stack.is_empty()}
+
+    ///Look at TOS without popping it.
+    pub fn peek(&self) -> Option<&Data> {self.stack.as_slice().last()}
+
+    ///Look at the item `depth` below TOS. `peek_n(0)` is `peek()`.
+    pub fn peek_n(&self, depth: usize) -> Option<&Data> {
+        if depth >= self.stack.len() {
+            return None;
+        }
+
+        Some(&self.stack.as_slice()[self.stack.len() - 1 - depth])
+    }
+
+    ///Iterate over the items from the bottom of the stack to TOS.
+    pub fn iter(&self) -> slice::Iter<'_, Data> {self.stack.as_slice().iter()}
+
+    ///Get the items from the bottom of the stack to TOS.
+    pub fn as_slice(&self) -> &[Data] {self.stack.as_slice()}
+
+    ///Remove every item.
+    pub fn clear(&mut self) {self.stack.truncate(0)}
+
+    ///Drop items from the top until at most `len` remain.
+    pub fn truncate(&mut self, len: usize) {self.stack.truncate(len)}
+
 
     ///Push an item to the stack.
+    ///
+    ///Panics if fixed-capacity storage is full. Anything that may run on
+    ///fixed-capacity storage, such as an extender or a host function,
+    ///should use `try_push`.
     pub fn push(&mut self, value: Data) {
-        self.stack.push(value);
+        if self.stack.try_push(value).is_err() {
+            panic!("stack overflow");
+        }
+    }
+
+    ///Push an item to the stack, failing with `StackOverflow` if
+    ///fixed-capacity storage is full.
+    pub fn try_push(&mut self, value: Data) -> Result<(),Error> {
+        match self.stack.try_push(value) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::StackOverflow),
+        }
     }
 
     ///Pop an item from the stack.
@@ -86,22 +597,36 @@ impl Stack {
         }
     }
 
-    ///Pop two items of the same type.
+    ///Pop two items of the same type. An int and a float fail with
+    ///`TypeMismatch` unless `promote` has made them both floats.
     pub fn pop_two(&mut self) -> Result<Pair,Error> {
         let a = self.stack.pop();
         let b = self.stack.pop();
 
-        if let None = a {return Err(Error::StackUnderflow);}
-        if let None = b {return Err(Error::StackUnderflow);}
-
-        let a = a.unwrap();
-        let b = b.unwrap();
+        let a = match a { Some(n) => n, None => {return Err(Error::StackUnderflow);} };
+        let b = match b { Some(n) => n, None => {return Err(Error::StackUnderflow);} };
 
         match (a,b) {
             (Data::Float(x),Data::Float(y)) => Ok(Pair::Float(x,y)),
             (Data::Int(x),Data::Int(y)) => Ok(Pair::Int(x,y)),
-            (Data::Float(_),Data::Int(_)) => Err(Error::TypeMismatch),
-            (Data::Int(_),Data::Float(_)) => Err(Error::TypeMismatch),
+            (Data::Str(x),Data::Str(y)) => Ok(Pair::Str(x,y)),
+            (a,b) => Err(Error::TypeMismatch { expected: b.type_tag(), found: a.type_tag() }),
+        }
+    }
+
+    ///If one of TOS and NOS is an int and the other a float, turn the
+    ///int into a float, so the two can be added or compared.
+    pub fn promote(&mut self) {
+        let len = self.stack.len();
+        if len < 2 {
+            return;
+        }
+
+        let top = &mut self.stack.as_mut_slice()[len - 2..];
+        match (&top[0], &top[1]) {
+            (&Data::Int(n), &Data::Float(_)) => { top[0] = Data::Float(n as f64); },
+            (&Data::Float(_), &Data::Int(n)) => { top[1] = Data::Float(n as f64); },
+            _ => {},
         }
     }
 
@@ -115,11 +640,12 @@ impl Stack {
         };
 
         match value {
-            Data::Int(_) => {self.push(value);},
-            Data::Float(n) => {self.push(Data::Int(n as i64));}
+            Data::Int(_) => {self.try_push(value)?;},
+            Data::Float(n) => {self.try_push(Data::Int(n as i64))?;}
+            Data::Str(_) | Data::Array(_) | Data::Map(_) => {return Err(Error::TypeMismatch { expected: TypeTag::Number, found: value.type_tag() });}
         }
 
-        return Ok(());
+        Ok(())
     }
 
     ///Cast TOS to float. Float to float is valid.
@@ -132,73 +658,358 @@ impl Stack {
         };
 
         match value {
-            Data::Int(n) => {self.push(Data::Float(n as f64));},
-            Data::Float(_) => {self.push(value);}
+            Data::Int(n) => {self.try_push(Data::Float(n as f64))?;},
+            Data::Float(_) => {self.try_push(value)?;}
+            Data::Str(_) | Data::Array(_) | Data::Map(_) => {return Err(Error::TypeMismatch { expected: TypeTag::Number, found: value.type_tag() });}
         }
 
-        return Ok(());
+        Ok(())
     }
 
     ///Duplicate TOS.
     pub fn dup(&mut self) -> Result<(),Error> {
-        let value = self.pop();
+        let value = match self.peek() {
+            Some(n) => n.clone(),
+            None    => { return Err(Error::StackUnderflow); }
+        };
 
-        let value = match value {
+        self.try_push(value)
+    }
+
+    ///Swap TOS with NOS.
+    pub fn swap(&mut self) -> Result<(), Error> {
+        self.require(2)?;
+
+        let len = self.stack.len();
+        self.stack.as_mut_slice().swap(len - 1, len - 2);
+
+        Ok(())
+    }
+
+    ///Duplicate NOS, placing duplicate value above TOS.
+    pub fn over(&mut self) -> Result<(), Error> {
+        self.require(2)?;
+
+        let value = self.stack.as_slice()[self.stack.len() - 2].clone();
+        self.try_push(value)
+    }
+
+    ///Replace NOS and TOS with `op(nos, tos)` if both are ints and it
+    ///gives an answer, returning whether it did. Nothing changes if not.
+    #[inline]
+    pub(crate) fn combine_ints<F: FnOnce(i64, i64) -> Option<Data>>(&mut self, op: F) -> bool {
+        let len = self.stack.len();
+        if len < 2 {
+            return false;
+        }
+
+        let top = self.stack.as_mut_slice();
+        let value = match (&top[len - 2], &top[len - 1]) {
+            (&Data::Int(y), &Data::Int(x)) => op(y, x),
+            _ => None,
+        };
+
+        match value {
+            Some(n) => {
+                top[len - 2] = n;
+                self.stack.truncate(len - 1);
+                true
+            },
+            None => false,
+        }
+    }
+
+    ///Fail unless the stack holds at least `depth` items.
+    fn require(&self, depth: usize) -> Result<(),Error> {
+        if self.stack.len() < depth {
+            return Err(Error::StackUnderflow);
+        }
+
+        Ok(())
+    }
+
+    ///Rotate the third item to TOS: `a b c -- b c a`.
+    pub fn rot(&mut self) -> Result<(),Error> {
+        self.require(3)?;
+
+        let len = self.stack.len();
+        self.stack.as_mut_slice()[len - 3..].rotate_left(1);
+
+        Ok(())
+    }
+
+    ///Rotate TOS down to third: `a b c -- c a b`.
+    pub fn rrot(&mut self) -> Result<(),Error> {
+        self.require(3)?;
+
+        let len = self.stack.len();
+        self.stack.as_mut_slice()[len - 3..].rotate_right(1);
+
+        Ok(())
+    }
+
+    ///Drop NOS: `a b -- b`.
+    pub fn nip(&mut self) -> Result<(),Error> {
+        self.require(2)?;
+
+        let value = self.pop()?;
+        let len = self.stack.len();
+        self.stack.as_mut_slice()[len - 1] = value;
+
+        Ok(())
+    }
+
+    ///Copy TOS below NOS: `a b -- b a b`.
+    pub fn tuck(&mut self) -> Result<(),Error> {
+        self.require(2)?;
+
+        let value = self.stack.as_slice()[self.stack.len() - 1].clone();
+        self.try_push(value)?;
+
+        let len = self.stack.len();
+        self.stack.as_mut_slice()[len - 3..].rotate_right(1);
+
+        Ok(())
+    }
+
+    ///Duplicate the top pair: `a b -- a b a b`.
+    pub fn two_dup(&mut self) -> Result<(),Error> {
+        self.require(2)?;
+
+        let len = self.stack.len();
+        for n in len - 2..len {
+            let value = self.stack.as_slice()[n].clone();
+            self.try_push(value)?;
+        }
+
+        Ok(())
+    }
+
+    ///Swap the top two pairs: `a b c d -- c d a b`.
+    pub fn two_swap(&mut self) -> Result<(),Error> {
+        self.require(4)?;
+
+        let len = self.stack.len();
+        self.stack.as_mut_slice()[len - 4..].rotate_left(2);
+
+        Ok(())
+    }
+
+    ///Copy the item `depth` below TOS to TOS. `pick(0)` is `dup`.
+    pub fn pick(&mut self, depth: usize) -> Result<(),Error> {
+        self.require(depth + 1)?;
+
+        let len = self.stack.len();
+        let value = self.stack.as_slice()[len - 1 - depth].clone();
+        self.try_push(value)?;
+
+        Ok(())
+    }
+
+    ///Move the item `depth` below TOS to TOS. `roll(1)` is `swap` and
+    ///`roll(2)` is `rot`.
+    pub fn roll(&mut self, depth: usize) -> Result<(),Error> {
+        self.require(depth + 1)?;
+
+        let len = self.stack.len();
+        self.stack.as_mut_slice()[len - 1 - depth..].rotate_left(1);
+
+        Ok(())
+    }
+
+    ///Pop a depth for `pick` or `roll`. A negative one is out of bounds
+    ///rather than too deep.
+    fn pop_depth(&mut self) -> Result<usize,Error> {
+        match self.pop()? {
+            Data::Int(n) if n >= 0 => Ok(n as usize),
+            Data::Int(n) => Err(Error::MemoryOutOfBounds { addr: n, len: self.len() }),
+            other => Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() }),
+        }
+    }
+
+    ///Pop a depth, then `pick`.
+    pub fn pick_n(&mut self) -> Result<(),Error> {
+        let depth = self.pop_depth()?;
+        self.pick(depth)
+    }
+
+    ///Pop a depth, then `roll`.
+    pub fn roll_n(&mut self) -> Result<(),Error> {
+        let depth = self.pop_depth()?;
+        self.roll(depth)
+    }
+
+    ///Replace NOS and TOS with a number computed from them: by `int` if
+    ///both are ints, or by `float` if both are floats. Each is given NOS
+    ///first. Anything else fails with `TypeMismatch`, as does an int
+    ///with a float unless `promote` has made them both floats. The
+    ///arithmetic words are written this way, and an extender can add a
+    ///numeric word in a line, such as `max` with
+    ///`stack.binary_op(|y, x| Ok(y.max(x)), f64::max)`.
+    pub fn binary_op<I, F>(&mut self, int: I, float: F) -> Result<(),Error>
+    where I: FnOnce(i64, i64) -> Result<i64,Error>, F: FnOnce(f64, f64) -> f64 {
+        match self.pop_two()? {
+            Pair::Int(x,y) => self.try_push(Data::Int(int(y, x)?)),
+            Pair::Float(x,y) => self.try_push(Data::Float(float(y, x))),
+            Pair::Str(_,_) => Err(Error::TypeMismatch { expected: TypeTag::Number, found: TypeTag::Str }),
+        }
+    }
+
+    ///Replace TOS with a number computed from it, by `int` or `float`
+    ///for its type. Anything else fails with `TypeMismatch`.
+    pub fn unary_op<I, F>(&mut self, int: I, float: F) -> Result<(),Error>
+    where I: FnOnce(i64) -> Result<i64,Error>, F: FnOnce(f64) -> f64 {
+        match self.pop()? {
+            Data::Int(n) => self.try_push(Data::Int(int(n)?)),
+            Data::Float(n) => self.try_push(Data::Float(float(n))),
+            other => Err(Error::TypeMismatch { expected: TypeTag::Number, found: other.type_tag() }),
+        }
+    }
+
+    ///Add TOS to NOS. Integers wrap on overflow.
+    pub fn add(&mut self) -> Result<(),Error> {
+        self.add_with(ArithmeticPolicy::Wrapping)
+    }
+
+    ///Add TOS to NOS, handling integer overflow under a policy.
+    pub fn add_with(&mut self, policy: ArithmeticPolicy) -> Result<(),Error> {
+        self.binary_op(|y, x| policy.apply(y.checked_add(x), y.wrapping_add(x), y.saturating_add(x)), |y, x| y + x)
+    }
+
+    ///Subtract TOS from NOS. Integers wrap on overflow.
+    pub fn sub(&mut self) -> Result<(),Error> {
+        self.sub_with(ArithmeticPolicy::Wrapping)
+    }
+
+    ///Subtract TOS from NOS, handling integer overflow under a policy.
+    pub fn sub_with(&mut self, policy: ArithmeticPolicy) -> Result<(),Error> {
+        self.binary_op(|y, x| policy.apply(y.checked_sub(x), y.wrapping_sub(x), y.saturating_sub(x)), |y, x| y - x)
+    }
+
+    ///Multiply NOS by TOS. Integers wrap on overflow.
+    pub fn mul(&mut self) -> Result<(),Error> {
+        self.mul_with(ArithmeticPolicy::Wrapping)
+    }
+
+    ///Multiply NOS by TOS, handling integer overflow under a policy.
+    pub fn mul_with(&mut self, policy: ArithmeticPolicy) -> Result<(),Error> {
+        self.binary_op(|y, x| policy.apply(y.checked_mul(x), y.wrapping_mul(x), y.saturating_mul(x)), |y, x| y * x)
+    }
+
+    ///Divide NOS by TOS. Integer division by zero is an error; float
+    ///division follows IEEE 754 and gives an infinity or NaN. Dividing the
+    ///most negative int by -1 wraps.
+    pub fn div(&mut self) -> Result<(),Error> {
+        self.div_with(ArithmeticPolicy::Wrapping)
+    }
+
+    ///Divide NOS by TOS, handling integer overflow under a policy.
+    pub fn div_with(&mut self, policy: ArithmeticPolicy) -> Result<(),Error> {
+        self.binary_op(|y, x| match x {
+            0 => Err(Error::DivisionByZero),
+            _ => policy.apply(y.checked_div(x), y.wrapping_div(x), y.saturating_div(x)),
+        }, |y, x| y / x)
+    }
+
+    ///Remainder of NOS divided by TOS. Integer modulus by zero is an
+    ///error; float modulus by zero gives NaN.
+    pub fn modulus(&mut self) -> Result<(),Error> {
+        self.binary_op(|y, x| match x {
+            0 => Err(Error::DivisionByZero),
+            _ => Ok(y.wrapping_rem(x)),
+        }, |y, x| y % x)
+    }
+
+    ///Pop two values of the same type and order NOS against TOS. Floats
+    ///involving NaN are unordered.
+    fn pop_ordering(&mut self) -> Result<Option<Ordering>,Error> {
+        let values = self.pop_two();
+
+        let values = match values {
+            Err(n) => { return Err(n);},
+            Ok(n)  => { n }
+        };
+
+        match values {
+            Pair::Int(x,y) => Ok(Some(y.cmp(&x))),
+            Pair::Float(x,y) => Ok(y.partial_cmp(&x)),
+            Pair::Str(x,y) => Ok(Some(y.cmp(&x))),
+        }
+    }
+
+    ///Push 1 if NOS equals TOS, else 0.
+    pub fn eq(&mut self) -> Result<(),Error> {
+        let order = match self.pop_ordering() {
             Err(n) => { return Err(n);},
             Ok(n)  => { n }
         };
 
-        self.push(value);
-        self.push(value);
+        self.try_push(Data::from_bool(order == Some(Ordering::Equal)))?;
 
         Ok(())
     }
 
-    ///Swap TOS with NOS.
-    pub fn swap(&mut self) -> Result<(), Error> {
-        let x = self.pop();
-        let y = self.pop();
-
-        let x = match x {
+    ///Push 1 if NOS is less than TOS, else 0.
+    pub fn lt(&mut self) -> Result<(),Error> {
+        let order = match self.pop_ordering() {
             Err(n) => { return Err(n);},
             Ok(n)  => { n }
         };
 
-        let y = match y {
+        self.try_push(Data::from_bool(order == Some(Ordering::Less)))?;
+
+        Ok(())
+    }
+
+    ///Push 1 if NOS is greater than TOS, else 0.
+    pub fn gt(&mut self) -> Result<(),Error> {
+        let order = match self.pop_ordering() {
             Err(n) => { return Err(n);},
             Ok(n)  => { n }
         };
 
-        self.push(x);
-        self.push(y);
+        self.try_push(Data::from_bool(order == Some(Ordering::Greater)))?;
 
         Ok(())
     }
 
-    ///Duplicate NOS, placing duplicate value above TOS.
-    pub fn over(&mut self) -> Result<(), Error> {
-        let x = self.pop();
-        let y = self.pop();
+    ///Bitwise and of two ints.
+    pub fn and(&mut self) -> Result<(),Error> {
+        let values = self.pop_two();
 
-        let x = match x {
+        let values = match values {
             Err(n) => { return Err(n);},
             Ok(n)  => { n }
         };
 
-        let y = match y {
+        match values {
+            Pair::Int(x,y) => {self.try_push(Data::Int(y & x))?;}
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() });}
+        }
+
+        Ok(())
+    }
+
+    ///Bitwise or of two ints.
+    pub fn or(&mut self) -> Result<(),Error> {
+        let values = self.pop_two();
+
+        let values = match values {
             Err(n) => { return Err(n);},
             Ok(n)  => { n }
         };
 
-        self.push(y);
-        self.push(x);
-        self.push(y);
+        match values {
+            Pair::Int(x,y) => {self.try_push(Data::Int(y | x))?;}
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() });}
+        }
 
         Ok(())
     }
 
-    pub fn add(&mut self) -> Result<(),Error> {
-        let values = self.pop_two(); 
+    ///Bitwise exclusive or of two ints.
+    pub fn xor(&mut self) -> Result<(),Error> {
+        let values = self.pop_two();
 
         let values = match values {
             Err(n) => { return Err(n);},
@@ -206,15 +1017,17 @@ impl Stack {
         };
 
         match values {
-            Pair::Int(x,y) => {self.push(Data::Int(x+y));}
-            Pair::Float(x,y) => { self.push(Data::Float(x+y));}
+            Pair::Int(x,y) => {self.try_push(Data::Int(y ^ x))?;}
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() });}
         }
 
         Ok(())
     }
 
-    pub fn sub(&mut self) -> Result<(),Error> {
-        let values = self.pop_two(); 
+    ///Replace a `low high` pair of ints with a random int in
+    ///`[low, high)`. An empty range gives `low`.
+    pub fn random_range(&mut self, rng: &mut Rng) -> Result<(),Error> {
+        let values = self.pop_two();
 
         let values = match values {
             Err(n) => { return Err(n);},
@@ -222,15 +1035,22 @@ impl Stack {
         };
 
         match values {
-            Pair::Int(x,y) => {self.push(Data::Int(y-x));}
-            Pair::Float(x,y) => { self.push(Data::Float(y-x));}
+            Pair::Int(x,y) => {self.try_push(Data::Int(rng.range(y, x)))?;}
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() });}
         }
 
         Ok(())
     }
 
-    pub fn mul(&mut self) -> Result<(),Error> {
-        let values = self.pop_two(); 
+    ///Push a random float in `[0, 1)`.
+    pub fn random_float(&mut self, rng: &mut Rng) -> Result<(),Error> {
+        self.try_push(Data::Float(rng.next_f64()))
+    }
+
+    ///Pop an int operand and an int shift or rotate count above it, then
+    ///push `op` of them.
+    fn bit_op(&mut self, op: fn(u64, i64) -> u64) -> Result<(),Error> {
+        let values = self.pop_two();
 
         let values = match values {
             Err(n) => { return Err(n);},
@@ -238,15 +1058,80 @@ impl Stack {
         };
 
         match values {
-            Pair::Int(x,y) => {self.push(Data::Int(y*x));}
-            Pair::Float(x,y) => { self.push(Data::Float(y*x));}
+            Pair::Int(x,y) => {self.try_push(Data::Int(op(y as u64, x) as i64))?;}
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() });}
         }
 
         Ok(())
     }
 
-    pub fn div(&mut self) -> Result<(),Error> {
-        let values = self.pop_two(); 
+    ///Shift NOS left by TOS bits. Counts outside 0 to 63 give 0.
+    pub fn shl(&mut self) -> Result<(),Error> {
+        self.bit_op(|value, count| if (0..64).contains(&count) { value << count } else { 0 })
+    }
+
+    ///Shift NOS right by TOS bits, filling with zeroes. Counts outside 0
+    ///to 63 give 0.
+    pub fn shr(&mut self) -> Result<(),Error> {
+        self.bit_op(|value, count| if (0..64).contains(&count) { value >> count } else { 0 })
+    }
+
+    ///Shift NOS right by TOS bits, filling with the sign bit. Counts
+    ///outside 0 to 63 act like 63.
+    pub fn sar(&mut self) -> Result<(),Error> {
+        self.bit_op(|value, count| ((value as i64) >> count.clamp(0, 63)) as u64)
+    }
+
+    ///Rotate NOS left by TOS bits. Negative counts rotate right.
+    pub fn rotl(&mut self) -> Result<(),Error> {
+        self.bit_op(|value, count| value.rotate_left(count.rem_euclid(64) as u32))
+    }
+
+    ///Rotate NOS right by TOS bits. Negative counts rotate left.
+    pub fn rotr(&mut self) -> Result<(),Error> {
+        self.bit_op(|value, count| value.rotate_right(count.rem_euclid(64) as u32))
+    }
+
+    ///Replace an int with the number of bits set in it.
+    pub fn popcount(&mut self) -> Result<(),Error> {
+        let value = self.pop();
+
+        let value = match value {
+            Err(n) => { return Err(n);},
+            Ok(n)  => { n }
+        };
+
+        match value {
+            Data::Int(n) => {self.try_push(Data::Int(n.count_ones() as i64))?;}
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() });}
+        }
+
+        Ok(())
+    }
+
+    ///Logical not. Replaces a zero int or float with 1 and anything else
+    ///with 0.
+    pub fn not(&mut self) -> Result<(),Error> {
+        let value = self.pop();
+
+        let value = match value {
+            Err(n) => { return Err(n);},
+            Ok(n)  => { n }
+        };
+
+        let truthy = match value.is_truthy() {
+            Err(n) => { return Err(n);},
+            Ok(n)  => { n }
+        };
+
+        self.try_push(Data::from_bool(!truthy))?;
+
+        Ok(())
+    }
+
+    ///Concatenate two strings, NOS first.
+    pub fn concat(&mut self) -> Result<(),Error> {
+        let values = self.pop_two();
 
         let values = match values {
             Err(n) => { return Err(n);},
@@ -254,15 +1139,38 @@ impl Stack {
         };
 
         match values {
-            Pair::Int(x,y) => {self.push(Data::Int(y/x));}
-            Pair::Float(x,y) => { self.push(Data::Float(y/x));}
+            Pair::Str(x,y) => {
+                let mut joined = String::from(&*y);
+                joined.push_str(&x);
+                self.try_push(Data::Str(Arc::from(joined)))?;
+            }
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Str, found: other.type_tag() });}
         }
 
         Ok(())
     }
 
-    pub fn modulus(&mut self) -> Result<(),Error> {
-        let values = self.pop_two(); 
+    ///Replace a string on TOS with its length in characters.
+    pub fn str_len(&mut self) -> Result<(),Error> {
+        let value = self.pop();
+
+        let value = match value {
+            Err(n) => { return Err(n);},
+            Ok(n)  => { n }
+        };
+
+        match value {
+            Data::Str(s) => {self.try_push(Data::Int(s.chars().count() as i64))?;}
+            other => {return Err(Error::TypeMismatch { expected: TypeTag::Str, found: other.type_tag() });}
+        }
+
+        Ok(())
+    }
+
+    ///Compare two strings, pushing -1, 0 or 1 as NOS is less than,
+    ///equal to or greater than TOS.
+    pub fn str_compare(&mut self) -> Result<(),Error> {
+        let values = self.pop_two();
 
         let values = match values {
             Err(n) => { return Err(n);},
@@ -270,8 +1178,15 @@ impl Stack {
         };
 
         match values {
-            Pair::Int(x,y) => {self.push(Data::Int(y%x));}
-            Pair::Float(x,y) => { self.push(Data::Float(y%x));}
+            Pair::Str(x,y) => {
+                let order = match y.cmp(&x) {
+                    Ordering::Less => -1,
+                    Ordering::Equal => 0,
+                    Ordering::Greater => 1,
+                };
+                self.try_push(Data::Int(order))?;
+            }
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Str, found: other.type_tag() });}
         }
 
         Ok(())
@@ -285,225 +1200,416 @@ impl Stack {
 ///
 /// PC should be set to the beginning of one of the words in memory.
 /// A return without a branch triggers a full return, no longer a Return Stack Underflow.
-
-pub fn run<T: AtomExtender>(
-            code: &Vec<u8>,
+///
+/// The extender is borrowed, so state it builds up survives the call;
+/// closures taking `(u8, &mut Stack)` work as extenders too.
+///
+/// This is a convenience wrapper around `Vm`; use that directly to step
+/// through a program or keep its state between calls.
+pub fn run<T: AtomExtender + ?Sized>(
+            code: &[u8],
             stack: &mut Stack,
-            mut pc: usize,
-            mut extender: T,
+            pc: usize,
+            extender: &mut T,
             memory: &mut Vec<Data>
-            ) -> Result<(),(usize,Error)> {
+            ) -> Result<(),RuntimeError> {
 
-    let mut rstack: Vec<usize> = Vec::new();
+    run_with_config(code, stack, pc, extender, memory, RunConfig::default())
+}
 
-    let mut value: i64 = 0;
-    let mut divider: f64 = 1.0;
+/// Like `run`, but with limits such as a step budget. When the budget
+/// runs out the error carries the PC to pass back in to continue.
+pub fn run_with_config<T: AtomExtender + ?Sized>(
+            code: &[u8],
+            stack: &mut Stack,
+            pc: usize,
+            extender: &mut T,
+            memory: &mut Vec<Data>,
+            config: RunConfig
+            ) -> Result<(),RuntimeError> {
 
-    while pc < code.len() {
-        let instruction = code[pc];
-        pc += 1;
+    let mut vm = Vm::new(code.to_vec(), mem::take(memory));
+    vm.pc = pc;
+    vm.config = config;
+    mem::swap(&mut vm.stack, stack);
 
-        match instruction {
-            10 => {},
-            13 => {},   //Carriage Returns and Line feeds are ignored
-            32 => {},   //Tabs are not allowed but spaces are.
-            34 => {     //Double quote. Push constant as float
-                let v = value as f64;
-                stack.push(Data::Float(v / divider));
-            },
-            35 => {     //Pound sign. Load constant.
-                value = 0;
-                divider = 1.0;
-            },
-            36 => {     //Dollar sign. Invert constant.
-                value = -value;
-            },
-            37 => {     //Percent sign. Modulus.
-                if let Err(n) = stack.modulus() { return Err((pc, n)); }
-            }
-            39 => {     //Single quote. Push constant as integer.
-                stack.push(Data::Int(value));
-            },
-            42 => {     //Asterisk. Multiply.
-                if let Err(n) = stack.mul() { return Err((pc, n)); }
-            },
-            43 => {     //Plus sign. Add.
-                if let Err(n) = stack.add() { return Err((pc, n)); }
-            },
-            45 => {     //Minus sign. Subtract.
-                if let Err(n) = stack.sub() { return Err((pc, n)); }
-            },
-            46 => {     //Period. Increase the divider by three orders of magnitude.
-                divider *= 1000.0;
-            },
-            47 => {     //Slash. Divide.
-                if let Err(n) = stack.div() { return Err((pc, n)); }
-            },
-            48...57 => { //Numeral.
-                value *= 10;
-                value += (instruction as i64) - 48;
-            },
-            59 => {     //Semicolon. Return
-                let home = match rstack.pop() {
-                    Some(n) => n,
-                    None    => { return Ok(()) }
-                };
+    let result = vm.run(extender);
 
-                pc = home; 
-            },
-            82 => {     //"R" Read from memory
-                let value = stack.pop();
-                
-                let value = match value {
-                    Err(n) => { return Err((pc,n));},
-                    Ok(n)  => { n }
-                };
+    mem::swap(&mut vm.stack, stack);
+    *memory = vm.memory;
 
-                match value {
-                    Data::Float(_) => { return Err((pc, Error::TypeMismatch)); }
-                    Data::Int(n) => {
-                        let val = memory[(n as usize) % memory.len()];
-                        stack.push(val);
-                    }
-                }
-            },
-            87 => {
-                let address = stack.pop();
-                let value = stack.pop();
+    result
+}
 
-                let address = match address {
-                    Err(n) => { return Err((pc,n));},
-                    Ok(n)  => { n }
-                };
+///Run code that may be hostile under a sandbox's limits, returning the
+///stack it leaves. No sequence of bytes makes this panic, hang or
+///allocate past the limits; anything wrong with the code is an error.
+pub fn run_untrusted(code: &[u8], limits: &SandboxConfig) -> Result<Vec<Data>,RuntimeError> {
+    let mut vm = Vm::sandboxed(limits.clone());
+    vm.load(code.to_vec())?;
+    vm.run(&mut NullExtender {})?;
 
-                let value = match value {
-                    Err(n) => { return Err((pc,n));},
-                    Ok(n)  => { n }
-                };
+    Ok(vm.stack.iter().cloned().collect())
+}
 
-                match address {
-                    Data::Float(_) => { return Err((pc, Error::TypeMismatch)); }
-                    Data::Int(n) => {
-                        let addr = (n as usize) % memory.len();
-                        memory[addr] = value;
-                    }
-                }
-            },
-            98  => {    //"b". Jump to address.
-                //println!("{}",stack.len());
-                let value = stack.pop();
-                
-                let value = match value {
-                    Err(n) => { return Err((pc,n));},
-                    Ok(n)  => { n }
-                };
+///Quote a string for JSON.
+pub(crate) fn json_string(text: &str) -> String {
+    use core::fmt::Write;
+
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
 
-                match value {
-                    Data::Float(_) => { return Err((pc, Error::TypeMismatch)); }
-                    Data::Int(n) => {
-                        pc = n as usize;
-                    }
-                }
-            },
-            99 => {     //"c". Call address
-                //println!("{}",stack.len());
-                let value = stack.pop();
-                
-                let value = match value {
-                    Err(n) => { return Err((pc,n));},
-                    Ok(n)  => { n }
-                };
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
 
-                match value {
-                    Data::Float(_) => { return Err((pc, Error::TypeMismatch)); }
-                    Data::Int(n) => {
-                        rstack.push(pc);
-                        pc = n as usize;
-                    }
-                }
-            },
-            100 => {    //"d". Duplicate.
-                if let Err(n) = stack.dup() { return Err((pc, n)); }
+    use Stack;
+    use Error;
+    use Data;
+    use TypeTag;
+    #[cfg(feature = "std")]
+    use load_module;
+    use run;
+    use NullExtender;
+    use {AtomExtender, Context};
+    use RuntimeError;
+    #[cfg(feature = "std")]
+    use std::error;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        extern crate serde_json;
 
-            },
-            112 => {    //"p". Debug. Print type of variable, and value.
+        let stack: Stack = serde_json::from_str(r#"[{"Int":1},{"Float":2.5},{"Str":"hi"}]"#).unwrap();
+        assert_eq!(stack.to_string(), "<3> 1 2.5 \"hi\"");
+        assert_eq!(serde_json::to_string(&stack).unwrap(), r#"[{"Int":1},{"Float":2.5},{"Str":"hi"}]"#);
+    }
 
-                let value = stack.pop();
-                
-                let value = match value {
-                    Err(n) => { return Err((pc,n));},
-                    Ok(n)  => { n }
-                };
+    #[test]
+    fn strings() {
+        let mut s = Stack::new();
 
-                match value {
-                    Data::Int(n) => println!("Int:{}",n),
-                    Data::Float(n) => println!("Float:{}",n)
-                }
-            },
-            114 => {    //"r" Drop.
-                if let Err(n) = stack.pop() { return Err((pc, n)); }
+        s.push(Data::Str(Arc::from("green")));
+        s.push(Data::Str(Arc::from("gold")));
+        s.over().unwrap();
+        s.over().unwrap();
+        assert!(matches!(s.str_compare(), Ok(())));
+        assert!(matches!(s.pop(), Ok(Data::Int(1))));
+
+        assert!(matches!(s.concat(), Ok(())));
+        s.dup().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Str(ref v)) if &**v == "greengold"));
+        assert!(matches!(s.str_len(), Ok(())));
+        assert!(matches!(s.pop(), Ok(Data::Int(9))));
+
+        s.push(Data::Str(Arc::from("x")));
+        s.push(Data::Int(1));
+        assert!(matches!(s.add(), Err(Error::TypeMismatch { expected: TypeTag::Str, found: TypeTag::Int })));
+    }
 
-            },
+    #[test]
+    fn division_by_zero() {
+        let mut s = Stack::new();
 
-            115 => {    //"s" Swap.
-                if let Err(n) = stack.swap() { return Err((pc, n)); }
+        s.push(Data::Int(7));
+        s.push(Data::Int(0));
+        assert!(matches!(s.div(), Err(Error::DivisionByZero)));
 
-            }
-            118 => {    //"v" Over.
-                if let Err(n) = stack.over() { return Err((pc, n)); }
-            }
-            121 => {    //"y" Jump if non-zero.
-                let address = stack.pop();
+        s.push(Data::Int(7));
+        s.push(Data::Int(0));
+        assert!(matches!(s.modulus(), Err(Error::DivisionByZero)));
 
-                let address = match address { Err(n) => {return Err((pc,n));}, Ok(n) => {n} };
+        s.push(Data::Float(1.0));
+        s.push(Data::Float(0.0));
+        s.div().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Float(n)) if n == f64::INFINITY));
 
-                let data = stack.pop();
+        s.push(Data::Float(1.0));
+        s.push(Data::Float(0.0));
+        s.modulus().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Float(n)) if n.is_nan()));
+    }
 
-                let data = match data { Err(n) => {return Err((pc,n));}, Ok(n) => {n} };
+    #[test]
+    fn comparisons() {
+        let mut s = Stack::new();
 
-                let address = match address { Data::Float(_) => {return Err((pc, Error::TypeMismatch));}, Data::Int(n) => n as usize };
+        s.push(Data::Int(3));
+        s.push(Data::Int(5));
+        s.lt().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(1))));
+
+        s.push(Data::Float(3.0));
+        s.push(Data::Float(5.0));
+        s.gt().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(0))));
+
+        s.push(Data::Float(f64::NAN));
+        s.push(Data::Float(f64::NAN));
+        s.eq().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(0))));
+
+        s.push(Data::Str(Arc::from("a")));
+        s.push(Data::Str(Arc::from("a")));
+        s.eq().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(1))));
+
+        s.push(Data::Int(1));
+        s.push(Data::Float(1.0));
+        assert!(matches!(s.eq(), Err(Error::TypeMismatch { expected: TypeTag::Int, found: TypeTag::Float })));
+    }
 
-                let condition = match data {
-                    Data::Float(n) => if n != 0.0 {true} else {false},
-                    Data::Int(n)   => if n != 0 {true} else {false}
-                };
+    #[test]
+    fn shuffles() {
+        let mut s = Stack::new();
+        for n in 1..5 {
+            s.push(Data::Int(n));
+        }
 
-                if condition { pc = address; }
-            },
-            122 => {    //"z" Jump if zero.
-                let address = stack.pop();
+        s.rot().unwrap();
+        assert_eq!(s.to_string(), "<4> 1 3 4 2");
+        s.rrot().unwrap();
+        assert_eq!(s.to_string(), "<4> 1 2 3 4");
+        s.two_swap().unwrap();
+        assert_eq!(s.to_string(), "<4> 3 4 1 2");
+        s.tuck().unwrap();
+        assert_eq!(s.to_string(), "<5> 3 4 2 1 2");
+        s.nip().unwrap();
+        assert_eq!(s.to_string(), "<4> 3 4 2 2");
+        s.two_dup().unwrap();
+        assert_eq!(s.to_string(), "<6> 3 4 2 2 2 2");
 
-                let address = match address { Err(n) => {return Err((pc,n));}, Ok(n) => {n} };
+        let mut s = Stack::new();
+        s.push(Data::Int(1));
+        s.push(Data::Int(2));
+        assert!(matches!(s.rot(), Err(Error::StackUnderflow)));
+        assert!(matches!(s.two_swap(), Err(Error::StackUnderflow)));
+        assert_eq!(s.len(), 2);
 
-                let data = stack.pop();
+        let mut stack = Stack::new();
+        run(b"#1'#2'#3'o u n t D S", &mut stack, 0, &mut NullExtender {}, &mut Vec::new()).unwrap();
+        assert_eq!(stack.to_string(), "<5> 3 1 3 1 3");
+    }
 
-                let data = match data { Err(n) => {return Err((pc,n));}, Ok(n) => {n} };
+    #[test]
+    fn numeric_ops() {
+        let mut s = Stack::new();
+        s.push(Data::Int(3));
+        s.push(Data::Int(7));
+        s.binary_op(|y, x| Ok(y.max(x)), f64::max).unwrap();
+        assert_eq!(s.to_string(), "<1> 7");
+
+        s.push(Data::Float(2.5));
+        assert!(matches!(s.binary_op(|y, x| Ok(y.max(x)), f64::max), Err(Error::TypeMismatch { expected: TypeTag::Int, found: TypeTag::Float })));
+        s.push(Data::Int(-1));
+        s.push(Data::Float(2.5));
+        s.promote();
+        s.binary_op(|y, x| Ok(y.max(x)), f64::max).unwrap();
+        assert_eq!(s.to_string(), "<1> 2.5");
+
+        s.unary_op(|n| n.checked_neg().ok_or(Error::IntegerOverflow), |n| -n).unwrap();
+        assert_eq!(s.to_string(), "<1> -2.5");
+        s.push(Data::Int(i64::MIN));
+        assert!(matches!(s.unary_op(|n| n.checked_neg().ok_or(Error::IntegerOverflow), |n| -n), Err(Error::IntegerOverflow)));
+        s.push(Data::Str(Arc::from("x")));
+        assert!(matches!(s.unary_op(Ok, |n| n), Err(Error::TypeMismatch { expected: TypeTag::Number, found: TypeTag::Str })));
+    }
 
-                let address = match address { Data::Float(_) => {return Err((pc, Error::TypeMismatch));}, Data::Int(n) => n as usize };
+    #[test]
+    fn pick_and_roll() {
+        let mut s = Stack::new();
+        for n in 1..5 {
+            s.push(Data::Int(n));
+        }
 
-                let condition = match data {
-                    Data::Float(n) => if n == 0.0 {true} else {false},
-                    Data::Int(n)   => if n == 0 {true} else {false}
-                };
+        s.pick(3).unwrap();
+        assert_eq!(s.to_string(), "<5> 1 2 3 4 1");
+        s.roll(4).unwrap();
+        assert_eq!(s.to_string(), "<5> 2 3 4 1 1");
+        s.roll(0).unwrap();
+        assert_eq!(s.to_string(), "<5> 2 3 4 1 1");
+        assert!(matches!(s.pick(5), Err(Error::StackUnderflow)));
+        assert!(matches!(s.roll(5), Err(Error::StackUnderflow)));
+
+        let mut stack = Stack::new();
+        run(b"#7'#8'#9' #2'P #3'O", &mut stack, 0, &mut NullExtender {}, &mut Vec::new()).unwrap();
+        assert_eq!(stack.to_string(), "<4> 8 9 7 7");
+
+        let mut stack = Stack::new();
+        assert!(run(b"#1'#1'P", &mut stack, 0, &mut NullExtender {}, &mut Vec::new()).is_err());
+
+        let mut stack = Stack::new();
+        stack.push(Data::Int(1));
+        stack.push(Data::Int(-1));
+        assert!(matches!(stack.pick_n(), Err(Error::MemoryOutOfBounds { addr: -1, len: 1 })));
+        stack.push(Data::Int(-2));
+        assert!(matches!(stack.roll_n(), Err(Error::MemoryOutOfBounds { addr: -2, len: 1 })));
+    }
 
-                if condition { pc = address; }
-            },
-            _ => {
-                if let Err(n) = extender.atom(instruction, stack) { return Err((pc, n)); }
-            },
+    #[test]
+    fn truthiness() {
+        assert_eq!(Data::from_bool(true), Data::TRUE);
+        assert_eq!(Data::from_bool(false), Data::FALSE);
+        assert!(Data::Float(-0.5).is_truthy().unwrap());
+        assert!(!Data::Int(0).is_truthy().unwrap());
+        assert!(Data::Str(Arc::from("")).is_truthy().is_err());
+
+        let mut s = Stack::new();
+        s.push(Data::Int(2));
+        s.push(Data::Int(3));
+        s.lt().unwrap();
+        assert_eq!(s.pop().unwrap(), Data::TRUE);
+    }
+
+    #[test]
+    fn inspection() {
+        let mut s = Stack::new();
+        assert_eq!(s.peek(), None);
 
+        for n in 1..4 {
+            s.push(Data::Int(n));
         }
+
+        assert_eq!(s.peek(), Some(&Data::Int(3)));
+        assert_eq!(s.peek_n(2), Some(&Data::Int(1)));
+        assert_eq!(s.peek_n(3), None);
+        assert_eq!(s.iter().cloned().collect::<Vec<Data>>(), s.as_slice().to_vec());
+        assert_eq!(s.as_slice()[0], Data::Int(1));
+
+        s.truncate(1);
+        assert_eq!(s.to_string(), "<1> 1");
+        s.clear();
+        assert!(s.is_empty());
     }
 
-    Ok(())
+    #[test]
+    fn bits() {
+        let mut stack = Stack::new();
+        run(b"#1'#4'{ #256'#4'} #8$'#1'_ #1$'#1'} #3$'N", &mut stack, 0, &mut NullExtender {}, &mut Vec::new()).unwrap();
+        assert_eq!(stack.to_string(), "<5> 16 16 -4 9223372036854775807 63");
 
-}
+        let mut stack = Stack::new();
+        run(b"#1'#1$'L #1'#65'Q #1'#64'{", &mut stack, 0, &mut NullExtender {}, &mut Vec::new()).unwrap();
+        assert_eq!(stack.to_string(), "<3> -9223372036854775808 -9223372036854775808 0");
 
-#[cfg(test)]
-mod tests {
-    use Stack;
-    use Error;
-    use Data;
+        let mut s = Stack::new();
+        s.push(Data::Float(1.0));
+        s.push(Data::Int(1));
+        assert!(matches!(s.shl(), Err(Error::TypeMismatch { expected: TypeTag::Float, found: TypeTag::Int })));
+        s.push(Data::Float(1.0));
+        assert!(matches!(s.popcount(), Err(Error::TypeMismatch { expected: TypeTag::Int, found: TypeTag::Float })));
+    }
+
+    #[test]
+    fn logic() {
+        let mut s = Stack::new();
+
+        s.push(Data::Int(0b1100));
+        s.push(Data::Int(0b1010));
+        s.over().unwrap();
+        s.over().unwrap();
+        s.over().unwrap();
+        s.over().unwrap();
+        s.and().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(0b1000))));
+        s.or().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(0b1110))));
+        s.xor().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(0b0110))));
+
+        s.push(Data::Int(0));
+        s.not().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(1))));
+
+        s.push(Data::Float(2.5));
+        s.not().unwrap();
+        assert!(matches!(s.pop(), Ok(Data::Int(0))));
+
+        s.push(Data::Float(2.5));
+        s.push(Data::Float(1.5));
+        assert!(matches!(s.and(), Err(Error::TypeMismatch { expected: TypeTag::Int, found: TypeTag::Float })));
+    }
+
+    #[test]
+    fn error_display() {
+        let err = RuntimeError::new(12, Error::StackUnderflow);
+        assert_eq!(err.to_string(), "Stack Underflow at 12");
+
+        let err = RuntimeError::new(3, Error::TypeMismatch { expected: TypeTag::Number, found: TypeTag::Str });
+        assert_eq!(err.to_string(), "Type Mismatch: expected number, found string at 3");
+        assert_eq!(Error::MemoryOutOfBounds { addr: -1, len: 8 }.to_string(), "Memory Out Of Bounds");
+        assert_eq!(format!("{}", Error::MemoryOutOfBounds { addr: -1, len: 8 }), "Memory Out Of Bounds: address -1 outside 8 cells");
+        assert_eq!(format!("{}", Error::InvalidInstruction { opcode: b'J' }), "Invalid Instruction: J");
+
+        #[cfg(feature = "std")]
+        {
+            let err = load_module("no/such/module.ggb").unwrap_err();
+            assert!(format!("{}", err).starts_with("I/O Error: "));
+            assert!(error::Error::source(&err).is_some());
+        }
+    }
+
+    struct Peek {}
+    impl AtomExtender for Peek {
+        fn atom_with_context(&mut self, _: u8, context: &mut Context) -> Result<(),Error> {
+            let value = context.memory[0].clone();
+            context.stack.push(value);
+            context.rstack.push(context.pc);
+            context.pc = 0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn extender_context() {
+        let mut stack = Stack::new();
+        let mut memory = vec![Data::Int(42)];
+
+        run(b";\x80", &mut stack, 1, &mut Peek {}, &mut memory).unwrap();
+        assert!(matches!(stack.pop(), Ok(Data::Int(42))));
+    }
+
+    #[test]
+    fn closure_extender() {
+        let mut stack = Stack::new();
+        let mut calls = 0;
+
+        {
+            let mut count = |_: u8, stack: &mut Stack| {
+                calls += 1;
+                stack.push(Data::Int(calls));
+                Ok(())
+            };
+
+            run(b"\x80\x80", &mut stack, 0, &mut count, &mut Vec::new()).unwrap();
+
+            let extender: &mut dyn AtomExtender = &mut count;
+            run(b"\x80", &mut stack, 0, extender, &mut Vec::new()).unwrap();
+        }
+
+        assert_eq!(calls, 3);
+        assert!(matches!(stack.pop(), Ok(Data::Int(3))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn load_missing_module() {
+        let path = "no/such/module.ggb".to_string();
+        assert!(matches!(load_module(&path), Err(Error::Io(_))));
+    }
 
     #[test]
     fn it_works() {
@@ -511,7 +1617,7 @@ mod tests {
 
         let v = s.pop();
 
-        assert!(if let Err(Error::StackUnderflow) = v {true} else {false});
+        assert!(matches!(v, Err(Error::StackUnderflow)));
 
         s.push(Data::Int(5));
 
@@ -524,7 +1630,8 @@ mod tests {
                     5 => {},
                     _ => panic!("No good"),
                 },
-                Data::Float(_) => panic!("Wrong type")
+                Data::Float(_) => panic!("Wrong type"),
+                Data::Str(_) | Data::Array(_) | Data::Map(_) => panic!("Wrong type")
             }
         }
 
@@ -533,14 +1640,14 @@ mod tests {
 
         let pair = s.pop_two();
 
-        assert!(if let Err(Error::TypeMismatch) = pair {true} else {false});
+        assert!(matches!(pair, Err(Error::TypeMismatch { expected: TypeTag::Int, found: TypeTag::Float })));
 
-        assert!(if let Err(Error::StackUnderflow) = s.cast_to_int() {true} else {false});
+        assert!(matches!(s.cast_to_int(), Err(Error::StackUnderflow)));
 
         s.push(Data::Int(2));
         s.push(Data::Int(6));
 
-        assert!(if let Ok(()) = s.add() {true} else {false});
+        assert!(matches!(s.add(), Ok(())));
 
         let eight = s.pop();
 
@@ -551,9 +1658,35 @@ mod tests {
                     8 => {},
                     _ => panic!("No good"),
                 },
-                Data::Float(_) => panic!("Wrong type")
+                Data::Float(_) => panic!("Wrong type"),
+                Data::Str(_) | Data::Array(_) | Data::Map(_) => panic!("Wrong type")
+            }
+        }
+
+    }
+
+    #[test]
+    fn untrusted() {
+        use {run_untrusted, SandboxConfig};
+
+        let limits = SandboxConfig {
+            max_steps: 1000,
+            ..SandboxConfig::default()
+        };
+
+        //Every program of up to two bytes stops cleanly.
+        for a in 0..=255u8 {
+            let _ = run_untrusted(&[a], &limits);
+            for b in 0..=255u8 {
+                let _ = run_untrusted(&[a, b], &limits);
             }
         }
 
+        assert_eq!(run_untrusted(b"#2'#3'*", &limits).unwrap(), vec![Data::Int(6)]);
+        assert!(matches!(run_untrusted(b"#1'#0'/", &limits), Err(RuntimeError { kind: Error::DivisionByZero, .. })));
+        //Doubling a string in a loop stops at the limit instead of
+        //running out of memory.
+        assert!(matches!(run_untrusted(b"[ab]dk#7$'B", &limits), Err(RuntimeError { kind: Error::MemoryLimitExceeded, .. })));
+        assert!(matches!(run_untrusted(b"#1000000000000'a", &limits), Err(RuntimeError { kind: Error::MemoryLimitExceeded, .. })));
     }
 }
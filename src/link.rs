@@ -0,0 +1,153 @@
+//!Combining several modules into one image.
+//!
+//!Each module's code is placed after the previous one, so execution
+//!starts at the top-level code of the first module added. Code moves as a
+//!block: relative jumps and symbolic calls survive this, absolute jumps
+//!written by hand do not.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use disasm::{decode, Instruction};
+use module::Module;
+
+///Something that stops a set of modules from being linked.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    ///Two modules define a word or global with the same name.
+    DuplicateWord(String),
+    ///A symbolic call names a word no module defines.
+    MissingWord(String),
+    ///More than one module has initial memory. Memory addresses are
+    ///absolute, so the cells can't be moved to make room.
+    ConflictingData,
+    ///More than one module has a constant pool. Code refers to constants
+    ///by index, so the pools can't be merged.
+    ConflictingConstants,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LinkError::DuplicateWord(ref w) => write!(f, "Word defined twice: {}", w),
+            LinkError::MissingWord(ref w) => write!(f, "Word not defined: {}", w),
+            LinkError::ConflictingData => write!(f, "More than one module has initial memory"),
+            LinkError::ConflictingConstants => write!(f, "More than one module has a constant pool"),
+        }
+    }
+}
+
+///Collects modules to be linked.
+#[derive(Default)]
+pub struct Linker {
+    modules: Vec<Module>,
+}
+
+impl Linker {
+    ///Start with no modules.
+    pub fn new() -> Linker {
+        Linker {
+            modules: Vec::new()
+        }
+    }
+
+    ///Add a module after those already added.
+    pub fn add(&mut self, module: Module) -> &mut Linker {
+        self.modules.push(module);
+        self
+    }
+
+    ///Lay the modules out one after another, merging their dictionaries,
+    ///globals, initial memory, constant pools and other sections. Every symbolic call must name a
+    ///word defined by one of the modules. Where two modules carry a
+    ///section with the same name the first is kept.
+    pub fn link(&self) -> Result<Module, LinkError> {
+        let mut image = Module::default();
+
+        for module in &self.modules {
+            let base = image.code.len();
+
+            for (name, address) in module.dictionary.iter() {
+                if image.dictionary.get(name).is_some() {
+                    return Err(LinkError::DuplicateWord(String::from(name)));
+                }
+                image.dictionary.insert(name, base + address);
+            }
+
+            for (name, global) in &module.globals {
+                if image.globals.insert(name.clone(), global.clone()).is_some() {
+                    return Err(LinkError::DuplicateWord(name.clone()));
+                }
+            }
+
+            if !module.data.is_empty() {
+                if !image.data.is_empty() {
+                    return Err(LinkError::ConflictingData);
+                }
+                image.data = module.data.clone();
+            }
+
+            if !module.constants.is_empty() {
+                if !image.constants.is_empty() {
+                    return Err(LinkError::ConflictingConstants);
+                }
+                image.constants = module.constants.clone();
+            }
+
+            for (name, payload) in &module.sections {
+                image.sections.entry(name.clone()).or_insert_with(|| payload.clone());
+            }
+
+            image.code.extend_from_slice(&module.code);
+        }
+
+        for (_, instruction) in decode(&image.code) {
+            if let Instruction::Word(name) = instruction {
+                if image.dictionary.get(&name).is_none() {
+                    return Err(LinkError::MissingWord(name));
+                }
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use compiler::compile_module;
+    use link::{LinkError, Linker};
+    use module::Module;
+    use {Data, NullExtender, Vm};
+
+    #[test]
+    fn links_modules() {
+        let main = Module::from_code(b"#3'`cube`;".to_vec());
+        let library = compile_module(": square dup * ; : cube dup square * ;").unwrap();
+
+        let image = Linker::new().add(main).add(library).link().unwrap();
+        assert_eq!(image.dictionary.get("square"), Some(11));
+
+        let mut vm = Vm::from_module(image, Vec::new()).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(27))));
+    }
+
+    #[test]
+    fn errors() {
+        let library = compile_module(": square dup * ;").unwrap();
+
+        let result = Linker::new().add(library.clone()).add(library).link();
+        assert_eq!(result, Err(LinkError::DuplicateWord(String::from("square"))));
+
+        let (half, third) = (compile_module(": half 0.5 * ;").unwrap(), compile_module(": third 0.333 * ;").unwrap());
+        assert_eq!(Linker::new().add(half).add(third).link(), Err(LinkError::ConflictingConstants));
+
+        let result = Linker::new().add(Module::from_code(b"`cube`;".to_vec())).link();
+        assert_eq!(result, Err(LinkError::MissingWord(String::from("cube"))));
+    }
+}
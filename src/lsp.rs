@@ -0,0 +1,450 @@
+//!A language server for Forth-style source, for editors that speak the
+//!Language Server Protocol. `greengold-lsp` runs one over standard input
+//!and output.
+//!
+//!The server keeps the text of each open document, sent whole on every
+//!change, and answers from it: go to the definition of a word, variable
+//!or constant; hover for the stack effect of a word, or the description
+//!of a built-in one; completion of every name defined in the document and
+//!every built-in word. Each change is compiled, and checked with the
+//!validator, and the first error is published as a diagnostic. Compile
+//!errors don't say where they happened, so each is placed on the first
+//!token it names, or at the end of the document.
+//!
+//!`Server::handle` takes one message and gives back the messages to send,
+//!so the framing of the transport is up to the caller.
+
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+
+use serde_json::{json, Value};
+
+use compiler::{compile_module, lex, CompileError, Compiler, Lexeme, BUILTINS, EXTENDED_BUILTINS, KEYWORDS};
+use opcodes::{extended, opcode, Opcode};
+use validate::validate;
+
+///Something wrong with a document, and the bytes it is about.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+///What a name in a document was defined as.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind<'a> {
+    ///A word, with the text of its stack comment if it has one.
+    Word(Option<&'a str>),
+    Variable,
+    ///A constant, with the token giving its value.
+    Constant(&'a str),
+}
+
+///A name a document defines, and the bytes it takes up there.
+struct Name<'a> {
+    name: &'a str,
+    span: Range<usize>,
+    kind: Kind<'a>,
+}
+
+///Find the names defined in some tokens.
+fn names<'a>(tokens: &[(Range<usize>, Lexeme<'a>)]) -> Vec<Name<'a>> {
+    let mut names = Vec::new();
+    for (n, &(_, token)) in tokens.iter().enumerate() {
+        let kind = match (token, tokens.get(n + 2)) {
+            (Lexeme::Word(":"), Some(&(_, Lexeme::Comment(text)))) => Kind::Word(Some(text)),
+            (Lexeme::Word(":"), _) => Kind::Word(None),
+            (Lexeme::Word("variable"), _) => Kind::Variable,
+            (Lexeme::Word("constant"), _) => match n.checked_sub(1).map(|n| tokens[n].1) {
+                Some(Lexeme::Word(value)) => Kind::Constant(value),
+                _ => { continue; }
+            },
+            _ => { continue; }
+        };
+        if let Some(&(ref span, Lexeme::Word(name))) = tokens.get(n + 1) {
+            names.push(Name { name, span: span.clone(), kind });
+        }
+    }
+
+    names
+}
+
+///Find the word at a byte offset, counting the end of a word as in it so
+///a cursor just after one finds it.
+fn word_at<'a>(tokens: &[(Range<usize>, Lexeme<'a>)], offset: usize) -> Option<(Range<usize>, &'a str)> {
+    tokens.iter().find_map(|&(ref span, token)| match token {
+        Lexeme::Word(word) if span.start <= offset && offset <= span.end => Some((span.clone(), word)),
+        _ => None,
+    })
+}
+
+///The built-in opcode a word compiles to, if it is one.
+fn builtin_opcode(word: &str) -> Option<&'static Opcode> {
+    match BUILTINS.iter().find(|n| n.0 == word) {
+        Some(&(_, byte)) => opcode(byte),
+        None => EXTENDED_BUILTINS.iter().find(|n| n.0 == word).and_then(|&(_, byte)| extended(byte)),
+    }
+}
+
+///Check a document, giving its first problem, if it has one.
+pub fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    let err = match compile_module(source) {
+        Ok(module) => match validate(&module.code) {
+            Ok(_) => { return Vec::new(); },
+            Err(n) => {
+                //Validation errors come with an address, which the debug
+                //info knows the source of.
+                let span = module.debug_info.as_ref()
+                    .and_then(|info| info.locate(n.pc()))
+                    .map(|location| {
+                        let offset = offset(source, location.line - 1, 0);
+                        offset..source[offset..].find('\n').map_or(source.len(), |n| offset + n)
+                    })
+                    .unwrap_or(0..0);
+                return vec![Diagnostic { span, message: n.to_string() }];
+            },
+        },
+        Err(n) => n,
+    };
+
+    let tokens = lex(source).unwrap_or_default();
+    let span = match err {
+        CompileError::UnknownWord(ref w) | CompileError::InvalidNumber(ref w) |
+        CompileError::UnmatchedControl(ref w) | CompileError::UnclosedControl(ref w) => {
+            tokens.iter().find(|n| n.1 == Lexeme::Word(w)).map(|n| n.0.clone())
+        },
+        CompileError::StackEffectMismatch { ref word, .. } | CompileError::StackUnderflow(Some(ref word)) => {
+            names(&tokens).into_iter().find(|n| n.name == word).map(|n| n.span)
+        },
+        _ => None,
+    };
+
+    vec![Diagnostic {
+        span: span.unwrap_or(source.len()..source.len()),
+        message: err.to_string(),
+    }]
+}
+
+///Describe the word at a byte offset, in Markdown.
+pub fn hover(source: &str, offset: usize) -> Option<String> {
+    let tokens = lex(source).ok()?;
+    let (_, word) = word_at(&tokens, offset)?;
+
+    if let Some(name) = names(&tokens).into_iter().find(|n| n.name == word) {
+        return Some(match name.kind {
+            Kind::Word(comment) => {
+                let mut compiler = Compiler::new();
+                let _ = compiler.compile(source);
+                let mut text = match comment {
+                    Some(comment) => format!("```forth\n: {} ( {} )\n```", word, comment.trim()),
+                    None => format!("```forth\n: {}\n```", word),
+                };
+                if let Some(effect) = compiler.effect(word) {
+                    text.push_str(&format!("\n\nStack effect `{}`", effect));
+                }
+                text
+            },
+            Kind::Variable => format!("```forth\nvariable {}\n```\n\nPushes the address of its cell.", word),
+            Kind::Constant(value) => format!("```forth\n{} constant {}\n```", value, word),
+        });
+    }
+
+    let op = builtin_opcode(word)?;
+    Some(match op.stack_effect {
+        Some(effect) => format!("```forth\n{} {}\n```\n\n{}", word, effect, op.description),
+        None => format!("```forth\n{}\n```\n\n{}", word, op.description),
+    })
+}
+
+///Find where the word at a byte offset is defined.
+pub fn definition(source: &str, offset: usize) -> Option<Range<usize>> {
+    let tokens = lex(source).ok()?;
+    let (_, word) = word_at(&tokens, offset)?;
+
+    names(&tokens).into_iter().find(|n| n.name == word).map(|n| n.span)
+}
+
+///List the names that could finish the word before a byte offset, each
+///with a short description.
+pub fn completions(source: &str, offset: usize) -> Vec<(String, String)> {
+    let start = source[..offset].rfind(char::is_whitespace).map_or(0, |n| n + 1);
+    let prefix = &source[start..offset];
+
+    let mut found: BTreeMap<&str, String> = BTreeMap::new();
+    if let Ok(tokens) = lex(source) {
+        for name in names(&tokens) {
+            let detail = match name.kind {
+                Kind::Word(Some(comment)) => format!("( {} )", comment.trim()),
+                Kind::Word(None) => String::from("word"),
+                Kind::Variable => String::from("variable"),
+                Kind::Constant(value) => format!("constant {}", value),
+            };
+            found.insert(name.name, detail);
+        }
+    }
+    for &(word, _) in BUILTINS.iter().chain(EXTENDED_BUILTINS) {
+        if let Some(op) = builtin_opcode(word) {
+            found.entry(word).or_insert_with(|| String::from(op.description));
+        }
+    }
+    for &word in KEYWORDS {
+        found.entry(word).or_insert_with(|| String::from("keyword"));
+    }
+
+    found.into_iter()
+        .filter(|&(name, _)| name.starts_with(prefix))
+        .map(|(name, detail)| (String::from(name), detail))
+        .collect()
+}
+
+///Turn a byte offset into a line and a character, counted from 0 in
+///UTF-16 code units as the protocol has it.
+fn position(source: &str, offset: usize) -> Value {
+    let before = &source[..offset];
+    let start = before.rfind('\n').map_or(0, |n| n + 1);
+
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[start..].encode_utf16().count(),
+    })
+}
+
+///Turn a line and character into a byte offset, clamped to the line.
+fn offset(source: &str, line: u32, character: u32) -> usize {
+    let mut start = 0;
+    for _ in 0..line {
+        match source[start..].find('\n') {
+            Some(n) => { start += n + 1; },
+            None => { return source.len(); }
+        }
+    }
+
+    let mut units = 0;
+    for (n, c) in source[start..].char_indices() {
+        if c == '\n' || units >= character as usize {
+            return start + n;
+        }
+        units += c.len_utf16();
+    }
+
+    source.len()
+}
+
+fn range(source: &str, span: &Range<usize>) -> Value {
+    json!({"start": position(source, span.start), "end": position(source, span.end)})
+}
+
+///Speaks the protocol for a set of open documents.
+#[derive(Default)]
+pub struct Server {
+    documents: BTreeMap<String, String>,
+    shut_down: bool,
+    exited: bool,
+}
+
+impl Server {
+    ///Start with no documents open.
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    ///Check whether the client has asked the server to exit, and whether
+    ///it shut down first, as it should have.
+    pub fn exited(&self) -> Option<bool> {
+        if self.exited { Some(self.shut_down) } else { None }
+    }
+
+    fn publish(&self, uri: &str) -> Value {
+        let source = self.documents.get(uri).map_or("", |n| &n[..]);
+        let diagnostics: Vec<Value> = diagnostics(source).into_iter().map(|n| json!({
+            "range": range(source, &n.span),
+            "severity": 1,
+            "source": "greengold",
+            "message": n.message,
+        })).collect();
+
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        })
+    }
+
+    ///Find the document and byte offset a request is about.
+    fn at<'a>(&'a self, params: &Value) -> Option<(&'a str, &'a str, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let (uri, source) = self.documents.get_key_value(uri)?;
+        let line = params["position"]["line"].as_u64()? as u32;
+        let character = params["position"]["character"].as_u64()? as u32;
+
+        Some((uri, source, offset(source, line, character)))
+    }
+
+    ///Answer a request, or `None` if it isn't one the server knows.
+    fn request(&mut self, method: &str, params: &Value) -> Option<Value> {
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": {"name": "greengold"},
+            }),
+            "shutdown" => {
+                self.shut_down = true;
+                Value::Null
+            },
+            "textDocument/hover" => match self.at(params) {
+                Some((_, source, offset)) => match hover(source, offset) {
+                    Some(text) => json!({"contents": {"kind": "markdown", "value": text}}),
+                    None => Value::Null,
+                },
+                None => Value::Null,
+            },
+            "textDocument/definition" => match self.at(params) {
+                Some((uri, source, offset)) => match definition(source, offset) {
+                    Some(span) => json!({"uri": uri, "range": range(source, &span)}),
+                    None => Value::Null,
+                },
+                None => Value::Null,
+            },
+            "textDocument/completion" => match self.at(params) {
+                Some((_, source, offset)) => {
+                    let items: Vec<Value> = completions(source, offset).into_iter().map(|(label, detail)| json!({
+                        "label": label,
+                        "kind": 3,
+                        "detail": detail,
+                    })).collect();
+                    Value::from(items)
+                },
+                None => json!([]),
+            },
+            _ => { return None; }
+        };
+
+        Some(result)
+    }
+
+    ///Handle a message from the client, giving the messages to send back:
+    ///the response to a request, and diagnostics for a document that
+    ///changed.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+
+        if let Some(id) = message.get("id") {
+            return vec![match self.request(method, params) {
+                Some(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                None => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": -32601, "message": format!("Unknown method: {}", method)},
+                }),
+            }];
+        }
+
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(n) => String::from(n),
+            None => {
+                if method == "exit" {
+                    self.exited = true;
+                }
+                return Vec::new();
+            },
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(uri.clone(), String::from(text));
+            },
+            "textDocument/didChange" => {
+                //Changes are sent whole, so only the last one matters.
+                let text = params["contentChanges"].as_array()
+                    .and_then(|n| n.last())
+                    .and_then(|n| n["text"].as_str());
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), String::from(text));
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+            },
+            _ => { return Vec::new(); }
+        }
+
+        vec![self.publish(&uri)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use lsp::Server;
+
+    const SOURCE: &str = "variable total\n: square ( n -- n*n ) dup * ;\n3 square total !\n";
+
+    fn request(server: &mut Server, method: &str, line: u32, character: u32) -> Value {
+        let reply = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": {"uri": "file:///a.gg"},
+                "position": {"line": line, "character": character},
+            },
+        }));
+        reply[0]["result"].clone()
+    }
+
+    #[test]
+    fn session() {
+        let mut server = Server::new();
+        let reply = server.handle(&json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {}}));
+        assert_eq!(reply[0]["result"]["capabilities"]["hoverProvider"], json!(true));
+
+        let reply = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": "file:///a.gg", "text": SOURCE}},
+        }));
+        assert_eq!(reply[0]["params"]["diagnostics"], json!([]));
+
+        let found = request(&mut server, "textDocument/definition", 2, 4);
+        assert_eq!(found["range"], json!({"start": {"line": 1, "character": 2}, "end": {"line": 1, "character": 8}}));
+        let found = request(&mut server, "textDocument/definition", 2, 14);
+        assert_eq!(found["range"]["start"], json!({"line": 0, "character": 9}));
+
+        let text = request(&mut server, "textDocument/hover", 2, 4);
+        let text = text["contents"]["value"].as_str().unwrap();
+        assert!(text.contains(": square ( n -- n*n )"));
+        assert!(text.contains("( 1 -- 1 )"));
+        let text = request(&mut server, "textDocument/hover", 1, 22);
+        assert!(text["contents"]["value"].as_str().unwrap().starts_with("```forth\ndup ( 1 -- 2 )"));
+
+        let reply = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {"uri": "file:///a.gg"},
+                "contentChanges": [{"text": "3 sqare\n"}],
+            },
+        }));
+        let diagnostic = &reply[0]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["message"], json!("Unknown word: sqare"));
+        assert_eq!(diagnostic["range"]["start"], json!({"line": 0, "character": 2}));
+
+        let items = request(&mut server, "textDocument/completion", 0, 3);
+        let labels: Vec<&str> = items.as_array().unwrap().iter().map(|n| n["label"].as_str().unwrap()).collect();
+        assert!(labels.contains(&"swap"));
+        assert!(labels.contains(&"string@"));
+        assert!(!labels.contains(&"dup"));
+
+        assert_eq!(server.exited(), None);
+        server.handle(&json!({"jsonrpc": "2.0", "id": 2, "method": "shutdown"}));
+        server.handle(&json!({"jsonrpc": "2.0", "method": "exit"}));
+        assert_eq!(server.exited(), Some(true));
+    }
+}
@@ -0,0 +1,134 @@
+//!Floating-point maths as `Stack` methods, and an extender that maps them
+//!to opcodes.
+//!
+//!Ints are accepted wherever a float is and converted first, except by
+//!`abs`, `floor` and `round`, which leave an int an int.
+
+use storage::Storage;
+use {AtomExtender, Data, Error, Pair, Stack, TypeTag};
+
+pub const SQRT: u8 = 128;
+pub const SIN: u8 = 129;
+pub const COS: u8 = 130;
+pub const POW: u8 = 131;
+pub const LN: u8 = 132;
+pub const ABS: u8 = 133;
+pub const FLOOR: u8 = 134;
+pub const ROUND: u8 = 135;
+
+impl<S: Storage> Stack<S> {
+    ///Replace TOS with a function of it, as a float.
+    fn float_op(&mut self, op: fn(f64) -> f64) -> Result<(),Error> {
+        let value = match self.pop()? {
+            Data::Int(n) => n as f64,
+            Data::Float(n) => n,
+            other => { return Err(Error::TypeMismatch { expected: TypeTag::Number, found: other.type_tag() }); }
+        };
+
+        self.try_push(Data::Float(op(value)))?;
+
+        Ok(())
+    }
+
+    ///Replace a float TOS with a function of it. An int is left alone.
+    fn float_only_op(&mut self, op: fn(f64) -> f64) -> Result<(),Error> {
+        self.unary_op(Ok, op)
+    }
+
+    ///Square root of TOS.
+    pub fn sqrt(&mut self) -> Result<(),Error> {self.float_op(f64::sqrt)}
+
+    ///Sine of TOS, in radians.
+    pub fn sin(&mut self) -> Result<(),Error> {self.float_op(f64::sin)}
+
+    ///Cosine of TOS, in radians.
+    pub fn cos(&mut self) -> Result<(),Error> {self.float_op(f64::cos)}
+
+    ///Natural logarithm of TOS.
+    pub fn ln(&mut self) -> Result<(),Error> {self.float_op(f64::ln)}
+
+    ///Raise NOS to the power of TOS. The result is always a float.
+    pub fn pow(&mut self) -> Result<(),Error> {
+        match self.pop_two()? {
+            Pair::Int(x, y) => self.try_push(Data::Float((y as f64).powf(x as f64)))?,
+            Pair::Float(x, y) => self.try_push(Data::Float(y.powf(x)))?,
+            Pair::Str(_, _) => { return Err(Error::TypeMismatch { expected: TypeTag::Number, found: TypeTag::Str }); }
+        }
+
+        Ok(())
+    }
+
+    ///Absolute value of TOS. Wraps for the most negative int.
+    pub fn abs(&mut self) -> Result<(),Error> {
+        self.unary_op(|n| Ok(n.wrapping_abs()), f64::abs)
+    }
+
+    ///Round TOS down.
+    pub fn floor(&mut self) -> Result<(),Error> {self.float_only_op(f64::floor)}
+
+    ///Round TOS to the nearest whole number, halfway cases away from zero.
+    pub fn round(&mut self) -> Result<(),Error> {self.float_only_op(f64::round)}
+}
+
+///Runs the maths words for the opcodes above. Anything else is an
+///`InvalidInstruction`.
+pub struct MathExtender {}
+
+impl<S: Storage, M: Storage> AtomExtender<S, M> for MathExtender {
+    fn atom(&mut self, opcode: u8, stack: &mut Stack<S>) -> Result<(),Error> {
+        match opcode {
+            SQRT => stack.sqrt(),
+            SIN => stack.sin(),
+            COS => stack.cos(),
+            POW => stack.pow(),
+            LN => stack.ln(),
+            ABS => stack.abs(),
+            FLOOR => stack.floor(),
+            ROUND => stack.round(),
+            _ => Err(Error::InvalidInstruction { opcode }),
+        }
+    }
+
+    ///Only the correctly rounded operations. The transcendentals come
+    ///from the platform's maths library and may differ in the last bit.
+    fn is_deterministic(&self, opcode: u8) -> bool {
+        matches!(opcode, SQRT | ABS | FLOOR | ROUND)
+    }
+
+    fn opcodes(&self) -> &[u8] {
+        &[SQRT, SIN, COS, POW, LN, ABS, FLOOR, ROUND]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mathext::{MathExtender, POW, SQRT};
+    use {run, Data, Error, Stack, TypeTag};
+
+    #[test]
+    fn maths() {
+        let mut s = Stack::new();
+
+        s.push(Data::Int(-7));
+        s.abs().unwrap();
+        s.floor().unwrap();
+        assert_eq!(s.pop().unwrap(), Data::Int(7));
+
+        s.push(Data::Float(-2.5));
+        s.round().unwrap();
+        assert_eq!(s.pop().unwrap(), Data::Float(-3.0));
+
+        s.push(Data::Int(0));
+        s.cos().unwrap();
+        s.ln().unwrap();
+        assert_eq!(s.pop().unwrap(), Data::Float(0.0));
+
+        s.push(Data::Str("x".into()));
+        assert!(matches!(s.sin(), Err(Error::TypeMismatch { expected: TypeTag::Number, found: TypeTag::Str })));
+
+        let code = [b'#', b'2', b'\'', b'#', b'1', b'0', b'\'', POW, SQRT];
+        let mut stack = Stack::new();
+        run(&code, &mut stack, 0, &mut MathExtender {}, &mut Vec::new()).unwrap();
+        assert_eq!(stack.pop().unwrap(), Data::Float(32.0));
+    }
+}
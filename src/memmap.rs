@@ -0,0 +1,100 @@
+//!Ranges of memory backed by the host, for scripts that drive devices.
+//!
+//!A region binds a range of addresses to a `MemoryHandler`. The `R` and
+//!`W` opcodes look the address up before anything else, so a mapped
+//!address never reaches the machine's own memory and the memory policy
+//!isn't applied to it. Addresses outside every region behave as usual.
+//!Extenders reaching memory through a `Context` see only the machine's
+//!own memory.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use Data;
+
+///Answers reads and writes of the addresses in a region. Each is given
+///the address as the code used it, not an offset into the region.
+pub trait MemoryHandler: Send {
+    fn read(&mut self, addr: i64) -> Data;
+
+    fn write(&mut self, addr: i64, value: Data);
+}
+
+struct Region {
+    range: Range<i64>,
+    handler: Box<dyn MemoryHandler>,
+}
+
+///The regions of memory the host has mapped.
+#[derive(Default)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    ///Create a map with nothing mapped.
+    pub fn new() -> MemoryMap {
+        MemoryMap::default()
+    }
+
+    ///Send reads and writes of a range of addresses to a handler. Where
+    ///regions overlap the one mapped last wins.
+    pub fn map<H: MemoryHandler + 'static>(&mut self, range: Range<i64>, handler: H) {
+        self.regions.push(Region { range, handler: Box::new(handler) });
+    }
+
+    ///Remove every region starting at an address. Returns whether there
+    ///was one.
+    pub fn unmap(&mut self, start: i64) -> bool {
+        let len = self.regions.len();
+        self.regions.retain(|n| n.range.start != start);
+        self.regions.len() < len
+    }
+
+    ///Check whether nothing is mapped.
+    #[inline]
+    pub fn is_empty(&self) -> bool {self.regions.is_empty()}
+
+    ///Find the handler for an address, if it's mapped.
+    pub fn handler(&mut self, addr: i64) -> Option<&mut (dyn MemoryHandler + 'static)> {
+        self.regions.iter_mut().rev().find(|n| n.range.contains(&addr)).map(|n| &mut *n.handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memmap::{MemoryHandler, MemoryMap};
+    use Data;
+
+    struct Register(i64);
+
+    impl MemoryHandler for Register {
+        fn read(&mut self, _: i64) -> Data {Data::Int(self.0)}
+
+        fn write(&mut self, _: i64, value: Data) {
+            if let Data::Int(n) = value {
+                self.0 = n;
+            }
+        }
+    }
+
+    #[test]
+    fn regions() {
+        let mut map = MemoryMap::new();
+        assert!(map.is_empty());
+        map.map(100..110, Register(1));
+        map.map(105..106, Register(2));
+
+        assert!(map.handler(99).is_none());
+        assert!(map.handler(110).is_none());
+        assert_eq!(map.handler(100).unwrap().read(100), Data::Int(1));
+        assert_eq!(map.handler(105).unwrap().read(105), Data::Int(2));
+        map.handler(109).unwrap().write(109, Data::Int(7));
+        assert_eq!(map.handler(100).unwrap().read(100), Data::Int(7));
+
+        assert!(map.unmap(105));
+        assert!(!map.unmap(105));
+        assert_eq!(map.handler(105).unwrap().read(105), Data::Int(7));
+    }
+}
@@ -0,0 +1,467 @@
+//!Modules: bytecode bundled with the names of the words it defines and
+//!any other metadata a host needs to run it.
+//!
+//!A module file starts with the magic bytes `GGMD` and a little-endian
+//!`u16` format version, followed by a `u16` count of sections. Each
+//!section is a one-byte name length, the name, a `u32` payload length and
+//!the payload. The sections understood here are:
+//!
+//!* `code`: the bytecode.
+//!* `data`: initial memory cells, as a `u32` count of encoded values.
+//!* `words`: the dictionary, as a `u32` count of entries, each a `u16`
+//!  name length, the name and a `u64` address into the code.
+//!* `consts`: the constant pool the `\` opcode reads from, encoded like
+//!  `data`. Only written if the pool isn't empty.
+//!* `globals`: the names given to memory cells and values by `variable`
+//!  and `constant`, as a `u32` count of entries, each a `u16` name
+//!  length, the name, then 0 and a `u64` address or 1 and an encoded
+//!  value. Only written if there are any.
+//!* `debug`: an optional source map; see `debuginfo`.
+//!
+//!Other sections are kept as raw bytes so tools can round-trip them.
+//!Anything without the magic bytes is treated as bare bytecode.
+
+use alloc::collections::btree_map;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::str;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use load_module;
+use debuginfo::DebugInfo;
+use Data;
+use Error;
+
+///The bytes every module file starts with.
+pub const MAGIC: &[u8; 4] = b"GGMD";
+
+///The format version written by `serialize`, and the only one `parse`
+///accepts.
+pub const VERSION: u16 = 1;
+
+///Maps word names to their entry points in a module's code.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct Dictionary {
+    words: BTreeMap<String, usize>,
+}
+
+impl Dictionary {
+    ///Create an empty dictionary.
+    pub fn new() -> Dictionary {
+        Dictionary {
+            words: BTreeMap::new()
+        }
+    }
+
+    ///Define a word, replacing any earlier definition with the same name.
+    pub fn insert(&mut self, name: &str, address: usize) {
+        self.words.insert(String::from(name), address);
+    }
+
+    ///Look up the entry point of a word.
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.words.get(name).cloned()
+    }
+
+    ///Look up the word with an entry point, if there is one.
+    pub fn name_at(&self, address: usize) -> Option<&str> {
+        self.words.iter().find(|&(_, &n)| n == address).map(|(name, _)| name.as_str())
+    }
+
+    ///Get the number of words defined.
+    pub fn len(&self) -> usize {self.words.len()}
+
+    ///Check whether no words are defined.
+    pub fn is_empty(&self) -> bool {self.words.is_empty()}
+
+    ///Iterate over `(name, address)` pairs in name order.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { inner: self.words.iter() }
+    }
+}
+
+///Iterator over the words in a `Dictionary`.
+pub struct Iter<'a> {
+    inner: btree_map::Iter<'a, String, usize>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, usize);
+
+    fn next(&mut self) -> Option<(&'a str, usize)> {
+        self.inner.next().map(|(name, &address)| (name.as_str(), address))
+    }
+}
+
+///What a global name stands for.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Global {
+    ///A memory cell, by address.
+    Variable(usize),
+    ///A value fixed when the code was compiled.
+    Constant(Data),
+}
+
+///Bytecode together with its dictionary, initial memory and any other
+///sections.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Module {
+    pub dictionary: Dictionary,
+    pub code: Vec<u8>,
+    pub data: Vec<Data>,
+    ///Values too long or too precise to build from digits in the code.
+    pub constants: Vec<Data>,
+    ///Named variables and constants.
+    pub globals: BTreeMap<String, Global>,
+    ///Where the code came from in the source, if the compiler kept it.
+    ///Set it to `None` to ship without.
+    pub debug_info: Option<DebugInfo>,
+    ///Sections this version doesn't interpret, by name.
+    pub sections: BTreeMap<String, Vec<u8>>,
+}
+
+///Reads the fixed-width fields of a module, failing on truncation.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, pos: 0 }
+    }
+
+    ///Check whether everything has been read.
+    pub fn is_empty(&self) -> bool {self.pos >= self.bytes.len()}
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() - self.pos < len {
+            return Err(Error::InvalidModule);
+        }
+
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    ///Read an unsigned LEB128 number written by `write_varint`.
+    pub fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Error::InvalidModule)
+    }
+
+    pub fn str(&mut self, len: usize) -> Result<&'a str, Error> {
+        match str::from_utf8(self.bytes(len)?) {
+            Ok(n) => Ok(n),
+            Err(_) => Err(Error::InvalidModule),
+        }
+    }
+
+    ///Read a value written by `write_data`.
+    pub fn data(&mut self) -> Result<Data, Error> {
+        match self.u8()? {
+            0 => Ok(Data::Int(self.u64()? as i64)),
+            1 => Ok(Data::Float(f64::from_bits(self.u64()?))),
+            2 => {
+                let len = self.u32()? as usize;
+                Ok(Data::Str(Arc::from(self.str(len)?)))
+            },
+            3 => Ok(Data::Array(self.u64()? as usize)),
+            4 => Ok(Data::Map(self.u64()? as usize)),
+            _ => Err(Error::InvalidModule),
+        }
+    }
+}
+
+///Append a number in unsigned LEB128, seven bits to a byte, low first.
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+///Append a value as a type tag followed by its payload.
+pub(crate) fn write_data(out: &mut Vec<u8>, value: &Data) {
+    match *value {
+        Data::Int(n) => {
+            out.push(0);
+            out.extend_from_slice(&(n as u64).to_le_bytes());
+        },
+        Data::Float(n) => {
+            out.push(1);
+            out.extend_from_slice(&n.to_bits().to_le_bytes());
+        },
+        Data::Str(ref n) => {
+            out.push(2);
+            out.extend_from_slice(&(n.len() as u32).to_le_bytes());
+            out.extend_from_slice(n.as_bytes());
+        },
+        Data::Array(n) => {
+            out.push(3);
+            out.extend_from_slice(&(n as u64).to_le_bytes());
+        },
+        Data::Map(n) => {
+            out.push(4);
+            out.extend_from_slice(&(n as u64).to_le_bytes());
+        },
+    }
+}
+
+fn write_values(values: &[Data]) -> Vec<u8> {
+    let mut out = (values.len() as u32).to_le_bytes().to_vec();
+    for value in values {
+        write_data(&mut out, value);
+    }
+
+    out
+}
+
+fn read_values(payload: &[u8]) -> Result<Vec<Data>, Error> {
+    let mut section = Reader::new(payload);
+    let mut values = Vec::new();
+    for _ in 0..section.u32()? {
+        values.push(section.data()?);
+    }
+
+    Ok(values)
+}
+
+fn write_section(out: &mut Vec<u8>, name: &str, payload: &[u8]) {
+    out.push(name.len() as u8);
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+impl Module {
+    ///Create a module holding only code.
+    pub fn from_code(code: Vec<u8>) -> Module {
+        Module {
+            code,
+            ..Module::default()
+        }
+    }
+
+    ///Read a module file from disk.
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Module, Error> {
+        Module::parse(&load_module(path)?)
+    }
+
+    ///Parse a module. Bytes without the magic header are taken as bare
+    ///code; a header with a version other than `VERSION` is rejected with
+    ///`UnsupportedVersion`.
+    pub fn parse(bytes: &[u8]) -> Result<Module, Error> {
+        if !bytes.starts_with(MAGIC) {
+            return Ok(Module::from_code(bytes.to_vec()));
+        }
+
+        let mut reader = Reader::new(&bytes[MAGIC.len()..]);
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut module = Module::default();
+
+        for _ in 0..reader.u16()? {
+            let len = reader.u8()? as usize;
+            let name = reader.str(len)?;
+            let len = reader.u32()? as usize;
+            let payload = reader.bytes(len)?;
+
+            match name {
+                "code" => { module.code = payload.to_vec(); },
+                "data" => { module.data = read_values(payload)?; },
+                "consts" => { module.constants = read_values(payload)?; },
+                "globals" => {
+                    let mut section = Reader::new(payload);
+                    for _ in 0..section.u32()? {
+                        let len = section.u16()? as usize;
+                        let name = String::from(section.str(len)?);
+                        let global = match section.u8()? {
+                            0 => Global::Variable(section.u64()? as usize),
+                            1 => Global::Constant(section.data()?),
+                            _ => { return Err(Error::InvalidModule); }
+                        };
+                        module.globals.insert(name, global);
+                    }
+                },
+                "words" => {
+                    let mut section = Reader::new(payload);
+                    for _ in 0..section.u32()? {
+                        let len = section.u16()? as usize;
+                        let name = section.str(len)?;
+                        let address = section.u64()? as usize;
+                        module.dictionary.insert(name, address);
+                    }
+                },
+                "debug" => { module.debug_info = Some(DebugInfo::parse(payload)?); },
+                _ => { module.sections.insert(String::from(name), payload.to_vec()); },
+            }
+        }
+
+        if !reader.is_empty() {
+            return Err(Error::InvalidModule);
+        }
+
+        Ok(module)
+    }
+
+    ///Write the module out in the current format version.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        let optional = !self.constants.is_empty() as u16 + !self.globals.is_empty() as u16 + self.debug_info.is_some() as u16;
+        let sections = 3 + optional + self.sections.len() as u16;
+        out.extend_from_slice(&sections.to_le_bytes());
+
+        write_section(&mut out, "code", &self.code);
+
+        write_section(&mut out, "data", &write_values(&self.data));
+        if !self.constants.is_empty() {
+            write_section(&mut out, "consts", &write_values(&self.constants));
+        }
+
+        let mut entries: Vec<(&str, usize)> = self.dictionary.iter().collect();
+        entries.sort_by_key(|&(name, address)| (address, name));
+
+        let mut words = (entries.len() as u32).to_le_bytes().to_vec();
+        for (name, address) in entries {
+            words.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            words.extend_from_slice(name.as_bytes());
+            words.extend_from_slice(&(address as u64).to_le_bytes());
+        }
+        write_section(&mut out, "words", &words);
+
+        if !self.globals.is_empty() {
+            let mut globals = (self.globals.len() as u32).to_le_bytes().to_vec();
+            for (name, global) in &self.globals {
+                globals.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                globals.extend_from_slice(name.as_bytes());
+                match *global {
+                    Global::Variable(address) => {
+                        globals.push(0);
+                        globals.extend_from_slice(&(address as u64).to_le_bytes());
+                    },
+                    Global::Constant(ref value) => {
+                        globals.push(1);
+                        write_data(&mut globals, value);
+                    },
+                }
+            }
+            write_section(&mut out, "globals", &globals);
+        }
+
+        if let Some(ref debug_info) = self.debug_info {
+            write_section(&mut out, "debug", &debug_info.serialize());
+        }
+
+        for (name, payload) in &self.sections {
+            write_section(&mut out, name, payload);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use debuginfo::DebugInfo;
+    use module::{Dictionary, Global, Module, MAGIC};
+    use {Data, Error, NullExtender, Vm};
+
+    #[test]
+    fn round_trip() {
+        let mut dictionary = Dictionary::new();
+        dictionary.insert("square", 1);
+        dictionary.insert("cube", 4);
+
+        let mut module = Module::from_code(b";d*;".to_vec());
+        module.dictionary = dictionary;
+        module.data = vec![Data::Int(-3), Data::Float(0.1), Data::Str(Arc::from("hi"))];
+        module.sections.insert(String::from("notes"), b"anything".to_vec());
+
+        let bytes = module.serialize();
+        assert_eq!(Module::parse(&bytes).unwrap(), module);
+
+        module.constants = vec![Data::Float(0.1), Data::Int(i64::MIN)];
+        module.globals.insert(String::from("count"), Global::Variable(0));
+        module.globals.insert(String::from("rate"), Global::Constant(Data::Float(1.25)));
+        let mut debug_info = DebugInfo::new();
+        debug_info.insert(1, "shapes.gg", 1, 10, Some("square"));
+        module.debug_info = Some(debug_info);
+
+        let bytes = module.serialize();
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(Module::parse(&bytes).unwrap(), module);
+
+        assert!(matches!(Module::parse(&bytes[..bytes.len() - 1]), Err(Error::InvalidModule)));
+
+        let mut future = bytes.clone();
+        future[4] = 9;
+        assert!(matches!(Module::parse(&future), Err(Error::UnsupportedVersion(9))));
+
+        assert_eq!(Module::parse(b"#1'").unwrap().code, b"#1'".to_vec());
+    }
+
+    #[test]
+    fn symbolic_calls() {
+        let mut module = Module::from_code(b"#3'`square`;d*;".to_vec());
+        module.dictionary.insert("square", 12);
+        module.data = vec![Data::Int(7)];
+
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(9))));
+        assert!(matches!(vm.memory[0], Data::Int(7)));
+
+        let module = Module::from_code(b"#3'`cube`;".to_vec());
+        assert!(Vm::from_module(module, Vec::new()).is_err());
+    }
+}
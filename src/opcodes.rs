@@ -0,0 +1,438 @@
+//!The built-in instruction set as a single table.
+//!
+//!Each `Opcode` gives a byte's mnemonic, stack effect, description and
+//!handler. The disassembler, the validator and the interpreter all read
+//!it, and `generate_reference` turns it into an instruction reference, so
+//!adding an opcode here is what documents it.
+//!
+//!Bytes that aren't taken by the first page select from an extended page
+//!after a `!`, so `!R` is a single instruction two bytes long. `EXTENDED`
+//!lists them by their second byte.
+//!
+//!Opcodes that only work on the data stack are handled by a `StackOp`,
+//!which the VM applies straight from the table. The rest need more of the
+//!machine, such as memory, the return stack or the PC, and have arms of
+//!their own in the VM.
+
+use alloc::string::{String, ToString};
+use core::fmt::Write;
+
+use storage::Storage;
+use validate::StackEffect;
+use {ArithmeticPolicy, CoercionPolicy, Data, Error, Stack};
+
+///An operation on the data stack alone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Sar,
+    Rotl,
+    Rotr,
+    Popcount,
+    Lt,
+    Eq,
+    Gt,
+    StrCompare,
+    Concat,
+    StrLen,
+    Dup,
+    Drop,
+    Swap,
+    Over,
+    Tuck,
+    Nip,
+    Rot,
+    RRot,
+    TwoDup,
+    TwoSwap,
+    Pick,
+    Roll,
+}
+
+impl StackOp {
+    ///Apply the operation, with integer overflow and mixed ints and
+    ///floats handled by some policies.
+    #[inline(always)]
+    pub fn apply<S: Storage>(self, stack: &mut Stack<S>, arithmetic: ArithmeticPolicy, coercion: CoercionPolicy) -> Result<(),Error> {
+        if coercion == CoercionPolicy::Promote && self.is_numeric() {
+            stack.promote();
+        }
+
+        match self {
+            StackOp::Add => stack.add_with(arithmetic),
+            StackOp::Sub => stack.sub_with(arithmetic),
+            StackOp::Mul => stack.mul_with(arithmetic),
+            StackOp::Div => stack.div_with(arithmetic),
+            StackOp::Mod => stack.modulus(),
+            StackOp::And => stack.and(),
+            StackOp::Or => stack.or(),
+            StackOp::Xor => stack.xor(),
+            StackOp::Not => stack.not(),
+            StackOp::Shl => stack.shl(),
+            StackOp::Shr => stack.shr(),
+            StackOp::Sar => stack.sar(),
+            StackOp::Rotl => stack.rotl(),
+            StackOp::Rotr => stack.rotr(),
+            StackOp::Popcount => stack.popcount(),
+            StackOp::Lt => stack.lt(),
+            StackOp::Eq => stack.eq(),
+            StackOp::Gt => stack.gt(),
+            StackOp::StrCompare => stack.str_compare(),
+            StackOp::Concat => stack.concat(),
+            StackOp::StrLen => stack.str_len(),
+            StackOp::Dup => stack.dup(),
+            StackOp::Drop => stack.pop().map(|_: Data| ()),
+            StackOp::Swap => stack.swap(),
+            StackOp::Over => stack.over(),
+            StackOp::Tuck => stack.tuck(),
+            StackOp::Nip => stack.nip(),
+            StackOp::Rot => stack.rot(),
+            StackOp::RRot => stack.rrot(),
+            StackOp::TwoDup => stack.two_dup(),
+            StackOp::TwoSwap => stack.two_swap(),
+            StackOp::Pick => stack.pick_n(),
+            StackOp::Roll => stack.roll_n(),
+        }
+    }
+
+    ///Check whether the operation takes two numbers of either type,
+    ///as arithmetic and comparisons do.
+    pub fn is_numeric(self) -> bool {
+        matches!(self, StackOp::Add | StackOp::Sub | StackOp::Mul | StackOp::Div | StackOp::Mod | StackOp::Lt | StackOp::Eq | StackOp::Gt)
+    }
+}
+
+///What runs an opcode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Handler {
+    ///The VM applies the operation to its data stack.
+    Stack(StackOp),
+    ///The VM has an arm for it, since it needs more than the data stack.
+    Machine,
+}
+
+///A built-in opcode.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Opcode {
+    pub byte: u8,
+    pub mnemonic: &'static str,
+    ///`None` if it depends on the values on the stack, or on the code
+    ///around it.
+    pub stack_effect: Option<StackEffect>,
+    pub description: &'static str,
+    pub handler: Handler,
+}
+
+const fn op(byte: u8, mnemonic: &'static str, stack_effect: Option<(usize, usize)>, description: &'static str, handler: Handler) -> Opcode {
+    let stack_effect = match stack_effect {
+        Some((inputs, outputs)) => Some(StackEffect { inputs, outputs }),
+        None => None,
+    };
+
+    Opcode { byte, mnemonic, stack_effect, description, handler }
+}
+
+///Every built-in opcode, in byte order. Spaces, line feeds and carriage
+///returns are ignored and aren't listed.
+pub const OPCODES: &[Opcode] = {
+    use self::Handler::{Machine, Stack as S};
+    use self::StackOp::*;
+
+    &[
+        op(b'!', "escape", None, "Run the instruction on the extended page named by the next byte.", Machine),
+        op(b'"', "pushf", None, "Push the literal being built as a float, divided by its divider.", Machine),
+        op(b'#', "clear", None, "Start building a literal at zero.", Machine),
+        op(b'$', "negate", None, "Negate the literal being built.", Machine),
+        op(b'%', "mod", Some((2, 1)), "Replace NOS and TOS with the remainder of NOS divided by TOS.", S(Mod)),
+        op(b'&', "and", Some((2, 1)), "Bitwise and.", S(And)),
+        op(b'\'', "pushi", None, "Push the literal being built as an int.", Machine),
+        op(b'(', "tor", Some((1, 0)), "Move TOS, which must be an int, to the return stack.", Machine),
+        op(b')', "fromr", Some((0, 1)), "Move the top of the return stack to the data stack.", Machine),
+        op(b'*', "mul", Some((2, 1)), "Multiply.", S(Mul)),
+        op(b'+', "add", Some((2, 1)), "Add.", S(Add)),
+        op(b',', "printn", Some((1, 0)), "Print a number and a space.", Machine),
+        op(b'-', "sub", Some((2, 1)), "Subtract TOS from NOS.", S(Sub)),
+        op(b'.', "scale", None, "Divide the literal being built by another thousand.", Machine),
+        op(b'/', "div", Some((2, 1)), "Divide NOS by TOS.", S(Div)),
+        op(b'0', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'1', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'2', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'3', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'4', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'5', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'6', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'7', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'8', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b'9', "digit", None, "Append a digit to the literal being built.", Machine),
+        op(b':', "do", Some((2, 0)), "Start a counted loop, moving the limit in NOS and the first index in TOS to the return stack.", Machine),
+        op(b';', "ret", None, "Return from a call, or halt if there is nothing to return to.", Machine),
+        op(b'<', "lt", Some((2, 1)), "Push whether NOS is less than TOS.", S(Lt)),
+        op(b'=', "eq", Some((2, 1)), "Push whether NOS equals TOS.", S(Eq)),
+        op(b'>', "gt", Some((2, 1)), "Push whether NOS is greater than TOS.", S(Gt)),
+        op(b'?', "compare", Some((2, 1)), "Compare two strings.", S(StrCompare)),
+        op(b'@', "rfetch", Some((0, 1)), "Copy the top of the return stack to the data stack.", Machine),
+        op(b'A', "allot", Some((1, 0)), "Extend memory by TOS cells.", Machine),
+        op(b'B', "rjump", None, "Jump by the offset in TOS, from the next instruction.", Machine),
+        op(b'C', "rcall", None, "Call by the offset in TOS, from the next instruction.", Machine),
+        op(b'D', "2dup", Some((2, 4)), "Duplicate the top pair.", S(TwoDup)),
+        op(b'E', "mremove", Some((2, 0)), "Erase a map entry.", Machine),
+        op(b'F', "free", Some((1, 0)), "Release TOS cells from the end of memory.", Machine),
+        op(b'G', "mget", None, "Look up a map entry, pushing the value if found and then a flag.", Machine),
+        op(b'H', "here", Some((0, 1)), "Push the memory size, the address of the next cell allotted.", Machine),
+        op(b'I', "readln", Some((0, 2)), "Read a line of input, pushing it and a flag that is false at the end of input.", Machine),
+        op(b'J', "loop", None, "Add 1 to the loop index and jump by the offset in TOS, from the next instruction, unless it reached the limit. Drops the loop state when it ends.", Machine),
+        op(b'K', "key", Some((0, 1)), "Push the next input byte, or -1 at the end of input.", Machine),
+        op(b'L', "rotl", Some((2, 1)), "Rotate NOS left by TOS bits.", S(Rotl)),
+        op(b'M', "mnew", Some((0, 1)), "Push a new, empty map.", Machine),
+        op(b'N', "popcount", Some((1, 1)), "Count the bits set in TOS.", S(Popcount)),
+        op(b'O', "roll", None, "Roll the item TOS deep to the top.", S(Roll)),
+        op(b'P', "pick", None, "Push a copy of the item TOS deep.", S(Pick)),
+        op(b'Q', "rotr", Some((2, 1)), "Rotate NOS right by TOS bits.", S(Rotr)),
+        op(b'R', "read", Some((1, 1)), "Read the memory cell at the address in TOS.", Machine),
+        op(b'S', "2swap", Some((4, 4)), "Swap the top two pairs.", S(TwoSwap)),
+        op(b'T', "type", Some((1, 0)), "Print a string as it is.", Machine),
+        op(b'U', "frandom", Some((0, 1)), "Push a uniform random float in [0, 1).", Machine),
+        op(b'V', "mset", Some((3, 0)), "Set a map entry.", Machine),
+        op(b'W', "write", Some((2, 0)), "Write NOS to the memory cell at the address in TOS.", Machine),
+        op(b'X', "random", Some((2, 1)), "Replace low and high with a random int in [low, high).", Machine),
+        op(b'Y', "rjnz", None, "Jump by the offset in TOS, from the next instruction, if NOS is non-zero.", Machine),
+        op(b'Z', "rjz", None, "Jump by the offset in TOS, from the next instruction, if NOS is zero.", Machine),
+        op(b'[', "string", None, "Push the string up to the closing bracket. A backslash escapes the byte after it.", Machine),
+        op(b'\\', "const", Some((1, 1)), "Replace the index in TOS with that entry of the constant pool.", Machine),
+        op(b']', "float", Some((0, 1)), "Push the float in the next eight bytes of code, an IEEE 754 double, least significant byte first.", Machine),
+        op(b'^', "xor", Some((2, 1)), "Bitwise exclusive or.", S(Xor)),
+        op(b'_', "sar", Some((2, 1)), "Arithmetic shift right.", S(Sar)),
+        op(b'`', "word", None, "Call the word named up to the next backtick, resolved by `Vm::link`.", Machine),
+        op(b'a', "anew", Some((1, 1)), "Replace a length with a new array.", Machine),
+        op(b'b', "jump", None, "Jump to the address in TOS.", Machine),
+        op(b'c', "call", None, "Call the address in TOS.", Machine),
+        op(b'd', "dup", Some((1, 2)), "Duplicate TOS.", S(Dup)),
+        op(b'e', "emit", Some((1, 0)), "Print the character with the code point in TOS.", Machine),
+        op(b'f', "hfree", Some((1, 0)), "Free an array or map.", Machine),
+        op(b'g', "aget", Some((2, 1)), "Get an array cell.", Machine),
+        op(b'h', "host", None, "Call the host function with the index in TOS.", Machine),
+        op(b'i', "yield", Some((0, 0)), "Hand control back to the host until it resumes.", Machine),
+        op(b'j', "+loop", None, "Add NOS to the loop index and jump by the offset in TOS, from the next instruction, unless it crossed the limit. Drops the loop state when it ends.", Machine),
+        op(b'k', "concat", Some((2, 1)), "Concatenate strings.", S(Concat)),
+        op(b'l', "len", Some((1, 1)), "Replace a string with its length.", S(StrLen)),
+        op(b'm', "msize", Some((1, 1)), "Replace a map with its size.", Machine),
+        op(b'n', "nip", Some((2, 1)), "Drop NOS.", S(Nip)),
+        op(b'o', "rot", Some((3, 3)), "Rotate the third item to the top.", S(Rot)),
+        op(b'p', "print", Some((1, 0)), "Print the type and value of TOS, for debugging.", Machine),
+        op(b'q', "alen", Some((1, 1)), "Replace an array with its length.", Machine),
+        op(b'r', "drop", Some((1, 0)), "Drop TOS.", S(Drop)),
+        op(b's', "swap", Some((2, 2)), "Swap the top two items.", S(Swap)),
+        op(b't', "tuck", Some((2, 3)), "Copy TOS below NOS.", S(Tuck)),
+        op(b'u', "-rot", Some((3, 3)), "Rotate the top item down to third.", S(RRot)),
+        op(b'v', "over", Some((2, 3)), "Push a copy of NOS.", S(Over)),
+        op(b'w', "pause", Some((0, 0)), "End the running task's turn; see `Vm::run_round_robin`.", Machine),
+        op(b'x', "aset", Some((3, 0)), "Set an array cell.", Machine),
+        op(b'y', "jnz", None, "Jump to the address in TOS if NOS is non-zero.", Machine),
+        op(b'z', "jz", None, "Jump to the address in TOS if NOS is zero.", Machine),
+        op(b'{', "shl", Some((2, 1)), "Shift NOS left by TOS bits.", S(Shl)),
+        op(b'|', "or", Some((2, 1)), "Bitwise or.", S(Or)),
+        op(b'}', "shr", Some((2, 1)), "Logical shift right.", S(Shr)),
+        op(b'~', "not", Some((1, 1)), "Logical not.", S(Not)),
+    ]
+};
+
+///The byte that starts an instruction on the extended page.
+pub const ESCAPE: u8 = b'!';
+
+///Every opcode on the extended page, in order of the byte after the
+///`!`.
+pub const EXTENDED: &[Opcode] = {
+    use self::Handler::Machine;
+
+    &[
+        op(b'B', "cstore", Some((2, 0)), "Write the low 8 bits of NOS to the byte at the address in TOS.", Machine),
+        op(b'R', "lread", Some((1, 1)), "Replace an index with that local of the current frame.", Machine),
+        op(b'S', "sstore", Some((2, 1)), "Copy the bytes of the string in NOS to the address in TOS, replacing both with how many there were.", Machine),
+        op(b'W', "lwrite", Some((2, 0)), "Write NOS to the local of the current frame with the index in TOS.", Machine),
+        op(b'b', "cfetch", Some((1, 1)), "Replace an address with the byte there.", Machine),
+        op(b'c', "catch", None, "Call by the offset in TOS, from the next instruction. Pushes 0 when the call returns, or, if it fails, puts the stack depths back and pushes the error's code.", Machine),
+        op(b'f', "fill", Some((3, 0)), "Set the bytes from the address in the third item, as many as NOS, to TOS.", Machine),
+        op(b'm', "cmove", Some((3, 0)), "Copy TOS bytes from the address in the third item to the one in NOS. The two may overlap.", Machine),
+        op(b's', "sfetch", Some((2, 1)), "Replace an address and a length with a string of those bytes, with any invalid UTF-8 replaced.", Machine),
+        op(b't', "throw", None, "Fail with the code in TOS, unless it is zero.", Machine),
+        op(b'{', "enter", None, "Start a frame of TOS locals, moving that many items below it into them, the deepest first.", Machine),
+        op(b'}', "leave", Some((0, 0)), "Drop the current frame of locals.", Machine),
+    ]
+};
+
+///Where each byte's entry is in `OPCODES`, plus one, or zero if it has
+///none.
+static INDEX: [u8; 256] = {
+    let mut index = [0; 256];
+    let mut n = 0;
+    while n < OPCODES.len() {
+        index[OPCODES[n].byte as usize] = n as u8 + 1;
+        n += 1;
+    }
+
+    index
+};
+
+///The stack operation each byte is handled by, if any, for the VM's
+///dispatch.
+static STACK_OPS: [Option<StackOp>; 256] = {
+    let mut ops = [None; 256];
+    let mut n = 0;
+    while n < OPCODES.len() {
+        if let Handler::Stack(op) = OPCODES[n].handler {
+            ops[OPCODES[n].byte as usize] = Some(op);
+        }
+        n += 1;
+    }
+
+    ops
+};
+
+///Look up a built-in opcode.
+#[inline]
+pub fn opcode(byte: u8) -> Option<&'static Opcode> {
+    match INDEX[byte as usize] {
+        0 => None,
+        n => Some(&OPCODES[n as usize - 1]),
+    }
+}
+
+///Look up an opcode on the extended page by the byte after the `!`.
+pub fn extended(byte: u8) -> Option<&'static Opcode> {
+    EXTENDED.iter().find(|n| n.byte == byte)
+}
+
+///The stack operation that handles a byte, if it is one of those.
+#[inline(always)]
+pub fn stack_op(byte: u8) -> Option<StackOp> {
+    STACK_OPS[byte as usize]
+}
+
+///How a byte looks in Markdown code.
+fn code_span(byte: u8) -> String {
+    match byte {
+        b'`' => String::from("`` ` ``"),
+        b'|' => String::from("`\\|`"),
+        b => format!("`{}`", b as char),
+    }
+}
+
+fn effect_text(opcode: &Opcode) -> String {
+    match opcode.stack_effect {
+        Some(n) => n.to_string(),
+        None => String::from("varies"),
+    }
+}
+
+///Write an instruction reference in Markdown, one row per opcode. Runs of
+///opcodes that only differ in their byte, like the digits, share a row.
+pub fn generate_reference() -> String {
+    let mut out = String::from("\
+# Instruction reference
+
+Every instruction is a single byte, apart from those on the extended
+page. Spaces, line feeds and carriage returns are ignored; any other byte
+not listed here is passed to the extender. Bytes below 128 are reserved
+for built-ins, so extenders should only use the ones above. NOS is the
+item below TOS, the top of the data stack.
+
+| Byte | Mnemonic | Stack | Description |
+|------|----------|-------|-------------|
+");
+
+    let mut rows = OPCODES.iter().peekable();
+    while let Some(first) = rows.next() {
+        let mut last = first;
+        while let Some(&next) = rows.peek() {
+            if next.mnemonic != first.mnemonic || next.description != first.description {
+                break;
+            }
+            last = next;
+            rows.next();
+        }
+
+        let bytes = if last.byte == first.byte {
+            code_span(first.byte)
+        } else {
+            format!("{}–{}", code_span(first.byte), code_span(last.byte))
+        };
+        let _ = writeln!(out, "| {} | {} | {} | {} |", bytes, first.mnemonic, effect_text(first), first.description);
+    }
+
+    out.push_str("
+## Extended page
+
+Each of these is `!` followed by one more byte.
+
+| Bytes | Mnemonic | Stack | Description |
+|-------|----------|-------|-------------|
+");
+    for opcode in EXTENDED {
+        let _ = writeln!(out, "| `!{}` | {} | {} | {} |", opcode.byte as char, opcode.mnemonic, effect_text(opcode), opcode.description);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use disasm::{decode, Instruction};
+    use ir::{Op, Program};
+    use opcodes::{extended, generate_reference, opcode, stack_op, StackOp, ESCAPE, EXTENDED, OPCODES};
+    use validate::{validate, ValidationError};
+
+    #[test]
+    fn table() {
+        for pair in OPCODES.windows(2) {
+            assert!(pair[0].byte < pair[1].byte);
+        }
+        for pair in EXTENDED.windows(2) {
+            assert!(pair[0].byte < pair[1].byte);
+        }
+        assert_eq!(opcode(b'+').map(|n| n.mnemonic), Some("add"));
+        assert_eq!(opcode(0xf0), None);
+        assert_eq!(extended(b'R').map(|n| n.mnemonic), Some("lread"));
+        assert_eq!(extended(b'+'), None);
+        assert_eq!(opcode(b' '), None);
+        assert_eq!(stack_op(b'u'), Some(StackOp::RRot));
+        assert_eq!(stack_op(b'R'), None);
+    }
+
+    #[test]
+    fn reference() {
+        let reference = generate_reference();
+        assert!(reference.contains("| `0`–`9` | digit | varies | Append a digit to the literal being built. |\n"));
+        assert!(reference.contains("| `+` | add | ( 2 -- 1 ) | Add. |\n"));
+        assert!(reference.contains("| `\\|` | or |"));
+        assert!(reference.contains("| `` ` `` | word |"));
+        assert!(reference.contains("| `!}` | leave | ( 0 -- 0 ) | Drop the current frame of locals. |\n"));
+    }
+
+    #[test]
+    fn escapes() {
+        //The decoder, disassembler and validator all take each opcode on
+        //the extended page as one instruction two bytes long.
+        for opcode in EXTENDED {
+            let code = [ESCAPE, opcode.byte, b' '];
+            let decoded: Vec<_> = decode(&code).collect();
+            assert_eq!(decoded, vec![(0, Instruction::Extended(opcode.byte))]);
+            let program = Program::decode(&code);
+            assert_eq!(program.at(0).map(|n| (&n.op, n.next)), Some((&Op::Byte(ESCAPE), 2)));
+            assert_eq!(validate(&code).map(|_| ()), Ok(()));
+        }
+        assert_eq!(validate(b"!+").map(|_| ()), Err(ValidationError::InvalidOpcode { pc: 1, opcode: b'+' }));
+        assert_eq!(validate(b"#1'!").map(|_| ()), Err(ValidationError::InvalidOpcode { pc: 3, opcode: ESCAPE }));
+    }
+}
@@ -0,0 +1,48 @@
+//!Where the output words write to.
+
+use alloc::fmt;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io;
+
+use Error;
+
+///Takes the bytes a running program prints.
+pub trait OutputSink {
+    ///Write all of some bytes.
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(),Error>;
+
+    ///Write formatted text, so `write!` works on a sink.
+    fn write_fmt(&mut self, args: fmt::Arguments) -> Result<(),Error> {
+        self.write_all(fmt::format(args).as_bytes())
+    }
+}
+
+///Any writer is an output sink.
+#[cfg(feature = "std")]
+impl<W> OutputSink for W where W: io::Write {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(),Error> {
+        io::Write::write_all(self, bytes).map_err(Error::from)
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments) -> Result<(),Error> {
+        io::Write::write_fmt(self, args).map_err(Error::from)
+    }
+}
+
+///Collects output in memory.
+#[cfg(not(feature = "std"))]
+impl OutputSink for Vec<u8> {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(),Error> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+///Discards everything written to it.
+pub struct NullOutput {}
+
+impl OutputSink for NullOutput {
+    fn write_all(&mut self, _: &[u8]) -> Result<(),Error> {Ok(())}
+}
@@ -0,0 +1,100 @@
+//!Running one module against many inputs on rayon's thread pool, behind
+//!the `rayon` feature.
+//!
+//!Each worker thread builds its own machine from the module once and
+//!reuses it, so the code is linked and decoded once per thread rather
+//!than once per input. Machines, stacks and decoded programs are all
+//!`Send`, and modules are `Sync`, so a module can also be shared through
+//!an `Arc` by hosts that manage their own threads.
+
+use alloc::vec::Vec;
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use heap::Heap;
+use module::Module;
+use vm::{RunConfig, Vm};
+use {Data, NullExtender, RuntimeError};
+
+///Run a module from PC 0 once for each input, in parallel. Each run starts
+///with the input on its data stack, bottom first, a copy of `memory` and
+///an empty heap, and gives the stack it leaves or the error it failed
+///with, in the order of the inputs.
+///
+///Fails up front if the module can't be linked.
+pub fn run_parallel<I>(module: &Module, memory: &[Data], config: &RunConfig, inputs: I) -> Result<Vec<Result<Vec<Data>,RuntimeError>>,RuntimeError>
+where I: IntoParallelIterator<Item = Vec<Data>> {
+    //Link once here so the workers can't fail to.
+    Vm::from_module(module.clone(), memory.to_vec())?;
+
+    let setup = || {
+        let mut vm = Vm::from_module(module.clone(), memory.to_vec()).expect("module linked already");
+        vm.config = config.clone();
+        vm
+    };
+
+    Ok(inputs.into_par_iter().map_init(setup, |vm, input| {
+        vm.reset();
+        vm.memory.clone_from_slice(memory);
+        vm.heap = Heap::new();
+        for value in input {
+            vm.stack.try_push(value).map_err(|n| RuntimeError::new(0, n))?;
+        }
+
+        vm.run(&mut NullExtender {})?;
+        Ok(vm.stack.iter().cloned().collect())
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use compiler::compile_module;
+    use ir::Program;
+    use module::Module;
+    use parallel::run_parallel;
+    use vm::{RunConfig, Vm};
+    use {Data, Error, NullExtender, Stack};
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn thread_safety() {
+        assert_send::<Vm>();
+        assert_send::<Stack>();
+        assert_sync::<Module>();
+        assert_sync::<Program>();
+
+        let module = Arc::new(compile_module(": sq dup * ; 7 sq").unwrap());
+        let shared = module.clone();
+        let mut vm = thread::spawn(move || {
+            let mut vm = Vm::from_module((*shared).clone(), Vec::new()).unwrap();
+            vm.run(&mut NullExtender {}).unwrap();
+            vm
+        }).join().unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(49))));
+    }
+
+    #[test]
+    fn parallel() {
+        let module = compile_module("1 @ + dup 1 ! 10 /").unwrap();
+        let inputs: Vec<Vec<Data>> = (0..1000).map(|n| vec![Data::Int(n)]).collect();
+        let config = RunConfig {
+            max_steps: Some(1000),
+            ..RunConfig::default()
+        };
+
+        let results = run_parallel(&module, &[Data::Int(0), Data::Int(5)], &config, inputs).unwrap();
+        assert_eq!(results.len(), 1000);
+        for (n, result) in results.into_iter().enumerate() {
+            //Memory is fresh for every input.
+            assert_eq!(result.unwrap(), vec![Data::Int((n as i64 + 5) / 10)]);
+        }
+
+        let results = run_parallel(&module, &[Data::Int(0), Data::Int(5)], &config, vec![Vec::new()]).unwrap();
+        assert!(matches!(results[0], Err(ref n) if matches!(n.kind, Error::StackUnderflow)));
+    }
+}
@@ -0,0 +1,199 @@
+//!Running a snippet of source in one call, for online playgrounds and
+//!"run this" buttons in documentation.
+//!
+//!`evaluate` compiles the source, validates it, runs it in a sandbox and
+//!collects what it printed, so a host needs nothing else. Every run
+//!starts from a fresh machine; anything a snippet defines is gone once it
+//!returns. The result keeps whatever the run got through before an error,
+//!so a playground can show the output and stack alongside it.
+//!
+//!```
+//!use greengold::playground::{evaluate, EvalOptions};
+//!use greengold::Data;
+//!
+//!let result = evaluate(": square dup * ; 7 square dup .", &EvalOptions::default());
+//!assert_eq!(result.output, "49 ");
+//!assert_eq!(result.stack, vec![Data::Int(49)]);
+//!assert!(result.error.is_none());
+//!```
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use compiler::{CompileError, Compiler};
+use storage::Storage;
+use trace::Tracer;
+use validate::{validate, ValidationError};
+use vm::{MemoryPolicy, RunConfig, SandboxConfig};
+use {Data, NullExtender, RuntimeError, Stack, Vm};
+
+///How to run a snippet.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct EvalOptions {
+    ///The limits the snippet runs under. Only the opcodes this allows may
+    ///be used, but there is no extender behind them, so leave it empty.
+    pub sandbox: SandboxConfig,
+    ///What `key` and `read-line` read from.
+    pub input: String,
+    ///The seed for `random` and `frandom`, or `None` for the default.
+    pub seed: Option<u64>,
+}
+
+///Why a snippet didn't run to the end.
+#[derive(Debug)]
+pub enum EvalError {
+    Compile(CompileError),
+    Invalid(ValidationError),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EvalError::Compile(ref n) => write!(f, "Compile error: {}", n),
+            EvalError::Invalid(ref n) => write!(f, "Invalid code: {}", n),
+            EvalError::Runtime(ref n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl ::std::error::Error for EvalError {}
+
+///Numbers about a run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct EvalStats {
+    ///Bytes of code the snippet compiled to.
+    pub code_len: usize,
+    ///Instructions run, counting the one that failed, if any.
+    pub steps: u64,
+    ///Most items on the data stack after any instruction.
+    pub max_stack_depth: usize,
+}
+
+///What running a snippet did.
+#[derive(Debug)]
+pub struct EvalResult {
+    ///The data stack when the run stopped, from the bottom.
+    pub stack: Vec<Data>,
+    ///Everything the snippet printed, with any invalid UTF-8 replaced.
+    pub output: String,
+    ///What stopped the run early, if anything did.
+    pub error: Option<EvalError>,
+    pub stats: EvalStats,
+}
+
+///Collects what a snippet prints where `evaluate` can still read it.
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {Ok(())}
+}
+
+///Counts instructions, and how deep the stack gets.
+struct Counter<'a>(&'a mut EvalStats);
+
+impl<'a, S: Storage> Tracer<S> for Counter<'a> {
+    fn before_instruction(&mut self, _pc: usize, _opcode: u8, _stack: &Stack<S>) {
+        self.0.steps += 1;
+    }
+
+    fn after_instruction(&mut self, _pc: usize, _opcode: u8, stack: &Stack<S>) {
+        self.0.max_stack_depth = self.0.max_stack_depth.max(stack.len());
+    }
+}
+
+///Compile, validate and run a snippet of source, collecting its output.
+pub fn evaluate(source: &str, options: &EvalOptions) -> EvalResult {
+    let mut result = EvalResult {
+        stack: Vec::new(),
+        output: String::new(),
+        error: None,
+        stats: EvalStats::default(),
+    };
+
+    let mut compiler = Compiler::new();
+    if let Err(n) = compiler.compile(source) {
+        result.error = Some(EvalError::Compile(n));
+        return result;
+    }
+    let module = compiler.into_module();
+    result.stats.code_len = module.code.len();
+    if let Err(n) = validate(&module.code) {
+        result.error = Some(EvalError::Invalid(n));
+        return result;
+    }
+
+    let mut vm = match Vm::from_module(module, Vec::new()) {
+        Ok(n) => n,
+        Err(n) => {
+            result.error = Some(EvalError::Runtime(n));
+            return result;
+        },
+    };
+    //As `Vm::sandboxed` sets a machine up, but with the module loaded.
+    vm.config = RunConfig {
+        max_steps: Some(options.sandbox.max_steps),
+        memory: MemoryPolicy::Grow,
+        sandbox: Some(options.sandbox.clone()),
+        ..RunConfig::default()
+    };
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    vm.output = Box::new(Capture(printed.clone()));
+    vm.input = Box::new(io::Cursor::new(options.input.clone().into_bytes()));
+    if let Some(seed) = options.seed {
+        vm.seed(seed);
+    }
+
+    let run = vm.run_traced(&mut NullExtender {}, &mut Counter(&mut result.stats));
+    result.error = run.err().map(EvalError::Runtime);
+    result.stack = vm.stack.as_slice().to_vec();
+    result.output = String::from_utf8_lossy(&printed.lock().unwrap()).into_owned();
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use playground::{evaluate, EvalError, EvalOptions};
+    use vm::SandboxConfig;
+    use {Data, Error};
+
+    #[test]
+    fn evaluates() {
+        let options = EvalOptions { input: String::from("hi"), ..EvalOptions::default() };
+        let result = evaluate("variable n 3 n ! n @ 2 * . key", &options);
+        assert!(result.error.is_none());
+        assert_eq!(result.output, "6 ");
+        assert_eq!(result.stack, vec![Data::Int(b'h' as i64)]);
+        assert_eq!(result.stats.max_stack_depth, 2);
+        assert!(result.stats.steps > 0 && result.stats.code_len > 0);
+
+        let result = evaluate("1 2 nosuch", &options);
+        assert!(matches!(result.error, Some(EvalError::Compile(_))));
+        assert_eq!(result.stats.steps, 0);
+
+        let result = evaluate("s\" out\" type 1 0 /", &options);
+        assert_eq!(result.output, "out");
+        match result.error {
+            Some(EvalError::Runtime(ref n)) => assert!(matches!(n.kind, Error::DivisionByZero)),
+            ref n => panic!("{:?}", n),
+        }
+
+        let options = EvalOptions {
+            sandbox: SandboxConfig { max_steps: 100, ..SandboxConfig::default() },
+            ..EvalOptions::default()
+        };
+        let result = evaluate("begin again", &options);
+        assert_eq!(result.stats.steps, 100);
+        match result.error {
+            Some(EvalError::Runtime(ref n)) => assert!(matches!(n.kind, Error::FuelExhausted)),
+            ref n => panic!("{:?}", n),
+        }
+    }
+}
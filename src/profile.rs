@@ -0,0 +1,146 @@
+//!Counting where a program spends its time.
+//!
+//!A `Profiler` is a tracer, so profiling is switched on by running with
+//!`Vm::run_traced`. Time is charged to the word whose body an instruction
+//!sits in: the one with the highest entry point at or below its address.
+//!Code before the first word is charged to `<main>`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use module::Dictionary;
+use storage::Storage;
+use trace::Tracer;
+use {json_string, Stack};
+
+///Records how often each instruction runs and how long it takes.
+#[derive(Default)]
+pub struct Profiler {
+    hits: HashMap<usize, (u64, Duration)>,
+    started: Option<Instant>,
+}
+
+impl<S: Storage> Tracer<S> for Profiler {
+    fn before_instruction(&mut self, _pc: usize, _opcode: u8, _stack: &Stack<S>) {
+        self.started = Some(Instant::now());
+    }
+
+    fn after_instruction(&mut self, pc: usize, _opcode: u8, _stack: &Stack<S>) {
+        let elapsed = self.started.take().map(|n| n.elapsed()).unwrap_or_default();
+        let entry = self.hits.entry(pc).or_default();
+        entry.0 += 1;
+        entry.1 += elapsed;
+    }
+}
+
+///Totals for one word.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordProfile {
+    pub name: String,
+    pub hits: u64,
+    pub time: Duration,
+}
+
+///What a `Profiler` saw, summarised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileReport {
+    ///Hit counts per PC, most frequent first.
+    pub instructions: Vec<(usize, u64)>,
+    ///Instructions executed and time taken per word, slowest first.
+    pub words: Vec<WordProfile>,
+}
+
+impl Profiler {
+    ///Start with nothing recorded.
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    ///Forget everything recorded so far.
+    pub fn clear(&mut self) {
+        self.hits.clear();
+    }
+
+    ///Summarise, attributing instructions to the words in a dictionary.
+    pub fn report(&self, dictionary: &Dictionary) -> ProfileReport {
+        let mut instructions: Vec<(usize, u64)> = self.hits.iter().map(|(&pc, &(hits, _))| (pc, hits)).collect();
+        instructions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let mut entries: Vec<(usize, &str)> = dictionary.iter().map(|(name, address)| (address, name)).collect();
+        entries.sort();
+
+        let mut totals: HashMap<&str, (u64, Duration)> = HashMap::new();
+        for (&pc, &(hits, time)) in &self.hits {
+            let name = match entries.partition_point(|&(address, _)| address <= pc) {
+                0 => "<main>",
+                n => entries[n - 1].1,
+            };
+            let total = totals.entry(name).or_default();
+            total.0 += hits;
+            total.1 += time;
+        }
+
+        let mut words: Vec<WordProfile> = totals.into_iter().map(|(name, (hits, time))| WordProfile {
+            name: String::from(name),
+            hits,
+            time,
+        }).collect();
+        words.sort_by(|a, b| b.time.cmp(&a.time).then(b.hits.cmp(&a.hits)).then(a.name.cmp(&b.name)));
+
+        ProfileReport { instructions, words }
+    }
+}
+
+impl ProfileReport {
+    ///Export as a JSON object with `words` and `instructions` arrays. Times
+    ///are in nanoseconds.
+    pub fn to_json(&self) -> String {
+        let words: Vec<String> = self.words.iter().map(|w| {
+            format!("{{\"name\":{},\"hits\":{},\"nanos\":{}}}", json_string(&w.name), w.hits, w.time.as_nanos())
+        }).collect();
+        let instructions: Vec<String> = self.instructions.iter().map(|&(pc, hits)| {
+            format!("{{\"pc\":{},\"hits\":{}}}", pc, hits)
+        }).collect();
+
+        format!("{{\"words\":[{}],\"instructions\":[{}]}}", words.join(","), instructions.join(","))
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    ///A table of words, slowest first.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<16} {:>10} {:>12}", "word", "hits", "time (us)")?;
+        for word in &self.words {
+            writeln!(f, "{:<16} {:>10} {:>12}", word.name, word.hits, word.time.as_micros())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compiler::compile_module;
+    use profile::Profiler;
+    use {NullExtender, Vm};
+
+    #[test]
+    fn counts_words() {
+        let module = compile_module(": square dup * ; 2 square square").unwrap();
+        let dictionary = module.dictionary.clone();
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+
+        let mut profiler = Profiler::new();
+        vm.run_traced(&mut NullExtender {}, &mut profiler).unwrap();
+
+        let report = profiler.report(&dictionary);
+        let square = report.words.iter().find(|w| w.name == "square").unwrap();
+        assert_eq!(square.hits, 6);
+        assert!(report.words.iter().any(|w| w.name == "<main>"));
+        assert_eq!(report.instructions.iter().filter(|&&(_, hits)| hits == 2).count(), 3);
+
+        assert!(report.to_json().contains("{\"name\":\"square\",\"hits\":6,"));
+        assert!(report.to_string().starts_with("word"));
+    }
+}
@@ -0,0 +1,200 @@
+//!Python bindings, behind the `pyo3` feature. Build an extension module
+//!with `maturin build --features pyo3`, or anything else that builds a
+//!pyo3 `cdylib`.
+//!
+//!The module holds one class, `Greengold`, which owns a machine:
+//!
+//!```python
+//!from greengold import Greengold
+//!
+//!vm = Greengold()
+//!vm.register("clamp", lambda x, lo, hi: min(max(x, lo), hi), 3)
+//!code = vm.compile("2 * 0 10 clamp")
+//!vm.run(code, stack=[7])    # [10]
+//!```
+//!
+//!Ints, floats and strings cross over as themselves; Python bools become
+//!ints. Compile errors raise `ValueError`, runtime errors `RuntimeError`,
+//!and an exception raised by a registered function stops the run and is
+//!raised again from `run`.
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use std::io;
+use std::sync::Mutex;
+
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyFloat, PyString, PyTuple};
+
+use compiler::Compiler;
+use {Data, Error, NullExtender, Stack, Vm};
+
+fn to_python(py: Python, value: &Data) -> PyResult<Py<PyAny>> {
+    match *value {
+        Data::Int(n) => Ok(n.into_pyobject(py)?.into_any().unbind()),
+        Data::Float(n) => Ok(PyFloat::new(py, n).into_any().unbind()),
+        Data::Str(ref n) => Ok(PyString::new(py, n).into_any().unbind()),
+        Data::Array(_) | Data::Map(_) => Err(PyTypeError::new_err("arrays and maps can't be passed to Python")),
+    }
+}
+
+fn from_python(value: &Bound<PyAny>) -> PyResult<Data> {
+    if let Ok(n) = value.extract::<i64>() {
+        return Ok(Data::Int(n));
+    }
+    if let Ok(n) = value.extract::<f64>() {
+        return Ok(Data::Float(n));
+    }
+    if let Ok(n) = value.extract::<String>() {
+        return Ok(Data::from(n));
+    }
+
+    Err(PyTypeError::new_err("expected an int, a float or a str"))
+}
+
+///Call a Python function on the top `arity` values and push what it
+///returns: nothing for `None`, each element of a tuple, or one value.
+fn call(py: Python, function: &Py<PyAny>, arity: usize, stack: &mut Stack) -> PyResult<Result<(),Error>> {
+    if let Err(n) = stack.require(arity) {
+        return Ok(Err(n));
+    }
+
+    let base = stack.len() - arity;
+    let args = stack.as_slice()[base..].iter()
+        .map(|n| to_python(py, n))
+        .collect::<PyResult<Vec<_>>>()?;
+    stack.truncate(base);
+
+    let result = function.call1(py, PyTuple::new(py, args)?)?;
+    let result = result.bind(py);
+    let results = if result.is_none() {
+        Vec::new()
+    } else if let Ok(tuple) = result.cast::<PyTuple>() {
+        tuple.iter().map(|n| from_python(&n)).collect::<PyResult<Vec<_>>>()?
+    } else {
+        vec![from_python(result)?]
+    };
+
+    for value in results {
+        if let Err(n) = stack.try_push(value) {
+            return Ok(Err(n));
+        }
+    }
+
+    Ok(Ok(()))
+}
+
+///A machine Python code can compile for and run.
+#[pyclass(unsendable)]
+pub struct Greengold {
+    vm: Vm,
+    ///The exception a registered function raised during the current run.
+    raised: Arc<Mutex<Option<PyErr>>>,
+}
+
+#[pymethods]
+impl Greengold {
+    ///Create a machine with some cells of zeroed memory.
+    #[new]
+    #[pyo3(signature = (memory=1024))]
+    fn new(memory: usize) -> Greengold {
+        Greengold {
+            vm: Vm::new(Vec::new(), vec![Data::Int(0); memory]),
+            raised: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    ///Compile source to bytecode. Registered functions can be called by
+    ///name.
+    fn compile<'py>(&self, py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyBytes>> {
+        let mut compiler = Compiler::new();
+        compiler.use_host(&self.vm.host);
+        compiler.inline_constants(true);
+        compiler.compile(source).map_err(|n| PyValueError::new_err(n.to_string()))?;
+
+        Ok(PyBytes::new(py, &compiler.module().code))
+    }
+
+    ///Run bytecode on a fresh data stack holding `stack`, bottom first,
+    ///and return the stack it leaves. Memory is kept between runs.
+    #[pyo3(signature = (code, stack=None))]
+    fn run(&mut self, py: Python, code: Vec<u8>, stack: Option<Vec<Bound<PyAny>>>) -> PyResult<Vec<Py<PyAny>>> {
+        self.vm.stack.clear();
+        for value in stack.unwrap_or_default() {
+            self.vm.stack.try_push(from_python(&value)?).map_err(|n| PyRuntimeError::new_err(n.to_string()))?;
+        }
+
+        let result = self.vm.load(code).and_then(|_| self.vm.run(&mut NullExtender {}));
+        if let Some(err) = self.raised.lock().unwrap().take() {
+            return Err(err);
+        }
+        result.map_err(|n| PyRuntimeError::new_err(n.to_string()))?;
+
+        self.vm.stack.iter().map(|n| to_python(py, n)).collect()
+    }
+
+    ///Bind a Python function to a word taking `arity` values. It is called
+    ///with them in stack order, TOS last, and may return `None`, a value
+    ///or a tuple of values to push.
+    fn register(&mut self, name: &str, function: Py<PyAny>, arity: usize) {
+        let raised = self.raised.clone();
+        self.vm.bind(name, move |stack| {
+            Python::attach(|py| match call(py, &function, arity, stack) {
+                Ok(result) => result,
+                Err(err) => {
+                    *raised.lock().unwrap() = Some(err);
+                    Err(Error::Io(io::Error::other("Python function raised an exception")))
+                },
+            })
+        });
+    }
+}
+
+#[pymodule]
+fn greengold(module: &Bound<PyModule>) -> PyResult<()> {
+    module.add_class::<Greengold>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use pyo3::prelude::*;
+    use pyo3::types::PyDict;
+
+    use python::Greengold;
+
+    #[test]
+    fn formulas() {
+        Python::initialize();
+        Python::attach(|py| {
+            let class = py.get_type::<Greengold>();
+            let locals = PyDict::new(py);
+            locals.set_item("Greengold", class).unwrap();
+            let script = CString::new("
+vm = Greengold()
+vm.register('clamp', lambda x, lo, hi: min(max(x, lo), hi), 3)
+assert vm.run(vm.compile('2 * 0 10 clamp'), stack=[7]) == [10]
+assert vm.run(vm.compile('2 * 0 10 clamp'), stack=[2]) == [4]
+assert vm.run(vm.compile('+'), stack=[True, 2]) == [3]
+assert vm.run(vm.compile('+'), stack=[0.5, 1.0]) == [1.5]
+
+vm.register('fail', lambda: 1 // 0, 0)
+try:
+    vm.run(vm.compile('fail'))
+    assert False
+except ZeroDivisionError:
+    pass
+
+try:
+    vm.run(vm.compile('drop'))
+    assert False
+except RuntimeError as err:
+    assert str(err) == 'Stack Underflow at 1'
+").unwrap();
+            py.run(&script, None, Some(&locals)).unwrap();
+        });
+    }
+}
@@ -0,0 +1,307 @@
+//!Recording a run so a failure can be reproduced offline.
+//!
+//!A `Recorder` is a tracer. Run with `Vm::run_traced` and it logs each
+//!instruction's address and what it did to the data stack: how many
+//!items it took off and the values it left in their place. The log starts
+//!with a snapshot of the machine, so `Trace::replay` can put another
+//!machine loaded with the same code back where the run began and run it
+//!again.
+//!
+//!Instructions whose results come from outside the machine, which are
+//!the input words `I` and `K`, host calls with `h` and extender opcodes,
+//!aren't run again. Their recorded effect on the stack is applied
+//!instead, so a replay needs no host functions, extender or input and
+//!gives the same results wherever it runs. Every other instruction runs,
+//!and is checked against the log. Memory an extender writes through its
+//!`Context`, and reads of mapped memory, aren't recorded; replay with the
+//!same memory map if the code uses one.
+//!
+//!A recorder can also take checkpoints, snapshots of the machine partway
+//!through. `Recorder::rewind` puts a machine back to any recorded
+//!instruction by restoring the checkpoint before it and replaying from
+//!there, which is how the debugger steps backwards.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use opcodes::opcode;
+use storage::Storage;
+use trace::Tracer;
+use {Data, NullExtender, RuntimeError, Stack, Vm, VmState};
+
+///One instruction in a trace.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub pc: usize,
+    pub opcode: u8,
+    ///How many items the instruction took off the stack, counting any it
+    ///left as they were only if something below them changed.
+    pub popped: usize,
+    ///How many values it pushed in their place.
+    pub pushed: usize,
+}
+
+///The machine as it was before some event.
+#[derive(Debug, Clone, PartialEq)]
+struct Checkpoint {
+    event: usize,
+    ///Where the event's pushed values start.
+    value: usize,
+    state: VmState,
+}
+
+///A recorded run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    ///The machine as it was when recording started.
+    pub start: VmState,
+    events: Vec<Event>,
+    ///The values every event pushed, one after another.
+    values: Vec<Data>,
+    ///Checkpoints after the start, oldest first.
+    checkpoints: Vec<Checkpoint>,
+}
+
+///Why a replay stopped early.
+#[derive(Debug)]
+pub enum ReplayError {
+    ///An instruction failed, though it didn't when recorded.
+    Failed(RuntimeError),
+    ///The machine was at a different address than the trace at some
+    ///event, or the instruction there left the stack differently.
+    Diverged { event: usize, pc: usize },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::Failed(ref n) => write!(f, "Replay failed: {}", n),
+            ReplayError::Diverged { event, pc } => write!(f, "Replay diverged from the trace at event {}, at {}", event, pc),
+        }
+    }
+}
+
+///Check whether an instruction's result comes from outside the machine.
+fn is_external(byte: u8) -> bool {
+    match byte {
+        b'I' | b'K' | b'h' => true,
+        b'\n' | b' ' => false,
+        _ => opcode(byte).is_none(),
+    }
+}
+
+impl Trace {
+    ///Count the instructions recorded.
+    pub fn len(&self) -> usize {self.events.len()}
+
+    ///Check whether nothing was recorded.
+    pub fn is_empty(&self) -> bool {self.events.is_empty()}
+
+    ///The instructions recorded, in order, each with the values it pushed.
+    pub fn events(&self) -> impl Iterator<Item = (Event, &[Data])> + '_ {
+        let mut start = 0;
+        self.events.iter().map(move |&event| {
+            start += event.pushed;
+            (event, &self.values[start - event.pushed..start])
+        })
+    }
+
+    ///Restore the machine to where the trace starts and run through it,
+    ///leaving the machine as the recorded run left it. The machine must
+    ///have the same code. An instruction that failed while recording
+    ///isn't in the trace, so stepping once more reproduces the error.
+    pub fn replay<S: Storage, M: Storage>(&self, vm: &mut Vm<S, M>) -> Result<(),ReplayError> {
+        self.replay_to(vm, self.events.len())
+    }
+
+    ///Put the machine back as it was just before an event ran, from the
+    ///latest checkpoint before it. Past the last event, the machine ends
+    ///up as the recorded run left it.
+    pub fn replay_to<S: Storage, M: Storage>(&self, vm: &mut Vm<S, M>, event: usize) -> Result<(),ReplayError> {
+        let end = event.min(self.events.len());
+        let (first, mut value) = match self.checkpoints.iter().rev().find(|n| n.event <= end) {
+            Some(n) => {
+                vm.restore(n.state.clone()).map_err(|err| ReplayError::Failed(RuntimeError::new(n.state.pc, err)))?;
+                (n.event, n.value)
+            },
+            None => {
+                vm.restore(self.start.clone()).map_err(|err| ReplayError::Failed(RuntimeError::new(self.start.pc, err)))?;
+                (0, 0)
+            },
+        };
+
+        for index in first..end {
+            let event = self.events[index];
+            let pushed = &self.values[value..value + event.pushed];
+            value += event.pushed;
+
+            let code = vm.code();
+            if vm.pc != event.pc || code.get(vm.pc) != Some(&event.opcode) {
+                return Err(ReplayError::Diverged { event: index, pc: vm.pc });
+            }
+
+            let depth = vm.stack.len();
+            if depth < event.popped {
+                return Err(ReplayError::Diverged { event: index, pc: vm.pc });
+            }
+
+            if is_external(event.opcode) {
+                vm.stack.truncate(depth - event.popped);
+                for value in pushed {
+                    vm.stack.try_push(value.clone()).map_err(|n| ReplayError::Failed(RuntimeError::new(event.pc, n)))?;
+                }
+                vm.pc = match self.events.get(index + 1) {
+                    Some(next) => next.pc,
+                    None => event.pc + 1,
+                };
+                continue;
+            }
+
+            vm.step(&mut NullExtender {}).map_err(ReplayError::Failed)?;
+
+            let stack = vm.stack.as_slice();
+            let kept = depth - event.popped;
+            if stack.len() != kept + pushed.len() || !stack[kept..].iter().zip(pushed).all(|(a, b)| a.same_as(b)) {
+                return Err(ReplayError::Diverged { event: index, pc: event.pc });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///Builds a `Trace` as a machine runs.
+pub struct Recorder {
+    trace: Trace,
+    ///The stack before the current instruction.
+    before: Vec<Data>,
+}
+
+impl Recorder {
+    ///Start recording a machine from its current state.
+    pub fn new<S: Storage, M: Storage>(vm: &Vm<S, M>) -> Recorder {
+        Recorder {
+            trace: Trace {
+                start: vm.snapshot(),
+                events: Vec::new(),
+                values: Vec::new(),
+                checkpoints: Vec::new(),
+            },
+            before: Vec::new(),
+        }
+    }
+
+    ///The trace so far.
+    pub fn trace(&self) -> &Trace {&self.trace}
+
+    ///Stop recording.
+    pub fn into_trace(self) -> Trace {self.trace}
+
+    ///Snapshot the machine being recorded, so rewinding to here or later
+    ///doesn't have to replay what came before.
+    pub fn checkpoint<S: Storage, M: Storage>(&mut self, vm: &Vm<S, M>) {
+        let event = self.trace.events.len();
+        if event == 0 || self.trace.checkpoints.last().is_some_and(|n| n.event == event) {
+            return;
+        }
+
+        self.trace.checkpoints.push(Checkpoint {
+            event,
+            value: self.trace.values.len(),
+            state: vm.snapshot(),
+        });
+    }
+
+    ///Count the checkpoints taken, not counting the start.
+    pub fn checkpoints(&self) -> usize {self.trace.checkpoints.len()}
+
+    ///Forget everything before the oldest checkpoint, which becomes the
+    ///start of the trace, to bound how much is kept.
+    pub fn forget_oldest(&mut self) {
+        if self.trace.checkpoints.is_empty() {
+            return;
+        }
+
+        let oldest = self.trace.checkpoints.remove(0);
+        self.trace.events.drain(..oldest.event);
+        self.trace.values.drain(..oldest.value);
+        for n in &mut self.trace.checkpoints {
+            n.event -= oldest.event;
+            n.value -= oldest.value;
+        }
+        self.trace.start = oldest.state;
+    }
+
+    ///Put the machine back as it was just before an event ran and forget
+    ///that event and everything after it, so recording carries on from
+    ///there.
+    pub fn rewind<S: Storage, M: Storage>(&mut self, vm: &mut Vm<S, M>, event: usize) -> Result<(),ReplayError> {
+        self.trace.replay_to(vm, event)?;
+
+        let event = event.min(self.trace.events.len());
+        let value = self.trace.events[..event].iter().map(|n| n.pushed).sum();
+        self.trace.events.truncate(event);
+        self.trace.values.truncate(value);
+        self.trace.checkpoints.retain(|n| n.event <= event);
+
+        Ok(())
+    }
+}
+
+impl<S: Storage> Tracer<S> for Recorder {
+    fn before_instruction(&mut self, _pc: usize, _opcode: u8, stack: &Stack<S>) {
+        self.before.clear();
+        self.before.extend_from_slice(stack.as_slice());
+    }
+
+    fn after_instruction(&mut self, pc: usize, opcode: u8, stack: &Stack<S>) {
+        let after = stack.as_slice();
+        let kept = self.before.iter().zip(after).take_while(|&(a, b)| a.same_as(b)).count();
+
+        self.trace.events.push(Event {
+            pc,
+            opcode,
+            popped: self.before.len() - kept,
+            pushed: after.len() - kept,
+        });
+        self.trace.values.extend_from_slice(&after[kept..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    use record::{Recorder, ReplayError};
+    use {Data, NullExtender, Vm};
+
+    #[test]
+    fn record_and_replay() {
+        let mut vm = Vm::new(b"#0'h#0'h+#3'*".to_vec(), Vec::new());
+        let mut clock = 10;
+        vm.bind("clock", move |stack| {
+            stack.push(Data::Int(clock));
+            clock += 10;
+            Ok(())
+        });
+
+        let mut recorder = Recorder::new(&vm);
+        vm.run_traced(&mut NullExtender {}, &mut recorder).unwrap();
+        assert_eq!(vm.stack.to_string(), "<1> 90");
+        let trace = recorder.into_trace();
+        assert_eq!(trace.len(), 13);
+        let (event, pushed) = trace.events().nth(3).unwrap();
+        assert_eq!((event.pc, event.opcode, event.popped), (3, b'h', 1));
+        assert_eq!(pushed, &[Data::Int(10)]);
+
+        //Nothing is bound, so the host's results come from the trace.
+        let mut replay = Vm::new(b"#0'h#0'h+#3'*".to_vec(), Vec::new());
+        trace.replay(&mut replay).unwrap();
+        assert_eq!(replay.stack.to_string(), "<1> 90");
+        assert!(replay.is_finished());
+
+        let mut changed = Vm::new(b"#0'h#0'h-#3'*".to_vec(), Vec::new());
+        assert!(matches!(trace.replay(&mut changed), Err(ReplayError::Diverged { event: 8, pc: 8 })));
+    }
+}
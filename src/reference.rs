@@ -0,0 +1,527 @@
+//!A deliberately simple interpreter, to check the fast one against.
+//!
+//!`Machine` runs decoded code one instruction at a time, with a plain
+//!`Vec` for each stack and every opcode written out on its own, sharing
+//!nothing with `Vm` but the decoder and the error types. It is slow and
+//!meant to stay obviously correct: a differential test runs random
+//!programs through both and expects the same stack, memory and errors,
+//!so a superinstruction or threaded word that gets the semantics wrong
+//!shows up as a disagreement.
+//!
+//!It covers the core of the instruction set under the default policies:
+//!literals, arithmetic, comparisons, bitwise operations, stack shuffling,
+//!the return stack, counted loops, jumps, calls and memory, with wrapping
+//!integer arithmetic, strict coercion and wrapping addresses. Anything
+//!else stops it with `Stop::Unsupported`, and the program is skipped.
+//!
+//!Like `Vm::run`, an instruction that doesn't fit in the fuel left is
+//!run a byte at a time, so fuel runs out at the same address.
+
+use alloc::vec::Vec;
+
+use ir::{Op, Program};
+use {Data, Error, RuntimeError, TypeTag};
+
+///Why the reference machine stopped before the end of the code.
+#[derive(Debug)]
+pub enum Stop {
+    ///The code failed, as `Vm::run` would.
+    Failed(RuntimeError),
+    ///The code used an opcode the reference machine doesn't cover.
+    Unsupported { pc: usize, opcode: u8 },
+}
+
+///The reference machine.
+pub struct Machine {
+    pub stack: Vec<Data>,
+    pub memory: Vec<Data>,
+    pub rstack: Vec<usize>,
+    pub pc: usize,
+    ///Most instructions `run` may take, counted as raw steps.
+    pub max_steps: Option<u64>,
+    ///Most items the return stack may hold.
+    pub max_return_depth: usize,
+    code: Vec<u8>,
+    program: Program,
+    value: i64,
+    divider: f64,
+}
+
+impl Machine {
+    ///Create a machine for some code, with the PC at zero and the same
+    ///limits as a new `Vm`.
+    pub fn new(code: Vec<u8>, memory: Vec<Data>) -> Machine {
+        Machine {
+            stack: Vec::new(),
+            memory,
+            rstack: Vec::new(),
+            pc: 0,
+            max_steps: None,
+            max_return_depth: 1024,
+            program: Program::decode(&code),
+            code,
+            value: 0,
+            divider: 1.0,
+        }
+    }
+
+    ///Run until the code halts or fails.
+    pub fn run(&mut self) -> Result<(),Stop> {
+        let max = self.max_steps.unwrap_or(u64::MAX);
+        let mut steps: u64 = 0;
+
+        while self.pc < self.code.len() {
+            let pc = self.pc;
+            let (op, next, cost, literal) = match self.program.at(pc) {
+                Some(n) if max - steps >= n.steps => (n.op.clone(), n.next, n.steps, n.literal),
+                _ => (Op::Byte(self.code[pc]), pc + 1, 1, None),
+            };
+            if steps >= max {
+                return Err(Stop::Failed(RuntimeError::new(pc, Error::FuelExhausted)));
+            }
+            steps += cost;
+            self.pc = next;
+
+            let result = match op {
+                Op::Skip => Ok(()),
+                Op::Int(n) => self.push(Data::Int(n)),
+                Op::Float(n, divider) => self.push(Data::Float(n as f64 / divider)),
+                Op::ExactFloat(n) => self.push(Data::Float(n)),
+                Op::Str(text) => self.push(Data::Str(text)),
+                Op::Jump(target) => {
+                    self.pc = target;
+                    Ok(())
+                },
+                Op::JumpIf(target) | Op::JumpUnless(target) => {
+                    let when = matches!(op, Op::JumpIf(_));
+                    self.pop().and_then(|n| truthy(&n)).map(|n| if n == when { self.pc = target; })
+                },
+                Op::Call(target) => self.call(target, next),
+                Op::IntThen(n, byte) => match self.push(Data::Int(n)) {
+                    Ok(()) => self.byte(byte, next).ok_or(Stop::Unsupported { pc, opcode: byte })?,
+                    Err(n) => Err(n),
+                },
+                Op::DupThen(byte) => match self.shuffle(1, &[0, 0]) {
+                    Ok(()) => self.byte(byte, next).ok_or(Stop::Unsupported { pc, opcode: byte })?,
+                    Err(n) => Err(n),
+                },
+                Op::Byte(byte) => self.byte(byte, next).ok_or(Stop::Unsupported { pc, opcode: byte })?,
+            };
+            if let Err(n) = result {
+                return Err(Stop::Failed(RuntimeError::new(next, n)));
+            }
+            if let Some((value, divider)) = literal {
+                self.value = value;
+                self.divider = divider;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, value: Data) -> Result<(),Error> {
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Data,Error> {
+        self.stack.pop().ok_or(Error::StackUnderflow)
+    }
+
+    fn pop_int(&mut self) -> Result<i64,Error> {
+        match self.pop()? {
+            Data::Int(n) => Ok(n),
+            other => Err(mismatch(TypeTag::Int, &other)),
+        }
+    }
+
+    ///Pop NOS and TOS, in that order.
+    fn pop_two(&mut self) -> Result<(Data, Data),Error> {
+        let tos = self.pop();
+        let nos = self.pop();
+        Ok((nos?, tos?))
+    }
+
+    ///Take the top `inputs` items and push them back in the order given
+    ///by `outputs`, counting from the deepest of them.
+    fn shuffle(&mut self, inputs: usize, outputs: &[usize]) -> Result<(),Error> {
+        if self.stack.len() < inputs {
+            return Err(Error::StackUnderflow);
+        }
+
+        let items = self.stack.split_off(self.stack.len() - inputs);
+        for &n in outputs {
+            self.stack.push(items[n].clone());
+        }
+
+        Ok(())
+    }
+
+    fn call(&mut self, target: usize, home: usize) -> Result<(),Error> {
+        if self.rstack.len() >= self.max_return_depth {
+            return Err(Error::ReturnStackOverflow);
+        }
+
+        self.rstack.push(home);
+        self.pc = target;
+        Ok(())
+    }
+
+    fn address(&self, address: i64) -> Result<usize,Error> {
+        if self.memory.is_empty() {
+            return Err(Error::MemoryOutOfBounds { addr: address, len: 0 });
+        }
+
+        Ok(address.rem_euclid(self.memory.len() as i64) as usize)
+    }
+
+    ///Run one byte, with the PC already moved past it. `None` if it isn't
+    ///one the reference machine covers.
+    fn byte(&mut self, byte: u8, next: usize) -> Option<Result<(),Error>> {
+        Some(match byte {
+            b' ' | b'\n' | b'\r' => Ok(()),
+            b'#' => {
+                self.value = 0;
+                self.divider = 1.0;
+                Ok(())
+            },
+            b'0'..=b'9' => {
+                self.value = self.value.wrapping_mul(10).wrapping_add((byte - b'0') as i64);
+                Ok(())
+            },
+            b'$' => {
+                self.value = self.value.wrapping_neg();
+                Ok(())
+            },
+            b'.' => {
+                self.divider *= 1000.0;
+                Ok(())
+            },
+            b'\'' => self.push(Data::Int(self.value)),
+            b'"' => self.push(Data::Float(self.value as f64 / self.divider)),
+
+            b'+' | b'-' | b'*' | b'/' | b'%' => self.arithmetic(byte),
+            b'<' | b'=' | b'>' => self.compare(byte),
+            b'&' | b'|' | b'^' | b'{' | b'}' | b'_' | b'L' | b'Q' => self.bits(byte),
+            b'N' => self.pop_int().and_then(|n| self.push(Data::Int(n.count_ones() as i64))),
+            b'~' => self.pop().and_then(|n| truthy(&n)).and_then(|n| self.push(Data::Int(!n as i64))),
+
+            b'd' => self.shuffle(1, &[0, 0]),
+            b'r' => self.shuffle(1, &[]),
+            b's' => self.shuffle(2, &[1, 0]),
+            b'v' => self.shuffle(2, &[0, 1, 0]),
+            b'n' => self.shuffle(2, &[1]),
+            b't' => self.shuffle(2, &[1, 0, 1]),
+            b'o' => self.shuffle(3, &[1, 2, 0]),
+            b'u' => self.shuffle(3, &[2, 0, 1]),
+            b'D' => self.shuffle(2, &[0, 1, 0, 1]),
+            b'S' => self.shuffle(4, &[2, 3, 0, 1]),
+            b'P' | b'O' => self.pick(byte == b'O'),
+
+            b'(' => self.pop_int().and_then(|n| {
+                if self.rstack.len() >= self.max_return_depth {
+                    return Err(Error::ReturnStackOverflow);
+                }
+                self.rstack.push(n as usize);
+                Ok(())
+            }),
+            b')' => match self.rstack.pop() {
+                Some(n) => self.push(Data::Int(n as i64)),
+                None => Err(Error::ReturnStackUnderflow),
+            },
+            b'@' => match self.rstack.last() {
+                Some(&n) => self.push(Data::Int(n as i64)),
+                None => Err(Error::ReturnStackUnderflow),
+            },
+            b':' => self.start_loop(),
+            b'J' | b'j' => self.step_loop(byte, next),
+            b';' => {
+                self.pc = self.rstack.pop().unwrap_or(self.code.len());
+                Ok(())
+            },
+
+            b'b' | b'c' => self.pop_int().and_then(|n| match byte {
+                b'b' => {
+                    self.pc = n as usize;
+                    Ok(())
+                },
+                _ => self.call(n as usize, next),
+            }),
+            b'B' | b'C' => self.pop().and_then(|n| relative(byte, next, &n)).and_then(|target| match byte {
+                b'B' => {
+                    self.pc = target;
+                    Ok(())
+                },
+                _ => self.call(target, next),
+            }),
+            b'y' | b'z' => self.pop_two().and_then(|(flag, address)| {
+                let address = match address {
+                    Data::Int(n) => n as usize,
+                    other => { return Err(mismatch(TypeTag::Int, &other)); }
+                };
+                if truthy(&flag)? == (byte == b'y') {
+                    self.pc = address;
+                }
+                Ok(())
+            }),
+            b'Y' | b'Z' => self.pop_two().and_then(|(flag, offset)| {
+                let target = relative(byte, next, &offset)?;
+                if truthy(&flag)? == (byte == b'Y') {
+                    self.pc = target;
+                }
+                Ok(())
+            }),
+
+            b'R' => self.pop_int().and_then(|n| self.address(n)).and_then(|n| self.push(self.memory[n].clone())),
+            b'W' => self.pop_two().and_then(|(value, address)| {
+                let address = match address {
+                    Data::Int(n) => self.address(n)?,
+                    other => { return Err(mismatch(TypeTag::Int, &other)); }
+                };
+                self.memory[address] = value;
+                Ok(())
+            }),
+            b'H' => self.push(Data::Int(self.memory.len() as i64)),
+
+            _ => { return None; }
+        })
+    }
+
+    fn arithmetic(&mut self, op: u8) -> Result<(),Error> {
+        let value = match self.pop_two()? {
+            (Data::Int(_), Data::Int(0)) if op == b'/' || op == b'%' => { return Err(Error::DivisionByZero); },
+            (Data::Int(a), Data::Int(b)) => Data::Int(match op {
+                b'+' => a.wrapping_add(b),
+                b'-' => a.wrapping_sub(b),
+                b'*' => a.wrapping_mul(b),
+                b'/' => a.wrapping_div(b),
+                _ => a.wrapping_rem(b),
+            }),
+            (Data::Float(a), Data::Float(b)) => Data::Float(match op {
+                b'+' => a + b,
+                b'-' => a - b,
+                b'*' => a * b,
+                b'/' => a / b,
+                _ => a % b,
+            }),
+            (_, b) => { return Err(mismatch(TypeTag::Number, &b)); }
+        };
+
+        self.push(value)
+    }
+
+    fn compare(&mut self, op: u8) -> Result<(),Error> {
+        let order = match self.pop_two()? {
+            (Data::Int(a), Data::Int(b)) => a.partial_cmp(&b),
+            (Data::Float(a), Data::Float(b)) => a.partial_cmp(&b),
+            (Data::Str(a), Data::Str(b)) => a.partial_cmp(&b),
+            (_, b) => { return Err(mismatch(TypeTag::Number, &b)); }
+        };
+        let want = match op {
+            b'<' => core::cmp::Ordering::Less,
+            b'=' => core::cmp::Ordering::Equal,
+            _ => core::cmp::Ordering::Greater,
+        };
+
+        self.push(Data::Int((order == Some(want)) as i64))
+    }
+
+    fn bits(&mut self, op: u8) -> Result<(),Error> {
+        let (a, b) = match self.pop_two()? {
+            (Data::Int(a), Data::Int(b)) => (a, b),
+            (_, b) => { return Err(mismatch(TypeTag::Int, &b)); }
+        };
+        let value = match op {
+            b'&' => a & b,
+            b'|' => a | b,
+            b'^' => a ^ b,
+            b'{' if (0..64).contains(&b) => ((a as u64) << b) as i64,
+            b'}' if (0..64).contains(&b) => ((a as u64) >> b) as i64,
+            b'{' | b'}' => 0,
+            b'_' => a >> b.clamp(0, 63),
+            b'L' => (a as u64).rotate_left(b.rem_euclid(64) as u32) as i64,
+            _ => (a as u64).rotate_right(b.rem_euclid(64) as u32) as i64,
+        };
+
+        self.push(Data::Int(value))
+    }
+
+    ///Copy, or move, the item some depth below TOS to the top.
+    fn pick(&mut self, remove: bool) -> Result<(),Error> {
+        let depth = match self.pop()? {
+            Data::Int(n) if n >= 0 && (n as u64) < self.stack.len() as u64 => n as usize,
+            Data::Int(n) if n < 0 => { return Err(Error::MemoryOutOfBounds { addr: n, len: self.stack.len() }); },
+            Data::Int(_) => { return Err(Error::StackUnderflow); },
+            other => { return Err(mismatch(TypeTag::Int, &other)); }
+        };
+
+        let n = self.stack.len() - 1 - depth;
+        let value = if remove { self.stack.remove(n) } else { self.stack[n].clone() };
+        self.push(value)
+    }
+
+    fn start_loop(&mut self) -> Result<(),Error> {
+        let first = self.pop_int()?;
+        let limit = self.pop_int()?;
+        if self.rstack.len() + 2 > self.max_return_depth {
+            return Err(Error::ReturnStackOverflow);
+        }
+
+        self.rstack.push(limit as usize);
+        self.rstack.push(first as usize);
+        Ok(())
+    }
+
+    fn step_loop(&mut self, op: u8, next: usize) -> Result<(),Error> {
+        let offset = self.pop()?;
+        let step = if op == b'j' { self.pop_int()? } else { 1 };
+        let target = relative(op, next, &offset)?;
+        if self.rstack.len() < 2 {
+            return Err(Error::ReturnStackUnderflow);
+        }
+
+        //The loop ends when the index crosses from below the limit to at
+        //or above it, or the other way.
+        let index = self.rstack.pop().unwrap() as i64;
+        let limit = *self.rstack.last().unwrap() as i64;
+        let stepped = index.wrapping_add(step);
+        let before = index.wrapping_sub(limit) < 0;
+        let after = stepped.wrapping_sub(limit) < 0;
+        if before == after {
+            self.rstack.push(stepped as usize);
+            self.pc = target;
+        } else {
+            self.rstack.pop();
+        }
+
+        Ok(())
+    }
+}
+
+fn mismatch(expected: TypeTag, found: &Data) -> Error {
+    Error::TypeMismatch { expected, found: found.type_tag() }
+}
+
+fn truthy(value: &Data) -> Result<bool,Error> {
+    match *value {
+        Data::Int(n) => Ok(n != 0),
+        Data::Float(n) => Ok(n != 0.0),
+        ref other => Err(mismatch(TypeTag::Number, other)),
+    }
+}
+
+///Resolve a relative jump against the address after it.
+fn relative(op: u8, next: usize, offset: &Data) -> Result<usize,Error> {
+    match *offset {
+        Data::Int(n) => match (next as i64).saturating_add(n) {
+            target if target < 0 => Err(Error::InvalidInstruction { opcode: op }),
+            target => Ok(target as usize),
+        },
+        ref other => Err(mismatch(TypeTag::Int, other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use reference::{Machine, Stop};
+    use rng::Rng;
+    use {Data, NullExtender, Vm};
+
+    ///Bytes a random program is made of, besides literals.
+    const OPS: &[u8] = b"+-*/%<=>&|^{}_LQN~drsvntouDSPO()@:JjBCYZbcyzRWH; ";
+
+    ///Make a short random program, mostly small literals and the opcodes
+    ///the reference machine covers, with jumps that land anywhere.
+    fn program(rng: &mut Rng) -> Vec<u8> {
+        let mut code = Vec::new();
+        for _ in 0..rng.range(1, 32) {
+            match rng.range(0, 10) {
+                0..=3 => {
+                    let n = match rng.range(0, 8) {
+                        0 => i64::MAX - rng.range(0, 2),
+                        _ => rng.range(0, 24),
+                    };
+                    code.extend_from_slice(format!("#{}'", n).as_bytes());
+                    if rng.range(0, 4) == 0 {
+                        code.insert(code.len() - 1, b'$');
+                    }
+                },
+                4 => code.extend_from_slice(format!("#{}.{}\"", rng.range(0, 9), rng.range(0, 999)).as_bytes()),
+                5 if rng.range(0, 4) == 0 => code.extend_from_slice(b"[ab]"),
+                5 => code.extend_from_slice(b"#99999999999999999999'"),
+                _ => code.push(OPS[rng.range(0, OPS.len() as i64) as usize]),
+            }
+        }
+
+        code
+    }
+
+    ///How a run ended: the PC it halted at and the stack and memory it
+    ///left, or the address and kind of the error that stopped it. What
+    ///a failing instruction leaves on the stack is an implementation
+    ///detail, so it isn't compared.
+    type Outcome = Result<(usize, String), (usize, &'static str)>;
+
+    fn reference(code: &[u8]) -> Option<Outcome> {
+        let mut machine = Machine::new(code.to_vec(), vec![Data::Int(0); 4]);
+        machine.max_steps = Some(300);
+        machine.max_return_depth = 8;
+        match machine.run() {
+            Ok(()) => Some(Ok((machine.pc, format!("{:?} {:?}", machine.stack, machine.memory)))),
+            Err(Stop::Failed(n)) => Some(Err((n.pc, n.kind.to_string()))),
+            Err(Stop::Unsupported { .. }) => None,
+        }
+    }
+
+    fn vm(code: &[u8], raw: bool) -> Outcome {
+        let mut vm = Vm::new(code.to_vec(), vec![Data::Int(0); 4]);
+        vm.config.max_steps = Some(300);
+        vm.config.max_return_depth = 8;
+        let result = if raw { vm.run_bytes(&mut NullExtender {}) } else { vm.run(&mut NullExtender {}) };
+        match result {
+            Ok(()) => Ok((vm.pc, format!("{:?} {:?}", vm.stack.as_slice(), vm.memory))),
+            Err(n) => Err((n.pc, n.kind.to_string())),
+        }
+    }
+
+    #[test]
+    fn known_programs() {
+        let mut machine = Machine::new(b"#0'#3'#0':@+#7$'J #2'#1'W #1'R".to_vec(), vec![Data::Int(0); 2]);
+        machine.run().unwrap();
+        assert_eq!(machine.stack, vec![Data::Int(3), Data::Int(2)]);
+
+        let mut machine = Machine::new(b"#5'C #1'; #2';".to_vec(), Vec::new());
+        machine.run().unwrap();
+        assert_eq!(machine.stack, vec![Data::Int(2), Data::Int(1)]);
+
+        let mut machine = Machine::new(b"#1'#0'/".to_vec(), Vec::new());
+        match machine.run() {
+            Err(Stop::Failed(n)) => assert_eq!((n.pc, n.kind.to_string()), (7, "Division By Zero")),
+            n => panic!("{:?}", n),
+        }
+        let mut machine = Machine::new(b"#1'M".to_vec(), Vec::new());
+        assert!(matches!(machine.run(), Err(Stop::Unsupported { pc: 3, opcode: b'M' })));
+    }
+
+    #[test]
+    fn matches_vm() {
+        let mut rng = Rng::seeded(92);
+        let mut checked = 0;
+        for _ in 0..4000 {
+            let code = program(&mut rng);
+            let expected = match reference(&code) {
+                Some(n) => n,
+                None => { continue; }
+            };
+            let name = String::from_utf8_lossy(&code);
+            assert_eq!(vm(&code, false), expected, "{}", name);
+            assert_eq!(vm(&code, true), expected, "{}", name);
+            checked += 1;
+        }
+
+        assert!(checked > 3000);
+    }
+}
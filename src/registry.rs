@@ -0,0 +1,226 @@
+//!Which extender handles which opcode, checked when the extenders are put
+//!together rather than when the code first runs into a clash.
+//!
+//!The VM keeps every ASCII byte for itself: the built-ins, the literal
+//!syntax, whitespace and the bytes not taken yet, so that a new built-in
+//!can't land on a byte some extender already uses. Extenders get the
+//!bytes from `FIRST_EXTENSION` up and say which of them they handle with
+//!`AtomExtender::opcodes`.
+//!
+//!`Extenders` holds several extenders and passes each opcode to the one
+//!that claimed it. Adding one that claims a reserved byte, or a byte
+//!another has claimed, fails with an `OpcodeConflict` naming both.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use storage::Storage;
+use {AtomExtender, Context, Data, Error, Stack};
+
+///The lowest byte an extender may claim.
+pub const FIRST_EXTENSION: u8 = 128;
+
+///Check whether a byte is kept for the VM, now or in the future.
+pub fn is_reserved(byte: u8) -> bool {
+    byte < FIRST_EXTENSION
+}
+
+///Why an extender couldn't be added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpcodeConflict {
+    ///The extender claimed a byte the VM keeps for itself.
+    Reserved { opcode: u8, extender: String },
+    ///Two extenders claimed the same byte.
+    Claimed { opcode: u8, extender: String, owner: String },
+}
+
+impl fmt::Display for OpcodeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OpcodeConflict::Reserved { opcode, ref extender } => {
+                write!(f, "Extender {} claims opcode {}, which is reserved for built-ins", extender, opcode)
+            },
+            OpcodeConflict::Claimed { opcode, ref extender, ref owner } => {
+                write!(f, "Extender {} claims opcode {}, which extender {} already handles", extender, opcode, owner)
+            },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for OpcodeConflict {}
+
+///Records who owns each opcode.
+pub struct Registry {
+    names: Vec<String>,
+    ///Where each byte's owner is in `names`, if it has one.
+    owners: [Option<usize>; 256],
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+impl Registry {
+    ///Start with no opcodes claimed.
+    pub fn new() -> Registry {
+        Registry {
+            names: Vec::new(),
+            owners: [None; 256],
+        }
+    }
+
+    ///Claim opcodes for an extender. If any of them conflicts, none are
+    ///claimed.
+    pub fn claim(&mut self, name: &str, opcodes: &[u8]) -> Result<(),OpcodeConflict> {
+        for (n, &opcode) in opcodes.iter().enumerate() {
+            if is_reserved(opcode) {
+                return Err(OpcodeConflict::Reserved { opcode, extender: String::from(name) });
+            }
+            if let Some(owner) = self.owner(opcode) {
+                return Err(OpcodeConflict::Claimed { opcode, extender: String::from(name), owner: String::from(owner) });
+            }
+            if opcodes[..n].contains(&opcode) {
+                return Err(OpcodeConflict::Claimed { opcode, extender: String::from(name), owner: String::from(name) });
+            }
+        }
+
+        let index = self.names.len();
+        self.names.push(String::from(name));
+        for &opcode in opcodes {
+            self.owners[opcode as usize] = Some(index);
+        }
+
+        Ok(())
+    }
+
+    ///The name of the extender that claimed an opcode, if any did.
+    pub fn owner(&self, opcode: u8) -> Option<&str> {
+        self.owners[opcode as usize].map(|n| &*self.names[n])
+    }
+}
+
+///Several extenders, each handling the opcodes it claimed.
+pub struct Extenders<'a, S: Storage = Vec<Data>, M: Storage = Vec<Data>> {
+    registry: Registry,
+    extenders: Vec<Box<dyn AtomExtender<S, M> + 'a>>,
+}
+
+impl<'a, S: Storage, M: Storage> Default for Extenders<'a, S, M> {
+    fn default() -> Extenders<'a, S, M> {
+        Extenders::new()
+    }
+}
+
+impl<'a, S: Storage, M: Storage> Extenders<'a, S, M> {
+    ///Start with no extenders.
+    pub fn new() -> Extenders<'a, S, M> {
+        Extenders {
+            registry: Registry::new(),
+            extenders: Vec::new(),
+        }
+    }
+
+    ///Add an extender under a name used in errors. It gets the opcodes
+    ///its `opcodes` lists.
+    pub fn add<T: AtomExtender<S, M> + 'a>(&mut self, name: &str, extender: T) -> Result<(),OpcodeConflict> {
+        self.registry.claim(name, extender.opcodes())?;
+        self.extenders.push(Box::new(extender));
+
+        Ok(())
+    }
+
+    ///The opcodes claimed so far.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    fn owner(&mut self, opcode: u8) -> Option<&mut (dyn AtomExtender<S, M> + 'a)> {
+        match self.registry.owners[opcode as usize] {
+            Some(n) => Some(&mut *self.extenders[n]),
+            None => None,
+        }
+    }
+}
+
+impl<'a, S: Storage, M: Storage> AtomExtender<S, M> for Extenders<'a, S, M> {
+    fn atom(&mut self, opcode: u8, stack: &mut Stack<S>) -> Result<(),Error> {
+        match self.owner(opcode) {
+            Some(n) => n.atom(opcode, stack),
+            None => Err(Error::InvalidInstruction { opcode }),
+        }
+    }
+
+    fn atom_with_context(&mut self, opcode: u8, context: &mut Context<S, M>) -> Result<(),Error> {
+        match self.owner(opcode) {
+            Some(n) => n.atom_with_context(opcode, context),
+            None => Err(Error::InvalidInstruction { opcode }),
+        }
+    }
+
+    fn is_deterministic(&self, opcode: u8) -> bool {
+        match self.registry.owners[opcode as usize] {
+            Some(n) => self.extenders[n].is_deterministic(opcode),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+    use core::slice;
+
+    use registry::{is_reserved, Extenders, OpcodeConflict, Registry};
+    use {run, AtomExtender, Data, Error, Stack};
+
+    ///Pushes its opcode.
+    struct Echo(u8);
+
+    impl AtomExtender for Echo {
+        fn atom(&mut self, opcode: u8, stack: &mut Stack) -> Result<(),Error> {
+            stack.push(Data::Int(opcode as i64));
+            Ok(())
+        }
+
+        fn is_deterministic(&self, _: u8) -> bool {true}
+
+        fn opcodes(&self) -> &[u8] {
+            slice::from_ref(&self.0)
+        }
+    }
+
+    #[test]
+    fn claims() {
+        assert!(is_reserved(b'R') && is_reserved(b'\\') && !is_reserved(0x80));
+
+        let mut registry = Registry::new();
+        registry.claim("a", &[0x80, 0x81]).unwrap();
+        assert_eq!(registry.owner(0x81), Some("a"));
+        assert_eq!(registry.claim("b", &[0x90, b'W']), Err(OpcodeConflict::Reserved { opcode: b'W', extender: "b".into() }));
+        assert_eq!(registry.owner(0x90), None);
+        let err = registry.claim("c", &[0x81]).unwrap_err();
+        assert_eq!(err.to_string(), "Extender c claims opcode 129, which extender a already handles");
+    }
+
+    #[test]
+    fn dispatches() {
+        let mut extenders = Extenders::new();
+        extenders.add("one", Echo(0xf0)).unwrap();
+        extenders.add("two", Echo(0xf1)).unwrap();
+        assert!(matches!(extenders.add("again", Echo(0xf0)), Err(OpcodeConflict::Claimed { opcode: 0xf0, .. })));
+        assert!(matches!(extenders.add("low", Echo(b'R')), Err(OpcodeConflict::Reserved { opcode: b'R', .. })));
+        assert!(extenders.is_deterministic(0xf1));
+
+        let mut stack = Stack::new();
+        run(&[0xf1, 0xf0], &mut stack, 0, &mut extenders, &mut Vec::new()).unwrap();
+        assert_eq!(stack.pop().unwrap(), Data::Int(0xf0));
+        assert_eq!(stack.pop().unwrap(), Data::Int(0xf1));
+        assert!(run(&[0xf2], &mut stack, 0, &mut extenders, &mut Vec::new()).is_err());
+    }
+}
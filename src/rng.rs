@@ -0,0 +1,104 @@
+//!The VM's random number generator: xoshiro256**, seeded through
+//!splitmix64 so any `u64` makes a good seed. The same seed gives the same
+//!numbers on every platform.
+
+///A seeded pseudo-random number generator.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Default for Rng {
+    ///A generator seeded with 0.
+    fn default() -> Rng {
+        Rng::seeded(0)
+    }
+}
+
+impl Rng {
+    ///Create a generator from a seed.
+    pub fn seeded(seed: u64) -> Rng {
+        let mut x = seed;
+        let mut next = || {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+
+        Rng {
+            state: [next(), next(), next(), next()]
+        }
+    }
+
+    ///The generator's internal state, for digests and snapshots.
+    pub fn state(&self) -> [u64; 4] {self.state}
+
+    ///Get the next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+
+        result
+    }
+
+    ///Get an int in `[low, high)`, without bias. An empty range gives
+    ///`low`.
+    pub fn range(&mut self, low: i64, high: i64) -> i64 {
+        if high <= low {
+            return low;
+        }
+
+        let span = high.wrapping_sub(low) as u64;
+        //Reject draws from the incomplete block at the top of the range.
+        let zone = u64::MAX - (u64::MAX - span + 1) % span;
+        loop {
+            let n = self.next_u64();
+            if n <= zone {
+                return low.wrapping_add((n % span) as i64);
+            }
+        }
+    }
+
+    ///Get a float in `[0, 1)` with 53 random bits.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use rng::Rng;
+
+    #[test]
+    fn reproducible() {
+        let mut a = Rng::seeded(42);
+        let mut b = Rng::seeded(42);
+        let first: Vec<u64> = (0..4).map(|_| a.next_u64()).collect();
+        let second: Vec<u64> = (0..4).map(|_| b.next_u64()).collect();
+        assert_eq!(first, second);
+
+        assert_ne!(Rng::seeded(1).next_u64(), Rng::seeded(2).next_u64());
+
+        let x = a.next_f64();
+        assert!((0.0..1.0).contains(&x));
+
+        for _ in 0..100 {
+            assert!((-3..4).contains(&a.range(-3, 4)));
+        }
+        assert_eq!(a.range(5, 5), 5);
+        a.range(i64::MIN, i64::MAX);
+    }
+}
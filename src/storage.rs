@@ -0,0 +1,188 @@
+//!Where the data stack and memory keep their cells.
+//!
+//!Both default to a `Vec<Data>`, which grows as needed. On targets that
+//!can't allocate, use a `FixedStorage` instead: a const-generic array
+//!that never allocates and refuses to grow past its capacity. A full data
+//!stack is a `StackOverflow`, and memory that can't grow is
+//!`MemoryOutOfBounds`.
+
+use alloc::vec::Vec;
+use core::array;
+use core::mem;
+
+use Data;
+
+///A growable run of cells, bottom first.
+pub trait Storage {
+    fn as_slice(&self) -> &[Data];
+
+    fn as_mut_slice(&mut self) -> &mut [Data];
+
+    ///Add a cell at the end, or hand the value back if there is no room.
+    fn try_push(&mut self, value: Data) -> Result<(),Data>;
+
+    ///Remove the last cell.
+    fn pop(&mut self) -> Option<Data>;
+
+    ///Drop cells from the end until at most `len` remain.
+    fn truncate(&mut self, len: usize);
+
+    ///Get the number of cells.
+    fn len(&self) -> usize {self.as_slice().len()}
+
+    ///Check whether there are no cells.
+    fn is_empty(&self) -> bool {self.len() == 0}
+
+    ///Add zeroed cells until there are `len`, returning false if they
+    ///don't fit. Cells that do fit are kept.
+    fn grow(&mut self, len: usize) -> bool {
+        while self.len() < len {
+            if self.try_push(Data::Int(0)).is_err() {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl Storage for Vec<Data> {
+    #[inline]
+    fn as_slice(&self) -> &[Data] {self}
+
+    #[inline]
+    fn as_mut_slice(&mut self) -> &mut [Data] {self}
+
+    #[inline]
+    fn try_push(&mut self, value: Data) -> Result<(),Data> {
+        self.push(value);
+        Ok(())
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<Data> {Vec::pop(self)}
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {Vec::truncate(self, len)}
+
+    #[inline]
+    fn grow(&mut self, len: usize) -> bool {
+        if self.len() < len {
+            if self.try_reserve(len - self.len()).is_err() {
+                return false;
+            }
+            self.resize(len, Data::Int(0));
+        }
+
+        true
+    }
+}
+
+///Room for at most `N` cells, held inline.
+pub struct FixedStorage<const N: usize> {
+    cells: [Data; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedStorage<N> {
+    ///Create empty storage.
+    pub fn new() -> FixedStorage<N> {
+        FixedStorage {
+            cells: array::from_fn(|_| Data::Int(0)),
+            len: 0,
+        }
+    }
+
+    ///Get the most cells this can hold.
+    pub fn capacity(&self) -> usize {N}
+}
+
+impl<const N: usize> Default for FixedStorage<N> {
+    fn default() -> FixedStorage<N> {
+        FixedStorage::new()
+    }
+}
+
+impl<const N: usize> Storage for FixedStorage<N> {
+    fn as_slice(&self) -> &[Data] {&self.cells[..self.len]}
+
+    fn as_mut_slice(&mut self) -> &mut [Data] {&mut self.cells[..self.len]}
+
+    fn try_push(&mut self, value: Data) -> Result<(),Data> {
+        if self.len == N {
+            return Err(value);
+        }
+
+        self.cells[self.len] = value;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Data> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        Some(mem::replace(&mut self.cells[self.len], Data::Int(0)))
+    }
+
+    fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use storage::{FixedStorage, Storage};
+    use {Data, Error, NullExtender, RuntimeError, Stack, Vm};
+
+    #[test]
+    fn fixed_capacity() {
+        let mut memory = FixedStorage::<3>::new();
+        assert!(memory.grow(2));
+        assert!(!memory.grow(4));
+        assert_eq!(memory.len(), 3);
+
+        let mut stack = Stack::with_storage(FixedStorage::<2>::new());
+        stack.try_push(Data::Int(1)).unwrap();
+        assert!(matches!(stack.dup(), Ok(())));
+        assert!(matches!(stack.dup(), Err(Error::StackOverflow)));
+        assert_eq!(stack.to_string(), "<2> 1 1");
+
+        let mut vm = Vm::with_storage(b"#1'#2'+ H".to_vec(), FixedStorage::<2>::new(), FixedStorage::<4>::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> 3 0");
+
+        vm.stack.pop().unwrap();
+        vm.load(b"d d".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::StackOverflow, pc: 3, .. })));
+
+        //A fused `#1'+` still needs room for the 1.
+        vm.load(b"#1'+".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::StackOverflow, pc: 3, .. })));
+
+        vm.load(b"#5'A".to_vec()).unwrap();
+        vm.stack.clear();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { .. }, .. })));
+
+        //A snapshot that doesn't fit is refused whole.
+        let mut big = Vm::new(b"#1'#2'#3'".to_vec(), vec![Data::Int(0); 5]);
+        big.run(&mut NullExtender {}).unwrap();
+        vm.stack.try_push(Data::Int(7)).unwrap();
+        let before = vm.snapshot();
+        assert!(matches!(vm.restore(big.snapshot()), Err(Error::StackOverflow)));
+        assert_eq!(vm.snapshot(), before);
+        big.stack.clear();
+        assert!(matches!(vm.restore(big.snapshot()), Err(Error::MemoryOutOfBounds { addr: 4, len: 4 })));
+        assert_eq!(vm.snapshot(), before);
+        big.memory.truncate(4);
+        vm.restore(big.snapshot()).unwrap();
+        assert_eq!((vm.pc, vm.stack.len(), vm.memory.len()), (9, 0, 4));
+    }
+}
@@ -0,0 +1,223 @@
+//!Strategies for property testing with `proptest`, behind the `proptest`
+//!feature.
+//!
+//!`data` makes values, `stack` makes stack contents from the bottom up,
+//!and `program` makes bytecode the validator accepts: literals, strings,
+//!floats and opcodes laid end to end, with jumps and calls whose literal
+//!targets land on the start of an instruction or at the end of the code.
+//!Arrays and maps are left out, since a handle means nothing without the
+//!heap it came from, and so are symbolic calls, which need a dictionary.
+//!
+//!A program can use any built-in opcode, including `K` and `I`, which
+//!read input, and jumps to computed addresses, which may go anywhere. Run
+//!one with a step limit and input that isn't the terminal:
+//!
+//!```
+//!# extern crate greengold;
+//!# extern crate proptest;
+//!use greengold::input::NullInput;
+//!use greengold::output::NullOutput;
+//!use greengold::{strategy, validate, NullExtender, Vm};
+//!use proptest::test_runner::TestRunner;
+//!
+//!# fn main() {
+//!TestRunner::default().run(&strategy::program(32), |code| {
+//!    assert!(validate::validate(&code).is_ok());
+//!    let mut vm = Vm::new(code, Vec::new());
+//!    vm.config.max_steps = Some(1000);
+//!    vm.input = Box::new(NullInput {});
+//!    vm.output = Box::new(NullOutput {});
+//!    let _ = vm.run(&mut NullExtender {});
+//!    Ok(())
+//!}).unwrap();
+//!# }
+//!```
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use proptest::prelude::*;
+use proptest::sample::Index;
+
+use opcodes::{EXTENDED, OPCODES};
+use Data;
+
+///Make an int, a float or a short string. Floats include infinities and
+///NaN.
+pub fn data() -> impl Strategy<Value = Data> {
+    prop_oneof![
+        any::<i64>().prop_map(Data::Int),
+        any::<f64>().prop_map(Data::Float),
+        proptest::collection::vec(any::<char>(), 0..16).prop_map(|n| Data::Str(Arc::from(n.into_iter().collect::<String>()))),
+    ]
+}
+
+impl Arbitrary for Data {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Data>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Data> {
+        data().boxed()
+    }
+}
+
+///Make the contents of a stack, bottom first, at most `depth` deep.
+pub fn stack(depth: usize) -> impl Strategy<Value = Vec<Data>> {
+    proptest::collection::vec(data(), 0..=depth)
+}
+
+///Digits in the address or offset of a jump, so every jump is the same
+///size whatever its target.
+const WIDTH: usize = 6;
+
+///One instruction of a program.
+#[derive(Debug, Clone)]
+enum Piece {
+    Int(i64),
+    ///A float literal: its digits and how many `.`s scale them.
+    Float(u32, u8),
+    Str(String),
+    ExactFloat(f64),
+    Opcode(u8),
+    Extended(u8),
+    ///A jump, call or loop with a literal target: the opcode and which
+    ///instruction it lands on, or the end of the code.
+    Jump(u8, Index),
+}
+
+impl Piece {
+    fn len(&self) -> usize {
+        match *self {
+            Piece::Int(n) => n.unsigned_abs().to_string().len() + 3 + (n < 0) as usize,
+            Piece::Float(n, scale) => n.to_string().len() + scale as usize + 2,
+            Piece::Str(ref text) => escape(text).len() + 2,
+            Piece::ExactFloat(_) => 9,
+            Piece::Opcode(_) => 1,
+            Piece::Extended(_) => 2,
+            //`#`, the digits, a sign, `'` and the opcode.
+            Piece::Jump(..) => WIDTH + 4,
+        }
+    }
+}
+
+///Escape the bytes a string literal treats specially.
+fn escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if c == ']' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+///Opcodes that stand alone. Literal syntax, strings, floats, symbolic
+///calls and the extended page are made some other way.
+fn opcodes() -> Vec<u8> {
+    OPCODES.iter()
+        .map(|n| n.byte)
+        .filter(|&b| !b.is_ascii_digit() && !b"!\"#$'.[]`".contains(&b))
+        .collect()
+}
+
+fn piece() -> impl Strategy<Value = Piece> {
+    let extended: Vec<u8> = EXTENDED.iter().map(|n| n.byte).collect();
+    prop_oneof![
+        4 => prop_oneof![any::<i64>(), -16i64..64].prop_map(Piece::Int),
+        1 => (0u32..100_000, 0u8..3).prop_map(|(n, scale)| Piece::Float(n, scale)),
+        1 => proptest::collection::vec(any::<char>(), 0..8).prop_map(|n| Piece::Str(n.into_iter().collect())),
+        1 => any::<f64>().prop_map(Piece::ExactFloat),
+        8 => proptest::sample::select(opcodes()).prop_map(Piece::Opcode),
+        1 => proptest::sample::select(extended).prop_map(Piece::Extended),
+        2 => (proptest::sample::select(&b"bcyzBCYZJj"[..]), any::<Index>()).prop_map(|(op, target)| Piece::Jump(op, target)),
+    ]
+}
+
+///Lay pieces out as code, resolving each jump's target.
+fn assemble(pieces: &[Piece]) -> Vec<u8> {
+    //The address each piece starts at, and then the end of the code.
+    let mut starts = vec![0];
+    for piece in pieces {
+        let end = starts[starts.len() - 1] + piece.len();
+        starts.push(end);
+    }
+
+    let mut code = Vec::new();
+    for (n, piece) in pieces.iter().enumerate() {
+        match *piece {
+            Piece::Int(value) => {
+                //A space keeps a jump after it from taking it as a target.
+                code.extend_from_slice(format!("#{}{}' ", value.unsigned_abs(), if value < 0 { "$" } else { "" }).as_bytes());
+            },
+            Piece::Float(value, scale) => {
+                code.extend_from_slice(format!("#{}{}\"", value, ".".repeat(scale as usize)).as_bytes());
+            },
+            Piece::Str(ref text) => {
+                code.extend_from_slice(format!("[{}]", escape(text)).as_bytes());
+            },
+            Piece::ExactFloat(value) => {
+                code.push(b']');
+                code.extend_from_slice(&value.to_le_bytes());
+            },
+            Piece::Opcode(op) => code.push(op),
+            Piece::Extended(op) => code.extend_from_slice(&[b'!', op]),
+            Piece::Jump(op, ref target) => {
+                let target = starts[target.index(starts.len())] as i64;
+                //Relative targets count from the address after the jump.
+                let value = if op.is_ascii_uppercase() || op == b'j' { target - starts[n + 1] as i64 } else { target };
+                let sign = if value < 0 { '$' } else { ' ' };
+                code.extend_from_slice(format!("#{:02$}{}'", value.abs(), sign, WIDTH).as_bytes());
+                code.push(op);
+            },
+        }
+    }
+
+    code
+}
+
+///Make a program of at most `len` instructions that the validator
+///accepts.
+pub fn program(len: usize) -> impl Strategy<Value = Vec<u8>> {
+    proptest::collection::vec(piece(), 0..=len).prop_map(|n| assemble(&n))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use proptest::prelude::*;
+
+    use input::NullInput;
+    use output::NullOutput;
+    use strategy::{program, stack};
+    use validate::validate;
+    use {Data, NullExtender, Stack, Vm};
+
+    proptest! {
+        #[test]
+        fn valid_programs_run(code in program(48)) {
+            prop_assert!(validate(&code).is_ok(), "{:?}", String::from_utf8_lossy(&code));
+
+            let mut vm = Vm::new(code, vec![Data::Int(0); 8]);
+            vm.config.max_steps = Some(2000);
+            vm.input = Box::new(NullInput {});
+            vm.output = Box::new(NullOutput {});
+            let _ = vm.run(&mut NullExtender {});
+        }
+
+        #[test]
+        fn dup_drop_is_identity(items in stack(16)) {
+            let mut vm = Vm::with_storage(b"dr".to_vec(), items.clone(), Vec::new());
+            let result = vm.run(&mut NullExtender {});
+            if items.is_empty() {
+                prop_assert!(result.is_err());
+            } else {
+                prop_assert!(result.is_ok());
+                prop_assert_eq!(vm.stack.to_string(), Stack::with_storage(items).to_string());
+            }
+        }
+    }
+}
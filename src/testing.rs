@@ -0,0 +1,229 @@
+//!Golden-file tests for programs written in the language itself.
+//!
+//!A directory holds `name.gg` source files, each with a `name.expected`
+//!file beside it and, optionally, a `name.input` file to read from.
+//!`run_dir` compiles and runs every program and compares its transcript
+//!with the expected one: whatever it printed, then a last line with the
+//!final stack, or the error that stopped it. `bless_dir` writes the
+//!transcripts out instead, for new tests or after a deliberate change.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use compiler::Compiler;
+use {Data, NullExtender, RunConfig, Vm};
+
+///Memory each program gets, as in the interactive session.
+const MEMORY_CELLS: usize = 1024;
+
+///Steps a program may take before it is assumed to be stuck.
+const MAX_STEPS: u64 = 10_000_000;
+
+///Collects what a program prints where the test can still read it.
+struct Transcript(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Transcript {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {Ok(())}
+}
+
+///Compile and run a program with some input, and get its transcript.
+pub fn run_source(source: &str, input: &str) -> String {
+    let mut compiler = Compiler::new();
+    if let Err(n) = compiler.compile(source) {
+        return format!("compile error: {}\n", n);
+    }
+
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    //Transcripts give errors by address alone, so comments and spacing
+    //in the source don't change them.
+    let mut module = compiler.into_module();
+    module.debug_info = None;
+    let mut vm = match Vm::from_module(module, vec![Data::Int(0); MEMORY_CELLS]) {
+        Ok(n) => n,
+        Err(n) => { return format!("error: {}\n", n); }
+    };
+    vm.config = RunConfig {
+        max_steps: Some(MAX_STEPS),
+        ..RunConfig::default()
+    };
+    vm.output = Box::new(Transcript(printed.clone()));
+    vm.input = Box::new(io::Cursor::new(input.as_bytes().to_vec()));
+    let result = vm.run(&mut NullExtender {});
+
+    let mut out = String::from_utf8_lossy(&printed.lock().unwrap()).into_owned();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    match result {
+        Ok(()) => out.push_str(&format!("ok {}\n", vm.stack)),
+        Err(n) => out.push_str(&format!("error: {} {}\n", n, vm.stack)),
+    }
+
+    out
+}
+
+///A program whose transcript didn't match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub path: PathBuf,
+    ///`None` if there is no `.expected` file yet.
+    pub expected: Option<String>,
+    pub actual: String,
+}
+
+impl Failure {
+    ///The differences between the expected and actual transcripts.
+    pub fn diff(&self) -> String {
+        diff(self.expected.as_ref().map_or("", |n| &n[..]), &self.actual)
+    }
+}
+
+///The outcome of running a directory of programs.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub passed: Vec<PathBuf>,
+    pub failed: Vec<Failure>,
+}
+
+impl Report {
+    ///Check whether every program matched.
+    pub fn is_success(&self) -> bool {self.failed.is_empty()}
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for failure in &self.failed {
+            match failure.expected {
+                Some(_) => writeln!(f, "--- {}", failure.path.display())?,
+                None => writeln!(f, "--- {} (no .expected file)", failure.path.display())?,
+            }
+            write!(f, "{}", failure.diff())?;
+        }
+        write!(f, "{} passed, {} failed", self.passed.len(), self.failed.len())
+    }
+}
+
+///Compare two texts line by line. Lines only in the expected text start
+///with `-`, lines only in the actual one with `+`, and lines in both with
+///a space.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+
+    //The length of the longest common run of lines from each position.
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            out.push_str(&format!(" {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            out.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        }
+    }
+
+    out
+}
+
+///The `.gg` files in a directory, in order.
+fn programs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|n| n == "gg") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+///Run a program file, reading its `.input` file if it has one.
+fn run_file(path: &Path) -> io::Result<String> {
+    let source = fs::read_to_string(path)?;
+    let input = match fs::read_to_string(path.with_extension("input")) {
+        Ok(n) => n,
+        Err(ref n) if n.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(n) => { return Err(n); }
+    };
+
+    Ok(run_source(&source, &input))
+}
+
+///Run every program in a directory against its `.expected` file.
+pub fn run_dir<P: AsRef<Path>>(dir: P) -> io::Result<Report> {
+    let mut report = Report::default();
+
+    for path in programs(dir.as_ref())? {
+        let actual = run_file(&path)?;
+        let expected = match fs::read_to_string(path.with_extension("expected")) {
+            Ok(n) => Some(n),
+            Err(ref n) if n.kind() == io::ErrorKind::NotFound => None,
+            Err(n) => { return Err(n); }
+        };
+
+        if expected.as_ref() == Some(&actual) {
+            report.passed.push(path);
+        } else {
+            report.failed.push(Failure { path, expected, actual });
+        }
+    }
+
+    Ok(report)
+}
+
+///Run every program in a directory and write its transcript to its
+///`.expected` file. Returns how many programs there were.
+pub fn bless_dir<P: AsRef<Path>>(dir: P) -> io::Result<usize> {
+    let paths = programs(dir.as_ref())?;
+    for path in &paths {
+        fs::write(path.with_extension("expected"), run_file(path)?)?;
+    }
+
+    Ok(paths.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use testing::{diff, run_source};
+
+    #[test]
+    fn transcripts() {
+        assert_eq!(run_source("1 2 + .", ""), "3 \nok <0>\n");
+        assert_eq!(run_source("read-line drop type 7", "hi\n"), "hi\nok <1> 7\n");
+        assert_eq!(run_source("1 +", ""), "error: Stack Underflow at 4 <0>\n");
+        assert_eq!(run_source("nope", ""), "compile error: Unknown word: nope\n");
+    }
+
+    #[test]
+    fn diffs() {
+        assert_eq!(diff("a\nb\nc\n", "a\nc\nd\n"), " a\n-b\n c\n+d\n");
+        assert_eq!(diff("", "x\n"), "+x\n");
+    }
+}
@@ -0,0 +1,83 @@
+//!Hooks for watching a program run one instruction at a time.
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use disasm::mnemonic;
+use storage::Storage;
+use Data;
+use Stack;
+
+///Receives a callback around every instruction `Vm::run_traced` executes.
+///Both methods do nothing by default, so implement only what you need.
+///The type parameter is the data stack's storage.
+pub trait Tracer<S: Storage = Vec<Data>> {
+    ///Called with the PC and opcode of an instruction before it runs.
+    fn before_instruction(&mut self, _pc: usize, _opcode: u8, _stack: &Stack<S>) {}
+
+    ///Called with the same PC and opcode once the instruction has run
+    ///without error.
+    fn after_instruction(&mut self, _pc: usize, _opcode: u8, _stack: &Stack<S>) {}
+}
+
+///A tracer that does nothing.
+pub struct NullTracer {}
+impl<S: Storage> Tracer<S> for NullTracer {}
+
+///Logs each instruction's address, mnemonic and the resulting stack depth
+///to stderr. Whitespace is skipped.
+#[cfg(feature = "std")]
+pub struct PrintTracer {}
+
+#[cfg(feature = "std")]
+impl<S: Storage> Tracer<S> for PrintTracer {
+    fn after_instruction(&mut self, pc: usize, opcode: u8, stack: &Stack<S>) {
+        if opcode.is_ascii_whitespace() {
+            return;
+        }
+
+        match mnemonic(opcode) {
+            Some(name) => eprintln!("{:04}  {:<8} depth {}", pc, name, stack.len()),
+            None => eprintln!("{:04}  ext {:#04x} depth {}", pc, opcode, stack.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use trace::Tracer;
+    use {NullExtender, Stack, Vm};
+
+    #[derive(Default)]
+    struct Recorder {
+        seen: Vec<(usize, u8, usize, usize)>,
+        depth: usize,
+    }
+
+    impl Tracer for Recorder {
+        fn before_instruction(&mut self, _pc: usize, _opcode: u8, stack: &Stack) {
+            self.depth = stack.len();
+        }
+
+        fn after_instruction(&mut self, pc: usize, opcode: u8, stack: &Stack) {
+            self.seen.push((pc, opcode, self.depth, stack.len()));
+        }
+    }
+
+    #[test]
+    fn sees_every_instruction() {
+        let mut vm = Vm::new(b"#2'd+".to_vec(), Vec::new());
+        let mut recorder = Recorder::default();
+        vm.run_traced(&mut NullExtender {}, &mut recorder).unwrap();
+
+        assert_eq!(recorder.seen, vec![
+            (0, b'#', 0, 0),
+            (1, b'2', 0, 0),
+            (2, b'\'', 0, 1),
+            (3, b'd', 1, 2),
+            (4, b'+', 2, 1),
+        ]);
+    }
+}
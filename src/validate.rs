@@ -0,0 +1,342 @@
+//!Checking code before it runs, so a host can refuse a bad module when it
+//!is loaded instead of finding out part of the way through a run.
+//!
+//!Only what can be known without running the code is checked: every
+//!byte is an opcode, every literal is finished, and every jump or call
+//!whose target is a literal lands inside the code on the start of an
+//!instruction. Jumps and calls to computed addresses are listed in the
+//!report but not checked.
+//!
+//!`opcode_effect` gives the stack effect of each built-in opcode, which
+//!the compiler uses to work out and check the effects of words.
+
+use alloc::collections::BTreeSet;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use disasm::mnemonic;
+use opcodes::{extended, opcode, ESCAPE};
+use vm::{float_immediate, string_literal};
+
+///The first problem found in some code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    ///A byte that is neither built in nor one of the extender's opcodes.
+    InvalidOpcode { pc: usize, opcode: u8 },
+    ///A literal jump or call past the end of the code.
+    OutOfRange { pc: usize, target: usize },
+    ///A literal jump or call into the middle of a literal, a string, a
+    ///float, a symbolic call or an instruction on the extended page.
+    MidInstruction { pc: usize, target: usize },
+    ///A `#` that isn't finished by `'` or `"`, or a digit, `.`, `$`, `'`
+    ///or `"` outside of one.
+    UnbalancedLiteral { pc: usize },
+    ///A `[` with no closing `]`.
+    UnterminatedString { pc: usize },
+    ///A backtick with no closing backtick.
+    UnterminatedWord { pc: usize },
+    ///A `]` without the eight bytes of its float after it.
+    TruncatedFloat { pc: usize },
+}
+
+impl ValidationError {
+    ///The address of the instruction at fault.
+    pub fn pc(&self) -> usize {
+        match *self {
+            ValidationError::InvalidOpcode { pc, .. } | ValidationError::OutOfRange { pc, .. } |
+            ValidationError::MidInstruction { pc, .. } | ValidationError::UnbalancedLiteral { pc } |
+            ValidationError::UnterminatedString { pc } | ValidationError::UnterminatedWord { pc } |
+            ValidationError::TruncatedFloat { pc } => pc,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::InvalidOpcode { pc, opcode } => write!(f, "Invalid opcode {} at {}", opcode, pc),
+            ValidationError::OutOfRange { pc, target } => write!(f, "Jump to {} past the end of the code at {}", target, pc),
+            ValidationError::MidInstruction { pc, target } => write!(f, "Jump into the middle of an instruction at {} from {}", target, pc),
+            ValidationError::UnbalancedLiteral { pc } => write!(f, "Unbalanced literal at {}", pc),
+            ValidationError::UnterminatedString { pc } => write!(f, "Unterminated string at {}", pc),
+            ValidationError::UnterminatedWord { pc } => write!(f, "Unterminated symbolic call at {}", pc),
+            ValidationError::TruncatedFloat { pc } => write!(f, "Truncated float at {}", pc),
+        }
+    }
+}
+
+///What validation learned about some code that passed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    ///Addresses that literal jumps and calls go to.
+    pub targets: BTreeSet<usize>,
+    ///Addresses of jumps and calls to computed targets, which weren't
+    ///checked.
+    pub dynamic: Vec<usize>,
+    ///The extender opcodes the code uses.
+    pub extender_opcodes: BTreeSet<u8>,
+    ///The names of symbolic calls, left for `Vm::link` to resolve.
+    pub words: BTreeSet<String>,
+}
+
+///How many items some code takes from the data stack, and how many it
+///leaves in their place.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl StackEffect {
+    pub const fn new(inputs: usize, outputs: usize) -> StackEffect {
+        StackEffect { inputs, outputs }
+    }
+
+    ///The effect of running this code and then some more.
+    pub fn then(self, next: StackEffect) -> StackEffect {
+        if self.outputs >= next.inputs {
+            StackEffect::new(self.inputs, self.outputs - next.inputs + next.outputs)
+        } else {
+            StackEffect::new(self.inputs + next.inputs - self.outputs, next.outputs)
+        }
+    }
+
+    ///How much the depth of the stack changes by.
+    pub fn net(self) -> isize {
+        self.outputs as isize - self.inputs as isize
+    }
+}
+
+impl fmt::Display for StackEffect {
+    ///Formats like a Forth stack comment, `( 2 -- 1 )`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "( {} -- {} )", self.inputs, self.outputs)
+    }
+}
+
+///The stack effect of a built-in opcode, or `None` if it depends on the
+///values on the stack, like `P`, or the opcode moves the PC. Taken from
+///`opcodes::OPCODES`, apart from whitespace.
+pub fn opcode_effect(op: u8) -> Option<StackEffect> {
+    match op {
+        b' ' | b'\n' | b'\r' => Some(StackEffect::new(0, 0)),
+        _ => opcode(op).and_then(|n| n.stack_effect),
+    }
+}
+
+///Validate code that uses no extender opcodes.
+pub fn validate(code: &[u8]) -> Result<ValidationReport,ValidationError> {
+    validate_with(code, &[])
+}
+
+///Validate code, allowing the opcodes an extender implements as well as
+///the built-in ones.
+pub fn validate_with(code: &[u8], extender: &[u8]) -> Result<ValidationReport,ValidationError> {
+    let mut report = ValidationReport::default();
+    //Literals, strings, floats and symbolic calls, which nothing may jump
+    //into.
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    let mut jumps: Vec<(usize, Option<usize>)> = Vec::new();
+
+    let mut pc = 0;
+    while pc < code.len() {
+        match code[pc] {
+            b'#' => {
+                let (end, value) = literal(code, pc)?;
+                spans.push((pc, end));
+                pc = end;
+
+                let target = match (code.get(pc), value) {
+                    (Some(b'b'), Some(n)) | (Some(b'c'), Some(n)) | (Some(b'y'), Some(n)) | (Some(b'z'), Some(n)) => {
+                        if n < 0 { None } else { Some(n as usize) }
+                    },
+                    (Some(b'B'), Some(n)) | (Some(b'C'), Some(n)) | (Some(b'Y'), Some(n)) | (Some(b'Z'), Some(n))
+                    | (Some(b'J'), Some(n)) | (Some(b'j'), Some(n)) => {
+                        match n.checked_add(pc as i64 + 1) {
+                            Some(n) if n >= 0 => Some(n as usize),
+                            _ => None,
+                        }
+                    },
+                    (Some(&ESCAPE), Some(n)) if code.get(pc + 1) == Some(&b'c') => {
+                        match n.checked_add(pc as i64 + 2) {
+                            Some(n) if n >= 0 => Some(n as usize),
+                            _ => None,
+                        }
+                    },
+                    _ => { continue; }
+                };
+
+                //A negative or overflowing target can't be in range.
+                jumps.push((pc, Some(target.unwrap_or(usize::MAX))));
+                if code[pc] == ESCAPE {
+                    spans.push((pc, pc + 2));
+                    pc += 1;
+                }
+            },
+            b'0'..=b'9' | b'.' | b'$' | b'\'' | b'"' => {
+                return Err(ValidationError::UnbalancedLiteral { pc });
+            },
+            b'[' => {
+                let next = match string_literal(code, pc + 1) {
+                    Some((_, n)) => n,
+                    None => { return Err(ValidationError::UnterminatedString { pc }); }
+                };
+
+                spans.push((pc, next));
+                pc = next;
+                continue;
+            },
+            b']' => {
+                let next = match float_immediate(code, pc + 1) {
+                    Some((_, n)) => n,
+                    None => { return Err(ValidationError::TruncatedFloat { pc }); }
+                };
+
+                spans.push((pc, next));
+                pc = next;
+                continue;
+            },
+            ESCAPE => {
+                match code.get(pc + 1) {
+                    Some(b'c') => { jumps.push((pc, None)); },
+                    Some(&b) if extended(b).is_some() => {},
+                    Some(&b) => { return Err(ValidationError::InvalidOpcode { pc: pc + 1, opcode: b }); },
+                    None => { return Err(ValidationError::InvalidOpcode { pc, opcode: ESCAPE }); }
+                }
+
+                spans.push((pc, pc + 2));
+                pc += 2;
+                continue;
+            },
+            b'`' => {
+                let end = match code[pc + 1..].iter().position(|&b| b == b'`') {
+                    Some(n) => pc + 1 + n,
+                    None => { return Err(ValidationError::UnterminatedWord { pc }); }
+                };
+
+                report.words.insert(String::from_utf8_lossy(&code[pc + 1..end]).to_string());
+                spans.push((pc, end + 1));
+                pc = end + 1;
+                continue;
+            },
+            b'b' | b'c' | b'y' | b'z' | b'B' | b'C' | b'Y' | b'Z' | b'J' | b'j' => {
+                jumps.push((pc, None));
+            },
+            b' ' | b'\n' | b'\r' => {},
+            b if mnemonic(b).is_some() => {},
+            b if extender.contains(&b) => {
+                report.extender_opcodes.insert(b);
+            },
+            b => {
+                return Err(ValidationError::InvalidOpcode { pc, opcode: b });
+            },
+        }
+
+        pc += 1;
+    }
+
+    for (pc, target) in jumps {
+        let target = match target {
+            Some(n) => n,
+            None => {
+                report.dynamic.push(pc);
+                continue;
+            }
+        };
+
+        if target > code.len() {
+            return Err(ValidationError::OutOfRange { pc, target });
+        }
+
+        //Spans are in address order and don't overlap.
+        let span = match spans.binary_search_by(|n| n.0.cmp(&target)) {
+            Ok(_) => None,
+            Err(0) => None,
+            Err(n) => Some(spans[n - 1]),
+        };
+        if let Some((_, end)) = span {
+            if target < end {
+                return Err(ValidationError::MidInstruction { pc, target });
+            }
+        }
+
+        report.targets.insert(target);
+    }
+
+    Ok(report)
+}
+
+///Scan the literal starting at the `#` at `start`, returning the address
+///after it and its value if it is an int that fits.
+fn literal(code: &[u8], start: usize) -> Result<(usize, Option<i64>),ValidationError> {
+    let mut value = Some(0i64);
+    let mut pc = start + 1;
+
+    loop {
+        match code.get(pc) {
+            Some(&b) if b.is_ascii_digit() => {
+                value = value.and_then(|n| n.checked_mul(10)).and_then(|n| n.checked_add((b - b'0') as i64));
+            },
+            Some(b'$') => { value = value.and_then(i64::checked_neg); },
+            Some(b'.') | Some(b' ') | Some(b'\n') | Some(b'\r') => {},
+            Some(b'\'') => { return Ok((pc + 1, value)); },
+            Some(b'"') => { return Ok((pc + 1, None)); },
+            _ => { return Err(ValidationError::UnbalancedLiteral { pc: start }); }
+        }
+        pc += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    use compiler::compile;
+    use validate::{opcode_effect, validate, validate_with, StackEffect, ValidationError};
+
+    #[test]
+    fn checks() {
+        let report = validate(b"#00012'c #3'#1'B [a]r ; `sq` #1.5\"r y;").unwrap();
+        assert_eq!(report.targets.iter().cloned().collect::<Vec<_>>(), vec![12, 17]);
+        assert_eq!(report.dynamic, vec![36]);
+        assert!(report.words.contains("sq"));
+
+        let code = compile(": sq dup * ; : f 1.5 - ; 3 sq . 2.25 f s\" x\" type").unwrap();
+        assert!(validate(&code).is_ok());
+
+        assert_eq!(validate(b"d\xf0"), Err(ValidationError::InvalidOpcode { pc: 1, opcode: 0xf0 }));
+        assert_eq!(validate_with(b"d\xf0", b"\xf0").unwrap().extender_opcodes.len(), 1);
+        assert!(validate(b"#2'!{#0'!R!}").is_ok());
+        assert_eq!(validate(b"d!+"), Err(ValidationError::InvalidOpcode { pc: 2, opcode: b'+' }));
+        assert_eq!(validate(b"d!"), Err(ValidationError::InvalidOpcode { pc: 1, opcode: b'!' }));
+        assert_eq!(validate(b"#5'b!}"), Err(ValidationError::MidInstruction { pc: 3, target: 5 }));
+        assert_eq!(validate(b"#1'!c;;").unwrap().targets.iter().cloned().collect::<Vec<_>>(), vec![6]);
+        assert_eq!(validate(b"#1'!c!t").unwrap_err(), ValidationError::MidInstruction { pc: 3, target: 6 });
+        assert_eq!(validate(b"!c").unwrap().dynamic, vec![0]);
+        assert_eq!(validate(b"#9'b"), Err(ValidationError::OutOfRange { pc: 3, target: 9 }));
+        assert_eq!(validate(b"#9$'B"), Err(ValidationError::OutOfRange { pc: 4, target: usize::MAX }));
+        assert_eq!(validate(b"#6'b#12'"), Err(ValidationError::MidInstruction { pc: 3, target: 6 }));
+        assert!(validate(b"#4'b#1'").is_ok());
+        assert_eq!(validate(b"#12+"), Err(ValidationError::UnbalancedLiteral { pc: 0 }));
+        assert_eq!(validate(b"d 5"), Err(ValidationError::UnbalancedLiteral { pc: 2 }));
+        assert_eq!(validate(b"[ab"), Err(ValidationError::UnterminatedString { pc: 0 }));
+        assert_eq!(validate(b"`ab"), Err(ValidationError::UnterminatedWord { pc: 0 }));
+        assert_eq!(validate(b"#6'b]#;'[`#@r"), Err(ValidationError::MidInstruction { pc: 3, target: 6 }));
+        assert_eq!(validate(b"d]\x00\x00"), Err(ValidationError::TruncatedFloat { pc: 1 }));
+    }
+
+    #[test]
+    fn effects() {
+        //`dup *` squares, `over +` keeps NOS.
+        let square = opcode_effect(b'd').unwrap().then(opcode_effect(b'*').unwrap());
+        assert_eq!(square, StackEffect::new(1, 1));
+        let keep = opcode_effect(b'v').unwrap().then(opcode_effect(b'+').unwrap());
+        assert_eq!(keep, StackEffect::new(2, 2));
+        assert_eq!(StackEffect::new(0, 1).then(StackEffect::new(3, 0)), StackEffect::new(2, 0));
+        assert_eq!(StackEffect::new(2, 0).net(), -2);
+        assert_eq!(StackEffect::new(2, 1).to_string(), "( 2 -- 1 )");
+        assert_eq!(opcode_effect(b'P'), None);
+    }
+}
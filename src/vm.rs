@@ -0,0 +1,2542 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use coverage::{Coverage, CoverageReport};
+use debuginfo::{DebugInfo, SourceLocation};
+use heap::{Heap, Object};
+use host::HostFunctions;
+#[cfg(feature = "async")]
+use host::HostFuture;
+#[cfg(feature = "std")]
+use input::StdinInput;
+#[cfg(not(feature = "std"))]
+use input::NullInput;
+use input::InputProvider;
+use ir::{fold, Instruction, Op, Program};
+use memmap::MemoryMap;
+use module::{write_data, Dictionary, Global, Module};
+use opcodes::{self, stack_op, ESCAPE};
+#[cfg(not(feature = "std"))]
+use output::NullOutput;
+use output::OutputSink;
+use rng::Rng;
+use storage::Storage;
+use trace::{NullTracer, Tracer};
+use AtomExtender;
+use Context;
+use Data;
+use Error;
+use Frame;
+use RuntimeError;
+use Stack;
+use TypeTag;
+
+mod globals;
+mod sandbox;
+mod stats;
+mod task;
+#[cfg(feature = "threaded")]
+mod threaded;
+
+use self::sandbox::{check_array, check_memory};
+pub use self::sandbox::SandboxConfig;
+pub use self::stats::ExecutionStats;
+pub use self::task::Task;
+
+///Whether the machine can keep executing after a step.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Running,
+    ///The running task gave up its turn with `w`.
+    Paused,
+    ///The code handed control back to the host with `i`.
+    Yielded,
+    Halted,
+}
+
+///What the `R` and `W` opcodes do with an address past the end of memory.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum MemoryPolicy {
+    ///Take the address modulo the memory size, so -1 is the last cell.
+    #[default]
+    Wrap,
+    ///Fail with `MemoryOutOfBounds`.
+    Trap,
+    ///Extend memory with zeroes until the address fits.
+    Grow,
+}
+
+///What integer arithmetic does when the result doesn't fit in an `i64`.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum ArithmeticPolicy {
+    ///Wrap around in two's complement.
+    #[default]
+    Wrapping,
+    ///Clamp to the nearest representable value.
+    Saturating,
+    ///Fail with `IntegerOverflow`.
+    Trapping,
+}
+
+///What arithmetic and comparisons do with an int and a float.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum CoercionPolicy {
+    ///Fail with `TypeMismatch`.
+    #[default]
+    Strict,
+    ///Convert the int to a float first, so `1 2.5 +` gives 3.5.
+    Promote,
+}
+
+impl ArithmeticPolicy {
+    ///Pick the result of an operation computed each of the three ways.
+    #[inline]
+    pub(crate) fn apply(self, checked: Option<i64>, wrapping: i64, saturating: i64) -> Result<i64,Error> {
+        match self {
+            ArithmeticPolicy::Wrapping => Ok(wrapping),
+            ArithmeticPolicy::Saturating => Ok(saturating),
+            ArithmeticPolicy::Trapping => checked.ok_or(Error::IntegerOverflow),
+        }
+    }
+}
+
+///Turn an address and a length from the stack into a range of byte
+///memory. Byte memory never wraps: a range past its end fails with
+///`MemoryOutOfBounds` unless the policy is to grow, up to a sandbox's
+///limit and what can be allocated.
+fn byte_range(bytes: &mut Vec<u8>, config: &RunConfig, addr: i64, len: i64) -> Result<Range<usize>,Error> {
+    let end = match (addr, len) {
+        (0.., 0..) => (addr as u64).checked_add(len as u64),
+        _ => None,
+    };
+    let end = match end {
+        Some(n) if n <= bytes.len() as u64 => n as usize,
+        Some(n) if config.memory == MemoryPolicy::Grow && n <= usize::MAX as u64 => {
+            check_memory(config, n as usize)?;
+            if bytes.try_reserve(n as usize - bytes.len()).is_err() {
+                return Err(Error::MemoryOutOfBounds { addr, len: bytes.len() });
+            }
+            bytes.resize(n as usize, 0);
+            n as usize
+        },
+        _ => { return Err(Error::MemoryOutOfBounds { addr, len: bytes.len() }); }
+    };
+
+    Ok(addr as usize..end)
+}
+
+///Run one of the byte memory instructions on the extended page.
+fn byte_op<S: Storage>(op: u8, stack: &mut Stack<S>, bytes: &mut Vec<u8>, config: &RunConfig) -> Result<(),Error> {
+    match op {
+        b'B' => {   //Write NOS to the byte at TOS.
+            let addr = stack.pop_int()?;
+            let value = stack.pop_int()?;
+            let range = byte_range(bytes, config, addr, 1)?;
+            bytes[range.start] = value as u8;
+        },
+        b'S' => {   //Copy the bytes of a string in NOS to the address in TOS, pushing how many.
+            let addr = stack.pop_int()?;
+            let text = match stack.pop()? {
+                Data::Str(n) => n,
+                other => { return Err(Error::TypeMismatch { expected: TypeTag::Str, found: other.type_tag() }); }
+            };
+            let range = byte_range(bytes, config, addr, text.len() as i64)?;
+            bytes[range].copy_from_slice(text.as_bytes());
+            stack.try_push(Data::Int(text.len() as i64))?;
+        },
+        b'b' => {   //Replace an address with the byte there.
+            let addr = stack.pop_int()?;
+            let range = byte_range(bytes, config, addr, 1)?;
+            stack.try_push(Data::Int(bytes[range.start] as i64))?;
+        },
+        b'f' => {   //Fill a range of bytes, given by address and length, with TOS.
+            let value = stack.pop_int()?;
+            let len = stack.pop_int()?;
+            let addr = stack.pop_int()?;
+            let range = byte_range(bytes, config, addr, len)?;
+            bytes[range].fill(value as u8);
+        },
+        b'm' => {   //Copy TOS bytes from the address in the third item to the one in NOS.
+            let len = stack.pop_int()?;
+            let to = stack.pop_int()?;
+            let from = stack.pop_int()?;
+            let source = byte_range(bytes, config, from, len)?;
+            let target = byte_range(bytes, config, to, len)?;
+            bytes.copy_within(source, target.start);
+        },
+        b's' => {   //Replace an address and a length with a string of those bytes.
+            let len = stack.pop_int()?;
+            let addr = stack.pop_int()?;
+            let range = byte_range(bytes, config, addr, len)?;
+            let text = String::from_utf8_lossy(&bytes[range]).into_owned();
+            stack.try_push(Data::Str(Arc::from(text)))?;
+        },
+        _ => { return Err(Error::InvalidInstruction { opcode: op }); }
+    }
+
+    Ok(())
+}
+
+///Turn an address from the stack into an index into memory under the
+///configured policy. Empty memory can't be wrapped, and memory can't
+///grow past a sandbox's limit.
+fn resolve<M: Storage>(memory: &mut M, config: &RunConfig, address: i64) -> Result<usize,Error> {
+    match config.memory {
+        MemoryPolicy::Wrap => {
+            if memory.is_empty() {
+                return Err(Error::MemoryOutOfBounds { addr: address, len: 0 });
+            }
+            Ok(address.rem_euclid(memory.len() as i64) as usize)
+        },
+        MemoryPolicy::Trap => {
+            if address < 0 || address as usize >= memory.len() {
+                return Err(Error::MemoryOutOfBounds { addr: address, len: memory.len() });
+            }
+            Ok(address as usize)
+        },
+        MemoryPolicy::Grow => {
+            if address < 0 {
+                return Err(Error::MemoryOutOfBounds { addr: address, len: memory.len() });
+            }
+            check_memory(config, (address as usize).saturating_add(1))?;
+            if !memory.grow(address as usize + 1) {
+                return Err(Error::MemoryOutOfBounds { addr: address, len: memory.len() });
+            }
+            Ok(address as usize)
+        },
+    }
+}
+
+///Resolve a relative jump offset against the address after the jump. A
+///target too far to count lands past the end of any code.
+#[inline]
+fn relative(opcode: u8, pc: usize, offset: Data) -> Result<usize,Error> {
+    match offset {
+        Data::Int(n) => {
+            let target = (pc as i64).saturating_add(n);
+            if target < 0 {
+                return Err(Error::InvalidInstruction { opcode });
+            }
+            Ok(target as usize)
+        },
+        other => Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() }),
+    }
+}
+
+///Pop a non-negative int for allot and free from memory of some length.
+fn pop_count<S: Storage>(stack: &mut Stack<S>, len: usize) -> Result<usize,Error> {
+    match stack.pop() {
+        Err(n) => Err(n),
+        Ok(Data::Int(n)) if n >= 0 => Ok(n as usize),
+        Ok(Data::Int(n)) => Err(Error::MemoryOutOfBounds { addr: n, len }),
+        Ok(other) => Err(Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() }),
+    }
+}
+
+///Steps a run takes between looks at the cancellation token and the
+///clock.
+const POLL_INTERVAL: u64 = 1024;
+
+///Limits and policies applied by `Vm::run`.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    ///Maximum number of instructions a single call to `run` may execute
+    ///before failing with `FuelExhausted`, or with a `cost` model, the
+    ///most fuel it may use. `None` means no limit.
+    pub max_steps: Option<u64>,
+    ///Maximum number of nested calls before failing with
+    ///`ReturnStackOverflow`.
+    pub max_return_depth: usize,
+    ///How out-of-range memory addresses are handled.
+    pub memory: MemoryPolicy,
+    ///How integer overflow in arithmetic and literals is handled.
+    pub arithmetic: ArithmeticPolicy,
+    ///How arithmetic and comparisons mix ints with floats.
+    pub coercion: CoercionPolicy,
+    ///Refuse extender opcodes that don't promise to be deterministic,
+    ///failing with `Nondeterministic`. The built-in opcodes always are:
+    ///float arithmetic is plain IEEE 754 double precision with
+    ///round-to-nearest-even and no fused operations, and the only
+    ///randomness is the VM's seeded generator. Together with the same
+    ///code, input and seed this makes `Vm::digest` reproducible.
+    pub deterministic: bool,
+    ///Fuse common pairs of instructions and fold arithmetic on literals
+    ///when decoding for `run`; see `ir::Program::optimize`. Results are
+    ///the same either way, so this is only worth turning off to rule the
+    ///optimizer out while debugging.
+    pub optimize: bool,
+    ///How many times `run` calls a word, or goes round a loop, before
+    ///compiling it to threaded code. `None` never compiles anything.
+    #[cfg(feature = "threaded")]
+    pub hot_threshold: Option<u32>,
+    ///Limits for code that isn't trusted; see `Vm::sandboxed`. Runs
+    ///under a sandbox skip the optimizer and threaded code so every
+    ///instruction can be checked.
+    pub sandbox: Option<SandboxConfig>,
+    ///Mark every instruction run in `Vm::coverage`. Like a sandbox, this
+    ///skips the optimizer and threaded code.
+    pub coverage: bool,
+    ///What each instruction costs in fuel, by its first byte, instead of
+    ///one each. An extender can price its own opcodes with
+    ///`AtomExtender::cost`; everything on the extended page costs what
+    ///`!` does. Like a sandbox, this skips the optimizer and threaded
+    ///code.
+    pub cost: Option<fn(u8) -> u64>,
+}
+
+impl Default for RunConfig {
+    fn default() -> RunConfig {
+        RunConfig {
+            max_steps: None,
+            max_return_depth: 1024,
+            memory: MemoryPolicy::Wrap,
+            arithmetic: ArithmeticPolicy::Wrapping,
+            coercion: CoercionPolicy::Strict,
+            deterministic: false,
+            optimize: true,
+            #[cfg(feature = "threaded")]
+            hot_threshold: Some(64),
+            sandbox: None,
+            coverage: false,
+            cost: None,
+        }
+    }
+}
+
+///Read a string literal that starts at `start`, just after its opening
+///bracket. A backslash escapes the byte after it. Returns the string and
+///the address following the closing bracket.
+pub fn string_literal(code: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut bytes = Vec::new();
+    let mut pc = start;
+
+    while pc < code.len() {
+        match code[pc] {
+            b']' => { return Some((String::from_utf8_lossy(&bytes).into_owned(), pc + 1)); }
+            b'\\' if pc + 1 < code.len() => {
+                bytes.push(code[pc + 1]);
+                pc += 2;
+            },
+            b => {
+                bytes.push(b);
+                pc += 1;
+            },
+        }
+    }
+
+    None
+}
+
+///Read the float given in full by the eight bytes at `start`, just after
+///a `]`, least significant first. Returns it and the address after it.
+pub fn float_immediate(code: &[u8], start: usize) -> Option<(f64, usize)> {
+    let bytes = code.get(start..start.checked_add(8)?)?;
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+
+    Some((f64::from_le_bytes(buf), start + 8))
+}
+
+///A `catch` waiting for the word it called to return or fail: what to
+///put back on an error, and where to carry on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CatchFrame {
+    ///Depth of the data stack once the offset was popped.
+    pub stack: usize,
+    ///Depth of the return stack before the call.
+    pub rstack: usize,
+    ///How many frames of locals there were.
+    pub frames: usize,
+    ///The address after the `catch`.
+    pub next: usize,
+}
+
+///Everything a `Vm` needs to carry on from where it stopped, apart from
+///its code, dictionary, configuration and I/O.
+///
+///Waiting tasks are left out too, since their stacks may be over storage
+///a snapshot has no way to rebuild. Take one when `tasks` is empty to
+///catch the whole machine.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VmState {
+    pub pc: usize,
+    pub stack: Vec<Data>,
+    pub rstack: Vec<usize>,
+    pub locals: Vec<Data>,
+    pub frames: Vec<usize>,
+    pub catches: Vec<CatchFrame>,
+    pub memory: Vec<Data>,
+    pub bytes: Vec<u8>,
+    ///The literal being built by `#`, digits, `.` and `$`.
+    pub value: i64,
+    pub divider: f64,
+    pub rng: Rng,
+    pub heap: Heap,
+}
+
+///A persistent virtual machine. Owns the code it is running along with
+///all of the state that `run` used to keep on its own stack frame, so
+///execution can be paused, inspected and resumed by the host.
+///
+///The data stack and memory are `Vec`s unless other storage is given to
+///`with_storage`; see `storage`.
+pub struct Vm<S = Vec<Data>, M = Vec<Data>> {
+    pub stack: Stack<S>,
+    pub memory: M,
+    ///Byte memory, apart from the cells, for packed binary data. It
+    ///starts empty; a host can fill it before a run, and the byte words
+    ///grow it under `MemoryPolicy::Grow`.
+    pub bytes: Vec<u8>,
+    pub rstack: Vec<usize>,
+    ///The locals of every frame, the current one last.
+    pub locals: Vec<Data>,
+    ///Where each frame starts in `locals`. The last is the frame pointer.
+    pub frames: Vec<usize>,
+    ///The `catch`es waiting, innermost last.
+    pub catches: Vec<CatchFrame>,
+    pub pc: usize,
+    pub dictionary: Dictionary,
+    ///Where the code came from, for locating errors in the source.
+    pub debug_info: Option<DebugInfo>,
+    ///The constant pool the `\` opcode reads from.
+    pub constants: Vec<Data>,
+    ///The names of variables and constants, from the module; see
+    ///`write_inputs` and `read_output`.
+    pub globals: BTreeMap<String, Global>,
+    pub config: RunConfig,
+    ///Where the output words and the debug `p` opcode write. Standard
+    ///output by default, or nowhere without the `std` feature; replace it
+    ///to capture or discard output.
+    pub output: Box<dyn OutputSink + Send>,
+    ///Where the input words read from. Standard input by default, or
+    ///always at its end without the `std` feature.
+    pub input: Box<dyn InputProvider + Send>,
+    ///The generator behind the random words.
+    pub rng: Rng,
+    ///Arrays and other objects reached through handles.
+    pub heap: Heap,
+    ///Addresses `R` and `W` hand to the host instead of memory.
+    pub memory_map: MemoryMap,
+    ///Handles the host is holding on to. `gc` keeps these and anything
+    ///they refer to alive.
+    pub roots: Vec<Data>,
+    ///Closures the `h` opcode can call.
+    pub host: HostFunctions<S>,
+    ///Tasks waiting for a turn; see `run_round_robin`.
+    pub tasks: VecDeque<Task<S>>,
+    ///The instructions run while `RunConfig::coverage` was set.
+    pub coverage: Coverage,
+    code: Vec<u8>,
+    links: BTreeMap<usize, (usize, usize)>,
+    ///The decoded code `run` executes, built on first use.
+    program: Option<Arc<Program>>,
+    ///Set by the host to stop a run; see `cancellation_token`.
+    cancel: Option<Arc<AtomicBool>>,
+    ///When the current `run_with_timeout` has to stop.
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+    ///Calls and loops counted, and words compiled, by `run`, by address.
+    #[cfg(feature = "threaded")]
+    words: Vec<threaded::Word<S, M>>,
+    value: i64,
+    divider: f64,
+}
+
+impl Vm {
+    ///Create a machine for some code, with PC at zero and an empty stack.
+    pub fn new(code: Vec<u8>, memory: Vec<Data>) -> Vm {
+        Vm::with_storage(code, Vec::new(), memory)
+    }
+
+    ///Create a machine for a module, resolving its symbolic calls
+    ///against its dictionary. The module's data section is copied over
+    ///the start of memory, growing it if needed.
+    pub fn from_module(module: Module, memory: Vec<Data>) -> Result<Vm,RuntimeError> {
+        Vm::from_module_with_storage(module, Vec::new(), memory)
+    }
+}
+
+impl<S: Storage, M: Storage> Vm<S, M> {
+    ///Create a machine whose data stack and memory use some storage,
+    ///keeping anything already in it.
+    pub fn with_storage(code: Vec<u8>, stack: S, memory: M) -> Vm<S, M> {
+        Vm {
+            stack: Stack::with_storage(stack),
+            memory,
+            bytes: Vec::new(),
+            rstack: Vec::new(),
+            locals: Vec::new(),
+            frames: Vec::new(),
+            catches: Vec::new(),
+            pc: 0,
+            dictionary: Dictionary::new(),
+            debug_info: None,
+            constants: Vec::new(),
+            globals: BTreeMap::new(),
+            config: RunConfig::default(),
+            #[cfg(feature = "std")]
+            output: Box::new(io::stdout()),
+            #[cfg(not(feature = "std"))]
+            output: Box::new(NullOutput {}),
+            #[cfg(feature = "std")]
+            input: Box::new(StdinInput {}),
+            #[cfg(not(feature = "std"))]
+            input: Box::new(NullInput {}),
+            rng: Rng::default(),
+            heap: Heap::new(),
+            memory_map: MemoryMap::new(),
+            roots: Vec::new(),
+            host: HostFunctions::default(),
+            tasks: VecDeque::new(),
+            coverage: Coverage::new(),
+            code,
+            links: BTreeMap::new(),
+            program: None,
+            cancel: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            #[cfg(feature = "threaded")]
+            words: Vec::new(),
+            value: 0,
+            divider: 1.0,
+        }
+    }
+
+    ///Like `from_module`, with the given storage. Fails with
+    ///`MemoryOutOfBounds` if the data section doesn't fit in memory.
+    pub fn from_module_with_storage(module: Module, stack: S, mut memory: M) -> Result<Vm<S, M>,RuntimeError> {
+        if !memory.grow(module.data.len()) {
+            let len = memory.len();
+            return Err(RuntimeError::new(0, Error::MemoryOutOfBounds { addr: module.data.len() as i64 - 1, len }));
+        }
+        for (cell, value) in memory.as_mut_slice().iter_mut().zip(module.data) {
+            *cell = value;
+        }
+
+        let mut vm = Vm::with_storage(module.code, stack, memory);
+        vm.dictionary = module.dictionary;
+        vm.debug_info = module.debug_info;
+        vm.constants = module.constants;
+        vm.globals = module.globals;
+        vm.link()?;
+
+        Ok(vm)
+    }
+
+    ///Resolve every symbolic call (`` `name` ``) in the code against the
+    ///dictionary. Fails with the address of the first call that names an
+    ///unknown word.
+    pub fn link(&mut self) -> Result<(),RuntimeError> {
+        self.links.clear();
+        self.program = None;
+        #[cfg(feature = "threaded")]
+        self.words.clear();
+
+        let mut pc = 0;
+        while pc < self.code.len() {
+            if self.code[pc] == b'[' {
+                pc = match string_literal(&self.code, pc + 1) {
+                    Some((_, next)) => next,
+                    None => { return Err(RuntimeError::new(pc, Error::InvalidInstruction { opcode: b'[' })); }
+                };
+                continue;
+            }
+
+            if self.code[pc] == b']' {
+                pc = match float_immediate(&self.code, pc + 1) {
+                    Some((_, next)) => next,
+                    None => { return Err(RuntimeError::new(pc, Error::InvalidInstruction { opcode: b']' })); }
+                };
+                continue;
+            }
+
+            if self.code[pc] == ESCAPE {
+                pc += 2;
+                continue;
+            }
+
+            if self.code[pc] != b'`' {
+                pc += 1;
+                continue;
+            }
+
+            let end = match self.code[pc + 1..].iter().position(|&b| b == b'`') {
+                Some(n) => pc + 1 + n,
+                None => { return Err(RuntimeError::new(pc, Error::InvalidInstruction { opcode: b'`' })); }
+            };
+
+            let target = String::from_utf8_lossy(&self.code[pc + 1..end]);
+            let target = match self.dictionary.get(&target) {
+                Some(n) => n,
+                None => { return Err(RuntimeError::new(pc, Error::UnknownWord)); }
+            };
+
+            self.links.insert(pc, (target, end + 1));
+            pc = end + 1;
+        }
+
+        Ok(())
+    }
+
+    ///Replace the code and start again from PC 0. The data stack and
+    ///memory are kept, so a host can feed a program in piece by piece.
+    pub fn load(&mut self, code: Vec<u8>) -> Result<(),RuntimeError> {
+        self.code = code;
+        self.rstack.clear();
+        self.locals.clear();
+        self.frames.clear();
+        self.catches.clear();
+        self.pc = 0;
+        self.value = 0;
+        self.divider = 1.0;
+
+        self.link()
+    }
+
+    ///Restart the random generator from a seed. Every machine starts
+    ///seeded with 0, so seed from something like the clock to get
+    ///different numbers on each run.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Rng::seeded(seed);
+    }
+
+    ///Bind a closure to a name so programs can call it, returning its
+    ///index. See `HostFunctions::bind`.
+    pub fn bind<F>(&mut self, name: &str, function: F) -> usize
+    where F: FnMut(&mut Stack<S>) -> Result<(),Error> + Send + 'static {
+        self.host.bind(name, function)
+    }
+
+    ///Bind an async host function; see `HostFunctions::bind_async`.
+    #[cfg(feature = "async")]
+    pub fn bind_async<F>(&mut self, name: &str, function: F) -> usize
+    where F: FnMut(&mut Stack<S>) -> Result<HostFuture,Error> + Send + 'static {
+        self.host.bind_async(name, function)
+    }
+
+    ///Free every heap object that can't be reached from the data stack,
+    ///memory, the locals, `roots` or a waiting task's data stack or
+    ///locals, returning how many were freed. The return stack only holds
+    ///addresses, so it never keeps anything alive.
+    pub fn gc(&mut self) -> usize {
+        let tasks = self.tasks.iter().flat_map(|n| n.stack.iter().chain(n.locals.iter()));
+        let roots = self.stack.iter().chain(self.memory.as_slice().iter()).chain(self.locals.iter()).chain(self.roots.iter()).chain(tasks);
+        self.heap.collect(roots)
+    }
+
+    ///Capture the running state so it can be restored later, possibly in
+    ///another machine loaded with the same code.
+    pub fn snapshot(&self) -> VmState {
+        VmState {
+            pc: self.pc,
+            stack: self.stack.as_slice().to_vec(),
+            rstack: self.rstack.clone(),
+            locals: self.locals.clone(),
+            frames: self.frames.clone(),
+            catches: self.catches.clone(),
+            memory: self.memory.as_slice().to_vec(),
+            bytes: self.bytes.clone(),
+            value: self.value,
+            divider: self.divider,
+            rng: self.rng.clone(),
+            heap: self.heap.clone(),
+        }
+    }
+
+    ///Replace the running state with a snapshot. Any waiting tasks are
+    ///dropped, as a snapshot has none.
+    ///
+    ///Fails if fixed-capacity storage is too small for it, with
+    ///`StackOverflow` for the data stack or `MemoryOutOfBounds` for
+    ///memory, and leaves the machine as it was.
+    pub fn restore(&mut self, state: VmState) -> Result<(),Error> {
+        let stack = self.stack.as_slice().to_vec();
+        let memory = self.memory.as_slice().to_vec();
+        if let Err(err) = self.fill(state.stack, state.memory) {
+            //These fit before, so they fit again.
+            let _ = self.fill(stack, memory);
+            return Err(err);
+        }
+
+        self.pc = state.pc;
+        self.rstack = state.rstack;
+        self.locals = state.locals;
+        self.frames = state.frames;
+        self.catches = state.catches;
+        self.bytes = state.bytes;
+        self.value = state.value;
+        self.divider = state.divider;
+        self.rng = state.rng;
+        self.heap = state.heap;
+        self.tasks.clear();
+
+        Ok(())
+    }
+
+    ///Replace the data stack and memory.
+    fn fill(&mut self, stack: Vec<Data>, memory: Vec<Data>) -> Result<(),Error> {
+        self.stack.clear();
+        for value in stack {
+            self.stack.try_push(value)?;
+        }
+        self.memory.truncate(0);
+        for (n, value) in memory.into_iter().enumerate() {
+            if self.memory.try_push(value).is_err() {
+                return Err(Error::MemoryOutOfBounds { addr: n as i64, len: n });
+            }
+        }
+
+        Ok(())
+    }
+
+    ///A 64-bit FNV-1a hash of the running state: PC, both stacks, the
+    ///locals and catches, both memories, the literal being built, the
+    ///generator and the heap. Two runs that should have done the same
+    ///thing can be compared with this.
+    pub fn digest(&self) -> u64 {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.pc as u64).to_le_bytes());
+
+        bytes.extend_from_slice(&(self.stack.len() as u64).to_le_bytes());
+        for value in self.stack.iter() {
+            write_data(&mut bytes, value);
+        }
+
+        bytes.extend_from_slice(&(self.rstack.len() as u64).to_le_bytes());
+        for &address in &self.rstack {
+            bytes.extend_from_slice(&(address as u64).to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.locals.len() as u64).to_le_bytes());
+        for value in &self.locals {
+            write_data(&mut bytes, value);
+        }
+        bytes.extend_from_slice(&(self.frames.len() as u64).to_le_bytes());
+        for &start in &self.frames {
+            bytes.extend_from_slice(&(start as u64).to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.catches.len() as u64).to_le_bytes());
+        for catch in &self.catches {
+            for &n in &[catch.stack, catch.rstack, catch.frames, catch.next] {
+                bytes.extend_from_slice(&(n as u64).to_le_bytes());
+            }
+        }
+
+        bytes.extend_from_slice(&(self.memory.len() as u64).to_le_bytes());
+        for value in self.memory.as_slice() {
+            write_data(&mut bytes, value);
+        }
+        bytes.extend_from_slice(&(self.bytes.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.bytes);
+
+        bytes.extend_from_slice(&self.value.to_le_bytes());
+        bytes.extend_from_slice(&self.divider.to_bits().to_le_bytes());
+        for word in &self.rng.state() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+
+        for (handle, object) in self.heap.iter() {
+            bytes.extend_from_slice(&(handle as u64).to_le_bytes());
+            match *object {
+                Object::Array(ref cells) => {
+                    bytes.extend_from_slice(&(cells.len() as u64).to_le_bytes());
+                    for value in cells {
+                        write_data(&mut bytes, value);
+                    }
+                },
+                Object::Map(ref entries) => {
+                    bytes.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+                    for (key, value) in entries {
+                        write_data(&mut bytes, &Data::from(key.clone()));
+                        write_data(&mut bytes, value);
+                    }
+                },
+            }
+        }
+
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    ///The memory size, which is the address the next allotted cell gets.
+    pub fn here(&self) -> usize {self.memory.len()}
+
+    ///Extend memory by some zeroed cells, returning the address of the
+    ///first. Fails with `MemoryOutOfBounds`, leaving memory alone, if
+    ///fixed-capacity storage can't hold them.
+    pub fn allot(&mut self, cells: usize) -> Result<usize,Error> {
+        let here = self.memory.len();
+        if !self.memory.grow(here + cells) {
+            self.memory.truncate(here);
+            return Err(Error::MemoryOutOfBounds { addr: (here + cells) as i64 - 1, len: here });
+        }
+
+        Ok(here)
+    }
+
+    ///Release cells from the end of memory.
+    pub fn release(&mut self, cells: usize) -> Result<(),Error> {
+        if cells > self.memory.len() {
+            return Err(Error::MemoryOutOfBounds { addr: self.memory.len() as i64 - cells as i64, len: self.memory.len() });
+        }
+
+        let len = self.memory.len() - cells;
+        self.memory.truncate(len);
+
+        Ok(())
+    }
+
+    ///The code being executed.
+    pub fn code(&self) -> &[u8] {&self.code}
+
+    ///Find where in the source the instruction at an address came from.
+    pub fn locate(&self, pc: usize) -> Option<SourceLocation> {
+        self.debug_info.as_ref().and_then(|n| n.locate(pc))
+    }
+
+    ///Total up which of the machine's instructions have run while
+    ///`RunConfig::coverage` was set.
+    pub fn coverage_report(&self) -> CoverageReport {
+        self.coverage.report(&self.code, &self.dictionary, self.debug_info.as_ref())
+    }
+
+    ///Record the return stack as the call chain of an error leaving the
+    ///machine, naming each word from the dictionary by the call that
+    ///entered it, or else from the debug info.
+    #[cold]
+    pub(crate) fn with_backtrace(&self, mut err: RuntimeError) -> RuntimeError {
+        if !err.backtrace.is_empty() {
+            return err;
+        }
+
+        let mut callees = BTreeMap::new();
+        for instruction in Program::decode_linked(&self.code, &self.links).instructions() {
+            if let Op::Call(target) = instruction.op {
+                callees.insert(instruction.next, target);
+            }
+        }
+        let callee = |address: Option<&usize>| {
+            address.and_then(|n| callees.get(n))
+                .and_then(|&n| self.dictionary.name_at(n))
+                .map(String::from)
+        };
+
+        //Each frame's PC is just past the instruction that failed or made
+        //the call.
+        let frame = |pc: usize, caller: Option<&usize>| {
+            let location = pc.checked_sub(1).and_then(|n| self.locate(n));
+            let word = callee(caller).or_else(|| location.as_ref().and_then(|n| n.word.clone()));
+            Frame { pc, word, location }
+        };
+
+        err.backtrace.push(frame(err.pc, self.rstack.last()));
+        for (depth, &address) in self.rstack.iter().enumerate().rev() {
+            err.backtrace.push(frame(address, depth.checked_sub(1).and_then(|n| self.rstack.get(n))));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::warn!(pc = err.pc, word = err.backtrace[0].word.as_deref(), error = %err.kind, "run failed");
+
+        err
+    }
+
+    ///Log a call to a word, with the `tracing` feature. A word can be left
+    ///by a return, a jump, a throw or a yield, so calls are events rather
+    ///than spans that would need closing.
+    #[inline(always)]
+    fn log_call(&self, _target: usize) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(pc = _target, word = self.dictionary.name_at(_target), "call");
+    }
+
+    ///Rewind to the start of the code, clearing both stacks, any
+    ///half-built literal and any waiting tasks. Memory is left alone.
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.rstack.clear();
+        self.locals.clear();
+        self.frames.clear();
+        self.catches.clear();
+        self.tasks.clear();
+        self.pc = 0;
+        self.value = 0;
+        self.divider = 1.0;
+    }
+
+    ///Run until the code falls off the end, returns with an empty
+    ///return stack, or yields with `i`. After a yield `is_finished` is
+    ///false and `resume` carries on from the next instruction.
+    ///
+    ///If the configured step budget runs out first this fails with
+    ///`FuelExhausted` and the PC of the next instruction; calling `run`
+    ///again picks up from there with a fresh budget. Stopping it through
+    ///the cancellation token works the same way, with `Interrupted`.
+    ///
+    ///The code is decoded once into an `ir::Program` and run from that;
+    ///`run_bytes` runs the raw bytes instead, with the same results. With
+    ///the `threaded` feature, words and loops run often enough are
+    ///compiled further; see `RunConfig::hot_threshold`.
+    ///
+    ///With the `tracing` feature each run is a `run` span with the PC it
+    ///started at. Inside it, calls to words are `trace` events and host
+    ///calls are spans of their own, and an error leaving the machine is a
+    ///`warn` event with its PC and word.
+    pub fn run<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<(),RuntimeError> {
+        self.run_metered(extender, &mut 0)
+    }
+
+    ///Like `run`, with `fuel` already used from the budget. It is left at
+    ///what has been used by the end, so one budget can cover several
+    ///runs.
+    pub(crate) fn run_metered<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T, fuel: &mut u64) -> Result<(),RuntimeError> {
+        if self.config.sandbox.is_some() || self.config.coverage || self.config.cost.is_some() {
+            return self.run_traced_metered(extender, &mut NullTracer {}, fuel);
+        }
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("run", pc = self.pc).entered();
+
+        let program = match self.program {
+            Some(ref n) if n.is_optimized() == self.config.optimize => n.clone(),
+            _ => {
+                let mut n = Program::decode_linked(&self.code, &self.links);
+                if self.config.optimize {
+                    n.optimize();
+                }
+
+                let n = Arc::new(n);
+                self.program = Some(n.clone());
+                n
+            },
+        };
+
+        let max = self.config.max_steps.unwrap_or(u64::MAX);
+        //The step count at which to next look at the cancellation token
+        //and the clock.
+        let mut poll: u64 = *fuel;
+
+        while self.pc < self.code.len() {
+            if *fuel >= poll {
+                if let Some(n) = self.stop_requested() {
+                    return Err(self.with_backtrace(RuntimeError::new(self.pc, n)));
+                }
+                poll = fuel.saturating_add(POLL_INTERVAL);
+            }
+
+            if let Some(instruction) = program.at(self.pc) {
+                if max - *fuel >= instruction.steps && self.run_folded(instruction) {
+                    *fuel += instruction.steps;
+                    #[cfg(feature = "threaded")]
+                    {
+                        let hot = match instruction.op {
+                            Op::Call(_) => true,
+                            Op::Jump(n) | Op::JumpIf(n) | Op::JumpUnless(n) => n <= instruction.pc && self.pc == n,
+                            _ => false,
+                        };
+                        if hot {
+                            //Compiled code only stops at the limit it's
+                            //given, so it has to come back to be polled.
+                            let limit = if self.is_polled() { max.min(poll.max(*fuel)) } else { max };
+                            let target = self.pc;
+                            if let Err(n) = self.run_hot(&program, target, limit, fuel) {
+                                self.recover(n).map_err(|n| self.with_backtrace(n))?;
+                            }
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            if *fuel >= max {
+                return Err(self.with_backtrace(RuntimeError::new(self.pc, Error::FuelExhausted)));
+            }
+            *fuel += 1;
+
+            let opcode = self.code[self.pc];
+            match self.execute(opcode, extender).map_err(|n| self.with_backtrace(n))? {
+                Status::Halted | Status::Yielded => { return Ok(()); },
+                Status::Running | Status::Paused => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Carry on after the code yields. The same as `run`, which always
+    ///picks up from the PC.
+    pub fn resume<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<(),RuntimeError> {
+        self.run(extender)
+    }
+
+    ///Check whether the code has finished, rather than yielded or been
+    ///stopped part of the way through.
+    pub fn is_finished(&self) -> bool {self.pc >= self.code.len()}
+
+    ///Get a token that stops a run from another thread. Once `true` is
+    ///stored in it, `run` fails with `Interrupted` within a thousand or
+    ///so steps, leaving the PC at the next instruction. Store `false`
+    ///again before carrying on, or every run stops straight away.
+    pub fn cancellation_token(&mut self) -> Arc<AtomicBool> {
+        self.cancel.get_or_insert_with(|| Arc::new(AtomicBool::new(false))).clone()
+    }
+
+    ///Stop runs with a token the host already has, such as one shared
+    ///by several machines.
+    pub fn set_cancellation_token(&mut self, token: Arc<AtomicBool>) {
+        self.cancel = Some(token);
+    }
+
+    ///Like `run`, failing with `Timeout` once the run has taken longer
+    ///than some time. The clock is read every thousand or so steps, so
+    ///a slow host call can carry it over. As with running out of fuel
+    ///the PC is left at the next instruction, and calling this again
+    ///carries on with a fresh budget.
+    #[cfg(feature = "std")]
+    pub fn run_with_timeout<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T, timeout: Duration) -> Result<(),RuntimeError> {
+        //A timeout too long to represent is the same as none.
+        self.deadline = Instant::now().checked_add(timeout);
+        let result = self.run(extender);
+        self.deadline = None;
+
+        result
+    }
+
+    ///Check whether anything could stop a run between instructions.
+    #[cfg(feature = "threaded")]
+    #[inline]
+    fn is_polled(&self) -> bool {
+        #[cfg(feature = "std")]
+        {
+            if self.deadline.is_some() {
+                return true;
+            }
+        }
+
+        self.cancel.is_some()
+    }
+
+    ///Check whether the host has asked the run to stop, or its time is
+    ///up, and get the error to stop with.
+    #[inline]
+    fn stop_requested(&self) -> Option<Error> {
+        if self.cancel.as_ref().is_some_and(|n| n.load(Ordering::Relaxed)) {
+            return Some(Error::Interrupted);
+        }
+        #[cfg(feature = "std")]
+        {
+            if self.deadline.is_some_and(|n| Instant::now() >= n) {
+                return Some(Error::Timeout);
+            }
+        }
+
+        None
+    }
+
+    ///What running the instruction starting with a byte costs in fuel.
+    #[inline]
+    pub(crate) fn fuel_cost<T: AtomExtender<S, M> + ?Sized>(&self, opcode: u8, extender: &T) -> u64 {
+        let cost = match self.config.cost {
+            Some(n) => n,
+            None => { return 1; }
+        };
+
+        match opcode {
+            b'\n' | b'\r' | b' ' => cost(opcode),
+            _ if opcodes::opcode(opcode).is_some() => cost(opcode),
+            _ => extender.cost(opcode).unwrap_or_else(|| cost(opcode)),
+        }
+    }
+
+    ///Like `run`, without decoding the code first.
+    pub fn run_bytes<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<(),RuntimeError> {
+        self.run_traced(extender, &mut NullTracer {})
+    }
+
+    ///Run a decoded instruction that stands for more than its first byte.
+    ///Returns false, having changed nothing, for a plain byte or if the
+    ///instruction would fail, leaving the raw path to run it and report
+    ///the error from the right byte.
+    #[inline]
+    fn run_folded(&mut self, instruction: &Instruction) -> bool {
+        let next = match instruction.op {
+            Op::Byte(_) => { return false; },
+            Op::Skip => instruction.next,
+            Op::Int(n) => match self.stack.try_push(Data::Int(n)) {
+                Ok(()) => instruction.next,
+                Err(_) => { return false; }
+            },
+            Op::Float(value, divider) => match self.stack.try_push(Data::Float(value as f64 / divider)) {
+                Ok(()) => instruction.next,
+                Err(_) => { return false; }
+            },
+            Op::ExactFloat(value) => match self.stack.try_push(Data::Float(value)) {
+                Ok(()) => instruction.next,
+                Err(_) => { return false; }
+            },
+            Op::Str(ref text) => match self.stack.try_push(Data::Str(text.clone())) {
+                Ok(()) => instruction.next,
+                Err(_) => { return false; }
+            },
+            Op::IntThen(n, op) => {
+                if !self.push_then(Data::Int(n), op) {
+                    return false;
+                }
+
+                instruction.next
+            },
+            Op::DupThen(op) => {
+                let tos = match self.stack.peek() {
+                    Some(&Data::Int(n)) => n,
+                    _ => { return false; }
+                };
+                if !self.push_then(Data::Int(tos), op) {
+                    return false;
+                }
+
+                instruction.next
+            },
+            Op::Jump(target) => target,
+            Op::JumpIf(target) | Op::JumpUnless(target) => {
+                let truthy = match self.stack.peek().map(Data::is_truthy) {
+                    Some(Ok(n)) => n,
+                    _ => { return false; }
+                };
+                let _ = self.stack.pop();
+
+                if truthy == (instruction.op == Op::JumpIf(target)) { target } else { instruction.next }
+            },
+            Op::Call(target) => {
+                if self.rstack.len() >= self.config.max_return_depth {
+                    return false;
+                }
+
+                self.rstack.push(instruction.next);
+                self.log_call(target);
+                target
+            },
+        };
+
+        if let Some((value, divider)) = instruction.literal {
+            self.value = value;
+            self.divider = divider;
+        }
+        self.pc = next;
+
+        true
+    }
+
+    ///Push an int and apply an arithmetic or comparison byte to it and
+    ///NOS, if they are both ints and the answer doesn't overflow. Returns
+    ///false, having changed nothing, if not.
+    #[inline]
+    fn push_then(&mut self, value: Data, op: u8) -> bool {
+        if self.stack.try_push(value).is_err() {
+            return false;
+        }
+        if !self.stack.combine_ints(|nos, tos| fold(op, nos, tos).map(Data::Int)) {
+            let _ = self.stack.pop();
+            return false;
+        }
+
+        true
+    }
+
+    ///Like `run`, calling the tracer around every instruction.
+    pub fn run_traced<T: AtomExtender<S, M> + ?Sized, R: Tracer<S> + ?Sized>(&mut self, extender: &mut T, tracer: &mut R) -> Result<(),RuntimeError> {
+        self.run_traced_metered(extender, tracer, &mut 0)
+    }
+
+    ///Like `run_traced`, with `fuel` already used; see `run_metered`.
+    fn run_traced_metered<T: AtomExtender<S, M> + ?Sized, R: Tracer<S> + ?Sized>(&mut self, extender: &mut T, tracer: &mut R, fuel: &mut u64) -> Result<(),RuntimeError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("run", pc = self.pc).entered();
+        //Checked once up front rather than per instruction; no budget is
+        //one that can't run out.
+        let max = self.config.max_steps.unwrap_or(u64::MAX);
+        let mut steps: u64 = 0;
+
+        while self.pc < self.code.len() {
+            let pc = self.pc;
+            let opcode = self.code[pc];
+
+            let cost = self.fuel_cost(opcode, extender);
+            if cost > max - *fuel {
+                return Err(self.with_backtrace(RuntimeError::new(self.pc, Error::FuelExhausted)));
+            }
+            if steps.is_multiple_of(POLL_INTERVAL) {
+                if let Some(n) = self.stop_requested() {
+                    return Err(self.with_backtrace(RuntimeError::new(self.pc, n)));
+                }
+            }
+            steps += 1;
+            *fuel += cost;
+
+            tracer.before_instruction(pc, opcode, &self.stack);
+            let status = self.execute(opcode, extender).map_err(|n| self.with_backtrace(n))?;
+            tracer.after_instruction(pc, opcode, &self.stack);
+            self.check_sandbox().map_err(|n| self.with_backtrace(n))?;
+
+            if let Status::Halted | Status::Yielded = status {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Like `step`, calling the tracer around the instruction.
+    pub fn step_traced<T: AtomExtender<S, M> + ?Sized, R: Tracer<S> + ?Sized>(&mut self, extender: &mut T, tracer: &mut R) -> Result<Status,RuntimeError> {
+        if self.pc >= self.code.len() {
+            return Ok(Status::Halted);
+        }
+
+        let pc = self.pc;
+        let opcode = self.code[pc];
+
+        tracer.before_instruction(pc, opcode, &self.stack);
+        let status = self.execute(opcode, extender).map_err(|n| self.with_backtrace(n))?;
+        tracer.after_instruction(pc, opcode, &self.stack);
+        self.check_sandbox().map_err(|n| self.with_backtrace(n))?;
+
+        Ok(status)
+    }
+
+    ///Execute a single instruction.
+    pub fn step<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> Result<Status,RuntimeError> {
+        if self.pc >= self.code.len() {
+            return Ok(Status::Halted);
+        }
+
+        let opcode = self.code[self.pc];
+        let status = self.execute(opcode, extender).map_err(|n| self.with_backtrace(n))?;
+        self.check_sandbox().map_err(|n| self.with_backtrace(n))?;
+
+        Ok(status)
+    }
+
+    ///Hand an error to the innermost `catch`, if there is one: put the
+    ///stacks and frames back as they were when it ran, push the error's
+    ///code and carry on after it. Running out of fuel or time, or being
+    ///interrupted, is left for the host, which can carry on.
+    pub(crate) fn recover(&mut self, err: RuntimeError) -> Result<(),RuntimeError> {
+        let catch = match self.catches.pop() {
+            Some(n) if !matches!(err.kind, Error::FuelExhausted | Error::Interrupted | Error::Timeout) => n,
+            Some(n) => {
+                self.catches.push(n);
+                return Err(err);
+            },
+            None => { return Err(err); }
+        };
+
+        //Items the word consumed can't be brought back, only the depth.
+        self.stack.truncate(catch.stack);
+        while self.stack.len() < catch.stack {
+            if let Err(n) = self.stack.try_push(Data::Int(0)) { return Err(RuntimeError::new(err.pc, n)); }
+        }
+        if let Err(n) = self.stack.try_push(Data::Int(err.kind.code())) { return Err(RuntimeError::new(err.pc, n)); }
+
+        self.rstack.truncate(catch.rstack);
+        if let Some(&start) = self.frames.get(catch.frames) {
+            self.locals.truncate(start);
+        }
+        self.frames.truncate(catch.frames);
+        self.pc = catch.next;
+
+        Ok(())
+    }
+
+    ///Execute the instruction at the PC, which the caller has already
+    ///fetched, handing any error to a waiting `catch`.
+    #[inline(always)]
+    fn execute<T: AtomExtender<S, M> + ?Sized>(&mut self, instruction: u8, extender: &mut T) -> Result<Status,RuntimeError> {
+        if self.config.coverage {
+            self.coverage.hit(self.pc);
+        }
+
+        match self.dispatch(instruction, extender) {
+            Err(n) if !self.catches.is_empty() => self.recover(n).map(|_| Status::Running),
+            status => status,
+        }
+    }
+
+    ///Execute an instruction. Inlined so `run` dispatches straight from
+    ///its loop.
+    #[inline(always)]
+    fn dispatch<T: AtomExtender<S, M> + ?Sized>(&mut self, instruction: u8, extender: &mut T) -> Result<Status,RuntimeError> {
+        let stack = &mut self.stack;
+        let memory = &mut self.memory;
+        self.pc += 1;
+        let pc = self.pc;
+
+        //Opcodes that only need the data stack are run from the table in
+        //`opcodes`; the rest have arms here.
+        if let Some(op) = stack_op(instruction) {
+            if let Err(n) = op.apply(stack, self.config.arithmetic, self.config.coercion) { return Err(RuntimeError::new(pc, n)); }
+            return Ok(Status::Running);
+        }
+
+        match instruction {
+            10 => {},
+            13 => {},   //Carriage Returns and Line feeds are ignored
+            32 => {},   //Tabs are not allowed but spaces are.
+            33 => {     //Exclamation mark. Run an instruction from the extended page.
+                let op = match self.code.get(pc) {
+                    Some(&n) => n,
+                    None => { return Err(RuntimeError::new(pc, Error::InvalidInstruction { opcode: instruction })); }
+                };
+                self.pc = pc + 1;
+                let pc = self.pc;
+
+                match op {
+                    b'R' => {  //Read a local of the current frame.
+                        let frame = &self.locals[self.frames.last().map_or(self.locals.len(), |&n| n)..];
+                        let value = match stack.pop_int() {
+                            Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                            Ok(n) if n >= 0 && (n as u64) < frame.len() as u64 => { frame[n as usize].clone() },
+                            Ok(n) => { return Err(RuntimeError::new(pc, Error::MemoryOutOfBounds { addr: n, len: frame.len() })); }
+                        };
+
+                        if let Err(n) = stack.try_push(value) { return Err(RuntimeError::new(pc, n)); }
+                    },
+                    b'W' => {  //Write NOS to a local of the current frame.
+                        let start = self.frames.last().map_or(self.locals.len(), |&n| n);
+                        let len = self.locals.len() - start;
+                        let index = match stack.pop_int() {
+                            Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                            Ok(n) if n >= 0 && (n as u64) < len as u64 => { start + n as usize },
+                            Ok(n) => { return Err(RuntimeError::new(pc, Error::MemoryOutOfBounds { addr: n, len })); }
+                        };
+
+                        match stack.pop() {
+                            Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                            Ok(n) => { self.locals[index] = n; }
+                        }
+                    },
+                    b'{' => {  //Enter a frame of TOS locals, taken from the items below it.
+                        let count = match pop_count(stack, 0) {
+                            Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                            Ok(n) if n > stack.len() => { return Err(RuntimeError::new(pc, Error::StackUnderflow)); },
+                            Ok(n) => { n }
+                        };
+
+                        if self.frames.len() >= self.config.max_return_depth {
+                            return Err(RuntimeError::new(pc, Error::ReturnStackOverflow));
+                        }
+
+                        let start = self.locals.len();
+                        self.frames.push(start);
+                        for _ in 0..count {
+                            if let Ok(n) = stack.pop() {
+                                self.locals.push(n);
+                            }
+                        }
+                        self.locals[start..].reverse();
+                    },
+                    b'}' => {  //Leave the current frame.
+                        match self.frames.pop() {
+                            Some(n) => { self.locals.truncate(n); },
+                            None => { return Err(RuntimeError::new(pc, Error::ReturnStackUnderflow)); }
+                        }
+                    },
+                    b'B' | b'S' | b'b' | b'f' | b'm' | b's' => {  //Byte memory.
+                        if let Err(n) = byte_op(op, stack, &mut self.bytes, &self.config) { return Err(RuntimeError::new(pc, n)); }
+                    },
+                    b'c' => {  //Call by the offset in TOS, from the next instruction, catching any error.
+                        let target = match stack.pop().and_then(|n| relative(op, pc, n)) {
+                            Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                            Ok(n)  => { n }
+                        };
+
+                        if self.rstack.len() >= self.config.max_return_depth {
+                            return Err(RuntimeError::new(pc, Error::ReturnStackOverflow));
+                        }
+
+                        self.catches.push(CatchFrame { stack: stack.len(), rstack: self.rstack.len(), frames: self.frames.len(), next: pc });
+                        self.rstack.push(pc);
+                        self.pc = target;
+                    },
+                    b't' => {  //Throw the code in TOS, unless it is zero.
+                        match stack.pop_int() {
+                            Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                            Ok(0) => {},
+                            Ok(n) => { return Err(RuntimeError::new(pc, Error::Thrown(n))); }
+                        }
+                    },
+                    _ => { return Err(RuntimeError::new(pc, Error::InvalidInstruction { opcode: op })); }
+                }
+            },
+            34 => {     //Double quote. Push constant as float
+                let v = self.value as f64;
+                if let Err(n) = stack.try_push(Data::Float(v / self.divider)) { return Err(RuntimeError::new(pc, n)); }
+            },
+            35 => {     //Pound sign. Load constant.
+                self.value = 0;
+                self.divider = 1.0;
+            },
+            36 => {     //Dollar sign. Invert constant.
+                let value = self.value;
+                self.value = match self.config.arithmetic.apply(value.checked_neg(), value.wrapping_neg(), value.saturating_neg()) {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n)  => { n }
+                };
+            },
+            39 => {     //Single quote. Push constant as integer.
+                if let Err(n) = stack.try_push(Data::Int(self.value)) { return Err(RuntimeError::new(pc, n)); }
+            },
+            40 => {     //Open parenthesis. Move TOS to the return stack. Only ints fit.
+                let value = match stack.pop() {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(Data::Int(n)) => { n },
+                    Ok(other) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() })); }
+                };
+
+                if self.rstack.len() >= self.config.max_return_depth {
+                    return Err(RuntimeError::new(pc, Error::ReturnStackOverflow));
+                }
+
+                self.rstack.push(value as usize);
+            },
+            41 => {     //Close parenthesis. Move the top of the return stack to the data stack.
+                match self.rstack.pop() {
+                    Some(n) => if let Err(n) = stack.try_push(Data::Int(n as i64)) { return Err(RuntimeError::new(pc, n)); },
+                    None => { return Err(RuntimeError::new(pc, Error::ReturnStackUnderflow)); }
+                }
+            },
+            44 => {     //Comma. Print a number and a space.
+                let written = match stack.pop() {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(Data::Str(_)) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Number, found: TypeTag::Str })); },
+                    Ok(n) => write!(self.output, "{} ", n),
+                };
+
+                if let Err(n) = written { return Err(RuntimeError::new(pc, n)); }
+            },
+            46 => {     //Period. Increase the divider by three orders of magnitude.
+                self.divider *= 1000.0;
+            },
+            48..=57 => { //Numeral.
+                let value = self.value;
+                let digit = (instruction as i64) - 48;
+                let checked = value.checked_mul(10).and_then(|n| n.checked_add(digit));
+                let wrapping = value.wrapping_mul(10).wrapping_add(digit);
+                let saturating = value.saturating_mul(10).saturating_add(digit);
+
+                self.value = match self.config.arithmetic.apply(checked, wrapping, saturating) {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n)  => { n }
+                };
+            },
+            58 => {     //Colon. Do. Move a loop's limit (NOS) and first index (TOS) to the return stack.
+                let (limit, start) = match stack.pop_int().and_then(|start| stack.pop_int().map(|limit| (limit, start))) {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n)  => { n }
+                };
+
+                if self.rstack.len() + 2 > self.config.max_return_depth {
+                    return Err(RuntimeError::new(pc, Error::ReturnStackOverflow));
+                }
+
+                self.rstack.push(limit as usize);
+                self.rstack.push(start as usize);
+            },
+            59 => {     //Semicolon. Return
+                let home = match self.rstack.pop() {
+                    Some(n) => n,
+                    None    => {
+                        self.pc = self.code.len();
+                        return Ok(Status::Halted);
+                    }
+                };
+
+                self.pc = home;
+                //A word called by `catch` returned without an error.
+                let depth = self.rstack.len();
+                if self.catches.last().is_some_and(|n| n.rstack == depth) {
+                    self.catches.pop();
+                    if let Err(n) = stack.try_push(Data::Int(0)) { return Err(RuntimeError::new(pc, n)); }
+                }
+            },
+            64 => {     //At sign. Copy the top of the return stack to the data stack.
+                match self.rstack.last() {
+                    Some(&n) => if let Err(n) = stack.try_push(Data::Int(n as i64)) { return Err(RuntimeError::new(pc, n)); },
+                    None => { return Err(RuntimeError::new(pc, Error::ReturnStackUnderflow)); }
+                }
+            },
+            65 => {     //"A" Allot. Extend memory by TOS cells.
+                let cells = match pop_count(stack, memory.len()) {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n)  => { n }
+                };
+
+                let here = memory.len();
+                if let Err(n) = check_memory(&self.config, here.saturating_add(cells)) { return Err(RuntimeError::new(pc, n)); }
+                if !memory.grow(here + cells) {
+                    memory.truncate(here);
+                    return Err(RuntimeError::new(pc, Error::MemoryOutOfBounds { addr: (here + cells) as i64 - 1, len: here }));
+                }
+            },
+            66 => {     //"B". Relative jump.
+                let target = match stack.pop().and_then(|n| relative(instruction, pc, n)) {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n)  => { n }
+                };
+
+                self.pc = target;
+            },
+            67 => {     //"C". Relative call.
+                let target = match stack.pop().and_then(|n| relative(instruction, pc, n)) {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n)  => { n }
+                };
+
+                if self.rstack.len() >= self.config.max_return_depth {
+                    return Err(RuntimeError::new(pc, Error::ReturnStackOverflow));
+                }
+
+                self.rstack.push(pc);
+                self.pc = target;
+                self.log_call(target);
+            },
+            69 => {     //"E" Erase a map entry.
+                if let Err(n) = stack.map_remove(&mut self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            70 => {     //"F" Free. Release TOS cells from the end of memory.
+                let cells = match pop_count(stack, memory.len()) {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n)  => { n }
+                };
+
+                if cells > memory.len() {
+                    return Err(RuntimeError::new(pc, Error::MemoryOutOfBounds { addr: memory.len() as i64 - cells as i64, len: memory.len() }));
+                }
+
+                let len = memory.len() - cells;
+                memory.truncate(len);
+            },
+            71 => {     //"G" Look up a map entry. Pushes the value if found, then a flag.
+                if let Err(n) = stack.map_get(&self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            72 => {     //"H" Here. Push the memory size, the address of the next cell allotted.
+                if let Err(n) = stack.try_push(Data::Int(memory.len() as i64)) { return Err(RuntimeError::new(pc, n)); }
+            },
+            73 => {     //"I" Input a line. Pushes it and a flag, which is false at the end of input.
+                match self.input.read_line() {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(Some(line)) => {
+                        if let Err(n) = stack.try_push(Data::Str(Arc::from(line))) { return Err(RuntimeError::new(pc, n)); }
+                        if let Err(n) = stack.try_push(Data::TRUE) { return Err(RuntimeError::new(pc, n)); }
+                    },
+                    Ok(None) => {
+                        if let Err(n) = stack.try_push(Data::Str(Arc::from(""))) { return Err(RuntimeError::new(pc, n)); }
+                        if let Err(n) = stack.try_push(Data::FALSE) { return Err(RuntimeError::new(pc, n)); }
+                    },
+                }
+            },
+            74 | 106 => {   //"J" and "j". Loop and plus-loop. Step the index by 1, or by NOS, and jump back unless it crossed the limit.
+                let offset = match stack.pop() { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let step = if instruction == 106 {
+                    match stack.pop_int() { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} }
+                } else {
+                    1
+                };
+
+                let target = match relative(instruction, pc, offset) { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let len = self.rstack.len();
+                if len < 2 {
+                    return Err(RuntimeError::new(pc, Error::ReturnStackUnderflow));
+                }
+
+                //The loop ends when the index passes from one side of the
+                //boundary between limit - 1 and limit to the other.
+                let index = self.rstack[len - 1] as i64;
+                let limit = self.rstack[len - 2] as i64;
+                let next = index.wrapping_add(step);
+                if (index.wrapping_sub(limit) ^ next.wrapping_sub(limit)) < 0 {
+                    self.rstack.truncate(len - 2);
+                } else {
+                    self.rstack[len - 1] = next as usize;
+                    self.pc = target;
+                }
+            },
+            75 => {     //"K" Key. Push the next input byte, or -1 at the end of input.
+                match self.input.key() {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(Some(n)) => if let Err(n) = stack.try_push(Data::Int(n as i64)) { return Err(RuntimeError::new(pc, n)); },
+                    Ok(None) => if let Err(n) = stack.try_push(Data::Int(-1)) { return Err(RuntimeError::new(pc, n)); },
+                }
+            },
+            77 => {     //"M" Push a new, empty map.
+                if let Err(n) = stack.map_new(&mut self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            82 => {     //"R" Read from memory
+                let value = stack.pop();
+
+                let value = match value {
+                    Err(n) => { return Err(RuntimeError::new(pc,n));},
+                    Ok(n)  => { n }
+                };
+
+                match value {
+                    Data::Float(_) | Data::Str(_) | Data::Array(_) | Data::Map(_) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: value.type_tag() })); }
+                    Data::Int(n) => {
+                        let value = match self.memory_map.handler(n) {
+                            Some(handler) => handler.read(n),
+                            None => {
+                                let addr = match resolve(memory, &self.config, n) {
+                                    Err(e) => { return Err(RuntimeError::new(pc, e)); },
+                                    Ok(a) => a,
+                                };
+                                memory.as_slice()[addr].clone()
+                            },
+                        };
+                        if let Err(n) = stack.try_push(value) { return Err(RuntimeError::new(pc, n)); }
+                    }
+                }
+            },
+            84 => {     //"T" Type. Print a string as it is.
+                let written = match stack.pop() {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(Data::Str(n)) => self.output.write_all(n.as_bytes()),
+                    Ok(other) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Str, found: other.type_tag() })); },
+                };
+
+                if let Err(n) = written { return Err(RuntimeError::new(pc, n)); }
+            },
+            85 => {     //"U" Push a uniform random float in [0, 1).
+                if let Err(n) = stack.random_float(&mut self.rng) { return Err(RuntimeError::new(pc, n)); }
+            },
+            86 => {     //"V" Set a map entry.
+                if let Err(n) = stack.map_insert(&mut self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            87 => {     //"W" Write to memory
+                let address = stack.pop();
+                let value = stack.pop();
+
+                let address = match address {
+                    Err(n) => { return Err(RuntimeError::new(pc,n));},
+                    Ok(n)  => { n }
+                };
+
+                let value = match value {
+                    Err(n) => { return Err(RuntimeError::new(pc,n));},
+                    Ok(n)  => { n }
+                };
+
+                match address {
+                    Data::Float(_) | Data::Str(_) | Data::Array(_) | Data::Map(_) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: address.type_tag() })); }
+                    Data::Int(n) => {
+                        match self.memory_map.handler(n) {
+                            Some(handler) => handler.write(n, value),
+                            None => {
+                                let addr = match resolve(memory, &self.config, n) {
+                                    Err(e) => { return Err(RuntimeError::new(pc, e)); },
+                                    Ok(a) => a,
+                                };
+                                memory.as_mut_slice()[addr] = value;
+                            },
+                        }
+                    }
+                }
+            },
+            88 => {     //"X" Replace low and high with a random int in [low, high).
+                if let Err(n) = stack.random_range(&mut self.rng) { return Err(RuntimeError::new(pc, n)); }
+            },
+            89 | 90 => {   //"Y" and "Z". Relative jump if non-zero or zero.
+                let offset = match stack.pop() { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let data = match stack.pop() { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let target = match relative(instruction, pc, offset) { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let truthy = match data.is_truthy() { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                if truthy == (instruction == 89) { self.pc = target; }
+            },
+            91 => {     //Open bracket. Push string literal up to the closing bracket.
+                let (text, next) = match string_literal(&self.code, pc) {
+                    Some(n) => n,
+                    None => { return Err(RuntimeError::new(pc, Error::InvalidInstruction { opcode: instruction })); }
+                };
+
+                if let Err(n) = stack.try_push(Data::Str(Arc::from(text))) { return Err(RuntimeError::new(pc, n)); }
+                self.pc = next;
+            },
+            92 => {     //Backslash. Replace the index in TOS with that entry of the constant pool.
+                let value = match stack.pop_int() {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(n) if n >= 0 && (n as u64) < self.constants.len() as u64 => { self.constants[n as usize].clone() },
+                    Ok(n) => { return Err(RuntimeError::new(pc, Error::MemoryOutOfBounds { addr: n, len: self.constants.len() })); }
+                };
+
+                if let Err(n) = stack.try_push(value) { return Err(RuntimeError::new(pc, n)); }
+            },
+            93 => {     //Close bracket. Push the float in the next eight bytes.
+                let (value, next) = match float_immediate(&self.code, pc) {
+                    Some(n) => n,
+                    None => { return Err(RuntimeError::new(pc, Error::InvalidInstruction { opcode: instruction })); }
+                };
+
+                if let Err(n) = stack.try_push(Data::Float(value)) { return Err(RuntimeError::new(pc, n)); }
+                self.pc = next;
+            },
+            96 => {     //Backtick. Call a word by name, resolved by `link`.
+                let (target, next) = match self.links.get(&(pc - 1)) {
+                    Some(&n) => n,
+                    None => { return Err(RuntimeError::new(pc, Error::UnknownWord)); }
+                };
+
+                if self.rstack.len() >= self.config.max_return_depth {
+                    return Err(RuntimeError::new(pc, Error::ReturnStackOverflow));
+                }
+
+                self.rstack.push(next);
+                self.pc = target;
+                self.log_call(target);
+            },
+            97 => {     //"a" Replace a length with a new array.
+                if let Err(n) = check_array(&self.config, &self.heap, stack.peek()) { return Err(RuntimeError::new(pc, n)); }
+                if let Err(n) = stack.array_new(&mut self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            98  => {    //"b". Jump to address.
+                let value = stack.pop();
+
+                let value = match value {
+                    Err(n) => { return Err(RuntimeError::new(pc,n));},
+                    Ok(n)  => { n }
+                };
+
+                match value {
+                    Data::Float(_) | Data::Str(_) | Data::Array(_) | Data::Map(_) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: value.type_tag() })); }
+                    Data::Int(n) => {
+                        self.pc = n as usize;
+                    }
+                }
+            },
+            99 => {     //"c". Call address
+                let value = stack.pop();
+
+                let value = match value {
+                    Err(n) => { return Err(RuntimeError::new(pc,n));},
+                    Ok(n)  => { n }
+                };
+
+                match value {
+                    Data::Float(_) | Data::Str(_) | Data::Array(_) | Data::Map(_) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: value.type_tag() })); }
+                    Data::Int(n) => {
+                        if self.rstack.len() >= self.config.max_return_depth {
+                            return Err(RuntimeError::new(pc, Error::ReturnStackOverflow));
+                        }
+
+                        self.rstack.push(pc);
+                        self.pc = n as usize;
+                        self.log_call(self.pc);
+                    }
+                }
+            },
+            101 => {    //"e" Emit. Print the character with the code point in TOS.
+                let c = match stack.pop() {
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                    Ok(Data::Int(n)) => {
+                        match u32::try_from(n).ok().and_then(::core::char::from_u32) {
+                            Some(c) => c,
+                            None => { return Err(RuntimeError::new(pc, Error::InvalidCharacter(n))); },
+                        }
+                    },
+                    Ok(other) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() })); },
+                };
+
+                if let Err(n) = write!(self.output, "{}", c) { return Err(RuntimeError::new(pc, n)); }
+            },
+            102 => {    //"f" Free an array or map.
+                if let Err(n) = stack.heap_free(&mut self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            103 => {    //"g" Get an array cell.
+                if let Err(n) = stack.array_get(&self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            104 => {    //"h" Call the host function with the index in TOS.
+                let index = match stack.pop() {
+                    Ok(Data::Int(n)) if n >= 0 => n as usize,
+                    Ok(Data::Int(_)) => { return Err(RuntimeError::new(pc, Error::UnknownWord)); },
+                    Ok(other) => { return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: other.type_tag() })); },
+                    Err(n) => { return Err(RuntimeError::new(pc, n)); },
+                };
+
+                if self.config.sandbox.as_ref().is_some_and(|n| !n.allows(instruction)) {
+                    return Err(RuntimeError::new(pc, Error::OpcodeNotAllowed(instruction)));
+                }
+                if self.config.deterministic && !self.host.is_deterministic(index) {
+                    return Err(RuntimeError::new(pc, Error::Nondeterministic));
+                }
+
+                if let Err(n) = self.host.call(index, stack) { return Err(RuntimeError::new(pc, n)); }
+                #[cfg(feature = "async")]
+                {
+                    if self.host.is_pending() {
+                        return Ok(Status::Yielded);
+                    }
+                }
+            },
+            109 => {    //"m" Map size.
+                if let Err(n) = stack.map_len(&self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            112 => {    //"p". Debug. Print type of variable, and value.
+
+                let value = stack.pop();
+
+                let value = match value {
+                    Err(n) => { return Err(RuntimeError::new(pc,n));},
+                    Ok(n)  => { n }
+                };
+
+                let written = match value {
+                    Data::Int(n) => writeln!(self.output, "Int:{}",n),
+                    Data::Float(n) => writeln!(self.output, "Float:{}",n),
+                    Data::Str(n) => writeln!(self.output, "Str:{}",n),
+                    Data::Array(n) => writeln!(self.output, "Array:{}",n),
+                    Data::Map(n) => writeln!(self.output, "Map:{}",n)
+                };
+
+                if let Err(n) = written { return Err(RuntimeError::new(pc, n)); }
+            },
+            113 => {    //"q" Array length.
+                if let Err(n) = stack.array_len(&self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            105 => {    //"i" Yield. Hand control back to the host until it resumes.
+                return Ok(Status::Yielded);
+            },
+            119 => {    //"w" Pause. End the running task's turn; see `run_round_robin`.
+                return Ok(Status::Paused);
+            },
+            120 => {    //"x" Set an array cell.
+                if let Err(n) = stack.array_set(&mut self.heap) { return Err(RuntimeError::new(pc, n)); }
+            },
+            121 => {    //"y" Jump if non-zero.
+                let address = stack.pop();
+
+                let address = match address { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let data = stack.pop();
+
+                let data = match data { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let address = match address { Data::Float(_) | Data::Str(_) | Data::Array(_) | Data::Map(_) => {return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: address.type_tag() }));}, Data::Int(n) => n as usize };
+
+                let condition = match data.is_truthy() { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                if condition { self.pc = address; }
+            },
+            122 => {    //"z" Jump if zero.
+                let address = stack.pop();
+
+                let address = match address { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let data = stack.pop();
+
+                let data = match data { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {n} };
+
+                let address = match address { Data::Float(_) | Data::Str(_) | Data::Array(_) | Data::Map(_) => {return Err(RuntimeError::new(pc, Error::TypeMismatch { expected: TypeTag::Int, found: address.type_tag() }));}, Data::Int(n) => n as usize };
+
+                let condition = match data.is_truthy() { Err(n) => {return Err(RuntimeError::new(pc,n));}, Ok(n) => {!n} };
+
+                if condition { self.pc = address; }
+            },
+            _ => {
+                if self.config.sandbox.as_ref().is_some_and(|n| !n.allows(instruction)) {
+                    return Err(RuntimeError::new(pc, Error::OpcodeNotAllowed(instruction)));
+                }
+                if self.config.deterministic && !extender.is_deterministic(instruction) {
+                    return Err(RuntimeError::new(pc, Error::Nondeterministic));
+                }
+
+                let mut context = Context {
+                    stack,
+                    memory,
+                    rstack: &mut self.rstack,
+                    pc,
+                };
+
+                if let Err(n) = extender.atom_with_context(instruction, &mut context) { return Err(RuntimeError::new(pc, n)); }
+                self.pc = context.pc;
+            },
+
+        }
+
+        Ok(Status::Running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use alloc::boxed::Box;
+    use alloc::string::ToString;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    #[cfg(feature = "std")]
+    use std::io;
+    #[cfg(feature = "std")]
+    use std::io::Write;
+    #[cfg(feature = "std")]
+    use std::sync::Mutex;
+    use compiler::compile_module;
+    use vm::{ArithmeticPolicy, CoercionPolicy, MemoryPolicy, RunConfig, Vm, Status};
+    use Data;
+    use Error;
+    use RuntimeError;
+    use NullExtender;
+    use Stack;
+
+    #[test]
+    fn string_literals() {
+        let mut vm = Vm::new(b"[green\\]][gold]k dl".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(10))));
+        assert!(matches!(vm.stack.pop(), Ok(Data::Str(ref s)) if &**s == "green]gold"));
+
+        let mut vm = Vm::new(b"[oops".to_vec(), Vec::new());
+        assert!(vm.run(&mut NullExtender {}).is_err());
+    }
+
+    #[test]
+    fn comparisons() {
+        let mut vm = Vm::new(b"#2'#3'< #2'#3'> | #1'=~".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(0))));
+    }
+
+    #[test]
+    fn fuel() {
+        let mut vm = Vm::new(b"#0'b".to_vec(), Vec::new());
+        vm.config = RunConfig { max_steps: Some(100), ..RunConfig::default() };
+
+        match vm.run(&mut NullExtender {}) {
+            Err(RuntimeError { pc, kind: Error::FuelExhausted, .. }) => assert_eq!(pc, vm.pc),
+            _ => panic!("Expected FuelExhausted"),
+        }
+
+        let mut vm = Vm::new(b"#1'#2'+".to_vec(), Vec::new());
+        vm.config.max_steps = Some(4);
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::FuelExhausted, .. })));
+        vm.run(&mut NullExtender {}).unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(3))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn interrupt() {
+        use std::sync::atomic::Ordering;
+        use std::thread;
+        use std::time::Duration;
+
+        //Counts forever, until stopped from another thread.
+        let mut vm = Vm::new(b"#0'#1'+#3'b".to_vec(), Vec::new());
+        let token = vm.cancellation_token();
+        let stopper = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                token.store(true, Ordering::Relaxed);
+            })
+        };
+        match vm.run(&mut NullExtender {}) {
+            Err(RuntimeError { pc, kind: Error::Interrupted, .. }) => assert_eq!(pc, vm.pc),
+            _ => panic!("Expected Interrupted"),
+        }
+        stopper.join().unwrap();
+        let count = match vm.stack.peek() {
+            Some(&Data::Int(n)) => n,
+            _ => panic!("Expected the count"),
+        };
+        assert!(count > 0);
+
+        //Every run stops until the token is cleared, then carries on.
+        assert!(matches!(vm.run_bytes(&mut NullExtender {}), Err(RuntimeError { kind: Error::Interrupted, .. })));
+        token.store(false, Ordering::Relaxed);
+        vm.config.max_steps = Some(100);
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::FuelExhausted, .. })));
+        assert!(matches!(vm.stack.peek(), Some(&Data::Int(n)) if n > count));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn timeout() {
+        use std::time::Duration;
+
+        let mut vm = Vm::new(b"#0'#1'+#3'b".to_vec(), Vec::new());
+        match vm.run_with_timeout(&mut NullExtender {}, Duration::from_millis(10)) {
+            Err(RuntimeError { pc, kind: Error::Timeout, .. }) => assert_eq!(pc, vm.pc),
+            _ => panic!("Expected Timeout"),
+        }
+        assert!(matches!(vm.stack.peek(), Some(&Data::Int(n)) if n > 0));
+
+        //The deadline only applies to the run it was given for.
+        vm.config.max_steps = Some(100);
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::FuelExhausted, .. })));
+
+        let mut vm = Vm::new(b"#1'#2'+".to_vec(), Vec::new());
+        vm.run_with_timeout(&mut NullExtender {}, Duration::from_secs(60)).unwrap();
+        assert!(matches!(vm.stack.pop(), Ok(Data::Int(3))));
+    }
+
+    #[test]
+    fn memory_policies() {
+        let code = b"#7'#5'W#5'R".to_vec();
+
+        let mut vm = Vm::new(code.clone(), vec![Data::Int(0); 2]);
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.memory, vec![Data::Int(0), Data::Int(7)]);
+
+        //Negative addresses count back from the end.
+        let mut vm = Vm::new(b"#7'#0'#1'-W#0'#4'-R".to_vec(), vec![Data::Int(0); 3]);
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.memory, vec![Data::Int(0), Data::Int(0), Data::Int(7)]);
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(7));
+
+        let mut vm = Vm::new(code.clone(), vec![Data::Int(0); 2]);
+        vm.config.memory = MemoryPolicy::Trap;
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { .. }, .. })));
+
+        let mut vm = Vm::new(code.clone(), Vec::new());
+        vm.config.memory = MemoryPolicy::Grow;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.memory.len(), 6);
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(7));
+
+        let mut vm = Vm::new(code, Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { .. }, .. })));
+    }
+
+    #[test]
+    fn decoded_matches_bytes() {
+        let programs: [&[u8]; 10] = [
+            b"#3' d#1'- d #4'Y r",
+            b"#1'#2'+ #7'b #9'",
+            b"[x] #4'y",
+            b"#5'#2'b",
+            b"#9'C #1'; #2'",
+            b"#12345' #6'c",
+            b"#2'#3'+#4'd*- #1'<d= #2'#3'> #9223372036854775807'#1'+",
+            b"[s]#1'+",
+            b"#1.5\"d* #3'd*d#1'+ #3'#2'b",
+            b"]\x9a\x99\x99\x99\x99\x99\xb9\x3fd* ]\x00",
+        ];
+
+        for (code, &optimize) in programs.iter().flat_map(|n| [false, true].iter().map(move |o| (n, o))) {
+            for fuel in 0..16 {
+                let mut decoded = Vm::new(code.to_vec(), Vec::new());
+                let mut raw = Vm::new(code.to_vec(), Vec::new());
+                decoded.config.optimize = optimize;
+                decoded.config.max_steps = Some(fuel);
+                raw.config.max_steps = Some(fuel);
+
+                let a = decoded.run(&mut NullExtender {}).map_err(|n| (n.pc, n.kind.to_string()));
+                let b = raw.run_bytes(&mut NullExtender {}).map_err(|n| (n.pc, n.kind.to_string()));
+                assert_eq!(a, b);
+                assert_eq!(decoded.stack.to_string(), raw.stack.to_string());
+                assert_eq!((decoded.pc, decoded.value), (raw.pc, raw.value));
+            }
+        }
+    }
+
+    #[test]
+    fn relative_jumps() {
+        //Count down from 3, jumping back over the loop body.
+        let mut vm = Vm::new(b"#3' d#1'- d #14$'Y r".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.len(), 3);
+
+        let mut vm = Vm::new(b"#5'C #1'; #2';".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(1));
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(2));
+
+        let mut vm = Vm::new(b"#9$'B".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::InvalidInstruction { .. }, .. })));
+
+        //A target too far to count is past the end, so the code halts.
+        let mut vm = Vm::new(b"#9223372036854775807'B".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+    }
+
+    #[test]
+    fn exact_floats() {
+        let mut code = b"]".to_vec();
+        code.extend_from_slice(&1e-300f64.to_le_bytes());
+        code.extend_from_slice(b"]");
+        code.extend_from_slice(&0.1f64.to_le_bytes());
+        let mut vm = Vm::new(code, Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Float(0.1));
+        assert_eq!(vm.stack.pop().unwrap(), Data::Float(1e-300));
+
+        let mut vm = Vm::new(b"]\x00\x00".to_vec(), Vec::new());
+        assert!(matches!(vm.run_bytes(&mut NullExtender {}), Err(RuntimeError { kind: Error::InvalidInstruction { opcode: b']' }, .. })));
+    }
+
+    #[test]
+    fn counted_loops() {
+        let mut vm = Vm::new(b"#0'#3'#0':@+#7$'J".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(3));
+        assert!(vm.rstack.is_empty());
+
+        //Counting down by 2 from 6 runs the body for 0 as well.
+        let mut vm = Vm::new(b"#0'#0'#6':@+#2$'#12$'j".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(12));
+        assert!(vm.rstack.is_empty());
+
+        let mut vm = Vm::new(b"#0'J".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::ReturnStackUnderflow, .. })));
+    }
+
+    #[test]
+    fn locals() {
+        //A frame of two, the deepest item first, then a nested frame.
+        let mut vm = Vm::new(b"#7'#8'#2'!{#1'!R#1'!{#5'#0'!W#0'!R!}#0'!R!}".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> 5 7");
+        assert!(vm.locals.is_empty() && vm.frames.is_empty());
+
+        let mut vm = Vm::new(b"#1'#1'!{#1'!R".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { addr: 1, len: 1 }, pc: 13, .. })));
+        let mut vm = Vm::new(b"#2'!{".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::StackUnderflow, .. })));
+        let mut vm = Vm::new(b"!}".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::ReturnStackUnderflow, .. })));
+        let mut vm = Vm::new(b"!+".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::InvalidInstruction { opcode: b'+' }, .. })));
+    }
+
+    #[test]
+    fn catch_and_throw() {
+        let mut vm = Vm::new(b"#1'!c;#7'!t;".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<1> 7");
+        let mut vm = Vm::new(b"#1'!c;#5';".to_vec(), Vec::new());
+        vm.run_bytes(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> 5 0");
+
+        //The depth is put back even if the word took items, and the
+        //return stack, loops and frames it left are dropped.
+        let mut vm = Vm::new(b"#1'#2'#3'#1'!{#1'!c;#0'#9':(#0'!{rrr;".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<3> 0 0 -4");
+        assert!(vm.rstack.is_empty() && vm.catches.is_empty());
+        assert_eq!((vm.locals.len(), vm.frames.len()), (1, 1));
+
+        //Nested catches, and running out of fuel, which is never caught.
+        let mut vm = Vm::new(b"#1'!c;#1'!c;#0'#0'/;".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> -10 0");
+        let mut vm = Vm::new(b"#1'!c;#2$'B".to_vec(), Vec::new());
+        vm.config.max_steps = Some(50);
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::FuelExhausted, .. })));
+        assert_eq!(vm.catches.len(), 1);
+
+        let mut vm = Vm::new(b"#3'!t".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::Thrown(3), .. })));
+    }
+
+    #[test]
+    fn escapes() {
+        let mut vm = Vm::new(b"!+".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::InvalidInstruction { opcode: b'+' }, pc: 2, .. })));
+        let mut vm = Vm::new(b"#1'!".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::InvalidInstruction { opcode: b'!' }, .. })));
+    }
+
+    #[test]
+    fn return_stack_words() {
+        let mut vm = Vm::new(b"#1' #2$'( #3' @ ) +".to_vec(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(-4));
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(3));
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(1));
+        assert!(vm.rstack.is_empty());
+
+        let mut vm = Vm::new(b"@".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::ReturnStackUnderflow, .. })));
+
+        let mut vm = Vm::new(b"#1\"(".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::TypeMismatch { .. }, .. })));
+    }
+
+    #[test]
+    fn overflow() {
+        let code = b"#9223372036854775807' #1'+".to_vec();
+
+        let mut vm = Vm::new(code.clone(), Vec::new());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(i64::MIN));
+
+        let mut vm = Vm::new(code.clone(), Vec::new());
+        vm.config.arithmetic = ArithmeticPolicy::Saturating;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(i64::MAX));
+
+        let mut vm = Vm::new(code, Vec::new());
+        vm.config.arithmetic = ArithmeticPolicy::Trapping;
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { pc: 26, kind: Error::IntegerOverflow, .. })));
+
+        let mut vm = Vm::new(b"#99999999999999999999'".to_vec(), Vec::new());
+        vm.config.arithmetic = ArithmeticPolicy::Trapping;
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::IntegerOverflow, .. })));
+    }
+
+    #[test]
+    fn byte_memory() {
+        let code = b"#72'#0'!B #105'#1'!B #0'#2'!s [Hey]#4'!S #4'#0'#3'!m #7'#2'#33'!f #0'!b".to_vec();
+
+        //Byte memory starts empty, and only grows if memory may.
+        let mut vm = Vm::new(code.clone(), Vec::new());
+        vm.config.memory = MemoryPolicy::Trap;
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { addr: 0, len: 0 }, .. })));
+
+        let mut vm = Vm::new(code, Vec::new());
+        vm.config.memory = MemoryPolicy::Grow;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<3> \"Hi\" 3 72");
+        assert_eq!(vm.bytes, b"Hey\0Hey!!");
+
+        vm.load(b"#1$'#3'#0'!f".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { addr: -1, .. }, .. })));
+        //Growing past what can be allocated fails rather than aborting.
+        vm.load(b"#7'#9000000000000000000'!B".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { addr: 9000000000000000000, len: 9 }, .. })));
+        vm.load(b"#0'#1'!s".to_vec()).unwrap();
+        vm.bytes[0] = 0xff;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.pop().unwrap(), Data::Str(Arc::from("\u{fffd}")));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn memory_map() {
+        use memmap::MemoryHandler;
+
+        struct Port(Arc<Mutex<Vec<Data>>>);
+
+        impl MemoryHandler for Port {
+            fn read(&mut self, addr: i64) -> Data {Data::Int(addr * 10)}
+
+            fn write(&mut self, _: i64, value: Data) {
+                self.0.lock().unwrap().push(value);
+            }
+        }
+
+        //Mapped addresses needn't be in memory, even when it traps.
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = Vm::new(b"#1'#0'W #7'#4096'W #4097'R #0'R #4098'R".to_vec(), vec![Data::Int(0)]);
+        vm.config.memory = MemoryPolicy::Trap;
+        vm.memory_map.map(4096..4098, Port(written.clone()));
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { addr: 4098, .. }, .. })));
+        assert_eq!(vm.stack.to_string(), "<2> 40970 1");
+        assert_eq!(*written.lock().unwrap(), [Data::Int(7)]);
+    }
+
+    #[test]
+    fn coercion() {
+        let module = compile_module("1 2.5 + 2.0 2 = 3 1.5 <").unwrap();
+
+        let mut vm = Vm::from_module(module.clone(), Vec::new()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::TypeMismatch { .. }, .. })));
+
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        vm.config.coercion = CoercionPolicy::Promote;
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<3> 3.5 1 0");
+
+        //Only numbers are promoted.
+        let mut vm = Vm::from_module(compile_module("1 s\" a\" +").unwrap(), Vec::new()).unwrap();
+        vm.config.coercion = CoercionPolicy::Promote;
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::TypeMismatch { .. }, .. })));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn output() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {Ok(())}
+        }
+
+        let sink = Shared::default();
+        let mut vm = Vm::new(b"#42', #1.500\", [hi] T #10'e".to_vec(), Vec::new());
+        vm.output = Box::new(sink.clone());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(&sink.0.lock().unwrap()[..], b"42 1.5 hi\n");
+
+        let sink = Shared::default();
+        let mut vm = Vm::new(b"#7'p [x]p".to_vec(), Vec::new());
+        vm.output = Box::new(sink.clone());
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(&sink.0.lock().unwrap()[..], b"Int:7\nStr:x\n");
+
+        let mut vm = Vm::new(b"[hi],".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::TypeMismatch { .. }, .. })));
+    }
+
+    #[test]
+    fn invalid_characters() {
+        //Negative, and a surrogate.
+        let mut vm = Vm::new(b"#0'#1'-e".to_vec(), Vec::new());
+        let err = vm.run(&mut NullExtender {}).unwrap_err();
+        assert!(matches!(err.kind, Error::InvalidCharacter(-1)));
+        assert_eq!(format!("{}", err.kind), "Invalid Character: -1");
+        let mut vm = Vm::new(b"#55296'e".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::InvalidCharacter(0xD800), .. })));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn input() {
+        let mut vm = Vm::new(b"K I I I".to_vec(), Vec::new());
+        vm.input = Box::new(io::Cursor::new("xyz\r\nlast"));
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<7> 120 \"yz\" 1 \"last\" 1 \"\" 0");
+    }
+
+    #[test]
+    fn yield_and_resume() {
+        //A loop that yields each time round, then a call that yields.
+        let code = b"#3' i #1'-d#00003'y r #00036'c #9'; i#1';".to_vec();
+        for &optimize in &[false, true] {
+            let mut vm = Vm::new(code.clone(), Vec::new());
+            vm.config.optimize = optimize;
+            let mut yields = Vec::new();
+
+            vm.run(&mut NullExtender {}).unwrap();
+            while !vm.is_finished() {
+                yields.push(vm.stack.to_string());
+                vm.resume(&mut NullExtender {}).unwrap();
+            }
+            assert_eq!(yields, vec!["<1> 3", "<1> 2", "<1> 1", "<0>"]);
+            assert_eq!(vm.stack.to_string(), "<2> 1 9");
+        }
+
+        let mut vm = Vm::new(code, Vec::new());
+        vm.run_bytes(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.pc, 5);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let code = b"#5'#1'W#12.5\"".to_vec();
+        let mut vm = Vm::new(code.clone(), vec![Data::Int(0); 2]);
+        for _ in 0..10 {
+            vm.step(&mut NullExtender {}).unwrap();
+        }
+
+        let state = vm.snapshot();
+        assert_eq!(state.pc, 10);
+        assert_eq!(state.value, 12);
+
+        let mut resumed = Vm::new(code, Vec::new());
+        resumed.restore(state).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+        resumed.run(&mut NullExtender {}).unwrap();
+        assert_eq!(resumed.snapshot(), vm.snapshot());
+
+        //Waiting tasks aren't in a snapshot, and don't outlive a restore.
+        let state = vm.snapshot();
+        vm.spawn(0, Vec::new());
+        assert_eq!(vm.snapshot(), state);
+        vm.restore(state).unwrap();
+        assert!(vm.tasks.is_empty());
+    }
+
+    #[test]
+    fn deterministic() {
+        let code = b"#2\" #1.500\" * #3'".to_vec();
+
+        let mut a = Vm::new(code.clone(), vec![Data::Int(0)]);
+        let mut b = Vm::new(code, vec![Data::Int(0)]);
+        assert_eq!(a.digest(), b.digest());
+
+        a.run(&mut NullExtender {}).unwrap();
+        assert_ne!(a.digest(), b.digest());
+        b.run(&mut NullExtender {}).unwrap();
+        assert_eq!(a.digest(), b.digest());
+
+        let mut vm = Vm::new(vec![200], Vec::new());
+        vm.config.deterministic = true;
+        let mut extender = |_: u8, _: &mut Stack| Ok(());
+        assert!(matches!(vm.run(&mut extender), Err(RuntimeError { kind: Error::Nondeterministic, .. })));
+    }
+
+    #[test]
+    fn random() {
+        let code = b"#1'#7'X #1'#7'X U".to_vec();
+
+        let mut a = Vm::new(code.clone(), Vec::new());
+        a.seed(7);
+        a.run(&mut NullExtender {}).unwrap();
+
+        let mut b = Vm::new(code, Vec::new());
+        b.seed(7);
+        b.run(&mut NullExtender {}).unwrap();
+
+        assert_eq!(a.stack.as_slice(), b.stack.as_slice());
+        assert!(matches!(a.stack.pop().unwrap(), Data::Float(n) if (0.0..1.0).contains(&n)));
+        assert!(matches!(a.stack.pop().unwrap(), Data::Int(n) if (1..7).contains(&n)));
+    }
+
+    #[test]
+    fn heap() {
+        let mut vm = Vm::new(b"H #3'A #9' H#1'- W #1'F H".to_vec(), vec![Data::Int(0)]);
+        vm.run(&mut NullExtender {}).unwrap();
+
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(3));
+        assert_eq!(vm.stack.pop().unwrap(), Data::Int(1));
+        assert_eq!(vm.memory.len(), 3);
+
+        assert_eq!(vm.allot(2).unwrap(), 3);
+        assert!(vm.release(6).is_err());
+        assert!(vm.release(5).is_ok());
+        assert_eq!(vm.here(), 0);
+
+        let mut vm = Vm::new(b"#1$'A".to_vec(), Vec::new());
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryOutOfBounds { .. }, .. })));
+    }
+
+    #[test]
+    fn return_depth() {
+        let mut vm = Vm::new(b"#0'c".to_vec(), Vec::new());
+        vm.config.max_return_depth = 16;
+
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::ReturnStackOverflow, .. })));
+        assert_eq!(vm.rstack.len(), 16);
+    }
+
+    #[test]
+    fn backtrace() {
+        let module = compile_module(": inner 1 0 / ; : outer inner 2 ; outer 3").unwrap();
+        let entries = (module.dictionary.get("inner").unwrap(), module.dictionary.get("outer").unwrap());
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+
+        for decoded in [true, false] {
+            vm.reset();
+            let err = if decoded { vm.run(&mut NullExtender {}) } else { vm.run_bytes(&mut NullExtender {}) }.unwrap_err();
+            assert!(matches!(err.kind, Error::DivisionByZero));
+
+            let frames = err.backtrace();
+            let words: Vec<_> = frames.iter().map(|n| n.word.as_deref()).collect();
+            assert_eq!(words, [Some("inner"), Some("outer"), None]);
+            assert_eq!(frames[0].pc, err.pc);
+            assert!(frames[1].pc > entries.1 && frames[2].pc < entries.0);
+        }
+
+        assert!(RuntimeError::new(0, Error::StackUnderflow).backtrace().is_empty());
+    }
+
+    #[test]
+    fn step_and_reset() {
+        let mut vm = Vm::new(b"#2'#3'+;".to_vec(), Vec::new());
+        let mut ext = NullExtender {};
+
+        for _ in 0..6 {
+            assert_eq!(vm.step(&mut ext).unwrap(), Status::Running);
+        }
+        assert_eq!(vm.stack.len(), 2);
+
+        assert_eq!(vm.step(&mut ext).unwrap(), Status::Running);
+        assert_eq!(vm.step(&mut ext).unwrap(), Status::Halted);
+        assert_eq!(vm.stack.len(), 1);
+
+        match vm.stack.pop() {
+            Ok(Data::Int(5)) => {},
+            _ => panic!("Expected Int:5"),
+        }
+
+        vm.reset();
+        assert_eq!(vm.pc, 0);
+        vm.run(&mut ext).unwrap();
+        assert_eq!(vm.stack.len(), 1);
+    }
+
+    #[cfg(all(feature = "tracing", feature = "std"))]
+    #[test]
+    fn logs() {
+        use alloc::string::String;
+        use core::fmt;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        ///Writes each span and event as a line of its name or message and
+        ///fields.
+        struct Log(Arc<Mutex<Vec<String>>>);
+
+        struct Line<'a>(&'a mut String);
+
+        impl<'a> Visit for Line<'a> {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0.push_str(&format!(" {}={:?}", field.name(), value));
+            }
+        }
+
+        impl Subscriber for Log {
+            fn enabled(&self, _: &Metadata) -> bool {true}
+
+            fn new_span(&self, span: &Attributes) -> Id {
+                let mut line = String::from(span.metadata().name());
+                span.record(&mut Line(&mut line));
+                self.0.lock().unwrap().push(line);
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _: &Id, _: &Record) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut line = String::new();
+                event.record(&mut Line(&mut line));
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = Vm::from_module(compile_module(": half 2 / ; : twice 2 * ; : broken 0 / ; 6 half twice 0 call-host broken").unwrap(), Vec::new()).unwrap();
+        vm.bind("nothing", |_| Ok(()));
+        tracing::subscriber::with_default(Log(lines.clone()), || {
+            assert!(vm.run(&mut NullExtender {}).is_err());
+        });
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 5, "{:?}", lines);
+        assert_eq!(lines[0], "run pc=0");
+        assert!(lines[1].starts_with(" message=call pc=") && lines[1].ends_with(" word=\"half\""));
+        assert!(lines[2].ends_with(" word=\"twice\""));
+        assert_eq!(lines[3], "host_call index=0 name=\"nothing\"");
+        assert!(lines[4].starts_with(" message=run failed pc="));
+        assert!(lines[4].ends_with(" word=\"broken\" error=Division By Zero"));
+    }
+}
@@ -0,0 +1,88 @@
+//!Passing values in and out of a program by name.
+//!
+//!A machine made from a module keeps the module's globals, so the host
+//!can write a program's inputs to its variables before a run and read
+//!its results back afterwards without agreeing on addresses. Values go
+//!through the conversions in `args`, so they are native Rust types on the
+//!host's side.
+
+use args::{FromData, IntoData};
+use module::Global;
+use storage::Storage;
+use vm::Vm;
+use Error;
+
+impl<S: Storage, M: Storage> Vm<S, M> {
+    ///Get the address of the variable with a name.
+    pub fn variable(&self, name: &str) -> Option<usize> {
+        match self.globals.get(name) {
+            Some(&Global::Variable(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    ///Write values to the variables with some names. Fails with
+    ///`UnknownWord` if a name isn't a variable, or `MemoryOutOfBounds`
+    ///if its cell isn't in memory, having written nothing.
+    pub fn write_inputs<T: IntoData + Clone>(&mut self, inputs: &[(&str, T)]) -> Result<(),Error> {
+        let len = self.memory.len();
+        for &(name, _) in inputs {
+            match self.variable(name) {
+                Some(n) if n < len => {},
+                Some(n) => { return Err(Error::MemoryOutOfBounds { addr: n as i64, len }); },
+                None => { return Err(Error::UnknownWord); }
+            }
+        }
+
+        for (name, value) in inputs {
+            if let Some(n) = self.variable(name) {
+                self.memory.as_mut_slice()[n] = value.clone().into_data();
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Read the variable or constant with a name as a native type. Fails
+    ///with `UnknownWord` if there is no such global, `MemoryOutOfBounds`
+    ///if a variable's cell isn't in memory, or `TypeMismatch`.
+    pub fn read_output<T: FromData>(&self, name: &str) -> Result<T,Error> {
+        let value = match self.globals.get(name) {
+            Some(&Global::Variable(n)) => match self.memory.as_slice().get(n) {
+                Some(value) => value.clone(),
+                None => { return Err(Error::MemoryOutOfBounds { addr: n as i64, len: self.memory.len() }); }
+            },
+            Some(Global::Constant(value)) => value.clone(),
+            None => { return Err(Error::UnknownWord); }
+        };
+
+        T::from_data(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use compiler::compile_module;
+    use vm::Vm;
+    use {Data, Error, NullExtender};
+
+    #[test]
+    fn inputs_and_outputs() {
+        let module = compile_module("variable x variable y variable result 2.0 constant two
+            x @ dup * y @ dup * + two / result !").unwrap();
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+
+        vm.write_inputs(&[("x", 3.0), ("y", 4.0)]).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.read_output::<f64>("result").unwrap(), 12.5);
+        assert_eq!(vm.read_output::<f64>("two").unwrap(), 2.0);
+        assert_eq!(vm.read_output::<Data>("x").unwrap(), Data::Float(3.0));
+
+        assert!(matches!(vm.read_output::<i64>("result"), Err(Error::TypeMismatch { .. })));
+        assert!(matches!(vm.read_output::<f64>("z"), Err(Error::UnknownWord)));
+        assert!(matches!(vm.write_inputs(&[("x", 1), ("two", 2)]), Err(Error::UnknownWord)));
+        assert_eq!(vm.read_output::<f64>("x").unwrap(), 3.0);
+    }
+}
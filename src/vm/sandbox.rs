@@ -0,0 +1,200 @@
+//!Hard limits for running code that isn't trusted.
+//!
+//!A sandbox caps the data stack, memory and steps, and refuses every
+//!extender opcode and host call it wasn't told to allow. Limits are
+//!checked as the code runs, so a script that breaks one stops with
+//!`StackLimitExceeded`, `MemoryLimitExceeded`, `FuelExhausted` or
+//!`OpcodeNotAllowed` rather than taking the host down with it.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use heap::Heap;
+use input::NullInput;
+use output::NullOutput;
+use storage::Storage;
+use vm::{MemoryPolicy, RunConfig, Vm};
+use Data;
+use Error;
+use RuntimeError;
+
+///The limits a sandboxed machine runs under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SandboxConfig {
+    ///Most items the data stack may hold after any instruction.
+    pub max_stack_depth: usize,
+    ///Most cells memory may grow to.
+    pub max_memory: usize,
+    ///Most cells the live arrays on the heap may hold between them.
+    pub max_array_cells: usize,
+    ///Longest string, in bytes, an instruction may leave on top of the
+    ///stack. Strings are only made longer by concatenating them, so this
+    ///keeps each one at most twice as long, and everything a run
+    ///allocates within about `max_steps` of them.
+    pub max_string_len: usize,
+    ///Most instructions a single call to `run` may execute.
+    pub max_steps: u64,
+    ///Extender opcodes the code may use. Include `h` to allow calls to
+    ///host functions; nothing is allowed by default.
+    pub allowed_opcodes: BTreeSet<u8>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> SandboxConfig {
+        SandboxConfig {
+            max_stack_depth: 1024,
+            max_memory: 65536,
+            max_array_cells: 65536,
+            max_string_len: 4096,
+            max_steps: 1_000_000,
+            allowed_opcodes: BTreeSet::new(),
+        }
+    }
+}
+
+impl SandboxConfig {
+    ///Check whether the code may use an extender opcode, or `h`.
+    pub fn allows(&self, opcode: u8) -> bool {
+        self.allowed_opcodes.contains(&opcode)
+    }
+}
+
+impl Vm {
+    ///Create a machine with no code that runs under a sandbox. Memory
+    ///starts empty and grows as the code writes to it, up to the limit.
+    ///Input is always at its end and output goes nowhere until the host
+    ///replaces them.
+    pub fn sandboxed(sandbox: SandboxConfig) -> Vm {
+        let mut vm = Vm::new(Vec::new(), Vec::new());
+        vm.config = RunConfig {
+            max_steps: Some(sandbox.max_steps),
+            memory: MemoryPolicy::Grow,
+            sandbox: Some(sandbox),
+            ..RunConfig::default()
+        };
+        vm.input = Box::new(NullInput {});
+        vm.output = Box::new(NullOutput {});
+
+        vm
+    }
+}
+
+impl<S: Storage, M: Storage> Vm<S, M> {
+    ///Check that an instruction left the stack within the sandbox, if
+    ///there is one.
+    #[inline]
+    pub(crate) fn check_sandbox(&self) -> Result<(),RuntimeError> {
+        if let Some(ref sandbox) = self.config.sandbox {
+            if self.stack.len() > sandbox.max_stack_depth {
+                return Err(RuntimeError::new(self.pc, Error::StackLimitExceeded));
+            }
+            if let Some(Data::Str(n)) = self.stack.peek() {
+                if n.len() > sandbox.max_string_len {
+                    return Err(RuntimeError::new(self.pc, Error::MemoryLimitExceeded));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///Check that a new array with the length in some value fits alongside
+///the heap's other arrays under a run configuration. Lengths that aren't
+///valid are left for the array code to report.
+#[inline]
+pub(crate) fn check_array(config: &RunConfig, heap: &Heap, len: Option<&Data>) -> Result<(),Error> {
+    match (&config.sandbox, len) {
+        (Some(sandbox), Some(&Data::Int(n))) if n > 0 && heap.cells() as u64 + n as u64 > sandbox.max_array_cells as u64 => {
+            Err(Error::MemoryLimitExceeded)
+        },
+        _ => Ok(()),
+    }
+}
+
+///Check that memory may grow to some length under a run configuration.
+#[inline]
+pub(crate) fn check_memory(config: &RunConfig, len: usize) -> Result<(),Error> {
+    match config.sandbox {
+        Some(ref sandbox) if len > sandbox.max_memory => Err(Error::MemoryLimitExceeded),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeSet;
+    use alloc::string::ToString;
+
+    use super::SandboxConfig;
+    use vm::Vm;
+    use {Data, Error, NullExtender, RuntimeError, Stack};
+
+    #[test]
+    fn limits() {
+        let sandbox = SandboxConfig {
+            max_stack_depth: 4,
+            max_memory: 8,
+            max_array_cells: 8,
+            max_string_len: 8,
+            max_steps: 100,
+            allowed_opcodes: BTreeSet::new(),
+        };
+
+        let mut vm = Vm::sandboxed(sandbox.clone());
+        vm.load(b"#1'#2'#3'#4'#5'".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::StackLimitExceeded, pc: 15, .. })));
+
+        //Writes grow memory up to the limit and no further.
+        let mut vm = Vm::sandboxed(sandbox.clone());
+        vm.load(b"#1'#7'W#1'#8'W".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryLimitExceeded, .. })));
+        assert_eq!(vm.memory.len(), 8);
+
+        let mut vm = Vm::sandboxed(sandbox.clone());
+        vm.load(b"#9'A".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryLimitExceeded, .. })));
+        assert!(vm.memory.is_empty());
+
+        //Arrays count against their own limit, until they're freed.
+        let mut vm = Vm::sandboxed(sandbox.clone());
+        vm.load(b"#6'a#3'a".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryLimitExceeded, .. })));
+        vm.load(b"rf#3'a".to_vec()).unwrap();
+        vm.run(&mut NullExtender {}).unwrap();
+
+        let mut vm = Vm::sandboxed(sandbox.clone());
+        vm.load(b"[abc]dkdk".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::MemoryLimitExceeded, pc: 9, .. })));
+
+        let mut vm = Vm::sandboxed(sandbox);
+        vm.load(b"#0'#8'-B".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut NullExtender {}), Err(RuntimeError { kind: Error::FuelExhausted, .. })));
+    }
+
+    #[test]
+    fn host_surface() {
+        let mut extender = |opcode: u8, stack: &mut Stack| {
+            stack.push(Data::Int(opcode as i64));
+            Ok(())
+        };
+
+        let mut vm = Vm::sandboxed(SandboxConfig::default());
+        vm.bind("answer", |stack| {
+            stack.push(Data::Int(42));
+            Ok(())
+        });
+        vm.load(b"\xf0".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut extender), Err(RuntimeError { kind: Error::OpcodeNotAllowed(0xf0), .. })));
+        vm.load(b"#0'h".to_vec()).unwrap();
+        assert!(matches!(vm.run(&mut extender), Err(RuntimeError { kind: Error::OpcodeNotAllowed(b'h'), .. })));
+
+        let mut sandbox = SandboxConfig::default();
+        sandbox.allowed_opcodes.extend(b"\xf0h");
+        vm.config.sandbox = Some(sandbox);
+        vm.load(b"\xf0#0'h".to_vec()).unwrap();
+        vm.run(&mut extender).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> 240 42");
+    }
+}
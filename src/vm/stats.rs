@@ -0,0 +1,196 @@
+//!Numbers about a single run, for hosts that plan capacity or bill by
+//!use.
+//!
+//!`Vm::run_with_stats` runs like `run_bytes`, counting as it goes, and
+//!hands the counts back with the result. They cover the run up to where
+//!it stopped, whether it finished, yielded or failed.
+
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use opcodes::ESCAPE;
+use storage::Storage;
+use vm::{Status, Vm, POLL_INTERVAL};
+use AtomExtender;
+use Error;
+use RuntimeError;
+
+///What a run did.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct ExecutionStats {
+    ///Instructions run, including the one that failed, if any.
+    pub instructions: u64,
+    ///Fuel used, which is the number of instructions unless there is a
+    ///`RunConfig::cost` model. An instruction that failed is charged for;
+    ///one the budget couldn't cover is not.
+    pub fuel: u64,
+    ///Calls to words, by address or by name, including calls in a
+    ///`catch`.
+    pub calls: u64,
+    ///Calls to host functions with `h`.
+    pub host_calls: u64,
+    ///Most items on the data stack after any instruction.
+    pub max_stack_depth: usize,
+    ///Most items on the return stack after any instruction.
+    pub max_return_depth: usize,
+    ///Instructions that read memory.
+    pub memory_reads: u64,
+    ///Instructions that wrote memory. Copying within memory counts as a
+    ///read and a write.
+    pub memory_writes: u64,
+    ///How long the run took.
+    #[cfg(feature = "std")]
+    pub wall_time: Duration,
+}
+
+impl ExecutionStats {
+    ///Count an instruction that ran without error.
+    fn record<S: Storage, M: Storage>(&mut self, vm: &Vm<S, M>, opcode: u8, extended: Option<u8>) {
+        match (opcode, extended) {
+            (b'C', _) | (b'c', _) | (b'`', _) | (ESCAPE, Some(b'c')) => { self.calls += 1; },
+            (b'h', _) => { self.host_calls += 1; },
+            (b'R', _) | (ESCAPE, Some(b'b')) | (ESCAPE, Some(b's')) => { self.memory_reads += 1; },
+            (b'W', _) | (ESCAPE, Some(b'B')) | (ESCAPE, Some(b'S')) | (ESCAPE, Some(b'f')) => { self.memory_writes += 1; },
+            (ESCAPE, Some(b'm')) => {
+                self.memory_reads += 1;
+                self.memory_writes += 1;
+            },
+            _ => {},
+        }
+
+        self.max_stack_depth = self.max_stack_depth.max(vm.stack.len());
+        self.max_return_depth = self.max_return_depth.max(vm.rstack.len());
+    }
+}
+
+impl<S: Storage, M: Storage> Vm<S, M> {
+    ///Like `run_bytes`, counting what the run does.
+    pub fn run_with_stats<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T) -> (Result<(),RuntimeError>, ExecutionStats) {
+        let mut stats = ExecutionStats::default();
+        #[cfg(feature = "std")]
+        let started = Instant::now();
+
+        let result = self.run_counted(extender, &mut stats);
+        #[cfg(feature = "std")]
+        {
+            stats.wall_time = started.elapsed();
+        }
+
+        (result, stats)
+    }
+
+    fn run_counted<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T, stats: &mut ExecutionStats) -> Result<(),RuntimeError> {
+        let max = self.config.max_steps.unwrap_or(u64::MAX);
+        stats.max_stack_depth = self.stack.len();
+        stats.max_return_depth = self.rstack.len();
+
+        while self.pc < self.code.len() {
+            let opcode = self.code[self.pc];
+
+            let cost = self.fuel_cost(opcode, extender);
+            if cost > max - stats.fuel {
+                return Err(self.with_backtrace(RuntimeError::new(self.pc, Error::FuelExhausted)));
+            }
+            if stats.instructions.is_multiple_of(POLL_INTERVAL) {
+                if let Some(n) = self.stop_requested() {
+                    return Err(self.with_backtrace(RuntimeError::new(self.pc, n)));
+                }
+            }
+            stats.instructions += 1;
+            stats.fuel += cost;
+
+            let extended = match opcode {
+                ESCAPE => self.code.get(self.pc + 1).cloned(),
+                _ => None,
+            };
+
+            let status = self.execute(opcode, extender).map_err(|n| self.with_backtrace(n))?;
+            stats.record(self, opcode, extended);
+            self.check_sandbox().map_err(|n| self.with_backtrace(n))?;
+
+            if let Status::Halted | Status::Yielded = status {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use compiler::compile_module;
+    use vm::{ExecutionStats, Vm};
+    use {AtomExtender, Data, Error, NullExtender, RuntimeError, Stack};
+
+    #[test]
+    fn counts() {
+        let module = compile_module("variable n : bump n @ 1 + n ! ; : twice bump bump ; twice twice n @").unwrap();
+        let mut vm = Vm::from_module(module, Vec::new()).unwrap();
+        vm.bind("nothing", |_| Ok(()));
+        let (result, stats) = vm.run_with_stats(&mut NullExtender {});
+        result.unwrap();
+        assert_eq!(vm.stack.as_slice().to_vec(), vec![Data::Int(4)]);
+        assert_eq!(stats.memory_reads, 5);
+        assert_eq!(stats.memory_writes, 4);
+        assert_eq!(stats.max_return_depth, 2);
+        assert!(stats.calls >= 2 && stats.instructions > 20);
+
+        //The 4 left from the last run is still on the stack.
+        vm.load(b"#1'#2'#0'h#0'h#1'#0'/".to_vec()).unwrap();
+        let (result, stats) = vm.run_with_stats(&mut NullExtender {});
+        assert!(matches!(result.map_err(|n| n.kind), Err(Error::DivisionByZero)));
+        let expected = ExecutionStats {
+            instructions: 21,
+            fuel: 21,
+            host_calls: 2,
+            max_stack_depth: 5,
+            ..ExecutionStats::default()
+        };
+        #[cfg(feature = "std")]
+        let expected = ExecutionStats { wall_time: stats.wall_time, ..expected };
+        assert_eq!(stats, expected);
+    }
+
+    #[test]
+    fn costs() {
+        fn cost(opcode: u8) -> u64 {
+            match opcode {
+                b'R' | b'W' | b'h' => 10,
+                b' ' => 0,
+                _ => 1,
+            }
+        }
+
+        struct Service;
+
+        impl AtomExtender for Service {
+            fn atom(&mut self, _: u8, stack: &mut Stack) -> Result<(),Error> {
+                stack.push(Data::Int(0));
+                Ok(())
+            }
+
+            fn cost(&self, _: u8) -> Option<u64> {
+                Some(100)
+            }
+        }
+
+        let mut vm = Vm::new(b"#5'#0'W #0'R \xf0".to_vec(), vec![Data::Int(0)]);
+        vm.config.cost = Some(cost);
+        let (result, stats) = vm.run_with_stats(&mut Service);
+        result.unwrap();
+        assert_eq!((stats.instructions, stats.fuel), (14, 129));
+
+        //Not enough left for the extender's opcode.
+        vm.reset();
+        vm.config.max_steps = Some(128);
+        let (result, stats) = vm.run_with_stats(&mut Service);
+        assert!(matches!(result, Err(RuntimeError { kind: Error::FuelExhausted, pc: 13, .. })));
+        assert_eq!(stats.fuel, 29);
+
+        vm.reset();
+        assert!(matches!(vm.run(&mut Service), Err(RuntimeError { kind: Error::FuelExhausted, pc: 13, .. })));
+    }
+}
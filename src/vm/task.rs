@@ -0,0 +1,185 @@
+//!Cooperative multitasking, in the style of a classic Forth round-robin
+//!multitasker.
+//!
+//!A task is a PC, a data stack, a return stack, its frames of locals and
+//!the catches it is waiting in. Every task on a machine shares its code,
+//!memory and heap. The machine's own registers
+//!are the running task; the others wait in `Vm::tasks` and are swapped in
+//!one at a time by `run_round_robin`. A task gives up its turn with the
+//!`w` opcode, or when it has run its share of steps.
+
+use alloc::vec::Vec;
+use core::mem;
+
+use storage::Storage;
+use vm::{CatchFrame, Status, Vm, POLL_INTERVAL};
+use AtomExtender;
+use Data;
+use Error;
+use RuntimeError;
+use Stack;
+
+///A thread of execution waiting for its turn on a machine.
+pub struct Task<S = Vec<Data>> {
+    pub pc: usize,
+    pub stack: Stack<S>,
+    pub rstack: Vec<usize>,
+    pub locals: Vec<Data>,
+    pub frames: Vec<usize>,
+    pub catches: Vec<CatchFrame>,
+    value: i64,
+    divider: f64,
+}
+
+impl<S: Storage> Task<S> {
+    ///Create a task that starts at some address, with a data stack over
+    ///some storage, keeping any items already in it.
+    pub fn new(pc: usize, stack: S) -> Task<S> {
+        Task {
+            pc,
+            stack: Stack::with_storage(stack),
+            rstack: Vec::new(),
+            locals: Vec::new(),
+            frames: Vec::new(),
+            catches: Vec::new(),
+            value: 0,
+            divider: 1.0,
+        }
+    }
+}
+
+impl<S: Storage, M: Storage> Vm<S, M> {
+    ///Add a task that starts at some address to the end of the queue.
+    pub fn spawn(&mut self, pc: usize, stack: S) {
+        self.tasks.push_back(Task::new(pc, stack));
+    }
+
+    ///Swap the running task's registers with a waiting one's.
+    fn switch(&mut self, task: &mut Task<S>) {
+        mem::swap(&mut self.pc, &mut task.pc);
+        mem::swap(&mut self.stack, &mut task.stack);
+        mem::swap(&mut self.rstack, &mut task.rstack);
+        mem::swap(&mut self.locals, &mut task.locals);
+        mem::swap(&mut self.frames, &mut task.frames);
+        mem::swap(&mut self.catches, &mut task.catches);
+        mem::swap(&mut self.value, &mut task.value);
+        mem::swap(&mut self.divider, &mut task.divider);
+    }
+
+    ///Run the current task and every waiting one in turn, each for up to
+    ///`steps_per_task` steps (at least one) or until it pauses, until all
+    ///of them have finished.
+    ///
+    ///The configured fuel budget covers the instructions of every task,
+    ///priced by the cost model if there is one. If a task fails, yields,
+    ///is interrupted or the budget runs out, it is left as the running
+    ///task with the others still waiting, so calling this again carries
+    ///on.
+    pub fn run_round_robin<T: AtomExtender<S, M> + ?Sized>(&mut self, extender: &mut T, steps_per_task: u64) -> Result<(),RuntimeError> {
+        let max = self.config.max_steps.unwrap_or(u64::MAX);
+        let mut steps: u64 = 0;
+        let mut fuel: u64 = 0;
+
+        loop {
+            let mut finished = false;
+            for _ in 0..steps_per_task.max(1) {
+                if self.pc >= self.code.len() {
+                    finished = true;
+                    break;
+                }
+
+                let opcode = self.code[self.pc];
+                let cost = self.fuel_cost(opcode, extender);
+                if cost > max - fuel {
+                    return Err(self.with_backtrace(RuntimeError::new(self.pc, Error::FuelExhausted)));
+                }
+                if steps.is_multiple_of(POLL_INTERVAL) {
+                    if let Some(n) = self.stop_requested() {
+                        return Err(self.with_backtrace(RuntimeError::new(self.pc, n)));
+                    }
+                }
+                steps += 1;
+                fuel += cost;
+
+                let status = self.execute(opcode, extender).map_err(|n| self.with_backtrace(n))?;
+                self.check_sandbox().map_err(|n| self.with_backtrace(n))?;
+                match status {
+                    Status::Running => {},
+                    Status::Paused => { break; },
+                    Status::Yielded => { return Ok(()); },
+                    Status::Halted => {
+                        finished = true;
+                        break;
+                    },
+                }
+            }
+            finished = finished || self.pc >= self.code.len();
+
+            let mut next = match self.tasks.pop_front() {
+                Some(n) => n,
+                None if finished => { return Ok(()); },
+                None => { continue; }
+            };
+            self.switch(&mut next);
+            if !finished {
+                self.tasks.push_back(next);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use vm::Vm;
+    use {Data, Error, NullExtender, RuntimeError};
+
+    #[test]
+    fn round_robin() {
+        //Each task writes its number to the next free cell, counted in
+        //cell 15, and pauses, three times over.
+        let code = b"v#15'RW#15'R#1'+#15'Ww#1'-d#0'yrr;".to_vec();
+        let mut vm = Vm::new(code, vec![Data::Int(0); 16]);
+        vm.memory[15] = Data::Int(1);
+        vm.stack.push(Data::Int(1));
+        vm.stack.push(Data::Int(3));
+        vm.spawn(0, vec![Data::Int(2), Data::Int(3)]);
+        vm.spawn(0, vec![Data::Int(3), Data::Int(3)]);
+        vm.run_round_robin(&mut NullExtender {}, 1000).unwrap();
+
+        assert!(vm.tasks.is_empty());
+        let expected: Vec<Data> = [1, 2, 3, 1, 2, 3, 1, 2, 3].iter().map(|&n| Data::Int(n)).collect();
+        assert_eq!(vm.memory[1..10].to_vec(), expected);
+
+        //Turns also end after the given number of steps, even in the
+        //middle of a literal.
+        let mut vm = Vm::new(b"#1'#2'#3';".to_vec(), Vec::new());
+        vm.spawn(3, Vec::new());
+        vm.run_round_robin(&mut NullExtender {}, 1).unwrap();
+        assert_eq!(vm.stack.iter().cloned().collect::<Vec<_>>(), vec![Data::Int(1), Data::Int(2), Data::Int(3)]);
+    }
+
+    #[test]
+    fn costs() {
+        fn cost(opcode: u8) -> u64 {
+            match opcode {
+                b'W' => 10,
+                _ => 1,
+            }
+        }
+
+        //Two tasks of 16 fuel each.
+        let mut vm = Vm::new(b"#1'#0'W".to_vec(), vec![Data::Int(0)]);
+        vm.config.cost = Some(cost);
+        vm.config.max_steps = Some(31);
+        vm.spawn(0, Vec::new());
+        assert!(matches!(vm.run_round_robin(&mut NullExtender {}, 100), Err(RuntimeError { kind: Error::FuelExhausted, pc: 6, .. })));
+
+        vm.reset();
+        vm.config.max_steps = Some(32);
+        vm.spawn(0, Vec::new());
+        vm.run_round_robin(&mut NullExtender {}, 100).unwrap();
+        assert!(vm.tasks.is_empty());
+    }
+}
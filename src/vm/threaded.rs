@@ -0,0 +1,536 @@
+//!Direct-threaded code for hot words, behind the `threaded` feature.
+//!
+//!`Vm::run` counts the calls it makes to each address, and the times it
+//!jumps back to the top of each loop. Once one has been reached
+//!`RunConfig::hot_threshold` times its decoded instructions, from there
+//!up to the first `;`, are compiled into a list of cells that each hold
+//!a pointer to the function running them, with jumps inside the word
+//!turned into cell indices. From then on the cells run straight through
+//!without looking anything up by address.
+//!
+//!Anything a cell can't do — an extender opcode, a computed jump, a jump
+//!out of the word, a push that would overflow or a fuel budget about to
+//!run out — hands the machine back to the interpreter at that byte, so
+//!results, errors and fuel are exactly those of `Vm::run_bytes`.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use disasm::mnemonic;
+use ir::{Op, Program};
+use storage::Storage;
+use Data;
+use NullExtender;
+use RuntimeError;
+
+use super::{CoercionPolicy, Vm};
+
+///What to do after a cell.
+enum Flow {
+    ///Run the next cell.
+    Next,
+    ///Run the cell at an index.
+    Goto(usize),
+    ///The cell has run and set the PC; carry on in the interpreter.
+    Leave,
+    ///The cell has called a word and set the PC to it.
+    Call(usize),
+    ///The cell didn't run; the interpreter should run it instead.
+    Bail,
+}
+
+type Handler<S, M> = fn(&mut Vm<S, M>, &Cell<S, M>) -> Result<Flow,RuntimeError>;
+
+///A jump target: its address, and its cell if it is inside the word.
+#[derive(Clone, Copy)]
+struct Target {
+    pc: usize,
+    cell: Option<usize>,
+}
+
+enum Arg {
+    None,
+    Int(i64),
+    Float(f64),
+    Str(Arc<str>),
+    Target(Target),
+    Byte(u8),
+    Then(i64, u8),
+}
+
+struct Cell<S, M> {
+    run: Handler<S, M>,
+    arg: Arg,
+    pc: usize,
+    next: usize,
+    steps: u64,
+    literal: Option<(i64, f64)>,
+}
+
+///A compiled word.
+pub(super) struct Threaded<S, M> {
+    cells: Vec<Cell<S, M>>,
+    ///Cell indices by address, for return addresses inside the word.
+    index: BTreeMap<usize, usize>,
+}
+
+///How often `run` has reached an address so far, or the code compiled
+///from it.
+pub(super) enum Word<S, M> {
+    Cold(u32),
+    Compiled(Arc<Threaded<S, M>>),
+}
+
+impl<S: Storage, M: Storage> Threaded<S, M> {
+    ///Compile the word at `entry`. It stops before the first byte a cell
+    ///can't run, so it may be empty.
+    fn compile(program: &Program, entry: usize) -> Threaded<S, M> {
+        let mut cells: Vec<Cell<S, M>> = Vec::new();
+        let mut index = BTreeMap::new();
+        let mut pc = entry;
+        //Only a word that calls itself can return into itself. The word
+        //ends at its first `;`, so any such call comes before it.
+        let mut recursive = false;
+
+        while let Some(instruction) = program.at(pc) {
+            let (run, arg): (Handler<S, M>, Arg) = match instruction.op {
+                Op::Int(n) => (push_int, Arg::Int(n)),
+                Op::Float(value, divider) => (push_float, Arg::Float(value as f64 / divider)),
+                Op::ExactFloat(value) => (push_float, Arg::Float(value)),
+                Op::Str(ref n) => (push_str, Arg::Str(n.clone())),
+                Op::Jump(n) => (jump, target(n)),
+                Op::JumpIf(n) => (jump_if, target(n)),
+                Op::JumpUnless(n) => (jump_unless, target(n)),
+                Op::Call(n) => {
+                    recursive |= n == entry;
+                    (call, target(n))
+                },
+                Op::IntThen(n, op) => (int_then, Arg::Then(n, op)),
+                Op::DupThen(op) => (dup_then, Arg::Byte(op)),
+                Op::Skip => (skip, Arg::None),
+                Op::Byte(b';') if recursive => (ret_inner, Arg::None),
+                Op::Byte(b';') => (ret, Arg::None),
+                Op::Byte(b'+') => (add, Arg::None),
+                Op::Byte(b'-') => (sub, Arg::None),
+                Op::Byte(b'*') => (mul, Arg::None),
+                Op::Byte(b'<') => (lt, Arg::None),
+                Op::Byte(b'=') => (eq, Arg::None),
+                Op::Byte(b'>') => (gt, Arg::None),
+                Op::Byte(b'd') => (dup, Arg::None),
+                Op::Byte(b'r') => (drop, Arg::None),
+                Op::Byte(b's') => (swap, Arg::None),
+                Op::Byte(b'v') => (over, Arg::None),
+                //These move the PC somewhere only the stack knows.
+                Op::Byte(b'B') | Op::Byte(b'C') | Op::Byte(b'b') | Op::Byte(b'c') | Op::Byte(b'[') | Op::Byte(b'`')
+                | Op::Byte(b'Y') | Op::Byte(b'Z') | Op::Byte(b'y') | Op::Byte(b'z') | Op::Byte(b'J') | Op::Byte(b'j') => { break; },
+                //These hand control back to the host.
+                Op::Byte(b'i') => { break; },
+                #[cfg(feature = "async")]
+                Op::Byte(b'h') => { break; },
+                Op::Byte(n) if mnemonic(n).is_some() => (byte, Arg::Byte(n)),
+                Op::Byte(_) => { break; },
+            };
+
+            index.insert(pc, cells.len());
+            cells.push(Cell {
+                run,
+                arg,
+                pc,
+                next: instruction.next,
+                steps: instruction.steps,
+                literal: instruction.literal,
+            });
+
+            pc = instruction.next;
+            if instruction.op == Op::Byte(b';') {
+                break;
+            }
+        }
+
+        for cell in cells.iter_mut() {
+            if let Arg::Target(ref mut n) = cell.arg {
+                n.cell = index.get(&n.pc).cloned();
+            }
+        }
+
+        Threaded { cells, index }
+    }
+}
+
+fn target(pc: usize) -> Arg {
+    Arg::Target(Target { pc, cell: None })
+}
+
+impl<S: Storage, M: Storage> Vm<S, M> {
+    ///Count a call or a loop `run` has just gone to `target`, and while
+    ///the code there is compiled run it, following it into the words it
+    ///calls.
+    pub(super) fn run_hot(&mut self, program: &Program, mut target: usize, max: u64, steps: &mut u64) -> Result<(),RuntimeError> {
+        loop {
+            let code = match self.hot(program, target) {
+                Some(n) => n,
+                None => { return Ok(()); }
+            };
+
+            target = match self.run_threaded(&code, max, steps)? {
+                Some(n) => n,
+                None => { return Ok(()); }
+            };
+        }
+    }
+
+    fn hot(&mut self, program: &Program, target: usize) -> Option<Arc<Threaded<S, M>>> {
+        let threshold = self.config.hot_threshold?;
+        if self.words.len() < self.code.len() {
+            let len = self.code.len();
+            self.words.resize_with(len, || Word::Cold(0));
+        }
+        let word = self.words.get_mut(target)?;
+
+        if let Word::Cold(ref mut n) = *word {
+            *n += 1;
+            if *n < threshold {
+                return None;
+            }
+        }
+        if let Word::Cold(_) = *word {
+            *word = Word::Compiled(Arc::new(Threaded::compile(program, target)));
+        }
+
+        match *word {
+            Word::Compiled(ref n) => Some(n.clone()),
+            Word::Cold(_) => None,
+        }
+    }
+
+    ///Run compiled code from its first cell, with the PC at its entry.
+    ///Returns the word called if it stopped by calling one.
+    #[inline(never)]
+    fn run_threaded(&mut self, code: &Threaded<S, M>, max: u64, steps: &mut u64) -> Result<Option<usize>,RuntimeError> {
+        let mut n = 0;
+
+        while let Some(cell) = code.cells.get(n) {
+            if max - *steps < cell.steps {
+                self.pc = cell.pc;
+                return Ok(None);
+            }
+
+            match (cell.run)(self, cell)? {
+                Flow::Next => { n += 1; },
+                Flow::Goto(i) => { n = i; },
+                Flow::Leave => {
+                    *steps += cell.steps;
+                    return Ok(None);
+                },
+                Flow::Call(target) => {
+                    *steps += cell.steps;
+                    return Ok(Some(target));
+                },
+                Flow::Bail => {
+                    self.pc = cell.pc;
+                    return Ok(None);
+                },
+            }
+            *steps += cell.steps;
+
+            //A return into the word, from a call it made to itself.
+            if n == usize::MAX {
+                n = match code.index.get(&self.pc) {
+                    Some(&i) => i,
+                    None => { return Ok(None); }
+                };
+            }
+        }
+
+        if let Some(cell) = code.cells.last() {
+            self.pc = cell.next;
+        }
+        Ok(None)
+    }
+}
+
+fn literal<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) {
+    if let Some((value, divider)) = cell.literal {
+        vm.value = value;
+        vm.divider = divider;
+    }
+}
+
+fn push<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>, value: Data) -> Result<Flow,RuntimeError> {
+    match vm.stack.try_push(value) {
+        Ok(()) => {
+            literal(vm, cell);
+            Ok(Flow::Next)
+        },
+        Err(_) => Ok(Flow::Bail),
+    }
+}
+
+fn push_int<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    match cell.arg {
+        Arg::Int(n) => push(vm, cell, Data::Int(n)),
+        _ => Ok(Flow::Bail),
+    }
+}
+
+fn push_float<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    match cell.arg {
+        Arg::Float(n) => push(vm, cell, Data::Float(n)),
+        _ => Ok(Flow::Bail),
+    }
+}
+
+fn push_str<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    match cell.arg {
+        Arg::Str(ref n) => push(vm, cell, Data::Str(n.clone())),
+        _ => Ok(Flow::Bail),
+    }
+}
+
+fn int_then<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    match cell.arg {
+        Arg::Then(n, op) if vm.push_then(Data::Int(n), op) => {
+            literal(vm, cell);
+            Ok(Flow::Next)
+        },
+        _ => Ok(Flow::Bail),
+    }
+}
+
+fn dup_then<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    let tos = match vm.stack.peek() {
+        Some(&Data::Int(n)) => n,
+        _ => { return Ok(Flow::Bail); }
+    };
+
+    match cell.arg {
+        Arg::Byte(op) if vm.push_then(Data::Int(tos), op) => Ok(Flow::Next),
+        _ => Ok(Flow::Bail),
+    }
+}
+
+fn skip<S: Storage, M: Storage>(_: &mut Vm<S, M>, _: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    Ok(Flow::Next)
+}
+
+///Go to a target, staying in the word if it is inside it.
+fn go<S: Storage, M: Storage>(vm: &mut Vm<S, M>, target: Target) -> Flow {
+    match target.cell {
+        Some(n) => Flow::Goto(n),
+        None => {
+            vm.pc = target.pc;
+            Flow::Leave
+        },
+    }
+}
+
+fn jump<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    match cell.arg {
+        Arg::Target(target) => {
+            literal(vm, cell);
+            Ok(go(vm, target))
+        },
+        _ => Ok(Flow::Bail),
+    }
+}
+
+fn branch<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>, when: bool) -> Result<Flow,RuntimeError> {
+    let target = match cell.arg {
+        Arg::Target(n) => n,
+        _ => { return Ok(Flow::Bail); }
+    };
+    let truthy = match vm.stack.peek().map(Data::is_truthy) {
+        Some(Ok(n)) => n,
+        _ => { return Ok(Flow::Bail); }
+    };
+
+    let _ = vm.stack.pop();
+    literal(vm, cell);
+
+    if truthy == when { Ok(go(vm, target)) } else { Ok(Flow::Next) }
+}
+
+fn jump_if<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    branch(vm, cell, true)
+}
+
+fn jump_unless<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    branch(vm, cell, false)
+}
+
+fn call<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    let target = match cell.arg {
+        Arg::Target(n) => n,
+        _ => { return Ok(Flow::Bail); }
+    };
+    if vm.rstack.len() >= vm.config.max_return_depth {
+        return Ok(Flow::Bail);
+    }
+
+    vm.rstack.push(cell.next);
+    vm.log_call(target.pc);
+    literal(vm, cell);
+
+    //A word calling itself stays in its own code.
+    match target.cell {
+        Some(0) => Ok(Flow::Goto(0)),
+        _ => {
+            vm.pc = target.pc;
+            Ok(Flow::Call(target.pc))
+        },
+    }
+}
+
+fn ret<S: Storage, M: Storage>(vm: &mut Vm<S, M>, _: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    //The interpreter finishes a `catch` the return may end.
+    if !vm.catches.is_empty() {
+        return Ok(Flow::Bail);
+    }
+
+    match vm.rstack.pop() {
+        //The interpreter halts.
+        None => Ok(Flow::Bail),
+        Some(home) => {
+            vm.pc = home;
+            Ok(Flow::Leave)
+        },
+    }
+}
+
+///Return, looking for the cell returned to in a word that calls itself.
+fn ret_inner<S: Storage, M: Storage>(vm: &mut Vm<S, M>, _: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    if !vm.catches.is_empty() {
+        return Ok(Flow::Bail);
+    }
+
+    match vm.rstack.pop() {
+        None => Ok(Flow::Bail),
+        Some(home) => {
+            vm.pc = home;
+            Ok(Flow::Goto(usize::MAX))
+        },
+    }
+}
+
+///Run a built-in opcode through the interpreter. Carries on in the
+///interpreter if it moved the PC, as a `catch` does, or the error it
+///raised was caught.
+fn byte<S: Storage, M: Storage>(vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+    let opcode = match cell.arg {
+        Arg::Byte(n) => n,
+        _ => { return Ok(Flow::Bail); }
+    };
+
+    vm.pc = cell.pc;
+    vm.execute(opcode, &mut NullExtender {})?;
+
+    if vm.pc != cell.next { Ok(Flow::Leave) } else { Ok(Flow::Next) }
+}
+
+///Wrap a stack operation as a handler, failing from the byte after it
+///like the interpreter. A fast path for two ints, if given, is tried
+///first, and otherwise ints are promoted to floats if so configured.
+macro_rules! handler {
+    ($name:ident, $vm:ident => $op:expr) => {
+        fn $name<S: Storage, M: Storage>($vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+            match $op {
+                Ok(_) => Ok(Flow::Next),
+                Err(n) => {
+                    $vm.pc = cell.pc + 1;
+                    Err(RuntimeError::new(cell.pc + 1, n))
+                },
+            }
+        }
+    };
+    ($name:ident, $vm:ident => $op:expr, |$y:ident, $x:ident| $ints:expr) => {
+        fn $name<S: Storage, M: Storage>($vm: &mut Vm<S, M>, cell: &Cell<S, M>) -> Result<Flow,RuntimeError> {
+            if $vm.stack.combine_ints(|$y: i64, $x: i64| $ints) {
+                return Ok(Flow::Next);
+            }
+            if $vm.config.coercion == CoercionPolicy::Promote {
+                $vm.stack.promote();
+            }
+
+            match $op {
+                Ok(_) => Ok(Flow::Next),
+                Err(n) => {
+                    $vm.pc = cell.pc + 1;
+                    Err(RuntimeError::new(cell.pc + 1, n))
+                },
+            }
+        }
+    };
+}
+
+handler!(add, vm => vm.stack.add_with(vm.config.arithmetic), |y, x| y.checked_add(x).map(Data::Int));
+handler!(sub, vm => vm.stack.sub_with(vm.config.arithmetic), |y, x| y.checked_sub(x).map(Data::Int));
+handler!(mul, vm => vm.stack.mul_with(vm.config.arithmetic), |y, x| y.checked_mul(x).map(Data::Int));
+handler!(lt, vm => vm.stack.lt(), |y, x| Some(Data::from_bool(y < x)));
+handler!(eq, vm => vm.stack.eq(), |y, x| Some(Data::from_bool(y == x)));
+handler!(gt, vm => vm.stack.gt(), |y, x| Some(Data::from_bool(y > x)));
+handler!(dup, vm => vm.stack.dup());
+handler!(drop, vm => vm.stack.pop());
+handler!(swap, vm => vm.stack.swap());
+handler!(over, vm => vm.stack.over());
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    use super::Word;
+    use {ArithmeticPolicy, CoercionPolicy, NullExtender, Vm};
+
+    #[test]
+    fn threaded_matches_bytes() {
+        let programs: [&[u8]; 8] = [
+            //A word with a loop in it, called three times.
+            b"#00026'b  #1'-d#00009'yr; #3'#00009'c#4'#00009'c#5'#00009'c",
+            //A word calling another through one that falls back on `R`.
+            b"#00031'b  #1'+;  #0'R#00009'c; #00016'c#00016'c#00016'c",
+            //Recursion down to zero.
+            b"#00035'b  d#00032'z#1'-#00009'c  ; #6'#00009'c#2'#00009'c",
+            //A word that fails part of the way through.
+            b"#00018'b  d*[x]+; #2'#00009'c#3'#00009'c",
+            //An early return.
+            b"#00028'b  d#00021'y;  #1'+; #0'#00009'c#1'#00009'c#0'#00009'c",
+            //Doubling until it overflows.
+            b"#00023'b  d+d#00009'y; #1'#00009'c",
+            //A word caught three times, throwing once.
+            b"#00017'b d#3'>!t;#2'#00021$'!c#5'#00034$'!c#1'#00047$'!c",
+            //Adding ints to a float.
+            b"#00016'b  #1'+; #5\"#00009'c#00009'c#00009'c",
+        ];
+
+        let policies = [ArithmeticPolicy::Wrapping, ArithmeticPolicy::Saturating, ArithmeticPolicy::Trapping];
+        let coercions = [CoercionPolicy::Strict, CoercionPolicy::Promote];
+        let configs: Vec<_> = policies.iter().flat_map(|&p| coercions.iter().map(move |&c| (p, c))).collect();
+        for (code, &(policy, coercion)) in programs.iter().flat_map(|n| configs.iter().map(move |p| (n, p))) {
+            for fuel in 0..1024 {
+                let mut threaded = Vm::new(code.to_vec(), vec![::Data::Int(0)]);
+                let mut raw = Vm::new(code.to_vec(), vec![::Data::Int(0)]);
+                threaded.config.hot_threshold = Some(1);
+                threaded.config.max_steps = Some(fuel);
+                raw.config.max_steps = Some(fuel);
+                threaded.config.arithmetic = policy;
+                raw.config.arithmetic = policy;
+                threaded.config.coercion = coercion;
+                raw.config.coercion = coercion;
+
+                let a = threaded.run(&mut NullExtender {}).map_err(|n| (n.pc, n.kind.to_string()));
+                let b = raw.run_bytes(&mut NullExtender {}).map_err(|n| (n.pc, n.kind.to_string()));
+                assert_eq!(a, b, "{:?} with fuel {}", ::core::str::from_utf8(code), fuel);
+                assert_eq!(threaded.stack.to_string(), raw.stack.to_string());
+                assert_eq!((threaded.pc, &threaded.rstack, threaded.value), (raw.pc, &raw.rstack, raw.value));
+                assert_eq!(threaded.catches, raw.catches);
+            }
+        }
+
+        let mut vm = Vm::new(programs[2].to_vec(), Vec::new());
+        vm.config.hot_threshold = Some(2);
+        vm.run(&mut NullExtender {}).unwrap();
+        assert_eq!(vm.stack.to_string(), "<2> 0 0");
+        assert!(matches!(vm.words[9], Word::Compiled(_)));
+    }
+}
@@ -0,0 +1,195 @@
+//!Bindings for running greengold from JavaScript, behind the `wasm`
+//!feature. Build a module with
+//!
+//!```text
+//!cargo rustc --lib --release --features wasm --target wasm32-unknown-unknown --crate-type cdylib
+//!```
+//!
+//!and run `wasm-bindgen` over the result.
+//!
+//!A `Machine` keeps its compiler between calls like the REPL, so words
+//!defined by one `compile` can be used by the next. Errors are thrown as
+//!strings, and output goes to the callback given to `onOutput`:
+//!
+//!```js
+//!const machine = new Machine(1024);
+//!machine.onOutput(text => console.log(text));
+//!machine.compile(": square dup * ; 7 square .");
+//!machine.run();
+//!machine.stack();
+//!```
+//!
+//!`evaluate` runs a snippet in one call, in a fresh sandbox each time,
+//!for playgrounds that don't need words to carry over.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use js_sys::{Array, Function, Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+use compiler::{Compiler, TokenKind};
+use input::NullInput;
+use output::{NullOutput, OutputSink};
+use playground::EvalOptions;
+use {Data, Error, NullExtender, Vm};
+
+///Compile source to bytecode that starts at PC 0.
+#[wasm_bindgen]
+pub fn compile(source: &str) -> Result<Vec<u8>,JsValue> {
+    ::compiler::compile(source).map_err(throw)
+}
+
+///Split source into tokens for highlighting, each an array of its kind
+///and the UTF-16 offsets it starts and ends at, as JavaScript strings
+///count them.
+#[wasm_bindgen]
+pub fn tokenize(source: &str) -> Array {
+    let units = |offset: usize| source[..offset].encode_utf16().count() as u32;
+    ::compiler::tokenize(source).into_iter().map(|token| {
+        let kind = match token.kind {
+            TokenKind::Literal => "literal",
+            TokenKind::Definition => "definition",
+            TokenKind::Call => "call",
+            TokenKind::Comment => "comment",
+            TokenKind::Control => "control",
+        };
+        let item = Array::new();
+        item.push(&JsValue::from_str(kind));
+        item.push(&JsValue::from(units(token.span.start)));
+        item.push(&JsValue::from(units(token.span.end)));
+        JsValue::from(item)
+    }).collect()
+}
+
+///Compile and run a snippet in a fresh sandbox, with `input` to read and
+///at most `max_steps` instructions, and get an object with its `stack`,
+///`output`, `error` (a string, or null) and the `steps` it took.
+#[wasm_bindgen]
+pub fn evaluate(source: &str, input: String, max_steps: u32) -> Object {
+    let mut options = EvalOptions { input, ..EvalOptions::default() };
+    options.sandbox.max_steps = u64::from(max_steps);
+    let result = ::playground::evaluate(source, &options);
+
+    let stack: Array = result.stack.iter().map(value).collect();
+    let error = match result.error {
+        Some(n) => JsValue::from_str(&n.to_string()),
+        None => JsValue::NULL,
+    };
+    let out = Object::new();
+    let _ = Reflect::set(&out, &JsValue::from_str("stack"), &stack);
+    let _ = Reflect::set(&out, &JsValue::from_str("output"), &JsValue::from_str(&result.output));
+    let _ = Reflect::set(&out, &JsValue::from_str("error"), &error);
+    let _ = Reflect::set(&out, &JsValue::from_str("steps"), &JsValue::from_f64(result.stats.steps as f64));
+
+    out
+}
+
+///Turn a value into JavaScript. Numbers become numbers, strings become
+///strings, and arrays and maps become their handles.
+fn value(data: &Data) -> JsValue {
+    match *data {
+        Data::Int(n) => JsValue::from_f64(n as f64),
+        Data::Float(n) => JsValue::from_f64(n),
+        Data::Str(ref n) => JsValue::from_str(n),
+        Data::Array(n) | Data::Map(n) => JsValue::from_f64(n as f64),
+    }
+}
+
+fn throw<E: ToString>(err: E) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+///Hands output to a JavaScript function as a string.
+struct Callback {
+    function: Function,
+}
+
+impl OutputSink for Callback {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(),Error> {
+        let text = JsValue::from_str(&String::from_utf8_lossy(bytes));
+        match self.function.call1(&JsValue::NULL, &text) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::Io(::std::io::Error::other("output callback threw"))),
+        }
+    }
+}
+
+///A machine and the compiler feeding it.
+#[wasm_bindgen]
+pub struct Machine {
+    vm: Vm,
+    compiler: Compiler,
+}
+
+#[wasm_bindgen]
+impl Machine {
+    ///Create a machine with some cells of memory. Output is discarded
+    ///until `onOutput` is called, and input is always at its end.
+    #[wasm_bindgen(constructor)]
+    pub fn new(memory: usize) -> Machine {
+        let mut vm = Vm::new(Vec::new(), vec![Data::Int(0); memory]);
+        vm.output = Box::new(NullOutput {});
+        vm.input = Box::new(NullInput {});
+
+        Machine {
+            vm,
+            compiler: Compiler::new(),
+        }
+    }
+
+    ///Send output to a function taking a string.
+    #[wasm_bindgen(js_name = onOutput)]
+    pub fn on_output(&mut self, callback: Function) {
+        self.vm.output = Box::new(Callback { function: callback });
+    }
+
+    ///Limit how many instructions each `run` may execute.
+    #[wasm_bindgen(js_name = setMaxSteps)]
+    pub fn set_max_steps(&mut self, steps: Option<u32>) {
+        self.vm.config.max_steps = steps.map(u64::from);
+    }
+
+    ///Compile source onto the end of the program and load it, ready to
+    ///run from the start of the new code.
+    pub fn compile(&mut self, source: &str) -> Result<(),JsValue> {
+        let entry = self.compiler.compile(source).map_err(throw)?;
+        self.vm.load(self.compiler.module().code.clone()).map_err(throw)?;
+        self.vm.constants = self.compiler.module().constants.clone();
+        self.vm.pc = entry;
+
+        Ok(())
+    }
+
+    ///Load raw bytecode, replacing the program and the compiler's words.
+    pub fn load(&mut self, code: Vec<u8>) -> Result<(),JsValue> {
+        self.compiler = Compiler::new();
+        self.vm.load(code).map_err(throw)
+    }
+
+    ///Run until the program halts or fails.
+    pub fn run(&mut self) -> Result<(),JsValue> {
+        self.vm.run(&mut NullExtender {}).map_err(throw)
+    }
+
+    ///Get the data stack, bottom first. Numbers become numbers, strings
+    ///become strings, and arrays and maps become their handles.
+    pub fn stack(&self) -> Array {
+        self.vm.stack.iter().map(value).collect()
+    }
+
+    ///Get the data stack as the REPL prints it, like `<2> 1 "a"`.
+    #[wasm_bindgen(js_name = stackString)]
+    pub fn stack_string(&self) -> String {
+        self.vm.stack.to_string()
+    }
+
+    ///Get the program counter.
+    pub fn pc(&self) -> usize {self.vm.pc}
+
+    ///Empty the stacks and go back to PC 0, keeping memory.
+    pub fn reset(&mut self) {
+        self.vm.reset();
+    }
+}
@@ -0,0 +1,22 @@
+//!Runs the programs in `tests/golden` against their `.expected` files.
+//!Set `GREENGOLD_BLESS` to write the files from the current output.
+
+extern crate greengold;
+
+use std::env;
+use std::path::Path;
+
+use greengold::testing;
+
+#[test]
+fn golden() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+
+    if env::var_os("GREENGOLD_BLESS").is_some() {
+        testing::bless_dir(&dir).unwrap();
+        return;
+    }
+
+    let report = testing::run_dir(&dir).unwrap();
+    assert!(report.is_success(), "\n{}", report);
+}